@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use dns_name;
+use journal;
+use notify;
+use query_type::QueryType;
+use zone::Zone;
+use zone_config::ZoneConfig;
+use zone_parser::ZoneParser;
+
+/// The zones this server is authoritative for.
+pub struct Authority {
+    // Zones by lowercased name
+    zones: RwLock<HashMap<String, Arc<Zone>>>,
+    configs: Vec<ZoneConfig>,
+}
+
+/// The zone in `file`, with the changes dynamic updates have made since.
+fn load_zone(config: &ZoneConfig, file: &str) -> Result<Zone, Error> {
+    let records = try!(ZoneParser::load(file, &config.name));
+    let zone = try!(Zone::new(&config.name, records));
+    let diffs = try!(journal::read(&journal::path(file), &config.name));
+    journal::replay(zone, diffs)
+}
+
+impl Authority {
+    pub fn new(configs: Vec<ZoneConfig>) -> Authority {
+        Authority {
+            zones: RwLock::new(HashMap::new()),
+            configs: configs,
+        }
+    }
+
+    /// Reads the zone files of `configs`. Secondary zones start out with the
+    /// copy in their file if there is one, and are otherwise empty until
+    /// they're transferred.
+    pub fn load(configs: &[ZoneConfig]) -> Result<Authority, Error> {
+        let authority = Authority::new(configs.to_vec());
+        for config in configs {
+            let file = match config.file {
+                Some(ref x) => x,
+                None => continue,
+            };
+            if config.is_secondary() && !Path::new(file).exists() {
+                continue;
+            }
+            let zone = try!(load_zone(config, file));
+            info!(
+                "Loaded zone {} with serial {} from {}",
+                config.name,
+                zone.serial(),
+                file
+            );
+            authority.insert(zone);
+        }
+        Ok(authority)
+    }
+
+    /// Reads the zone files again. Zones whose file has become unreadable
+    /// keep their current version, and secondary zones are left to their
+    /// primaries.
+    pub fn reload(&self) {
+        for config in &self.configs {
+            let file = match config.file {
+                Some(ref x) if !config.is_secondary() => x,
+                _ => continue,
+            };
+            match load_zone(config, file) {
+                Ok(zone) => {
+                    let serial = zone.serial();
+                    if self.insert(zone) {
+                        info!("Reloaded zone {} with serial {}", config.name, serial);
+                    }
+                }
+                Err(e) => error!("Failed to reload zone {}: {}", config.name, e),
+            }
+        }
+    }
+
+    /// Adds `zone`, replacing any earlier version of it, keeping track of
+    /// what changed and telling the zone's secondaries. A version with the
+    /// same serial as the current one is ignored, and false returned.
+    pub fn insert(&self, mut zone: Zone) -> bool {
+        let name = zone.name.to_ascii_lowercase();
+        let mut zones = write(&self.zones);
+        if let Some(old) = zones.get(&name) {
+            if zone.serial() == old.serial() {
+                if zone.records() != old.records() {
+                    warn!(
+                        "Zone {} changed without its serial {} changing, keeping the old data",
+                        zone.name,
+                        zone.serial()
+                    );
+                }
+                return false;
+            }
+            zone.follow(old);
+        }
+        if let Some(config) = self.config(&name) {
+            notify::send(&zone.name, zone.soa(), &config.notify);
+        }
+        zones.insert(name, Arc::new(zone));
+        true
+    }
+
+    /// Stops serving the zone `name`.
+    pub fn remove(&self, name: &str) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        write(&self.zones).remove(&name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Zone>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        read(&self.zones).get(&name).cloned()
+    }
+
+    /// The closest zone holding `name`. DS records are looked up in the
+    /// parent of a zone's apex when we have it (RFC 4035 section 3.1.4.1).
+    pub fn find(&self, name: &str, qtype: QueryType) -> Option<Arc<Zone>> {
+        let name = name.to_ascii_lowercase();
+        let zones = read(&self.zones);
+
+        let mut found = None;
+        for ancestor in dns_name::ancestors(&name) {
+            if let Some(zone) = zones.get(ancestor) {
+                if found.is_some() {
+                    return Some(zone.clone());
+                }
+                found = Some(zone.clone());
+                if qtype != QueryType::DS || ancestor != name.trim_end_matches('.') {
+                    break;
+                }
+            }
+        }
+        found
+    }
+
+    pub fn config(&self, name: &str) -> Option<&ZoneConfig> {
+        let name = name.trim_end_matches('.');
+        self.configs
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The closest configured zone holding `name`, whether or not we have
+    /// its data.
+    pub fn configured(&self, name: &str) -> Option<&ZoneConfig> {
+        dns_name::ancestors(name)
+            .into_iter()
+            .filter_map(|ancestor| self.config(ancestor))
+            .next()
+    }
+
+    /// True if `client` may transfer the zone `name`.
+    pub fn allows_transfer(&self, name: &str, client: IpAddr, key: Option<&str>) -> bool {
+        match self.config(name) {
+            Some(config) => config.allows_transfer(client, key),
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        read(&self.zones).is_empty()
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<T> {
+    match lock.read() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<T> {
+    match lock.write() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
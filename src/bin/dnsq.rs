@@ -0,0 +1,155 @@
+extern crate dns;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+use dns::byte_packet_buffer::BytePacketBuffer;
+use dns::dns_packet::DnsPacket;
+use dns::query_command_line::{QueryCommandLine, USAGE};
+use dns::resolver::random_id;
+use dns::tsig::{self, Signer};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The first nameserver in /etc/resolv.conf.
+fn system_resolver() -> Option<IpAddr> {
+    let file = match File::open("/etc/resolv.conf") {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        let mut fields = line.split_whitespace();
+        if fields.next() == Some("nameserver") {
+            if let Some(addr) = fields.next().and_then(|addr| addr.parse::<IpAddr>().ok()) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Sends `request`, signed by `signer` if given, and returns the raw
+/// response.
+fn exchange(
+    request: &mut DnsPacket,
+    signer: Option<&mut Signer>,
+    server: SocketAddr,
+    tcp: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut req_buffer = BytePacketBuffer::with_size(0xFFFF);
+    try!(request.write(&mut req_buffer));
+    if let Some(signer) = signer {
+        try!(signer.sign(&mut req_buffer, tsig::now()));
+    }
+    let len = req_buffer.pos();
+    let data = try!(req_buffer.get_range(0, len)).to_vec();
+
+    if tcp {
+        let mut stream = try!(TcpStream::connect_timeout(&server, TIMEOUT));
+        try!(stream.set_read_timeout(Some(TIMEOUT)));
+        try!(stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8]));
+        try!(stream.write_all(&data));
+
+        let mut len_buf = [0u8; 2];
+        try!(stream.read_exact(&mut len_buf));
+        let mut response = vec![0u8; ((len_buf[0] as usize) << 8) | len_buf[1] as usize];
+        try!(stream.read_exact(&mut response));
+        return Ok(response);
+    }
+
+    let local: SocketAddr = if server.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(TIMEOUT)));
+    try!(socket.send_to(&data, server));
+
+    loop {
+        let mut response = vec![0u8; 0xFFFF];
+        let (len, src) = try!(socket.recv_from(&mut response));
+        // Skip anything that isn't the reply to our query
+        if src == server && len >= 2 && response[0..2] == data[0..2] {
+            response.truncate(len);
+            return Ok(response);
+        }
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let query = match QueryCommandLine::parse(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\nRun with --help for usage.", e);
+            process::exit(2);
+        }
+    };
+
+    let server = match query.server.or_else(system_resolver) {
+        Some(x) => SocketAddr::new(x, query.port),
+        None => SocketAddr::from(([127, 0, 0, 1], query.port)),
+    };
+
+    let mut request = query.packet(random_id());
+    let mut signer = query.key.clone().map(Signer::new);
+    let started = Instant::now();
+    let response = match exchange(&mut request, signer.as_mut(), server, query.tcp) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            eprintln!(";; connection timed out; no servers could be reached");
+            process::exit(9);
+        }
+        Err(e) => {
+            eprintln!(";; query failed: {}", e);
+            process::exit(9);
+        }
+    };
+    let elapsed = started.elapsed();
+
+    let mut res_buffer = BytePacketBuffer::with_size(response.len());
+    res_buffer.buf.copy_from_slice(&response);
+    let packet = match DnsPacket::from_buffer(&mut res_buffer) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!(";; malformed response: {}", e);
+            process::exit(9);
+        }
+    };
+
+    println!("; <<>> dnsq <<>> {}", args.join(" "));
+    println!("{}", packet);
+    if let Some(ref mut signer) = signer {
+        match signer.verify(&response, tsig::now()) {
+            Ok(()) => println!(";; TSIG signature verified with key {}", signer.key.name),
+            Err(e) => println!(";; Couldn't verify signature: {}", e),
+        }
+    }
+    println!(
+        ";; Query time: {} msec",
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    );
+    println!(
+        ";; SERVER: {}#{}({}) ({})",
+        server.ip(),
+        server.port(),
+        server.ip(),
+        if query.tcp { "TCP" } else { "UDP" }
+    );
+    println!(";; MSG SIZE  rcvd: {}", response.len());
+}
@@ -0,0 +1,94 @@
+extern crate dns;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::process;
+
+use dns::update_script::{parse_key, Script};
+
+const USAGE: &str = "Usage: dnsupdate [-y [alg:]name:secret] [-v] [file]
+
+Sends dynamic updates (RFC 2136) built from a script, read from file or
+standard input, and prints the server's response code for each.
+
+Script commands:
+  server address [port]          Server to send updates to (127.0.0.1 53)
+  zone name                      Zone to update
+  key [alg:]name secret          Sign updates with a TSIG key, hmac-sha256
+                                 unless another algorithm is given
+  ttl seconds                    TTL for added records that don't give one
+  prereq nxdomain name           Require that name has no records
+  prereq yxdomain name           Require that name has records
+  prereq nxrrset name type       Require that name has no records of type
+  prereq yxrrset name type [data]
+                                 Require that name has records of type, or
+                                 exactly the records given
+  update add name [ttl] type data
+                                 Add a record
+  update delete name [type [data]]
+                                 Delete every record at name, its records
+                                 of type, or the record given
+  send                           Send the update built so far; so does an
+                                 empty line
+
+Options:
+  -y [alg:]name:secret  Same as the key command
+  -v                    Send updates over TCP instead of UDP
+  -h, --help            Show this help
+
+Exits with 1 if an update failed, and 2 if the script is invalid.";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let mut script = Script::new();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let result = match arg.as_str() {
+            "-v" => {
+                script.tcp = true;
+                Ok(())
+            }
+            "-y" => match args.next() {
+                Some(key) => parse_key(key).map(|key| script.key = Some(key)),
+                None => Err("-y needs a key".to_string()),
+            },
+            _ if arg.starts_with('-') => Err(format!("Unknown option {}", arg)),
+            _ if path.is_none() => {
+                path = Some(arg.clone());
+                Ok(())
+            }
+            _ => Err(format!("Unexpected argument {}", arg)),
+        };
+        if let Err(e) = result {
+            eprintln!("{}\nRun with --help for usage.", e);
+            process::exit(2);
+        }
+    }
+
+    let (name, input): (String, Box<dyn Read>) = match path {
+        Some(path) => match File::open(&path) {
+            Ok(file) => (path, Box::new(file)),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(2);
+            }
+        },
+        None => ("<stdin>".to_string(), Box::new(io::stdin())),
+    };
+
+    match script.run(&name, BufReader::new(input), &mut io::stdout()) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
+}
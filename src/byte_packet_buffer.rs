@@ -1,14 +1,18 @@
 use std::io::{Error, ErrorKind};
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(512)
+    }
+
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -17,18 +21,13 @@ impl BytePacketBuffer {
         self.pos
     }
 
-    pub fn step(&mut self, steps: usize) -> Result<(), Error> {
-        self.pos += steps;
-        Ok(())
-    }
-
     fn seek(&mut self, pos: usize) -> Result<(), Error> {
         self.pos = pos;
         Ok(())
     }
 
     fn read(&mut self) -> Result<u8, Error> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
 
@@ -38,14 +37,14 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8, Error> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8], Error> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         Ok(&self.buf[start..start + len as usize])
@@ -56,6 +55,17 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        self.read()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let start = self.pos;
+        let res = try!(self.get_range(start, len)).to_vec();
+        self.pos += len;
+        Ok(res)
+    }
+
     pub fn read_u32(&mut self) -> Result<u32, Error> {
         let res = ((try!(self.read()) as u32) << 24) | ((try!(self.read()) as u32) << 16)
             | ((try!(self.read()) as u32) << 8)
@@ -90,7 +100,7 @@ impl BytePacketBuffer {
                 outstr.push_str(delim);
 
                 let str_buffer = try!(self.get_range(pos, len as usize));
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
                 delim = ".";
 
@@ -106,7 +116,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<(), Error> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
         }
         self.buf[self.pos] = val;
@@ -133,12 +143,19 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    pub fn write_bytes(&mut self, val: &[u8]) -> Result<(), Error> {
+        for b in val {
+            try!(self.write(*b));
+        }
+        Ok(())
+    }
+
     pub fn write_qname(&mut self, qname: &str) -> Result<(), Error> {
-        // let splitted_str = qname.split('.').collect::<Vec<&str>>();
-        let splitted_str = qname.split('.');
+        // The root name is "" and has no labels at all
+        let splitted_str = qname.split('.').filter(|label| !label.is_empty());
         for label in splitted_str {
             let len = label.len();
-            if len > 0x34 {
+            if len > 0x3F {
                 return Err(Error::new(
                     ErrorKind::InvalidInput,
                     "Single label exceeds 63 character of length",
@@ -0,0 +1,390 @@
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use dns_packet::DnsPacket;
+use dns_record::DnsRecord;
+use dnssec::Security;
+use metrics::METRICS;
+use query_type::QueryType;
+use result_code::ResultCode;
+
+// Longest we keep anything, whatever its TTL says
+const MAX_TTL: u32 = 86400;
+// RFC 8767 section 4: after a failed refresh, serve stale data without
+// retrying for this long
+const FAILURE_RECHECK: Duration = Duration::from_secs(30);
+
+/// Name (lowercased), type, class and CD bit of a cached answer.
+pub type CacheKey = (String, QueryType, u16, bool);
+
+pub enum CacheLookup {
+    /// The flag asks for a refresh, as the answer is popular and about to
+    /// expire.
+    Fresh(DnsPacket, Security, bool),
+    /// Expired, but within the stale window. The flag tells whether a
+    /// refresh failed recently.
+    Stale(DnsPacket, Security, bool),
+    Miss,
+}
+
+struct CacheEntry {
+    packet: DnsPacket,
+    security: Security,
+    stored: Instant,
+    ttl: u32,
+    failed: Option<Instant>,
+    // Lookups since the answer was stored, and whether it's being prefetched
+    hits: u32,
+    prefetching: bool,
+}
+
+impl CacheEntry {
+    fn age(&self, now: Instant) -> u32 {
+        now.duration_since(self.stored).as_secs() as u32
+    }
+
+    fn expires(&self) -> Instant {
+        self.stored + Duration::from_secs(self.ttl as u64)
+    }
+}
+
+/// Resolved answers, kept for their TTL and then for `max_stale` seconds
+/// more in case upstreams fail (RFC 8767).
+///
+/// Answers looked up at least `prefetch_hits` times are refreshed once
+/// they're within the last `prefetch_percent` of their TTL.
+pub struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // The entries again, soonest to expire first, for eviction
+    expiring: BTreeSet<(Instant, CacheKey)>,
+    max_entries: usize,
+    max_stale: u32,
+    prefetch_hits: u32,
+    prefetch_percent: u32,
+}
+
+impl Cache {
+    pub fn new(
+        max_entries: usize,
+        max_stale: u32,
+        prefetch_hits: u32,
+        prefetch_percent: u32,
+    ) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            expiring: BTreeSet::new(),
+            max_entries: max_entries,
+            max_stale: max_stale,
+            prefetch_hits: prefetch_hits,
+            prefetch_percent: prefetch_percent,
+        }
+    }
+
+    /// The answer cached for `key`, with TTLs counted down, or set to
+    /// `stale_ttl` if it has expired.
+    pub fn get(&mut self, key: &CacheKey, stale_ttl: u32) -> CacheLookup {
+        let now = Instant::now();
+        let expired = match self.entries.get(key) {
+            Some(entry) => entry.age(now) >= entry.ttl.saturating_add(self.max_stale),
+            None => return CacheLookup::Miss,
+        };
+        if expired {
+            self.remove(key);
+            return CacheLookup::Miss;
+        }
+
+        let (prefetch_hits, prefetch_percent) = (self.prefetch_hits, self.prefetch_percent);
+        let entry = match self.entries.get_mut(key) {
+            Some(x) => x,
+            None => return CacheLookup::Miss,
+        };
+        let age = entry.age(now);
+        let mut packet = entry.packet.clone();
+        if age < entry.ttl {
+            entry.hits = entry.hits.saturating_add(1);
+            let remaining = (entry.ttl - age) as u64;
+            let prefetch = !entry.prefetching && entry.hits >= prefetch_hits
+                && remaining * 100 <= entry.ttl as u64 * prefetch_percent as u64;
+            if prefetch {
+                entry.prefetching = true;
+            }
+
+            set_ttls(&mut packet, |ttl| ttl.saturating_sub(age));
+            return CacheLookup::Fresh(packet, entry.security, prefetch);
+        }
+
+        set_ttls(&mut packet, |ttl| cmp::min(ttl, stale_ttl));
+        let recently_failed = match entry.failed {
+            Some(failed) => now.duration_since(failed) < FAILURE_RECHECK,
+            None => false,
+        };
+        CacheLookup::Stale(packet, entry.security, recently_failed)
+    }
+
+    /// Caches `packet` if it's a definite answer.
+    pub fn insert(&mut self, key: CacheKey, packet: &DnsPacket, security: Security) {
+        if let Security::Bogus(_) = security {
+            return;
+        }
+        let ttl = match cache_ttl(packet) {
+            Some(ttl) if ttl > 0 => cmp::min(ttl, MAX_TTL),
+            _ => return,
+        };
+
+        if !self.remove(&key) && self.entries.len() >= self.max_entries {
+            self.evict();
+        }
+
+        let entry = CacheEntry {
+            packet: packet.clone(),
+            security: security,
+            stored: Instant::now(),
+            ttl: ttl,
+            failed: None,
+            hits: 0,
+            prefetching: false,
+        };
+        self.expiring.insert((entry.expires(), key.clone()));
+        self.entries.insert(key, entry);
+    }
+
+    /// Notes that refreshing `key` failed. A prefetch may try again.
+    pub fn mark_failed(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.failed = Some(Instant::now());
+            entry.prefetching = false;
+        }
+    }
+
+    /// Notes that the prefetch asked for by the last lookup of `key` won't
+    /// happen, so a later lookup may ask again.
+    pub fn cancel_prefetch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.prefetching = false;
+        }
+    }
+
+    /// Removes `key`, telling whether it was there.
+    fn remove(&mut self, key: &CacheKey) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => self.expiring.remove(&(entry.expires(), key.clone())),
+            None => false,
+        }
+    }
+
+    /// Drops everything past its stale window, or failing that, whatever
+    /// expires first.
+    fn evict(&mut self) {
+        let now = Instant::now();
+        let max_stale = Duration::from_secs(self.max_stale as u64);
+        let before = self.entries.len();
+        loop {
+            let key = match self.expiring.iter().next() {
+                Some(&(expires, ref key)) => {
+                    let full = self.entries.len() >= self.max_entries;
+                    if expires + max_stale > now && !full {
+                        break;
+                    }
+                    key.clone()
+                }
+                None => break,
+            };
+            self.remove(&key);
+        }
+        METRICS.cache_evictions(before - self.entries.len());
+    }
+}
+
+/// How long `packet` may be cached: the lowest TTL of its answers, or for
+/// negative answers the SOA TTL capped at its minimum (RFC 2308).
+fn cache_ttl(packet: &DnsPacket) -> Option<u32> {
+    match packet.header.rescode {
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+        _ => return None,
+    }
+
+    if !packet.answers.is_empty() {
+        return packet.answers.iter().map(|rec| rec.get_ttl()).min();
+    }
+
+    packet
+        .authorities
+        .iter()
+        .filter_map(|rec| match *rec {
+            DnsRecord::SOA { minimum, ttl, .. } => Some(cmp::min(minimum, ttl)),
+            _ => None,
+        })
+        .min()
+}
+
+fn set_ttls<F: Fn(u32) -> u32>(packet: &mut DnsPacket, f: F) {
+    for rec in packet
+        .answers
+        .iter_mut()
+        .chain(packet.authorities.iter_mut())
+        .chain(packet.resources.iter_mut())
+    {
+        let ttl = rec.get_ttl();
+        rec.set_ttl(f(ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn answer(ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: ttl,
+        });
+        packet
+    }
+
+    fn key() -> CacheKey {
+        ("example.com".to_string(), QueryType::A, 1, false)
+    }
+
+    fn age(cache: &mut Cache, secs: u64) {
+        for entry in cache.entries.values_mut() {
+            entry.stored -= Duration::from_secs(secs);
+        }
+        cache.expiring = cache
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.expires(), key.clone()))
+            .collect();
+    }
+
+    #[test]
+    fn serves_fresh_then_stale_then_nothing() {
+        let mut cache = Cache::new(10, 100, 0, 0);
+        cache.insert(key(), &answer(60), Security::Secure);
+
+        age(&mut cache, 20);
+        match cache.get(&key(), 30) {
+            CacheLookup::Fresh(packet, Security::Secure, false) => {
+                assert_eq!(packet.answers[0].get_ttl(), 40)
+            }
+            _ => panic!("expected a fresh answer"),
+        }
+
+        age(&mut cache, 60);
+        match cache.get(&key(), 30) {
+            CacheLookup::Stale(packet, _, false) => assert_eq!(packet.answers[0].get_ttl(), 30),
+            _ => panic!("expected a stale answer"),
+        }
+
+        cache.mark_failed(&key());
+        match cache.get(&key(), 30) {
+            CacheLookup::Stale(_, _, true) => {}
+            _ => panic!("expected a recently failed stale answer"),
+        }
+
+        age(&mut cache, 100);
+        match cache.get(&key(), 30) {
+            CacheLookup::Miss => {}
+            _ => panic!("expected the entry to be gone"),
+        }
+    }
+
+    fn prefetch_wanted(cache: &mut Cache) -> bool {
+        match cache.get(&key(), 30) {
+            CacheLookup::Fresh(_, _, prefetch) => prefetch,
+            _ => panic!("expected a fresh answer"),
+        }
+    }
+
+    #[test]
+    fn prefetches_popular_answers_once() {
+        let mut cache = Cache::new(10, 100, 3, 10);
+        cache.insert(key(), &answer(100), Security::Insecure);
+
+        // Popular, but not about to expire
+        for _ in 0..3 {
+            assert!(!prefetch_wanted(&mut cache));
+        }
+
+        age(&mut cache, 90);
+        assert!(prefetch_wanted(&mut cache));
+        // Already on its way
+        assert!(!prefetch_wanted(&mut cache));
+
+        // The refreshed answer has to earn its hits again
+        cache.insert(key(), &answer(100), Security::Insecure);
+        age(&mut cache, 95);
+        assert!(!prefetch_wanted(&mut cache));
+    }
+
+    #[test]
+    fn prefetches_again_after_a_failed_refresh() {
+        let mut cache = Cache::new(10, 100, 0, 10);
+        cache.insert(key(), &answer(100), Security::Insecure);
+        age(&mut cache, 90);
+        assert!(prefetch_wanted(&mut cache));
+
+        cache.mark_failed(&key());
+        assert!(prefetch_wanted(&mut cache));
+
+        cache.cancel_prefetch(&key());
+        assert!(prefetch_wanted(&mut cache));
+        assert!(!prefetch_wanted(&mut cache));
+    }
+
+    #[test]
+    fn skips_bogus_and_failed_answers() {
+        let mut cache = Cache::new(10, 100, 0, 0);
+        cache.insert(key(), &answer(60), Security::Bogus("no valid signature"));
+        let mut failed = answer(60);
+        failed.header.rescode = ResultCode::SERVFAIL;
+        cache.insert(key(), &failed, Security::Insecure);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn negative_answers_use_the_soa_minimum() {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        });
+        assert_eq!(cache_ttl(&packet), Some(300));
+    }
+
+    #[test]
+    fn evicts_when_full() {
+        let mut cache = Cache::new(2, 100, 0, 0);
+        let keys = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| (name.to_string(), QueryType::A, 1, false))
+            .collect::<Vec<_>>();
+        cache.insert(keys[0].clone(), &answer(60), Security::Insecure);
+        cache.insert(keys[1].clone(), &answer(30), Security::Insecure);
+        // Replacing an entry doesn't make room
+        cache.insert(keys[1].clone(), &answer(90), Security::Insecure);
+        assert_eq!(cache.entries.len(), 2);
+
+        // Whatever expires first goes
+        cache.insert(keys[2].clone(), &answer(120), Security::Insecure);
+        assert!(!cache.entries.contains_key(&keys[0]));
+
+        // Then everything past its stale window
+        age(&mut cache, 250);
+        cache.insert(keys[3].clone(), &answer(60), Security::Insecure);
+        assert_eq!(cache.entries.keys().collect::<Vec<_>>(), vec![&keys[3]]);
+        assert_eq!(cache.expiring.len(), 1);
+    }
+}
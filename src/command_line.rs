@@ -0,0 +1,165 @@
+use std::net::SocketAddr;
+
+use config::parse_address;
+use log_level::LogLevel;
+
+pub const USAGE: &str = "Usage: dns [OPTIONS]
+
+A caching, validating DNS resolver and authoritative server.
+
+Options:
+  -c, --config <FILE>      Read settings from a TOML file; the options below
+                           override it
+  -l, --listen <ADDR>      Address and port to listen on, may be repeated
+  -u, --upstream <ADDR>    Upstream resolver, port 53 unless given, may be
+                           repeated
+      --log-level <LEVEL>  error, warn, info or debug
+  -f, --foreground         Stay attached to the terminal instead of running
+                           in the background
+      --pid-file <FILE>    Write the server's process id to FILE
+      --check-config       Check the configuration and exit
+  -h, --help               Show this help and exit
+
+Logs are written to standard output.";
+
+/// The server's command-line arguments.
+#[derive(Debug, PartialEq)]
+pub struct CommandLine {
+    pub config: Option<String>,
+    pub listen: Vec<SocketAddr>,
+    pub upstreams: Vec<SocketAddr>,
+    pub log_level: Option<LogLevel>,
+    pub foreground: bool,
+    pub pid_file: Option<String>,
+    pub check_config: bool,
+    pub help: bool,
+}
+
+impl CommandLine {
+    pub fn new() -> CommandLine {
+        CommandLine {
+            config: None,
+            listen: Vec::new(),
+            upstreams: Vec::new(),
+            log_level: None,
+            foreground: false,
+            pid_file: None,
+            check_config: false,
+            help: false,
+        }
+    }
+
+    /// Parses `args`, less the program name. Options taking a value accept
+    /// both `--option value` and `--option=value`.
+    pub fn parse(args: &[String]) -> Result<CommandLine, String> {
+        let mut res = CommandLine::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+
+            let takes_value = match name {
+                "-c" | "--config" | "-l" | "--listen" | "-u" | "--upstream" | "--log-level"
+                | "--pid-file" => true,
+                "-f" | "--foreground" | "--check-config" | "-h" | "--help" => false,
+                _ if name.starts_with('-') => return Err(format!("Unknown option {}", name)),
+                _ => return Err(format!("Unexpected argument {}", name)),
+            };
+
+            let value = if !takes_value {
+                if inline.is_some() {
+                    return Err(format!("{} doesn't take a value", name));
+                }
+                String::new()
+            } else {
+                match inline.or_else(|| args.next().cloned()) {
+                    Some(x) => x,
+                    None => return Err(format!("{} needs a value", name)),
+                }
+            };
+
+            match name {
+                "-c" | "--config" => res.config = Some(value),
+                "-l" | "--listen" => match parse_address(&value, None) {
+                    Some(addr) => res.listen.push(addr),
+                    None => return Err(format!("Invalid listen address {}", value)),
+                },
+                "-u" | "--upstream" => match parse_address(&value, Some(53)) {
+                    Some(addr) => res.upstreams.push(addr),
+                    None => return Err(format!("Invalid upstream address {}", value)),
+                },
+                "--log-level" => match LogLevel::from_name(&value) {
+                    Some(level) => res.log_level = Some(level),
+                    None => return Err(format!("Invalid log level {}", value)),
+                },
+                "--pid-file" => res.pid_file = Some(value),
+                "-f" | "--foreground" => res.foreground = true,
+                "--check-config" => res.check_config = true,
+                _ => res.help = true,
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> CommandLine {
+        CommandLine::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CommandLine, String> {
+        CommandLine::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn parses_options() {
+        let cli = parse(&[
+            "--config",
+            "/etc/dns.toml",
+            "-l",
+            "127.0.0.1:53",
+            "--listen=[::1]:53",
+            "-u",
+            "192.0.2.1",
+            "--log-level=debug",
+            "-f",
+            "--pid-file",
+            "/run/dns.pid",
+        ]).unwrap();
+
+        assert_eq!(cli.config, Some("/etc/dns.toml".to_string()));
+        assert_eq!(
+            cli.listen,
+            vec!["127.0.0.1:53".parse().unwrap(), "[::1]:53".parse().unwrap()]
+        );
+        assert_eq!(cli.upstreams, vec!["192.0.2.1:53".parse().unwrap()]);
+        assert_eq!(cli.log_level, Some(LogLevel::Debug));
+        assert!(cli.foreground);
+        assert_eq!(cli.pid_file, Some("/run/dns.pid".to_string()));
+        assert!(!cli.check_config && !cli.help);
+
+        assert_eq!(parse(&[]).unwrap(), CommandLine::new());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse(&["--verbose"]), Err("Unknown option --verbose".to_string()));
+        assert_eq!(parse(&["dns.toml"]), Err("Unexpected argument dns.toml".to_string()));
+        assert_eq!(parse(&["--config"]), Err("--config needs a value".to_string()));
+        assert_eq!(parse(&["--help=yes"]), Err("--help doesn't take a value".to_string()));
+        assert_eq!(
+            parse(&["--listen", "localhost"]),
+            Err("Invalid listen address localhost".to_string())
+        );
+        assert_eq!(parse(&["--log-level", "loud"]), Err("Invalid log level loud".to_string()));
+    }
+}
@@ -0,0 +1,947 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use toml::value::{Table, Value};
+
+use dnssec;
+use dns_record::DnsRecord;
+use doh::DohUpstream;
+use log_level::LogLevel;
+use network::Network;
+use qname_minimisation::QnameMinimisation;
+use presentation;
+use query_log::QueryLogConfig;
+use resolver::ResolverConfig;
+use tsig::TsigKey;
+use zone_config::ZoneConfig;
+
+/// Everything the server can be configured with, as read from a TOML file.
+pub struct Config {
+    // Addresses to listen on, over UDP and/or TCP
+    pub listen: Vec<SocketAddr>,
+    pub udp: bool,
+    pub tcp: bool,
+    // Addresses to listen on over TLS (RFC 7858), with the certificate chain
+    // and private key to present, each a PEM file
+    pub tls_listen: Vec<SocketAddr>,
+    // Addresses to listen on over HTTPS (RFC 8484), with the same certificate
+    pub https_listen: Vec<SocketAddr>,
+    // Addresses to listen on over QUIC (RFC 9250), likewise
+    pub quic_listen: Vec<SocketAddr>,
+    // Addresses to serve Prometheus metrics on over plain HTTP
+    pub metrics_listen: Vec<SocketAddr>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // Answer for names outside our zones through the resolver
+    pub recursion: bool,
+    pub log_level: LogLevel,
+    pub query_log: QueryLogConfig,
+    pub resolver: ResolverConfig,
+    // TSIG keys, which zones refer to by name
+    pub keys: Vec<TsigKey>,
+    pub zones: Vec<ZoneConfig>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 2053))],
+            udp: true,
+            tcp: true,
+            tls_listen: Vec::new(),
+            https_listen: Vec::new(),
+            quic_listen: Vec::new(),
+            metrics_listen: Vec::new(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            recursion: true,
+            log_level: LogLevel::Info,
+            query_log: QueryLogConfig::new(),
+            resolver: ResolverConfig::new(),
+            keys: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+        Config::parse(&text).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+
+    /// Reads a config from `text`, with defaults for whatever it leaves out.
+    pub fn parse(text: &str) -> Result<Config, Error> {
+        let root = try!(text.parse::<Value>()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())));
+        let root = try!(as_table(&root, ""));
+        try!(check_keys(
+            root,
+            "",
+            &["server", "upstream", "resolver", "cache", "dnssec", "log", "keys", "zones"],
+        ));
+
+        let mut config = Config::new();
+        try!(config.read_server(root));
+        try!(config.read_upstream(root));
+        try!(config.read_resolver(root));
+        try!(config.read_cache(root));
+        try!(config.read_dnssec(root));
+        try!(config.read_log(root));
+        try!(config.read_keys(root));
+        try!(config.read_zones(root));
+
+        let resolver = &config.resolver;
+        let upstreams = resolver.upstreams.len()
+            + resolver.tls_upstreams.len()
+            + resolver.https_upstreams.len()
+            + resolver.quic_upstreams.len();
+        if config.recursion && upstreams == 0 && !resolver.iterative {
+            return Err(invalid(
+                "upstream.servers",
+                "at least one server is needed unless resolving iteratively",
+            ));
+        }
+
+        Ok(config)
+    }
+
+    fn read_server(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = [
+            "listen",
+            "udp",
+            "tcp",
+            "tls_listen",
+            "https_listen",
+            "quic_listen",
+            "metrics_listen",
+            "tls_cert_file",
+            "tls_key_file",
+            "recursion",
+        ];
+        let server = match try!(section(root, "server", &keys)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(listen) = try!(addresses(server, "server.listen", None)) {
+            if listen.is_empty() {
+                return Err(invalid("server.listen", "at least one address is needed"));
+            }
+            self.listen = listen;
+        }
+        if let Some(udp) = try!(boolean(server, "server.udp")) {
+            self.udp = udp;
+        }
+        if let Some(tcp) = try!(boolean(server, "server.tcp")) {
+            self.tcp = tcp;
+        }
+        if !self.udp && !self.tcp {
+            return Err(invalid("server.tcp", "one of udp and tcp has to be enabled"));
+        }
+        if let Some(listen) = try!(addresses(server, "server.tls_listen", None)) {
+            self.tls_listen = listen;
+        }
+        if let Some(listen) = try!(addresses(server, "server.https_listen", None)) {
+            self.https_listen = listen;
+        }
+        if let Some(listen) = try!(addresses(server, "server.quic_listen", None)) {
+            self.quic_listen = listen;
+        }
+        if let Some(listen) = try!(addresses(server, "server.metrics_listen", None)) {
+            self.metrics_listen = listen;
+        }
+        self.tls_cert_file = try!(string(server, "server.tls_cert_file")).map(String::from);
+        self.tls_key_file = try!(string(server, "server.tls_key_file")).map(String::from);
+        if !self.tls_listen.is_empty() || !self.https_listen.is_empty()
+            || !self.quic_listen.is_empty()
+        {
+            if self.tls_cert_file.is_none() {
+                return Err(invalid("server.tls_cert_file", "needed to listen over TLS"));
+            }
+            if self.tls_key_file.is_none() {
+                return Err(invalid("server.tls_key_file", "needed to listen over TLS"));
+            }
+        }
+        if let Some(recursion) = try!(boolean(server, "server.recursion")) {
+            self.recursion = recursion;
+        }
+
+        Ok(())
+    }
+
+    fn read_upstream(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = [
+            "servers",
+            "timeout_ms",
+            "tls_servers",
+            "tls_name",
+            "tls_pins",
+            "tls_ca_file",
+            "https_servers",
+            "https_bootstrap",
+            "quic_servers",
+            "iterative",
+            "root_servers",
+        ];
+        let upstream = match try!(section(root, "upstream", &keys)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(servers) = try!(addresses(upstream, "upstream.servers", Some(53))) {
+            self.resolver.upstreams = servers;
+        }
+        if let Some(ms) = try!(integer(upstream, "upstream.timeout_ms", 1, 60000)) {
+            self.resolver.upstream_timeout = Duration::from_millis(ms as u64);
+        }
+        if let Some(servers) = try!(addresses(upstream, "upstream.tls_servers", Some(853))) {
+            self.resolver.tls_upstreams = servers;
+        }
+        if let Some(servers) = try!(addresses(upstream, "upstream.quic_servers", Some(853))) {
+            self.resolver.quic_upstreams = servers;
+        }
+        self.resolver.tls_name = try!(string(upstream, "upstream.tls_name")).map(String::from);
+        if let Some(pins) = try!(strings(upstream, "upstream.tls_pins")) {
+            for (i, pin) in pins.iter().enumerate() {
+                match presentation::parse_base64(pin) {
+                    Some(ref digest) if digest.len() == 32 => {
+                        self.resolver.tls_pins.push(digest.clone())
+                    }
+                    _ => {
+                        return Err(invalid(
+                            &format!("upstream.tls_pins[{}]", i),
+                            "expected the base64 SHA-256 digest of a public key",
+                        ))
+                    }
+                }
+            }
+        }
+        if let Some(path) = try!(string(upstream, "upstream.tls_ca_file")) {
+            self.resolver.tls_ca_file = Some(path.to_string());
+        }
+        let mut bootstrap = Vec::new();
+        if let Some(addrs) = try!(strings(upstream, "upstream.https_bootstrap")) {
+            for (i, addr) in addrs.iter().enumerate() {
+                match addr.parse::<IpAddr>() {
+                    Ok(ip) => bootstrap.push(ip),
+                    Err(_) => {
+                        return Err(invalid(
+                            &format!("upstream.https_bootstrap[{}]", i),
+                            "expected an IP address",
+                        ))
+                    }
+                }
+            }
+        }
+        if let Some(urls) = try!(strings(upstream, "upstream.https_servers")) {
+            for (i, url) in urls.iter().enumerate() {
+                match DohUpstream::parse(url, &bootstrap) {
+                    Ok(x) => self.resolver.https_upstreams.push(x),
+                    Err(e) => return Err(invalid(&format!("upstream.https_servers[{}]", i), &e)),
+                }
+            }
+        }
+        if let Some(iterative) = try!(boolean(upstream, "upstream.iterative")) {
+            self.resolver.iterative = iterative;
+        }
+        if let Some(roots) = try!(addresses(upstream, "upstream.root_servers", Some(53))) {
+            if roots.is_empty() {
+                return Err(invalid("upstream.root_servers", "at least one address is needed"));
+            }
+            self.resolver.root_servers = roots;
+        }
+
+        Ok(())
+    }
+
+    fn read_resolver(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = ["qname_minimisation", "case_randomisation"];
+        let resolver = match try!(section(root, "resolver", &keys)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(mode) = try!(string(resolver, "resolver.qname_minimisation")) {
+            self.resolver.qname_minimisation = match mode {
+                "off" => QnameMinimisation::Off,
+                "relaxed" => QnameMinimisation::Relaxed,
+                "strict" => QnameMinimisation::Strict,
+                _ => {
+                    return Err(invalid(
+                        "resolver.qname_minimisation",
+                        "expected \"off\", \"relaxed\" or \"strict\"",
+                    ))
+                }
+            };
+        }
+        if let Some(randomise) = try!(boolean(resolver, "resolver.case_randomisation")) {
+            self.resolver.case_randomisation = randomise;
+        }
+
+        Ok(())
+    }
+
+    fn read_cache(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = [
+            "size",
+            "max_stale",
+            "stale_answer_ttl",
+            "client_response_timeout_ms",
+            "prefetch_hits",
+            "prefetch_percent",
+        ];
+        let cache = match try!(section(root, "cache", &keys)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(size) = try!(integer(cache, "cache.size", 1, 100_000_000)) {
+            self.resolver.cache_size = size as usize;
+        }
+        if let Some(secs) = try!(integer(cache, "cache.max_stale", 0, 7 * 86400)) {
+            self.resolver.max_stale = secs as u32;
+        }
+        if let Some(ttl) = try!(integer(cache, "cache.stale_answer_ttl", 1, 86400)) {
+            self.resolver.stale_answer_ttl = ttl as u32;
+        }
+        if let Some(ms) = try!(integer(cache, "cache.client_response_timeout_ms", 1, 60000)) {
+            self.resolver.client_response_timeout = Duration::from_millis(ms as u64);
+        }
+        if let Some(hits) = try!(integer(cache, "cache.prefetch_hits", 0, 1_000_000)) {
+            self.resolver.prefetch_hits = hits as u32;
+        }
+        if let Some(percent) = try!(integer(cache, "cache.prefetch_percent", 0, 100)) {
+            self.resolver.prefetch_percent = percent as u32;
+        }
+
+        Ok(())
+    }
+
+    fn read_dnssec(&mut self, root: &Table) -> Result<(), Error> {
+        let dnssec = match try!(section(root, "dnssec", &["enabled", "trust_anchors"])) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(enabled) = try!(boolean(dnssec, "dnssec.enabled")) {
+            self.resolver.dnssec = enabled;
+        }
+        if let Some(anchors) = try!(strings(dnssec, "dnssec.trust_anchors")) {
+            let mut records = Vec::new();
+            for (i, anchor) in anchors.iter().enumerate() {
+                let key = format!("dnssec.trust_anchors[{}]", i);
+                records.push(try!(parse_trust_anchor(anchor).map_err(|e| invalid(&key, &e))));
+            }
+            if records.is_empty() {
+                return Err(invalid("dnssec.trust_anchors", "at least one anchor is needed"));
+            }
+            self.resolver.trust_anchors = records;
+        }
+
+        Ok(())
+    }
+
+    fn read_log(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = ["level", "query_log", "dnstap_file", "dnstap_socket"];
+        let log = match try!(section(root, "log", &keys)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if let Some(level) = try!(string(log, "log.level")) {
+            self.log_level = match LogLevel::from_name(level) {
+                Some(x) => x,
+                None => {
+                    return Err(invalid(
+                        "log.level",
+                        "expected \"error\", \"warn\", \"info\" or \"debug\"",
+                    ))
+                }
+            };
+        }
+
+        let query_log = &mut self.query_log;
+        query_log.file = try!(string(log, "log.query_log")).map(String::from);
+        query_log.dnstap_file = try!(string(log, "log.dnstap_file")).map(String::from);
+        query_log.dnstap_socket = try!(string(log, "log.dnstap_socket")).map(String::from);
+
+        Ok(())
+    }
+
+    fn read_keys(&mut self, root: &Table) -> Result<(), Error> {
+        let keys = match root.get("keys") {
+            Some(value) => try!(value
+                .as_array()
+                .ok_or_else(|| invalid("keys", "expected an array of tables"))),
+            None => return Ok(()),
+        };
+
+        for (i, value) in keys.iter().enumerate() {
+            let path = format!("keys[{}]", i);
+            let table = try!(as_table(value, &path));
+            try!(check_keys(table, &path, &["name", "algorithm", "secret"]));
+
+            let key = format!("{}.name", path);
+            let name = match try!(string(table, &key)) {
+                Some(x) => x,
+                None => return Err(invalid(&key, "missing")),
+            };
+            if self.keys.iter().any(|k| k.name.eq_ignore_ascii_case(name.trim_end_matches('.'))) {
+                return Err(invalid(&key, "key is listed twice"));
+            }
+            let key = format!("{}.algorithm", path);
+            let algorithm = try!(string(table, &key)).unwrap_or("hmac-sha256");
+            if !TsigKey::is_supported(algorithm) {
+                return Err(invalid(
+                    &key,
+                    "expected \"hmac-sha256\", \"hmac-sha384\" or \"hmac-sha512\"",
+                ));
+            }
+            let key = format!("{}.secret", path);
+            let secret = match try!(string(table, &key)).map(presentation::parse_base64) {
+                Some(Some(x)) => x,
+                Some(None) => return Err(invalid(&key, "expected base64")),
+                None => return Err(invalid(&key, "missing")),
+            };
+            self.keys.push(TsigKey::new(name, algorithm, secret));
+        }
+
+        Ok(())
+    }
+
+    /// The names in the array `key`, each of which has to be a key of ours.
+    fn key_names(&self, table: &Table, key: &str) -> Result<Option<Vec<String>>, Error> {
+        let names = match try!(strings(table, key)) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let mut res = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            match self.key(name) {
+                Some(k) => res.push(k.name.clone()),
+                None => return Err(invalid(&format!("{}[{}]", key, i), "no such key")),
+            }
+        }
+        Ok(Some(res))
+    }
+
+    fn key(&self, name: &str) -> Option<&TsigKey> {
+        let name = name.trim_end_matches('.');
+        self.keys.iter().find(|k| k.name.eq_ignore_ascii_case(name))
+    }
+
+    fn read_zones(&mut self, root: &Table) -> Result<(), Error> {
+        let zones = match root.get("zones") {
+            Some(value) => try!(value
+                .as_array()
+                .ok_or_else(|| invalid("zones", "expected an array of tables"))),
+            None => return Ok(()),
+        };
+
+        for (i, value) in zones.iter().enumerate() {
+            let path = format!("zones[{}]", i);
+            let zone = try!(as_table(value, &path));
+            let keys = [
+                "name",
+                "file",
+                "primaries",
+                "allow_transfer",
+                "notify",
+                "allow_notify",
+                "allow_update",
+                "transfer_keys",
+                "update_keys",
+                "key",
+            ];
+            try!(check_keys(zone, &path, &keys));
+
+            let key = format!("{}.name", path);
+            let name = match try!(string(zone, &key)) {
+                Some(x) => x,
+                None => return Err(invalid(&key, "missing")),
+            };
+            let mut config = ZoneConfig::new(name);
+            config.file = try!(string(zone, &format!("{}.file", path))).map(|f| f.to_string());
+            let key = format!("{}.primaries", path);
+            if let Some(primaries) = try!(addresses(zone, &key, Some(53))) {
+                config.primaries = primaries;
+            }
+            if config.file.is_none() && !config.is_secondary() {
+                return Err(invalid(&path, "expected a file, primaries or both"));
+            }
+
+            let key = format!("{}.allow_transfer", path);
+            if let Some(networks) = try!(networks(zone, &key)) {
+                config.allow_transfer = networks;
+            }
+            let key = format!("{}.notify", path);
+            if let Some(servers) = try!(addresses(zone, &key, Some(53))) {
+                config.notify = servers;
+            }
+            let key = format!("{}.allow_notify", path);
+            if let Some(networks) = try!(networks(zone, &key)) {
+                config.allow_notify = networks;
+            }
+            let key = format!("{}.allow_update", path);
+            if let Some(networks) = try!(networks(zone, &key)) {
+                config.allow_update = networks;
+            }
+            let key = format!("{}.transfer_keys", path);
+            if let Some(names) = try!(self.key_names(zone, &key)) {
+                config.transfer_keys = names;
+            }
+            let key = format!("{}.update_keys", path);
+            if let Some(names) = try!(self.key_names(zone, &key)) {
+                config.update_keys = names;
+            }
+            let key = format!("{}.key", path);
+            if let Some(name) = try!(string(zone, &key)) {
+                match self.key(name) {
+                    Some(k) => config.key = Some(k.clone()),
+                    None => return Err(invalid(&key, "no such key")),
+                }
+            }
+            if self.zones.iter().any(|z| z.name.eq_ignore_ascii_case(&config.name)) {
+                return Err(invalid(&format!("{}.name", path), "zone is listed twice"));
+            }
+            self.zones.push(config);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+/// A DS record in presentation format, less the class and type:
+/// `<zone> <key tag> <algorithm> <digest type> <digest>`.
+fn parse_trust_anchor(anchor: &str) -> Result<DnsRecord, String> {
+    let fields = anchor.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 5 {
+        return Err("expected \"<zone> <key tag> <algorithm> <digest type> <digest>\"".into());
+    }
+
+    let zone = fields[0].trim_end_matches('.');
+    let key_tag = try!(fields[1].parse::<u16>().map_err(|_| "malformed key tag".to_string()));
+    let algorithm = try!(fields[2].parse::<u8>().map_err(|_| "malformed algorithm".to_string()));
+    let digest_type = try!(fields[3]
+        .parse::<u8>()
+        .map_err(|_| "malformed digest type".to_string()));
+    dnssec::trust_anchor(zone, key_tag, algorithm, digest_type, fields[4])
+        .map_err(|e| e.to_string())
+}
+
+fn invalid(key: &str, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("`{}`: {}", key, msg))
+}
+
+fn as_table<'a>(value: &'a Value, key: &str) -> Result<&'a Table, Error> {
+    value.as_table().ok_or_else(|| invalid(key, "expected a table"))
+}
+
+fn check_keys(table: &Table, path: &str, allowed: &[&str]) -> Result<(), Error> {
+    for key in table.keys() {
+        if !allowed.contains(&key.as_str()) {
+            let full = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            return Err(invalid(&full, "unknown key"));
+        }
+    }
+    Ok(())
+}
+
+/// The table `name` in `root`, if it's there and holds nothing but `keys`.
+fn section<'a>(root: &'a Table, name: &str, keys: &[&str]) -> Result<Option<&'a Table>, Error> {
+    match root.get(name) {
+        Some(value) => {
+            let table = try!(as_table(value, name));
+            try!(check_keys(table, name, keys));
+            Ok(Some(table))
+        }
+        None => Ok(None),
+    }
+}
+
+fn field<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    let name = key.rsplit('.').next().unwrap_or(key);
+    table.get(name)
+}
+
+fn boolean(table: &Table, key: &str) -> Result<Option<bool>, Error> {
+    match field(table, key) {
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| invalid(key, "expected true or false")),
+        None => Ok(None),
+    }
+}
+
+fn integer(table: &Table, key: &str, min: i64, max: i64) -> Result<Option<i64>, Error> {
+    let value = match field(table, key) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    match value.as_integer() {
+        Some(n) if n >= min && n <= max => Ok(Some(n)),
+        Some(_) => Err(invalid(key, &format!("expected a value from {} to {}", min, max))),
+        None => Err(invalid(key, "expected an integer")),
+    }
+}
+
+fn string<'a>(table: &'a Table, key: &str) -> Result<Option<&'a str>, Error> {
+    match field(table, key) {
+        Some(value) => value
+            .as_str()
+            .map(Some)
+            .ok_or_else(|| invalid(key, "expected a string")),
+        None => Ok(None),
+    }
+}
+
+fn strings<'a>(table: &'a Table, key: &str) -> Result<Option<Vec<&'a str>>, Error> {
+    let values = match field(table, key) {
+        Some(value) => try!(value
+            .as_array()
+            .ok_or_else(|| invalid(key, "expected an array of strings"))),
+        None => return Ok(None),
+    };
+
+    let mut res = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        match value.as_str() {
+            Some(s) => res.push(s),
+            None => return Err(invalid(&format!("{}[{}]", key, i), "expected a string")),
+        }
+    }
+    Ok(Some(res))
+}
+
+/// An array of socket addresses. If `default_port` is set, the port may be
+/// left out.
+fn addresses(
+    table: &Table,
+    key: &str,
+    default_port: Option<u16>,
+) -> Result<Option<Vec<SocketAddr>>, Error> {
+    let values = match try!(strings(table, key)) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let mut res = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        match parse_address(value, default_port) {
+            Some(addr) => res.push(addr),
+            None => {
+                let msg = if default_port.is_some() {
+                    "expected an IP address, optionally with a port"
+                } else {
+                    "expected an IP address and port"
+                };
+                return Err(invalid(&format!("{}[{}]", key, i), msg));
+            }
+        }
+    }
+    Ok(Some(res))
+}
+
+fn networks(table: &Table, key: &str) -> Result<Option<Vec<Network>>, Error> {
+    let values = match try!(strings(table, key)) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let mut res = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        match Network::parse(value) {
+            Some(network) => res.push(network),
+            None => {
+                return Err(invalid(
+                    &format!("{}[{}]", key, i),
+                    "expected an IP address or network such as 192.0.2.0/24",
+                ))
+            }
+        }
+    }
+    Ok(Some(res))
+}
+
+pub fn parse_address(value: &str, default_port: Option<u16>) -> Option<SocketAddr> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    match (value.parse::<IpAddr>(), default_port) {
+        (Ok(ip), Some(port)) => Some(SocketAddr::new(ip, port)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        match Config::parse(text) {
+            Ok(_) => panic!("expected {:?} to be rejected", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_the_example_config() {
+        let config = Config::parse(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config.listen.len(), 1);
+        assert!(config.udp && config.tcp);
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert_eq!(config.resolver.upstreams.len(), 2);
+        assert_eq!(config.resolver.upstream_timeout, Duration::from_secs(2));
+        assert_eq!(config.resolver.trust_anchors.len(), 2);
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let config = Config::parse("[upstream]\nservers = [\"192.0.2.1\", \"[2001:db8::1]:5353\"]")
+            .unwrap();
+        assert_eq!(
+            config.resolver.upstreams,
+            vec![
+                "192.0.2.1:53".parse().unwrap(),
+                "[2001:db8::1]:5353".parse().unwrap(),
+            ]
+        );
+        assert_eq!(config.listen, Config::new().listen);
+        assert_eq!(config.resolver.cache_size, ResolverConfig::new().cache_size);
+    }
+
+    #[test]
+    fn points_to_the_offending_key() {
+        assert_eq!(error("[cache]\nsise = 10"), "`cache.sise`: unknown key");
+        assert_eq!(error("[cache]\nsize = \"big\""), "`cache.size`: expected an integer");
+        assert_eq!(
+            error("[cache]\nprefetch_percent = 150"),
+            "`cache.prefetch_percent`: expected a value from 0 to 100"
+        );
+        assert_eq!(
+            error("[server]\nlisten = [\"0.0.0.0:53\", \"localhost\"]"),
+            "`server.listen[1]`: expected an IP address and port"
+        );
+        assert_eq!(
+            error("[dnssec]\ntrust_anchors = [\". 20326 8 2 E06\"]"),
+            "`dnssec.trust_anchors[0]`: Malformed trust anchor digest"
+        );
+        assert_eq!(error("log = 1"), "`log`: expected a table");
+    }
+
+    #[test]
+    fn reads_tls_settings() {
+        let config = Config::parse(
+            "[server]
+tls_listen = [\"0.0.0.0:853\"]
+quic_listen = [\"0.0.0.0:853\"]
+tls_cert_file = \"cert.pem\"
+tls_key_file = \"key.pem\"
+
+[upstream]
+tls_servers = [\"192.0.2.1\", \"192.0.2.2:8853\"]
+quic_servers = [\"192.0.2.3\"]
+tls_name = \"dns.example\"
+tls_pins = [\"47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=\"]",
+        ).unwrap();
+        assert_eq!(config.tls_listen, vec!["0.0.0.0:853".parse().unwrap()]);
+        assert!(config.https_listen.is_empty());
+        assert_eq!(config.quic_listen, vec!["0.0.0.0:853".parse().unwrap()]);
+        assert_eq!(config.tls_key_file, Some("key.pem".to_string()));
+        assert_eq!(
+            config.resolver.tls_upstreams,
+            vec!["192.0.2.1:853".parse().unwrap(), "192.0.2.2:8853".parse().unwrap()]
+        );
+        assert_eq!(config.resolver.quic_upstreams, vec!["192.0.2.3:853".parse().unwrap()]);
+        assert_eq!(config.resolver.tls_name, Some("dns.example".to_string()));
+        assert_eq!(config.resolver.tls_pins[0].len(), 32);
+
+        assert_eq!(
+            error("[server]\ntls_listen = [\"0.0.0.0:853\"]\ntls_cert_file = \"cert.pem\""),
+            "`server.tls_key_file`: needed to listen over TLS"
+        );
+        assert_eq!(
+            error("[server]\nhttps_listen = [\"0.0.0.0:443\"]"),
+            "`server.tls_cert_file`: needed to listen over TLS"
+        );
+        assert_eq!(
+            error("[server]\nquic_listen = [\"0.0.0.0:853\"]"),
+            "`server.tls_cert_file`: needed to listen over TLS"
+        );
+        assert_eq!(
+            error("[upstream]\ntls_pins = [\"c2VjcmV0\"]"),
+            "`upstream.tls_pins[0]`: expected the base64 SHA-256 digest of a public key"
+        );
+    }
+
+    #[test]
+    fn needs_an_upstream_over_some_transport() {
+        assert_eq!(
+            error("[upstream]\nservers = []"),
+            "`upstream.servers`: at least one server is needed unless resolving iteratively"
+        );
+        assert!(Config::parse("[upstream]\nservers = []\ntls_servers = [\"192.0.2.1\"]").is_ok());
+        assert!(Config::parse("[upstream]\nservers = []\nquic_servers = [\"192.0.2.1\"]").is_ok());
+        assert!(Config::parse(
+            "[upstream]\nservers = []\nhttps_servers = [\"https://192.0.2.1/dns-query\"]"
+        ).is_ok());
+        assert!(Config::parse("[upstream]\nservers = []\niterative = true").is_ok());
+    }
+
+    #[test]
+    fn reads_https_upstreams() {
+        let config = Config::parse(
+            "[upstream]
+https_servers = [\"https://dns.example/dns-query{?dns}\", \"https://[2001:db8::1]:8443\"]
+https_bootstrap = [\"192.0.2.1\", \"2001:db8::53\"]",
+        ).unwrap();
+        let upstreams = &config.resolver.https_upstreams;
+        assert_eq!(upstreams[0].host, "dns.example");
+        assert_eq!(upstreams[0].path, "/dns-query");
+        assert_eq!(
+            upstreams[0].addrs,
+            vec!["192.0.2.1:443".parse().unwrap(), "[2001:db8::53]:443".parse().unwrap()]
+        );
+        assert_eq!(upstreams[1].authority, "[2001:db8::1]:8443");
+        assert_eq!(upstreams[1].addrs, vec!["[2001:db8::1]:8443".parse().unwrap()]);
+
+        assert_eq!(
+            error("[upstream]\nhttps_servers = [\"https://dns.example/dns-query\"]"),
+            "`upstream.https_servers[0]`: a bootstrap address is needed to reach dns.example"
+        );
+        assert_eq!(
+            error("[upstream]\nhttps_servers = [\"http://192.0.2.1/dns-query\"]"),
+            "`upstream.https_servers[0]`: expected an https:// URL"
+        );
+    }
+
+    #[test]
+    fn reads_query_log_settings() {
+        let config = Config::parse(
+            "[log]
+level = \"warn\"
+query_log = \"/var/log/dns/queries.jsonl\"
+dnstap_socket = \"/run/dnstap.sock\"",
+        ).unwrap();
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.query_log.file, Some("/var/log/dns/queries.jsonl".to_string()));
+        assert_eq!(config.query_log.dnstap_file, None);
+        assert_eq!(config.query_log.dnstap_socket, Some("/run/dnstap.sock".to_string()));
+        assert_eq!(Config::new().query_log, QueryLogConfig::new());
+        assert!(config.metrics_listen.is_empty());
+
+        let config = Config::parse("[server]\nmetrics_listen = [\"127.0.0.1:9153\"]").unwrap();
+        assert_eq!(config.metrics_listen, vec!["127.0.0.1:9153".parse().unwrap()]);
+
+        assert_eq!(error("[log]\ndnstap_file = 1"), "`log.dnstap_file`: expected a string");
+    }
+
+    #[test]
+    fn reads_zones() {
+        let config = Config::parse(
+            "[server]
+recursion = false
+
+[[zones]]
+name = \"example.com.\"
+file = \"example.com.zone\"
+
+[[keys]]
+name = \"transfer.\"
+secret = \"c2VjcmV0\"
+
+[[keys]]
+name = \"update\"
+algorithm = \"hmac-sha512\"
+secret = \"c2VjcmV0\"
+
+[[zones]]
+name = \"example.net\"
+file = \"example.net.zone\"
+allow_transfer = [\"192.0.2.0/24\", \"2001:db8::1\"]
+allow_update = [\"127.0.0.1\"]
+transfer_keys = [\"transfer\"]
+update_keys = [\"update\"]
+
+[[zones]]
+name = \"example.org\"
+primaries = [\"192.0.2.53\", \"[2001:db8::53]:5353\"]
+notify = [\"192.0.2.54\"]
+allow_notify = [\"198.51.100.0/24\"]
+key = \"transfer\"",
+        ).unwrap();
+        assert!(!config.recursion);
+        assert_eq!(config.zones.len(), 3);
+        assert_eq!(config.zones[0].name, "example.com");
+        assert_eq!(config.zones[1].file, Some("example.net.zone".to_string()));
+        assert!(config.zones[0].allow_transfer.is_empty());
+        assert_eq!(config.zones[1].allow_transfer.len(), 2);
+        assert_eq!(config.zones[1].allow_update.len(), 1);
+        assert_eq!(config.keys.len(), 2);
+        assert_eq!(config.keys[1].algorithm, "hmac-sha512");
+        assert_eq!(config.keys[1].secret, b"secret".to_vec());
+        let other = "198.51.100.1".parse().unwrap();
+        assert!(config.zones[1].allows_transfer(other, Some("transfer")));
+        assert!(!config.zones[1].allows_transfer(other, Some("update")));
+        assert!(!config.zones[1].allows_transfer(other, None));
+        assert!(config.zones[1].allows_update(other, Some("update")));
+        assert_eq!(config.zones[2].key, Some(config.keys[0].clone()));
+        assert!(!config.zones[1].is_secondary());
+        assert_eq!(config.zones[2].file, None);
+        assert_eq!(
+            config.zones[2].primaries,
+            vec![
+                "192.0.2.53:53".parse().unwrap(),
+                "[2001:db8::53]:5353".parse().unwrap(),
+            ]
+        );
+        assert_eq!(config.zones[2].notify, vec!["192.0.2.54:53".parse().unwrap()]);
+        assert!(config.zones[2].allows_notify("192.0.2.53".parse().unwrap()));
+        assert!(config.zones[2].allows_notify("198.51.100.7".parse().unwrap()));
+        assert!(!config.zones[2].allows_notify("192.0.2.54".parse().unwrap()));
+
+        assert_eq!(
+            error("[[zones]]\nname = \"example.com\""),
+            "`zones[0]`: expected a file, primaries or both"
+        );
+        assert_eq!(
+            error("[[zones]]\nname = \"a\"\nfile = \"a\"\nallow_transfer = [\"10/8\"]"),
+            "`zones[0].allow_transfer[0]`: expected an IP address or network such as 192.0.2.0/24"
+        );
+        assert_eq!(
+            error("[[zones]]\nname = \"a\"\nfile = \"a\"\n[[zones]]\nname = \"A.\"\nfile = \"b\""),
+            "`zones[1].name`: zone is listed twice"
+        );
+        assert_eq!(
+            error("[[zones]]\nname = \"a\"\nfile = \"a\"\ntransfer_keys = [\"k\"]"),
+            "`zones[0].transfer_keys[0]`: no such key"
+        );
+        assert_eq!(
+            error("[[keys]]\nname = \"k\"\nalgorithm = \"hmac-md5\"\nsecret = \"\""),
+            "`keys[0].algorithm`: expected \"hmac-sha256\", \"hmac-sha384\" or \"hmac-sha512\""
+        );
+        assert_eq!(
+            error("[[keys]]\nname = \"k\"\nsecret = \"secret\""),
+            "`keys[0].secret`: expected base64"
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert!(error("[cache\nsize = 1").contains("line 1"));
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// Detaches from the terminal: forks, lets the parent exit and starts a new
+/// session. Standard input is pointed at /dev/null, and so are standard
+/// output and error if they're the terminal. Otherwise they're left for
+/// the logs.
+///
+/// Has to be called before any threads are started.
+pub fn daemonize() -> Result<(), Error> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(Error::last_os_error()),
+        0 => {}
+        _ => process::exit(0),
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    let null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+    for fd in 0..3 {
+        if fd > 0 && unsafe { libc::isatty(fd) } == 0 {
+            continue;
+        }
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_pid_file(path: &str) -> Result<(), Error> {
+    let mut file = try!(File::create(path));
+    writeln!(file, "{}", process::id())
+}
+
+/// Records SIGHUP instead of exiting on it, for `take_hangup` to pick up.
+pub fn catch_hangup() -> Result<(), Error> {
+    let handler = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// True if SIGHUP arrived since the last call.
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
@@ -1,4 +1,6 @@
+use std::fmt;
 use std::io::Error;
+use opcode::Opcode;
 use result_code::ResultCode;
 use byte_packet_buffer::BytePacketBuffer;
 
@@ -9,7 +11,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,
     pub truncated_message: bool,
     pub authoritative_answer: bool,
-    pub opcode: u8, // 4 bits
+    pub opcode: Opcode, // 4 bits
     pub response: bool,
 
     pub rescode: ResultCode, //  4bits
@@ -31,7 +33,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResultCode::NOERROR,
@@ -55,8 +57,8 @@ impl DnsHeader {
         let b = (flags & 0xFF) as u8;
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
-        self.authoritative_answer = (a & (a << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.authoritative_answer = (a & (1 << 2)) > 0;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
         self.rescode = ResultCode::from_num(b & 0x0F);
@@ -78,7 +80,7 @@ impl DnsHeader {
 
         try!(buffer.write_u8(
             ((self.recursion_desired as u8)) | ((self.truncated_message as u8) << 1)
-                | ((self.authoritative_answer as u8) << 2) | (self.opcode << 3)
+                | ((self.authoritative_answer as u8) << 2) | (self.opcode.to_num() << 3)
                 | ((self.response as u8) << 7) as u8
         ));
 
@@ -96,3 +98,40 @@ impl DnsHeader {
         Ok(())
     }
 }
+
+impl fmt::Display for DnsHeader {
+    /// The two comment lines dig starts a response with.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            self.opcode,
+            self.rescode,
+            self.id
+        ));
+
+        let flags = [
+            ("qr", self.response),
+            ("aa", self.authoritative_answer),
+            ("tc", self.truncated_message),
+            ("rd", self.recursion_desired),
+            ("ra", self.recursion_available),
+            ("ad", self.authed_data),
+            ("cd", self.checking_disabled),
+        ];
+        let flags = flags
+            .iter()
+            .filter(|&&(_, set)| set)
+            .map(|&(name, _)| name)
+            .collect::<Vec<&str>>();
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions,
+            self.answers,
+            self.authoritative_entries,
+            self.resource_entries
+        )
+    }
+}
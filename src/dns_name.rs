@@ -0,0 +1,98 @@
+use std::cmp::Ordering;
+
+// Names are kept the way `read_qname` produces them: labels joined by '.',
+// without a trailing dot, and "" for the root.
+
+pub fn labels(name: &str) -> Vec<&str> {
+    name.split('.').filter(|label| !label.is_empty()).collect()
+}
+
+pub fn label_count(name: &str) -> usize {
+    labels(name).len()
+}
+
+pub fn names_equal(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+pub fn parent(name: &str) -> Option<&str> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return None;
+    }
+
+    match name.find('.') {
+        Some(idx) => Some(&name[idx + 1..]),
+        None => Some(""),
+    }
+}
+
+/// `name` itself followed by each of its ancestors, ending with the root.
+pub fn ancestors(name: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut current = Some(name.trim_end_matches('.'));
+    while let Some(name) = current {
+        res.push(name);
+        current = parent(name);
+    }
+    res
+}
+
+/// True if `name` is `zone` or lies below it.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = labels(name);
+    let zone = labels(zone);
+    if zone.len() > name.len() {
+        return false;
+    }
+
+    name.iter()
+        .rev()
+        .zip(zone.iter().rev())
+        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// The rightmost `count` labels of `name`.
+pub fn suffix(name: &str, count: usize) -> String {
+    let labels = labels(name);
+    let skip = labels.len().saturating_sub(count);
+    labels[skip..].join(".")
+}
+
+/// Canonical DNS name order (RFC 4034 section 6.1).
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a = labels(a);
+    let b = labels(b);
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        let x = x.to_ascii_lowercase();
+        let y = y.to_ascii_lowercase();
+        match x.as_bytes().cmp(y.as_bytes()) {
+            Ordering::Equal => continue,
+            ord => return ord,
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Uncompressed wire format of `name`.
+pub fn to_wire(name: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    for label in labels(name) {
+        res.push(label.len() as u8);
+        res.extend(label.as_bytes());
+    }
+    res.push(0);
+    res
+}
+
+/// Uncompressed, lowercased wire format of `name`.
+pub fn to_canonical_wire(name: &str) -> Vec<u8> {
+    let mut res = Vec::new();
+    for label in labels(name) {
+        res.push(label.len() as u8);
+        res.extend(label.to_ascii_lowercase().as_bytes());
+    }
+    res.push(0);
+    res
+}
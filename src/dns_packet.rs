@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::Error;
 use dns_header::DnsHeader;
 use dns_question::DnsQuestion;
@@ -5,6 +6,9 @@ use dns_record::DnsRecord;
 use byte_packet_buffer::BytePacketBuffer;
 use query_type::QueryType;
 
+// DO bit within the TTL field of an OPT record
+const DNSSEC_OK: u32 = 1 << 15;
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -53,6 +57,36 @@ impl DnsPacket {
         Ok(result)
     }
 
+    pub fn get_edns(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|rec| rec.get_querytype() == QueryType::OPT)
+    }
+
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        match self.get_edns() {
+            Some(&DnsRecord::OPT { packet_len, .. }) => Some(packet_len),
+            _ => None,
+        }
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        match self.get_edns() {
+            Some(&DnsRecord::OPT { flags, .. }) => (flags & DNSSEC_OK) > 0,
+            _ => false,
+        }
+    }
+
+    pub fn set_edns(&mut self, payload_size: u16, dnssec_ok: bool) {
+        self.resources
+            .retain(|rec| rec.get_querytype() != QueryType::OPT);
+        self.resources.push(DnsRecord::OPT {
+            packet_len: payload_size,
+            flags: if dnssec_ok { DNSSEC_OK } else { 0 },
+            data: Vec::new(),
+        });
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Error> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
@@ -80,3 +114,87 @@ impl DnsPacket {
         Ok(())
     }
 }
+
+fn write_section(f: &mut fmt::Formatter, title: &str, records: &[DnsRecord]) -> fmt::Result {
+    let mut records = records
+        .iter()
+        .filter(|rec| rec.get_querytype() != QueryType::OPT)
+        .peekable();
+    if records.peek().is_none() {
+        return Ok(());
+    }
+
+    try!(writeln!(f, "\n;; {} SECTION:", title));
+    for rec in records {
+        try!(writeln!(f, "{}", rec));
+    }
+    Ok(())
+}
+
+impl fmt::Display for DnsPacket {
+    /// The packet laid out in sections the way dig prints it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Counts as they'll be written, which may not be what was read
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
+        try!(writeln!(f, "{}", header));
+
+        if let Some(opt) = self.get_edns() {
+            try!(writeln!(f, "\n;; OPT PSEUDOSECTION:\n{}", opt));
+        }
+
+        try!(writeln!(f, "\n;; QUESTION SECTION:"));
+        for question in &self.questions {
+            try!(writeln!(f, ";{}", question));
+        }
+
+        try!(write_section(f, "ANSWER", &self.answers));
+        try!(write_section(f, "AUTHORITY", &self.authorities));
+        write_section(f, "ADDITIONAL", &self.resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use result_code::ResultCode;
+
+    #[test]
+    fn presents_packets_like_dig() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4660;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet
+            .questions
+            .push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        });
+        packet.set_edns(1232, true);
+
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NXDOMAIN, id: 4660
+;; flags: qr rd; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags: do; udp: 1232
+
+;; QUESTION SECTION:
+;www.example.com.\tIN\tA
+
+;; ANSWER SECTION:
+www.example.com.\t300\tIN\tA\t192.0.2.1
+"
+        );
+    }
+}
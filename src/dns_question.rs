@@ -1,11 +1,14 @@
+use std::fmt;
 use std::io::Error;
 use query_type::QueryType;
 use byte_packet_buffer::BytePacketBuffer;
+use presentation;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub class: u16,
 }
 
 impl DnsQuestion {
@@ -13,13 +16,14 @@ impl DnsQuestion {
         DnsQuestion {
             name: name,
             qtype: qtype,
+            class: 1, // IN
         }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Error> {
         try!(buffer.read_qname(&mut self.name));
         self.qtype = QueryType::from_num(try!(buffer.read_u16()));
-        let _ = try!(buffer.read_u16()); // class
+        self.class = try!(buffer.read_u16());
 
         Ok(())
     }
@@ -29,7 +33,19 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         try!(buffer.write_u16(typenum));
-        try!(buffer.write_u16(1));
+        try!(buffer.write_u16(self.class));
         Ok(())
     }
 }
+
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}",
+            presentation::name(&self.name),
+            presentation::class_name(self.class),
+            self.qtype
+        )
+    }
+}
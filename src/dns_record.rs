@@ -1,15 +1,18 @@
+use std::fmt;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use byte_packet_buffer::BytePacketBuffer;
 use query_type::QueryType;
+use dns_name;
+use presentation;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -27,30 +30,179 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    DNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 39
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    }, // 41
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 50
+}
+
+fn read_type_bitmap(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<QueryType>, Error> {
+    let mut types = Vec::new();
+    while buffer.pos() < end {
+        let window = try!(buffer.read_u8()) as u16;
+        let len = try!(buffer.read_u8());
+        for i in 0..len as u16 {
+            let bits = try!(buffer.read_u8());
+            for bit in 0..8 {
+                if bits & (0x80 >> bit) > 0 {
+                    types.push(QueryType::from_num((window << 8) | (i << 3) | bit));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+fn write_type_bitmap(buffer: &mut BytePacketBuffer, types: &[QueryType]) -> Result<(), Error> {
+    let mut nums = types.iter().map(|t| t.to_num()).collect::<Vec<u16>>();
+    nums.sort();
+    nums.dedup();
+
+    let mut i = 0;
+    while i < nums.len() {
+        let window = nums[i] >> 8;
+        let mut bits = [0u8; 32];
+        let mut len = 0;
+        while i < nums.len() && nums[i] >> 8 == window {
+            let low = (nums[i] & 0xFF) as usize;
+            bits[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            i += 1;
+        }
+
+        try!(buffer.write_u8(window as u8));
+        try!(buffer.write_u8(len as u8));
+        try!(buffer.write_bytes(&bits[0..len]));
+    }
+    Ok(())
 }
 
 impl DnsRecord {
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord, Error> {
+        DnsRecord::read_with_class(buffer).map(|(record, _)| record)
+    }
+
+    /// Reads a record along with its class, which UPDATE messages give
+    /// meaning to (RFC 2136 section 2.4). Records there may also come
+    /// without data, standing for a whole RRset; they're read as `UNKNOWN`
+    /// ones with empty data.
+    pub fn read_with_class(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord, u16), Error> {
         let mut domain = String::new();
         try!(buffer.read_qname(&mut domain));
 
         let qtype_num = try!(buffer.read_u16());
         let qtype = QueryType::from_num(qtype_num);
-        let _ = try!(buffer.read_u16()); // class, ignore
+        let class = try!(buffer.read_u16());
         let ttl = try!(buffer.read_u32());
         let data_len = try!(buffer.read_u16());
 
+        if data_len == 0 && qtype != QueryType::OPT {
+            let record = DnsRecord::UNKNOWN {
+                domain: domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                ttl: ttl,
+            };
+            return Ok((record, class));
+        }
+        let record = try!(DnsRecord::read_data(buffer, domain, qtype, class, ttl, data_len));
+        Ok((record, class))
+    }
+
+    fn read_data(
+        buffer: &mut BytePacketBuffer,
+        domain: String,
+        qtype: QueryType,
+        class: u16,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord, Error> {
+        let qtype_num = qtype.to_num();
+        let data_end = buffer.pos() + data_len as usize;
+
         match qtype {
             QueryType::A => {
                 let raw_addr = try!(buffer.read_u32());
@@ -105,6 +257,24 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                try!(buffer.read_qname(&mut ptr));
+                Ok(DnsRecord::PTR {
+                    domain: domain,
+                    host: ptr,
+                    ttl: ttl,
+                })
+            }
+            QueryType::DNAME => {
+                let mut dname = String::new();
+                try!(buffer.read_qname(&mut dname));
+                Ok(DnsRecord::DNAME {
+                    domain: domain,
+                    host: dname,
+                    ttl: ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = try!(buffer.read_u16());
                 let mut mx = String::new();
@@ -116,17 +286,258 @@ impl DnsRecord {
                     ttl: ttl,
                 })
             }
+            QueryType::TXT => {
+                let mut data = Vec::new();
+                while buffer.pos() < data_end {
+                    let len = try!(buffer.read_u8());
+                    data.push(try!(buffer.read_bytes(len as usize)));
+                }
+                Ok(DnsRecord::TXT {
+                    domain: domain,
+                    data: data,
+                    ttl: ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                try!(buffer.read_qname(&mut m_name));
+                let mut r_name = String::new();
+                try!(buffer.read_qname(&mut r_name));
+                Ok(DnsRecord::SOA {
+                    domain: domain,
+                    m_name: m_name,
+                    r_name: r_name,
+                    serial: try!(buffer.read_u32()),
+                    refresh: try!(buffer.read_u32()),
+                    retry: try!(buffer.read_u32()),
+                    expire: try!(buffer.read_u32()),
+                    minimum: try!(buffer.read_u32()),
+                    ttl: ttl,
+                })
+            }
+            QueryType::OPT => {
+                let data = try!(buffer.read_bytes(data_len as usize));
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    data: data,
+                })
+            }
+            QueryType::DS => {
+                let key_tag = try!(buffer.read_u16());
+                let algorithm = try!(buffer.read_u8());
+                let digest_type = try!(buffer.read_u8());
+                let digest = try!(buffer.read_bytes(data_end.saturating_sub(buffer.pos())));
+                Ok(DnsRecord::DS {
+                    domain: domain,
+                    key_tag: key_tag,
+                    algorithm: algorithm,
+                    digest_type: digest_type,
+                    digest: digest,
+                    ttl: ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_num(try!(buffer.read_u16()));
+                let algorithm = try!(buffer.read_u8());
+                let labels = try!(buffer.read_u8());
+                let original_ttl = try!(buffer.read_u32());
+                let expiration = try!(buffer.read_u32());
+                let inception = try!(buffer.read_u32());
+                let key_tag = try!(buffer.read_u16());
+                let mut signer_name = String::new();
+                try!(buffer.read_qname(&mut signer_name));
+                let signature = try!(buffer.read_bytes(data_end.saturating_sub(buffer.pos())));
+                Ok(DnsRecord::RRSIG {
+                    domain: domain,
+                    type_covered: type_covered,
+                    algorithm: algorithm,
+                    labels: labels,
+                    original_ttl: original_ttl,
+                    expiration: expiration,
+                    inception: inception,
+                    key_tag: key_tag,
+                    signer_name: signer_name,
+                    signature: signature,
+                    ttl: ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let mut next_domain = String::new();
+                try!(buffer.read_qname(&mut next_domain));
+                let types = try!(read_type_bitmap(buffer, data_end));
+                Ok(DnsRecord::NSEC {
+                    domain: domain,
+                    next_domain: next_domain,
+                    types: types,
+                    ttl: ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = try!(buffer.read_u16());
+                let protocol = try!(buffer.read_u8());
+                let algorithm = try!(buffer.read_u8());
+                let public_key = try!(buffer.read_bytes(data_end.saturating_sub(buffer.pos())));
+                Ok(DnsRecord::DNSKEY {
+                    domain: domain,
+                    flags: flags,
+                    protocol: protocol,
+                    algorithm: algorithm,
+                    public_key: public_key,
+                    ttl: ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let hash_algorithm = try!(buffer.read_u8());
+                let flags = try!(buffer.read_u8());
+                let iterations = try!(buffer.read_u16());
+                let salt_len = try!(buffer.read_u8());
+                let salt = try!(buffer.read_bytes(salt_len as usize));
+                let hash_len = try!(buffer.read_u8());
+                let next_hashed = try!(buffer.read_bytes(hash_len as usize));
+                let types = try!(read_type_bitmap(buffer, data_end));
+                Ok(DnsRecord::NSEC3 {
+                    domain: domain,
+                    hash_algorithm: hash_algorithm,
+                    flags: flags,
+                    iterations: iterations,
+                    salt: salt,
+                    next_hashed: next_hashed,
+                    types: types,
+                    ttl: ttl,
+                })
+            }
             QueryType::UNKNOWN(_) => {
-                try!(buffer.step(data_len as usize));
+                let data = match qtype_num {
+                    // MD, MF, MB, MG, MR and MINFO may use name compression
+                    // (RFC 3597 section 4), so store their names expanded
+                    3 | 4 | 7 | 8 | 9 | 14 => {
+                        let mut data = Vec::new();
+                        while buffer.pos() < data_end {
+                            let mut name = String::new();
+                            try!(buffer.read_qname(&mut name));
+                            data.extend(dns_name::to_wire(&name));
+                        }
+                        data
+                    }
+                    _ => try!(buffer.read_bytes(data_len as usize)),
+                };
                 Ok(DnsRecord::UNKNOWN {
                     domain: domain,
                     qtype: qtype_num,
-                    data_len: data_len,
+                    data: data,
                     ttl: ttl,
                 })
             }
         }
     }
+
+    pub fn get_querytype(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+        }
+    }
+
+    pub fn get_domain(&self) -> &str {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::DNAME { ref domain, .. }
+            | DnsRecord::DS { ref domain, .. }
+            | DnsRecord::RRSIG { ref domain, .. }
+            | DnsRecord::NSEC { ref domain, .. }
+            | DnsRecord::DNSKEY { ref domain, .. }
+            | DnsRecord::NSEC3 { ref domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. } => ttl,
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::PTR { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::TXT { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. }
+            | DnsRecord::DNAME { ref mut ttl, .. }
+            | DnsRecord::DS { ref mut ttl, .. }
+            | DnsRecord::RRSIG { ref mut ttl, .. }
+            | DnsRecord::NSEC { ref mut ttl, .. }
+            | DnsRecord::DNSKEY { ref mut ttl, .. }
+            | DnsRecord::NSEC3 { ref mut ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut domain, .. }
+            | DnsRecord::A { ref mut domain, .. }
+            | DnsRecord::NS { ref mut domain, .. }
+            | DnsRecord::CNAME { ref mut domain, .. }
+            | DnsRecord::SOA { ref mut domain, .. }
+            | DnsRecord::PTR { ref mut domain, .. }
+            | DnsRecord::MX { ref mut domain, .. }
+            | DnsRecord::TXT { ref mut domain, .. }
+            | DnsRecord::AAAA { ref mut domain, .. }
+            | DnsRecord::DNAME { ref mut domain, .. }
+            | DnsRecord::DS { ref mut domain, .. }
+            | DnsRecord::RRSIG { ref mut domain, .. }
+            | DnsRecord::NSEC { ref mut domain, .. }
+            | DnsRecord::DNSKEY { ref mut domain, .. }
+            | DnsRecord::NSEC3 { ref mut domain, .. } => *domain = new_domain.to_string(),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize, Error> {
         let start_pos = buffer.pos();
 
@@ -184,6 +595,42 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 try!(buffer.set_u16(pos, size as u16));
             }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::PTR.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(host));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
+            DnsRecord::DNAME {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::DNAME.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(host));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -204,6 +651,33 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 try!(buffer.set_u16(pos, size as u16));
             }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::TXT.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "TXT string exceeds 255 characters",
+                        ));
+                    }
+                    try!(buffer.write_u8(string.len() as u8));
+                    try!(buffer.write_bytes(string));
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
@@ -219,11 +693,537 @@ impl DnsRecord {
                     try!(buffer.write_u16(*octet))
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::SOA.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(m_name));
+                try!(buffer.write_qname(r_name));
+                try!(buffer.write_u32(serial));
+                try!(buffer.write_u32(refresh));
+                try!(buffer.write_u32(retry));
+                try!(buffer.write_u32(expire));
+                try!(buffer.write_u32(minimum));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref data,
+            } => {
+                try!(buffer.write_qname(""));
+                try!(buffer.write_u16(QueryType::OPT.to_num()));
+                try!(buffer.write_u16(packet_len));
+                try!(buffer.write_u32(flags));
+                try!(buffer.write_u16(data.len() as u16));
+                try!(buffer.write_bytes(data));
+            }
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::DS.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16(4 + digest.len() as u16));
+
+                try!(buffer.write_u16(key_tag));
+                try!(buffer.write_u8(algorithm));
+                try!(buffer.write_u8(digest_type));
+                try!(buffer.write_bytes(digest));
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::RRSIG.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_u16(type_covered.to_num()));
+                try!(buffer.write_u8(algorithm));
+                try!(buffer.write_u8(labels));
+                try!(buffer.write_u32(original_ttl));
+                try!(buffer.write_u32(expiration));
+                try!(buffer.write_u32(inception));
+                try!(buffer.write_u16(key_tag));
+                try!(buffer.write_qname(signer_name));
+                try!(buffer.write_bytes(signature));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next_domain,
+                ref types,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::NSEC.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_qname(next_domain));
+                try!(write_type_bitmap(buffer, types));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::DNSKEY.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16(4 + public_key.len() as u16));
+
+                try!(buffer.write_u16(flags));
+                try!(buffer.write_u8(protocol));
+                try!(buffer.write_u8(algorithm));
+                try!(buffer.write_bytes(public_key));
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(QueryType::NSEC3.to_num()));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+
+                let pos = buffer.pos();
+                try!(buffer.write_u16(0));
+
+                try!(buffer.write_u8(hash_algorithm));
+                try!(buffer.write_u8(flags));
+                try!(buffer.write_u16(iterations));
+                try!(buffer.write_u8(salt.len() as u8));
+                try!(buffer.write_bytes(salt));
+                try!(buffer.write_u8(next_hashed.len() as u8));
+                try!(buffer.write_bytes(next_hashed));
+                try!(write_type_bitmap(buffer, types));
+
+                let size = buffer.pos() - (pos + 2);
+                try!(buffer.set_u16(pos, size as u16));
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                try!(buffer.write_qname(domain));
+                try!(buffer.write_u16(qtype));
+                try!(buffer.write_u16(1));
+                try!(buffer.write_u32(ttl));
+                try!(buffer.write_u16(data.len() as u16));
+                try!(buffer.write_bytes(data));
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
+
+    /// Writes the record with `class` in place of IN.
+    pub fn write_with_class(&self, buffer: &mut BytePacketBuffer, class: u16) -> Result<usize, Error> {
+        let start_pos = buffer.pos();
+        let len = try!(self.write(buffer));
+        let class_pos = start_pos + dns_name::to_wire(self.get_domain()).len() + 2;
+        try!(buffer.set_u16(class_pos, class));
+        Ok(len)
+    }
+}
+
+fn type_list(types: &[QueryType]) -> String {
+    types
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl DnsRecord {
+    /// The record's data in presentation format, as in zone files. OPT
+    /// records, which have none, get the generic form (RFC 3597).
+    pub fn rdata_string(&self) -> String {
+        match *self {
+            DnsRecord::UNKNOWN { ref data, .. } if data.is_empty() => "\\# 0".to_string(),
+            DnsRecord::UNKNOWN { ref data, .. } => {
+                format!("\\# {} {}", data.len(), presentation::hex(data))
+            }
+            DnsRecord::A { addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { addr, .. } => addr.to_string(),
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. }
+            | DnsRecord::DNAME { ref host, .. } => presentation::name(host),
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => format!("{} {}", priority, presentation::name(host)),
+            DnsRecord::TXT { ref data, .. } => data.iter()
+                .map(|string| presentation::character_string(string))
+                .collect::<Vec<String>>()
+                .join(" "),
+            DnsRecord::SOA {
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                presentation::name(m_name),
+                presentation::name(r_name),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::OPT { ref data, .. } if data.is_empty() => "\\# 0".to_string(),
+            DnsRecord::OPT { ref data, .. } => {
+                format!("\\# {} {}", data.len(), presentation::hex(data))
+            }
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                presentation::hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                presentation::timestamp(expiration),
+                presentation::timestamp(inception),
+                key_tag,
+                presentation::name(signer_name),
+                presentation::base64(signature)
+            ),
+            DnsRecord::NSEC {
+                ref next_domain,
+                ref types,
+                ..
+            } => format!("{} {}", presentation::name(next_domain), type_list(types)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                presentation::base64(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ..
+            } => format!(
+                "{} {} {} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                if salt.is_empty() {
+                    "-".to_string()
+                } else {
+                    presentation::hex(salt)
+                },
+                presentation::base32hex(next_hashed),
+                type_list(types)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DnsRecord {
+    /// A zone file line: owner, TTL, class, type and data.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let DnsRecord::OPT {
+            packet_len, flags, ..
+        } = *self
+        {
+            // Not a real record, so shown the way dig shows it
+            return write!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                (flags >> 16) & 0xFF,
+                if flags & (1 << 15) > 0 { " do" } else { "" },
+                packet_len
+            );
+        }
+
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t{}",
+            presentation::name(self.get_domain()),
+            self.get_ttl(),
+            self.get_querytype(),
+            self.rdata_string()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `rec`, reads it back and checks that writing the result again
+    /// yields the same bytes.
+    fn round_trip(rec: DnsRecord) {
+        let mut buffer = BytePacketBuffer::new();
+        rec.write(&mut buffer).unwrap();
+        let len = buffer.pos();
+
+        buffer.pos = 0;
+        let read = DnsRecord::read(&mut buffer).unwrap();
+        assert_eq!(read, rec);
+        assert_eq!(buffer.pos(), len);
+
+        let mut again = BytePacketBuffer::new();
+        read.write(&mut again).unwrap();
+        assert_eq!(&again.buf[..again.pos()], &buffer.buf[..len]);
+    }
+
+    #[test]
+    fn names_keep_their_case() {
+        round_trip(DnsRecord::CNAME {
+            domain: "WwW.Example.com".to_string(),
+            host: "Host.EXAMPLE.net".to_string(),
+            ttl: 300,
+        });
+    }
+
+    #[test]
+    fn soa_round_trip() {
+        round_trip(DnsRecord::SOA {
+            domain: "example".to_string(),
+            m_name: "ns1.example".to_string(),
+            r_name: "bugs.x.w.example".to_string(),
+            serial: 1081539377,
+            refresh: 3600,
+            retry: 300,
+            expire: 3600000,
+            minimum: 3600,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn ds_round_trip() {
+        round_trip(DnsRecord::DS {
+            domain: "a.example".to_string(),
+            key_tag: 57855,
+            algorithm: 5,
+            digest_type: 1,
+            digest: vec![
+                0xb6, 0xdc, 0xd4, 0x85, 0x71, 0x9a, 0xdc, 0xa1, 0x8e, 0x5f, 0x3d, 0x48, 0xa2,
+                0x33, 0x1c, 0xe1, 0x8b, 0xe7, 0x84, 0x1d,
+            ],
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn rrsig_round_trip() {
+        round_trip(DnsRecord::RRSIG {
+            domain: "www.example.net".to_string(),
+            type_covered: QueryType::A,
+            algorithm: 13,
+            labels: 3,
+            original_ttl: 3600,
+            expiration: 1284026679,
+            inception: 1281607479,
+            key_tag: 55648,
+            signer_name: "example.net".to_string(),
+            signature: (0..64).collect(),
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn nsec_round_trip() {
+        round_trip(DnsRecord::NSEC {
+            domain: "ai.example".to_string(),
+            next_domain: "b.example".to_string(),
+            types: vec![
+                QueryType::A,
+                QueryType::UNKNOWN(13),
+                QueryType::AAAA,
+                QueryType::RRSIG,
+                QueryType::NSEC,
+            ],
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn dnskey_round_trip() {
+        round_trip(DnsRecord::DNSKEY {
+            domain: "example.com".to_string(),
+            flags: 257,
+            protocol: 3,
+            algorithm: 15,
+            public_key: (0..32).collect(),
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn nsec3_round_trip() {
+        round_trip(DnsRecord::NSEC3 {
+            domain: "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example".to_string(),
+            hash_algorithm: 1,
+            flags: 1,
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            next_hashed: (0..20).collect(),
+            types: vec![
+                QueryType::NS,
+                QueryType::SOA,
+                QueryType::MX,
+                QueryType::RRSIG,
+                QueryType::DNSKEY,
+                QueryType::UNKNOWN(51),
+            ],
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn txt_round_trip() {
+        round_trip(DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec![b"v=spf1 -all".to_vec(), Vec::new(), vec![0, 255]],
+            ttl: 300,
+        });
+    }
+
+    #[test]
+    fn presents_records() {
+        let txt = DnsRecord::TXT {
+            domain: "a b.example.com".to_string(),
+            data: vec![b"say \"hi\"".to_vec(), vec![7]],
+            ttl: 300,
+        };
+        assert_eq!(
+            txt.to_string(),
+            "a\\032b.example.com.\t300\tIN\tTXT\t\"say \\\"hi\\\"\" \"\\007\""
+        );
+
+        let mx = DnsRecord::MX {
+            domain: "".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 60,
+        };
+        assert_eq!(mx.to_string(), ".\t60\tIN\tMX\t10 mail.example.com.");
+
+        let unknown = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65280,
+            data: vec![0x0a, 0x00, 0x00, 0x01],
+            ttl: 0,
+        };
+        assert_eq!(
+            unknown.to_string(),
+            "example.com.\t0\tIN\tTYPE65280\t\\# 4 0A000001"
+        );
+
+        let nsec3 = DnsRecord::NSEC3 {
+            domain: "example".to_string(),
+            hash_algorithm: 1,
+            flags: 1,
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            next_hashed: vec![0xff; 5],
+            types: vec![QueryType::A, QueryType::RRSIG],
+            ttl: 3600,
+        };
+        assert_eq!(
+            nsec3.to_string(),
+            "example.\t3600\tIN\tNSEC3\t1 1 12 AABBCCDD VVVVVVVV A RRSIG"
+        );
+    }
 }
@@ -0,0 +1,1883 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use ring::signature;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_name;
+use dns_packet::DnsPacket;
+use dns_record::DnsRecord;
+use query_type::QueryType;
+use result_code::ResultCode;
+
+// DNSKEY flags
+const ZONE_KEY: u16 = 1 << 8;
+const REVOKE: u16 = 1 << 7;
+// NSEC3 flags
+const OPT_OUT: u8 = 1;
+// RFC 9276: treat zones using more iterations than this as insecure
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure,
+    Bogus(&'static str),
+}
+
+impl Security {
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+/// The DS records of the root zone KSKs published by IANA.
+pub fn root_trust_anchors() -> Vec<DnsRecord> {
+    vec![
+        trust_anchor(
+            "",
+            20326,
+            8,
+            2,
+            "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBF683457104237C7F8EC8D",
+        ).expect("Malformed root trust anchor"),
+        trust_anchor(
+            "",
+            38696,
+            8,
+            2,
+            "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+        ).expect("Malformed root trust anchor"),
+    ]
+}
+
+pub fn trust_anchor(
+    zone: &str,
+    key_tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    digest: &str,
+) -> Result<DnsRecord, Error> {
+    if digest.is_empty() || digest.len() % 2 != 0 || !digest.is_ascii() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Malformed trust anchor digest"));
+    }
+
+    let mut bytes = Vec::with_capacity(digest.len() / 2);
+    for i in 0..digest.len() / 2 {
+        match u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16) {
+            Ok(b) => bytes.push(b),
+            Err(_) => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Malformed trust anchor digest"))
+            }
+        }
+    }
+
+    Ok(DnsRecord::DS {
+        domain: zone.to_string(),
+        key_tag: key_tag,
+        algorithm: algorithm,
+        digest_type: digest_type,
+        digest: bytes,
+        ttl: 0,
+    })
+}
+
+struct RRset {
+    name: String,
+    qtype: QueryType,
+    records: Vec<DnsRecord>,
+    sigs: Vec<DnsRecord>,
+}
+
+fn group_rrsets(records: &[DnsRecord]) -> Vec<RRset> {
+    let mut rrsets: Vec<RRset> = Vec::new();
+    for rec in records {
+        let (qtype, is_sig) = match *rec {
+            DnsRecord::OPT { .. } => continue,
+            DnsRecord::RRSIG { type_covered, .. } => (type_covered, true),
+            _ => (rec.get_querytype(), false),
+        };
+
+        let idx = match rrsets.iter().position(|set| {
+            set.qtype == qtype && dns_name::names_equal(&set.name, rec.get_domain())
+        }) {
+            Some(idx) => idx,
+            None => {
+                rrsets.push(RRset {
+                    name: rec.get_domain().to_string(),
+                    qtype: qtype,
+                    records: Vec::new(),
+                    sigs: Vec::new(),
+                });
+                rrsets.len() - 1
+            }
+        };
+
+        if is_sig {
+            rrsets[idx].sigs.push(rec.clone());
+        } else {
+            rrsets[idx].records.push(rec.clone());
+        }
+    }
+    rrsets
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Authenticated zone keys and provably insecure names learnt while
+/// validating, kept across queries until their TTL or signatures run out.
+pub struct KeyCache {
+    keys: HashMap<String, (Option<Vec<DnsRecord>>, u32)>,
+    insecure: HashMap<String, (bool, u32)>,
+}
+
+impl KeyCache {
+    pub fn new() -> KeyCache {
+        KeyCache {
+            keys: HashMap::new(),
+            insecure: HashMap::new(),
+        }
+    }
+
+    fn expire(&mut self, now: u32) {
+        self.keys.retain(|_, entry| entry.1 > now);
+        self.insecure.retain(|_, entry| entry.1 > now);
+    }
+
+    fn keys(&self, zone: &str, now: u32) -> Option<&Option<Vec<DnsRecord>>> {
+        match self.keys.get(zone) {
+            Some(&(ref keys, expires)) if expires > now => Some(keys),
+            _ => None,
+        }
+    }
+
+    fn insecure(&self, name: &str, now: u32) -> Option<bool> {
+        match self.insecure.get(name) {
+            Some(&(res, expires)) if expires > now => Some(res),
+            _ => None,
+        }
+    }
+}
+
+impl Default for KeyCache {
+    fn default() -> KeyCache {
+        KeyCache::new()
+    }
+}
+
+/// The TTL an RRset of a validated response may be kept for.
+type TtlCap = (String, QueryType, u32);
+
+/// Validates responses against a chain of trust starting at the trust
+/// anchors, fetching the DS and DNSKEY records it needs through `fetch`.
+pub struct Validator<'a, F>
+where
+    F: FnMut(&str, QueryType) -> Result<DnsPacket, Error>,
+{
+    trust_anchors: &'a [DnsRecord],
+    cache: &'a mut KeyCache,
+    fetch: F,
+    pending: HashSet<String>,
+    now: u32,
+}
+
+impl<'a, F> Validator<'a, F>
+where
+    F: FnMut(&str, QueryType) -> Result<DnsPacket, Error>,
+{
+    pub fn new(
+        trust_anchors: &'a [DnsRecord],
+        cache: &'a mut KeyCache,
+        fetch: F,
+    ) -> Validator<'a, F> {
+        let now = now();
+        cache.expire(now);
+        Validator {
+            trust_anchors: trust_anchors,
+            cache: cache,
+            fetch: fetch,
+            pending: HashSet::new(),
+            now: now,
+        }
+    }
+
+    /// Validates `response`, lowering the TTLs of its authenticated records
+    /// to what their signatures allow.
+    pub fn validate(
+        &mut self,
+        qname: &str,
+        qtype: QueryType,
+        response: &mut DnsPacket,
+    ) -> Security {
+        let (security, _, caps) = self.check_response(qname, qtype, response);
+        cap_ttls(&mut response.answers, &caps);
+        cap_ttls(&mut response.authorities, &caps);
+        security
+    }
+
+    /// Returns the security of `response`, the authenticated NSEC/NSEC3
+    /// records it contained and the TTLs its signatures allow.
+    fn check_response(
+        &mut self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> (Security, Vec<DnsRecord>, Vec<TtlCap>) {
+        match response.header.rescode {
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+            _ => return (Security::Bogus("lookup failed"), Vec::new(), Vec::new()),
+        }
+
+        let mut security = Security::Secure;
+        let mut wildcards = Vec::new();
+        let mut caps = Vec::new();
+        let answers = group_rrsets(&response.answers);
+        for rrset in &answers {
+            // Covered by the signature over the DNAME it was synthesized from
+            if rrset.sigs.is_empty() && synthesized_from_dname(rrset, &answers) {
+                continue;
+            }
+
+            let (res, labels, ttl) = self.verify_rrset(rrset);
+            security = security.and(res);
+            if let Some(labels) = labels {
+                wildcards.push((rrset.name.clone(), labels));
+            }
+            if res == Security::Secure {
+                caps.push((rrset.name.clone(), rrset.qtype, ttl));
+            }
+        }
+
+        let mut proofs = Vec::new();
+        for rrset in group_rrsets(&response.authorities) {
+            match rrset.qtype {
+                QueryType::SOA | QueryType::NSEC | QueryType::NSEC3 => {}
+                _ => continue,
+            }
+
+            let (res, _, ttl) = self.verify_rrset(&rrset);
+            if res == Security::Secure {
+                caps.push((rrset.name.clone(), rrset.qtype, ttl));
+                if rrset.qtype != QueryType::SOA {
+                    proofs.extend(rrset.records);
+                }
+            }
+            security = security.and(res);
+        }
+
+        if security != Security::Secure {
+            return (security, proofs, caps);
+        }
+
+        // Names synthesized from a wildcard need proof that the name itself
+        // doesn't exist
+        for &(ref name, labels) in &wildcards {
+            security = security.and(prove_wildcard_expansion(name, labels, &proofs));
+        }
+
+        let target = follow_cnames(qname, &response.answers);
+        let positive = qtype == QueryType::CNAME && !dns_name::names_equal(&target, qname)
+            || response.answers.iter().any(|rec| {
+                rec.get_querytype() == qtype && dns_name::names_equal(rec.get_domain(), &target)
+            });
+
+        if !positive {
+            if proofs.is_empty() && self.is_insecure(&target) {
+                return (Security::Insecure, proofs, caps);
+            }
+
+            let denial = if response.header.rescode == ResultCode::NXDOMAIN {
+                prove_nxdomain(&target, &proofs)
+            } else {
+                prove_nodata(&target, qtype, &proofs)
+            };
+            security = security.and(denial);
+        }
+
+        (security, proofs, caps)
+    }
+
+    /// Verifies the signatures over `rrset`, returning the label count of the
+    /// signature if the records were expanded from a wildcard and the TTL the
+    /// records may be kept for.
+    fn verify_rrset(&mut self, rrset: &RRset) -> (Security, Option<u8>, u32) {
+        let ttl = min_ttl(&rrset.records);
+        if rrset.sigs.is_empty() {
+            if self.is_insecure(&rrset.name) {
+                return (Security::Insecure, None, ttl);
+            }
+            return (Security::Bogus("missing signatures"), None, ttl);
+        }
+
+        let mut result = Security::Bogus("no valid signature");
+        for sig in &rrset.sigs {
+            let (algorithm, labels, expiration, inception, key_tag, signer_name) = match *sig {
+                DnsRecord::RRSIG {
+                    algorithm,
+                    labels,
+                    expiration,
+                    inception,
+                    key_tag,
+                    ref signer_name,
+                    ..
+                } => (
+                    algorithm,
+                    labels,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                ),
+                _ => continue,
+            };
+
+            if !dns_name::is_subdomain(&rrset.name, signer_name)
+                || labels as usize > dns_name::label_count(&rrset.name)
+            {
+                continue;
+            }
+
+            if !self.is_current(inception, expiration) {
+                result = Security::Bogus("signature expired");
+                continue;
+            }
+
+            let keys = match self.trusted_keys(signer_name) {
+                Ok(Some(keys)) => keys,
+                Ok(None) => return (Security::Insecure, None, ttl),
+                Err(reason) => {
+                    result = Security::Bogus(reason);
+                    continue;
+                }
+            };
+
+            let data = match signed_data(&rrset.name, &rrset.records, sig) {
+                Some(x) => x,
+                None => continue,
+            };
+            for key in &keys {
+                if let DnsRecord::DNSKEY {
+                    algorithm: key_algorithm,
+                    ..
+                } = *key
+                {
+                    if key_algorithm == algorithm && dnskey_tag(key) == key_tag
+                        && verify_signature(key, sig, &data)
+                    {
+                        let wildcard = if (labels as usize) < dns_name::label_count(&rrset.name) {
+                            Some(labels)
+                        } else {
+                            None
+                        };
+                        return (Security::Secure, wildcard, self.signed_ttl(ttl, sig));
+                    }
+                }
+            }
+        }
+
+        (result, None, ttl)
+    }
+
+    fn is_current(&self, inception: u32, expiration: u32) -> bool {
+        // Serial number arithmetic, RFC 4034 section 3.1.5
+        (self.now.wrapping_sub(inception) as i32) >= 0
+            && (expiration.wrapping_sub(self.now) as i32) >= 0
+    }
+
+    /// RFC 4035 section 5.3.3: records authenticated by `sig` keep neither
+    /// more than its original TTL nor past its expiration.
+    fn signed_ttl(&self, ttl: u32, sig: &DnsRecord) -> u32 {
+        match *sig {
+            DnsRecord::RRSIG {
+                original_ttl,
+                expiration,
+                ..
+            } => ttl.min(original_ttl)
+                .min(expiration.wrapping_sub(self.now)),
+            _ => ttl,
+        }
+    }
+
+    fn anchors_for(&self, zone: &str) -> Vec<DnsRecord> {
+        self.trust_anchors
+            .iter()
+            .filter(|ds| dns_name::names_equal(ds.get_domain(), zone))
+            .cloned()
+            .collect()
+    }
+
+    /// The authenticated zone keys of `zone`, or None if the zone is provably
+    /// unsigned.
+    fn trusted_keys(&mut self, zone: &str) -> Result<Option<Vec<DnsRecord>>, &'static str> {
+        let zone = zone.to_ascii_lowercase();
+        if let Some(keys) = self.cache.keys(&zone, self.now) {
+            return Ok(keys.clone());
+        }
+
+        if !self.pending.insert(zone.clone()) {
+            return Err("validation loop");
+        }
+        let res = self.fetch_trusted_keys(&zone);
+        self.pending.remove(&zone);
+
+        let (keys, ttl) = try!(res);
+        self.cache
+            .keys
+            .insert(zone, (keys.clone(), self.now.saturating_add(ttl)));
+        Ok(keys)
+    }
+
+    /// Like `trusted_keys`, along with how long the answer holds.
+    fn fetch_trusted_keys(
+        &mut self,
+        zone: &str,
+    ) -> Result<(Option<Vec<DnsRecord>>, u32), &'static str> {
+        let mut ds_set = self.anchors_for(zone);
+        let mut ds_ttl = u32::MAX;
+        if ds_set.is_empty() {
+            if dns_name::parent(zone).is_none() {
+                return Err("no trust anchor");
+            }
+
+            let response = match (self.fetch)(zone, QueryType::DS) {
+                Ok(x) => x,
+                Err(_) => return Err("DS lookup failed"),
+            };
+
+            let (security, _, caps) = self.check_response(zone, QueryType::DS, &response);
+            ds_ttl = response_ttl(&response, &caps);
+            match security {
+                Security::Secure => {}
+                Security::Insecure => return Ok((None, ds_ttl)),
+                Security::Bogus(reason) => return Err(reason),
+            }
+
+            ds_set = response
+                .answers
+                .into_iter()
+                .filter(|rec| {
+                    rec.get_querytype() == QueryType::DS
+                        && dns_name::names_equal(rec.get_domain(), zone)
+                })
+                .collect();
+        }
+
+        // RFC 4035 section 5.2: a zone is only as secure as the algorithms
+        // its DS records use
+        ds_set.retain(|ds| match *ds {
+            DnsRecord::DS {
+                algorithm,
+                digest_type,
+                ..
+            } => supported_algorithm(algorithm) && ds_digest(digest_type).is_some(),
+            _ => false,
+        });
+        if ds_set.is_empty() {
+            return Ok((None, ds_ttl));
+        }
+
+        let response = match (self.fetch)(zone, QueryType::DNSKEY) {
+            Ok(x) => x,
+            Err(_) => return Err("DNSKEY lookup failed"),
+        };
+        let rrset = match group_rrsets(&response.answers).into_iter().find(|set| {
+            set.qtype == QueryType::DNSKEY && dns_name::names_equal(&set.name, zone)
+        }) {
+            Some(x) => x,
+            None => return Err("missing DNSKEY"),
+        };
+
+        for ds in &ds_set {
+            for key in rrset
+                .records
+                .iter()
+                .filter(|key| is_zone_key(key) && ds_matches(ds, key, zone))
+            {
+                for sig in &rrset.sigs {
+                    let valid = match *sig {
+                        DnsRecord::RRSIG {
+                            key_tag,
+                            expiration,
+                            inception,
+                            ref signer_name,
+                            ..
+                        } => {
+                            key_tag == dnskey_tag(key) && dns_name::names_equal(signer_name, zone)
+                                && self.is_current(inception, expiration)
+                        }
+                        _ => false,
+                    };
+                    if !valid {
+                        continue;
+                    }
+
+                    let verified = match signed_data(zone, &rrset.records, sig) {
+                        Some(data) => verify_signature(key, sig, &data),
+                        None => false,
+                    };
+                    if verified {
+                        let keys = rrset
+                            .records
+                            .iter()
+                            .filter(|key| is_zone_key(key))
+                            .cloned()
+                            .collect();
+                        let ttl = self.signed_ttl(min_ttl(&rrset.records), sig);
+                        return Ok((Some(keys), ttl.min(ds_ttl)));
+                    }
+                }
+            }
+        }
+
+        Err("DNSKEY does not match DS")
+    }
+
+    /// True if some ancestor of `name` is a provably unsigned delegation.
+    fn is_insecure(&mut self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        if let Some(res) = self.cache.insecure(&name, self.now) {
+            return res;
+        }
+
+        let key = format!("insecure:{}", name);
+        if !self.pending.insert(key.clone()) {
+            return false;
+        }
+        let (res, ttl) = self.find_insecure_delegation(&name);
+        self.pending.remove(&key);
+
+        if ttl > 0 {
+            self.cache
+                .insecure
+                .insert(name, (res, self.now.saturating_add(ttl)));
+        }
+        res
+    }
+
+    /// Like `is_insecure`, along with how long the answer holds. Failed
+    /// lookups yield a TTL of zero so they aren't remembered.
+    fn find_insecure_delegation(&mut self, name: &str) -> (bool, u32) {
+        let mut ttl = u32::MAX;
+        let ancestors = dns_name::ancestors(name);
+        for zone in ancestors.iter().rev() {
+            if !self.anchors_for(zone).is_empty() {
+                continue;
+            }
+
+            match self.cache.keys(zone, self.now) {
+                Some(&None) => return (true, ttl),
+                Some(&Some(_)) => continue,
+                None => {}
+            }
+
+            let response = match (self.fetch)(zone, QueryType::DS) {
+                Ok(x) => x,
+                Err(_) => return (false, 0),
+            };
+
+            let has_ds = response.answers.iter().any(|rec| {
+                rec.get_querytype() == QueryType::DS && dns_name::names_equal(rec.get_domain(), zone)
+            });
+
+            let (security, proofs, caps) = self.check_response(zone, QueryType::DS, &response);
+            ttl = ttl.min(response_ttl(&response, &caps));
+            match security {
+                Security::Bogus(_) => return (false, 0),
+                Security::Insecure => return (true, ttl),
+                Security::Secure => {
+                    if !has_ds && is_delegation(zone, &proofs) {
+                        return (true, ttl);
+                    }
+                }
+            }
+        }
+
+        (false, ttl)
+    }
+}
+
+/// True if `rrset` is a CNAME synthesized from one of the DNAMEs in
+/// `answers` (RFC 6672 section 5.3.1).
+fn synthesized_from_dname(rrset: &RRset, answers: &[RRset]) -> bool {
+    if rrset.qtype != QueryType::CNAME || rrset.records.len() != 1 {
+        return false;
+    }
+    let host = match rrset.records[0] {
+        DnsRecord::CNAME { ref host, .. } => host,
+        _ => return false,
+    };
+
+    let labels = dns_name::labels(&rrset.name);
+    answers
+        .iter()
+        .filter(|set| set.qtype == QueryType::DNAME)
+        .flat_map(|set| set.records.iter())
+        .any(|rec| match *rec {
+            DnsRecord::DNAME {
+                ref domain,
+                host: ref target,
+                ..
+            } => {
+                let owner_labels = dns_name::label_count(domain);
+                if owner_labels >= labels.len() || !dns_name::is_subdomain(&rrset.name, domain) {
+                    return false;
+                }
+
+                let prefix = labels[..labels.len() - owner_labels].join(".");
+                let expected = if target.is_empty() {
+                    prefix
+                } else {
+                    format!("{}.{}", prefix, target)
+                };
+                dns_name::names_equal(host, &expected)
+            }
+            _ => false,
+        })
+}
+
+fn min_ttl(records: &[DnsRecord]) -> u32 {
+    records
+        .iter()
+        .map(|rec| rec.get_ttl())
+        .min()
+        .unwrap_or(0)
+}
+
+/// How long the conclusion drawn from `response` holds: as long as its
+/// authenticated records, or its plain records if nothing was signed.
+fn response_ttl(response: &DnsPacket, caps: &[TtlCap]) -> u32 {
+    match caps.iter().map(|cap| cap.2).min() {
+        Some(ttl) => ttl,
+        None => {
+            let records = response
+                .answers
+                .iter()
+                .chain(response.authorities.iter())
+                .cloned()
+                .collect::<Vec<DnsRecord>>();
+            min_ttl(&records)
+        }
+    }
+}
+
+fn cap_ttls(records: &mut [DnsRecord], caps: &[TtlCap]) {
+    for rec in records.iter_mut() {
+        let qtype = match *rec {
+            DnsRecord::RRSIG { type_covered, .. } => type_covered,
+            _ => rec.get_querytype(),
+        };
+        let cap = caps
+            .iter()
+            .find(|cap| cap.1 == qtype && dns_name::names_equal(&cap.0, rec.get_domain()))
+            .map(|cap| cap.2);
+        if let Some(cap) = cap {
+            if rec.get_ttl() > cap {
+                rec.set_ttl(cap);
+            }
+        }
+    }
+}
+
+/// Zone keys usable for validation; RFC 5011 revoked keys are not.
+fn is_zone_key(key: &DnsRecord) -> bool {
+    match *key {
+        DnsRecord::DNSKEY {
+            flags, protocol, ..
+        } => (flags & ZONE_KEY) > 0 && (flags & REVOKE) == 0 && protocol == 3,
+        _ => false,
+    }
+}
+
+/// The name the CNAMEs in `answers` lead to from `qname`.
+pub fn follow_cnames(qname: &str, answers: &[DnsRecord]) -> String {
+    let mut target = qname.to_string();
+    // Bound the chain so a CNAME loop can't keep us here
+    for _ in 0..answers.len() {
+        let next = answers.iter().filter_map(|rec| match *rec {
+            DnsRecord::CNAME {
+                ref domain,
+                ref host,
+                ..
+            } if dns_name::names_equal(domain, &target) => Some(host.clone()),
+            _ => None,
+        }).next();
+
+        match next {
+            Some(host) => target = host,
+            None => break,
+        }
+    }
+    target
+}
+
+fn supported_algorithm(algorithm: u8) -> bool {
+    match algorithm {
+        8 | 13 | 14 | 15 => true,
+        _ => false,
+    }
+}
+
+fn ds_digest(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+fn dnskey_rdata(key: &DnsRecord) -> Vec<u8> {
+    match *key {
+        DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            ref public_key,
+            ..
+        } => {
+            let mut rdata = vec![(flags >> 8) as u8, (flags & 0xFF) as u8, protocol, algorithm];
+            rdata.extend(public_key);
+            rdata
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// RFC 4034 appendix B
+pub fn dnskey_tag(key: &DnsRecord) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, b) in dnskey_rdata(key).iter().enumerate() {
+        if i & 1 == 1 {
+            ac += *b as u32;
+        } else {
+            ac += (*b as u32) << 8;
+        }
+    }
+    ac += (ac >> 16) & 0xFFFF;
+    (ac & 0xFFFF) as u16
+}
+
+fn ds_matches(ds: &DnsRecord, key: &DnsRecord, zone: &str) -> bool {
+    match (ds, key) {
+        (
+            &DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            },
+            &DnsRecord::DNSKEY {
+                algorithm: key_algorithm,
+                ..
+            },
+        ) => {
+            if key_tag != dnskey_tag(key) || algorithm != key_algorithm {
+                return false;
+            }
+
+            let alg = match ds_digest(digest_type) {
+                Some(x) => x,
+                None => return false,
+            };
+
+            let mut data = dns_name::to_canonical_wire(zone);
+            data.extend(dnskey_rdata(key));
+            digest::digest(alg, &data).as_ref() == &digest[..]
+        }
+        _ => false,
+    }
+}
+
+fn verify_signature(key: &DnsRecord, sig: &DnsRecord, data: &[u8]) -> bool {
+    let (algorithm, public_key) = match *key {
+        DnsRecord::DNSKEY {
+            algorithm,
+            ref public_key,
+            ..
+        } => (algorithm, public_key),
+        _ => return false,
+    };
+    let sig = match *sig {
+        DnsRecord::RRSIG { ref signature, .. } => signature,
+        _ => return false,
+    };
+
+    match algorithm {
+        // RSA/SHA-256, public key as in RFC 3110
+        8 => {
+            if public_key.is_empty() {
+                return false;
+            }
+            let (exp_len, offset) = if public_key[0] == 0 {
+                if public_key.len() < 3 {
+                    return false;
+                }
+                (((public_key[1] as usize) << 8) | public_key[2] as usize, 3)
+            } else {
+                (public_key[0] as usize, 1)
+            };
+            if public_key.len() < offset + exp_len {
+                return false;
+            }
+
+            let e = &public_key[offset..offset + exp_len];
+            let n = &public_key[offset + exp_len..];
+            let n = &n[n.iter().take_while(|b| **b == 0).count()..];
+            signature::RsaPublicKeyComponents { n: n, e: e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    sig,
+                )
+                .is_ok()
+        }
+        // ECDSA keys are stored without the uncompressed point prefix
+        13 | 14 => {
+            let alg = if algorithm == 13 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            let mut point = vec![4];
+            point.extend(public_key);
+            signature::UnparsedPublicKey::new(alg, &point)
+                .verify(data, sig)
+                .is_ok()
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Copy of `rec` with the names inside its RDATA lowercased (RFC 4034
+/// section 6.2).
+fn canonical_record(rec: &DnsRecord) -> DnsRecord {
+    let mut rec = rec.clone();
+    match rec {
+        DnsRecord::NS { ref mut host, .. }
+        | DnsRecord::CNAME { ref mut host, .. }
+        | DnsRecord::PTR { ref mut host, .. }
+        | DnsRecord::DNAME { ref mut host, .. }
+        | DnsRecord::MX { ref mut host, .. } => *host = host.to_ascii_lowercase(),
+        DnsRecord::SOA {
+            ref mut m_name,
+            ref mut r_name,
+            ..
+        } => {
+            *m_name = m_name.to_ascii_lowercase();
+            *r_name = r_name.to_ascii_lowercase();
+        }
+        DnsRecord::RRSIG {
+            ref mut signer_name,
+            ..
+        } => *signer_name = signer_name.to_ascii_lowercase(),
+        _ => {}
+    }
+    rec
+}
+
+fn canonical_rdata(rec: &DnsRecord) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::with_size(0x10000);
+    if canonical_record(rec).write(&mut buffer).is_err() {
+        return Vec::new();
+    }
+
+    // Skip owner, type, class, ttl and rdlength
+    let start = dns_name::to_canonical_wire(rec.get_domain()).len() + 10;
+    buffer.buf[start..buffer.pos()].to_vec()
+}
+
+/// The data covered by `sig` over the RRset `records` (RFC 4034 section
+/// 3.1.8.1), or None if `sig` can't be encoded.
+fn signed_data(name: &str, records: &[DnsRecord], sig: &DnsRecord) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let (type_covered, labels, original_ttl, signature) = match *sig {
+        DnsRecord::RRSIG {
+            type_covered,
+            labels,
+            original_ttl,
+            ref signature,
+            ..
+        } => (type_covered, labels, original_ttl, signature),
+        _ => return None,
+    };
+
+    let mut rrsig = canonical_rdata(sig);
+    // Everything but the signature itself
+    let len = match rrsig.len().checked_sub(signature.len()) {
+        Some(x) => x,
+        None => return None,
+    };
+    rrsig.truncate(len);
+    data.extend(rrsig);
+
+    let owner = if (labels as usize) < dns_name::label_count(name) {
+        let suffix = dns_name::suffix(name, labels as usize);
+        if suffix.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", suffix)
+        }
+    } else {
+        name.to_string()
+    };
+    let owner = dns_name::to_canonical_wire(&owner);
+
+    let mut rdatas = records.iter().map(canonical_rdata).collect::<Vec<Vec<u8>>>();
+    rdatas.sort();
+    rdatas.dedup();
+
+    for rdata in rdatas {
+        data.extend(&owner);
+        let typenum = type_covered.to_num();
+        data.extend(&[(typenum >> 8) as u8, (typenum & 0xFF) as u8, 0, 1]);
+        data.extend(&[
+            (original_ttl >> 24) as u8,
+            (original_ttl >> 16) as u8,
+            (original_ttl >> 8) as u8,
+            original_ttl as u8,
+        ]);
+        data.extend(&[(rdata.len() >> 8) as u8, (rdata.len() & 0xFF) as u8]);
+        data.extend(rdata);
+    }
+
+    Some(data)
+}
+
+fn wildcard_of(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", name)
+    }
+}
+
+fn nsec_covers(owner: &str, next: &str, name: &str) -> bool {
+    let after_owner = dns_name::canonical_cmp(owner, name) == Ordering::Less;
+    let before_next = dns_name::canonical_cmp(name, next) == Ordering::Less;
+    if dns_name::canonical_cmp(owner, next) == Ordering::Less {
+        after_owner && before_next
+    } else {
+        // The last NSEC of a zone points back to the apex
+        after_owner || before_next
+    }
+}
+
+fn nsec_covering<'b>(name: &str, proofs: &'b [DnsRecord]) -> Option<&'b DnsRecord> {
+    proofs.iter().find(|rec| match **rec {
+        DnsRecord::NSEC {
+            ref domain,
+            ref next_domain,
+            ..
+        } => nsec_covers(domain, next_domain, name),
+        _ => false,
+    })
+}
+
+/// The longest ancestor of `name` shared with either end of the NSEC that
+/// covers it (RFC 4035 section 5.4).
+fn nsec_closest_encloser<'b>(name: &'b str, owner: &str, next: &str) -> &'b str {
+    dns_name::ancestors(name)
+        .into_iter()
+        .skip(1)
+        .find(|a| dns_name::is_subdomain(owner, a) || dns_name::is_subdomain(next, a))
+        .unwrap_or("")
+}
+
+fn nsec_types<'b>(name: &str, proofs: &'b [DnsRecord]) -> Option<&'b Vec<QueryType>> {
+    proofs
+        .iter()
+        .filter_map(|rec| match *rec {
+            DnsRecord::NSEC {
+                ref domain,
+                ref types,
+                ..
+            } if dns_name::names_equal(domain, name) => Some(types),
+            _ => None,
+        })
+        .next()
+}
+
+fn base32hex_decode(label: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in label.bytes() {
+        let val = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'v' => c - b'a' + 10,
+            b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        acc = (acc << 5) | val as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(res)
+}
+
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = dns_name::to_canonical_wire(name);
+    data.extend(salt);
+    let mut hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data)
+        .as_ref()
+        .to_vec();
+    for _ in 0..iterations {
+        let mut data = hash.clone();
+        data.extend(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data)
+            .as_ref()
+            .to_vec();
+    }
+    hash
+}
+
+/// Compares `name` against an NSEC3 record, yielding the hash of its owner,
+/// the hash of `name` and the record's types and flags.
+fn nsec3_compare<'b>(
+    rec: &'b DnsRecord,
+    name: &str,
+) -> Option<(Vec<u8>, Vec<u8>, &'b [u8], &'b [QueryType], u8)> {
+    match *rec {
+        DnsRecord::NSEC3 {
+            ref domain,
+            hash_algorithm,
+            flags,
+            iterations,
+            ref salt,
+            ref next_hashed,
+            ref types,
+            ..
+        } => {
+            let labels = dns_name::labels(domain);
+            if hash_algorithm != 1 || labels.is_empty() {
+                return None;
+            }
+
+            let zone = labels[1..].join(".");
+            if !dns_name::is_subdomain(name, &zone) {
+                return None;
+            }
+
+            let owner = match base32hex_decode(labels[0]) {
+                Some(x) => x,
+                None => return None,
+            };
+            Some((
+                owner,
+                nsec3_hash(name, salt, iterations),
+                next_hashed,
+                types,
+                flags,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn nsec3_matching<'b>(name: &str, proofs: &'b [DnsRecord]) -> Option<&'b [QueryType]> {
+    proofs
+        .iter()
+        .filter_map(|rec| match nsec3_compare(rec, name) {
+            Some((owner, hash, _, types, _)) if owner == hash => Some(types),
+            _ => None,
+        })
+        .next()
+}
+
+/// Flags of an NSEC3 record whose hash interval covers `name`.
+fn nsec3_covering(name: &str, proofs: &[DnsRecord]) -> Option<u8> {
+    proofs
+        .iter()
+        .filter_map(|rec| match nsec3_compare(rec, name) {
+            Some((owner, hash, next, _, flags)) => {
+                let covered = if &owner[..] < next {
+                    owner < hash && &hash[..] < next
+                } else {
+                    owner < hash || &hash[..] < next
+                };
+                if covered {
+                    Some(flags)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+        .next()
+}
+
+/// RFC 5155 section 8.3: the closest encloser of `name` together with the
+/// flags of the NSEC3 covering the next closer name.
+fn closest_encloser(name: &str, proofs: &[DnsRecord]) -> Option<(String, u8)> {
+    let ancestors = dns_name::ancestors(name);
+    for i in 1..ancestors.len() {
+        if nsec3_matching(ancestors[i], proofs).is_some() {
+            return nsec3_covering(ancestors[i - 1], proofs)
+                .map(|flags| (ancestors[i].to_string(), flags));
+        }
+    }
+    None
+}
+
+fn uses_nsec3(proofs: &[DnsRecord]) -> bool {
+    proofs
+        .iter()
+        .any(|rec| rec.get_querytype() == QueryType::NSEC3)
+}
+
+fn excessive_iterations(proofs: &[DnsRecord]) -> bool {
+    proofs.iter().any(|rec| match *rec {
+        DnsRecord::NSEC3 { iterations, .. } => iterations > MAX_NSEC3_ITERATIONS,
+        _ => false,
+    })
+}
+
+fn lacks_type(types: &[QueryType], qtype: QueryType) -> bool {
+    !types.contains(&qtype) && !types.contains(&QueryType::CNAME)
+}
+
+/// Checks the type bitmap of the NSEC or NSEC3 record matching a name that
+/// supposedly has no data of type `qtype`.
+fn nodata_types(name: &str, types: &[QueryType], qtype: QueryType) -> Security {
+    let apex = types.contains(&QueryType::SOA);
+    if qtype == QueryType::DS {
+        if apex && !name.is_empty() {
+            return Security::Bogus("DS denied by child zone");
+        }
+    } else if types.contains(&QueryType::NS) && !apex {
+        // RFC 6840 section 4.1: the parent side of a delegation can't deny
+        // data held by the child
+        return Security::Bogus("data denied by parent zone");
+    }
+
+    // RFC 6672 section 5.3.4.1: names below a DNAME are redirected
+    if types.contains(&QueryType::DNAME) {
+        return Security::Bogus("name redirected by DNAME");
+    }
+
+    if lacks_type(types, qtype) {
+        Security::Secure
+    } else {
+        Security::Bogus("type exists")
+    }
+}
+
+/// True if names below the owner of `types` belong to another zone or are
+/// redirected, so the owner can't be the closest encloser of a missing name.
+fn blocks_descendants(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::DNAME)
+        || types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+fn prove_nxdomain(name: &str, proofs: &[DnsRecord]) -> Security {
+    if uses_nsec3(proofs) {
+        if excessive_iterations(proofs) {
+            return Security::Insecure;
+        }
+
+        return match closest_encloser(name, proofs) {
+            Some((ref ce, _))
+                if nsec3_matching(ce, proofs).is_some_and(blocks_descendants) =>
+            {
+                Security::Bogus("closest encloser is a delegation or DNAME")
+            }
+            Some((ce, flags)) => match nsec3_covering(&wildcard_of(&ce), proofs) {
+                Some(_) if flags & OPT_OUT > 0 => Security::Insecure,
+                Some(_) => Security::Secure,
+                None => Security::Bogus("wildcard not denied"),
+            },
+            None => Security::Bogus("no closest encloser proof"),
+        };
+    }
+
+    let covering = match nsec_covering(name, proofs) {
+        Some(x) => x,
+        None => return Security::Bogus("name not denied"),
+    };
+
+    let ce = match *covering {
+        DnsRecord::NSEC {
+            ref domain,
+            ref next_domain,
+            ref types,
+            ..
+        } => {
+            if dns_name::is_subdomain(name, domain) && blocks_descendants(types) {
+                return Security::Bogus("name below a delegation or DNAME");
+            }
+            nsec_closest_encloser(name, domain, next_domain)
+        }
+        _ => "",
+    };
+
+    if nsec_covering(&wildcard_of(ce), proofs).is_some() {
+        Security::Secure
+    } else {
+        Security::Bogus("wildcard not denied")
+    }
+}
+
+fn prove_nodata(name: &str, qtype: QueryType, proofs: &[DnsRecord]) -> Security {
+    if uses_nsec3(proofs) {
+        if excessive_iterations(proofs) {
+            return Security::Insecure;
+        }
+
+        if let Some(types) = nsec3_matching(name, proofs) {
+            return nodata_types(name, types, qtype);
+        }
+
+        return match closest_encloser(name, proofs) {
+            // Unsigned delegations inside an opt-out span
+            Some((_, flags)) if qtype == QueryType::DS && flags & OPT_OUT > 0 => {
+                Security::Insecure
+            }
+            Some((ce, _)) => match nsec3_matching(&wildcard_of(&ce), proofs) {
+                Some(types) => nodata_types(&wildcard_of(&ce), types, qtype),
+                None => Security::Bogus("no data not proven"),
+            },
+            None => Security::Bogus("no closest encloser proof"),
+        };
+    }
+
+    if let Some(types) = nsec_types(name, proofs) {
+        return nodata_types(name, types, qtype);
+    }
+
+    // No data at a wildcard that would have matched
+    if let Some(&DnsRecord::NSEC {
+        ref domain,
+        ref next_domain,
+        ..
+    }) = nsec_covering(name, proofs)
+    {
+        let ce = nsec_closest_encloser(name, domain, next_domain);
+        if let Some(types) = nsec_types(&wildcard_of(ce), proofs) {
+            return nodata_types(&wildcard_of(ce), types, qtype);
+        }
+    }
+
+    Security::Bogus("no data not proven")
+}
+
+fn prove_wildcard_expansion(name: &str, labels: u8, proofs: &[DnsRecord]) -> Security {
+    if uses_nsec3(proofs) {
+        if excessive_iterations(proofs) {
+            return Security::Insecure;
+        }
+
+        let next_closer = dns_name::suffix(name, labels as usize + 1);
+        return match nsec3_covering(&next_closer, proofs) {
+            Some(flags) if flags & OPT_OUT > 0 => Security::Insecure,
+            Some(_) => Security::Secure,
+            None => Security::Bogus("wildcard expansion not proven"),
+        };
+    }
+
+    match nsec_covering(name, proofs) {
+        Some(_) => Security::Secure,
+        None => Security::Bogus("wildcard expansion not proven"),
+    }
+}
+
+/// True if the proofs show `name` to be a delegation point.
+fn is_delegation(name: &str, proofs: &[DnsRecord]) -> bool {
+    let types: Option<&[QueryType]> = match nsec_types(name, proofs) {
+        Some(types) => Some(types),
+        None => nsec3_matching(name, proofs),
+    };
+
+    match types {
+        Some(types) => types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn base64(input: &str) -> Vec<u8> {
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut res = Vec::new();
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+        for c in input.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+            let val = alphabet.iter().position(|a| *a == c).unwrap() as u32;
+            acc = (acc << 6) | val;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                res.push((acc >> bits) as u8);
+                acc &= (1 << bits) - 1;
+            }
+        }
+        res
+    }
+
+    fn hex(input: &str) -> Vec<u8> {
+        (0..input.len() / 2)
+            .map(|i| u8::from_str_radix(&input[i * 2..i * 2 + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn dnskey(zone: &str, flags: u16, algorithm: u8, public_key: &str) -> DnsRecord {
+        DnsRecord::DNSKEY {
+            domain: zone.to_string(),
+            flags: flags,
+            protocol: 3,
+            algorithm: algorithm,
+            public_key: base64(public_key),
+            ttl: 3600,
+        }
+    }
+
+    fn ds(zone: &str, key_tag: u16, algorithm: u8, digest_type: u8, digest: &str) -> DnsRecord {
+        DnsRecord::DS {
+            domain: zone.to_string(),
+            key_tag: key_tag,
+            algorithm: algorithm,
+            digest_type: digest_type,
+            digest: hex(digest),
+            ttl: 3600,
+        }
+    }
+
+    fn rrsig(
+        name: &str,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        times: (u32, u32),
+        signer: (u16, &str),
+        signature: &str,
+    ) -> DnsRecord {
+        DnsRecord::RRSIG {
+            domain: name.to_string(),
+            type_covered: type_covered,
+            algorithm: algorithm,
+            labels: labels,
+            original_ttl: 3600,
+            expiration: times.0,
+            inception: times.1,
+            key_tag: signer.0,
+            signer_name: signer.1.to_string(),
+            signature: base64(signature),
+            ttl: 3600,
+        }
+    }
+
+    fn a(name: &str, addr: Ipv4Addr) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            addr: addr,
+            ttl: 3600,
+        }
+    }
+
+    fn verifies(key: &DnsRecord, records: &[DnsRecord], sig: &DnsRecord) -> bool {
+        let data = signed_data(sig.get_domain(), records, sig).unwrap();
+        verify_signature(key, sig, &data)
+    }
+
+    #[test]
+    fn rsa_sha256_rfc5702() {
+        let key = dnskey(
+            "example.net",
+            256,
+            8,
+            "AwEAAcFcGsaxxdgiuuGmCkVImy4h99CqT7jwY3pexPGcnUFtR2Fh36BponcwtkZ4cAgtvd4Qs8P
+             kxUdp6p/DlUmObdk=",
+        );
+        assert_eq!(dnskey_tag(&key), 9033);
+
+        // 20300101000000 20000101000000
+        let sig = rrsig(
+            "www.example.net",
+            QueryType::A,
+            8,
+            3,
+            (1893456000, 946684800),
+            (9033, "example.net"),
+            "kRCOH6u7l0QGy9qpC9l1sLncJcOKFLJ7GhiUOibu4teYp5VE9RncriShZNz85mwlMgNEa
+             cFYK/lPtPiVYP4bwg==",
+        );
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 91))];
+        // The example key has only 512 bits, below what we accept
+        assert!(!verifies(&key, &records, &sig));
+    }
+
+    #[test]
+    fn rsa_sha256() {
+        // The RFC 5702 record signed with a 1024 bit key
+        let key = dnskey(
+            "example.net",
+            256,
+            8,
+            "AwEAAcTaoXEHkEuc1zo7CfLKJ0QKMJq9dGtqGBHAaV7+LU9qSFiIF2IzXAU2aAjDzKqz+RxozcgoORd8
+             Slk9OBz4vO4ZgWFCqQQ5l5oVkTH+kTxqgmtraNvO7Ft8BpP1eAkDHxArZrjfRLd2Dfw3KWQVxDP5o3dF
+             +huZ1s5onrKD1IqH",
+        );
+        assert_eq!(dnskey_tag(&key), 4860);
+
+        let sig = rrsig(
+            "www.example.net",
+            QueryType::A,
+            8,
+            3,
+            (1893456000, 946684800),
+            (4860, "example.net"),
+            "gPqFyBPP2jyHh+0/26ZsSOUuZ8u0nD4B+WzJXV0GB//cZZiH33iBe+Rij4ZJpXqg8zu+blbjTVqtvp+g
+             UeanmcBFBp9dORNI8uDRy10CrDp/9kt0WE8xKfmy50vqSUiGJl8LpAzuQN6326lAc+4inrcMSYGX/5/V
+             t0WAUz9hfjY=",
+        );
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 91))];
+        assert!(verifies(&key, &records, &sig));
+
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 92))];
+        assert!(!verifies(&key, &records, &sig));
+    }
+
+    #[test]
+    fn ecdsa_p256_rfc6605() {
+        let key = dnskey(
+            "example.net",
+            257,
+            13,
+            "GojIhhXUN/u4v54ZQqGSnyhWJwaubCvTmeexv7bR6edbkrSqQpF64cYbcB7wNcP+e+MAnLr+Wi9xMWyQ
+             Lc8NAA==",
+        );
+        assert_eq!(dnskey_tag(&key), 55648);
+        assert!(ds_matches(
+            &ds(
+                "example.net",
+                55648,
+                13,
+                2,
+                "b4c8c1fe2e7477127b27115656ad6256f424625bf5c1e2770ce6d6e37df61d17",
+            ),
+            &key,
+            "example.net",
+        ));
+
+        // 20100909100439 20100812100439
+        let sig = rrsig(
+            "www.example.net",
+            QueryType::A,
+            13,
+            3,
+            (1284026679, 1281607479),
+            (55648, "example.net"),
+            "qx6wLYqmh+l9oCKTN6qIc+bw6ya+KJ8oMz0YP107epXAyGmt+3SNruPFKG7tZoLBLlUzGGus7ZwmwWep
+             666VCw==",
+        );
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 1))];
+        assert!(verifies(&key, &records, &sig));
+
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 2))];
+        assert!(!verifies(&key, &records, &sig));
+    }
+
+    #[test]
+    fn ecdsa_p384_rfc6605() {
+        let key = dnskey(
+            "example.net",
+            257,
+            14,
+            "xKYaNhWdGOfJ+nPrL8/arkwf2EY3MDJ+SErKivBVSum1w/egsXvSADtNJhyem5RCOpgQ6K8X1DRSEkrb
+             YQ+OB+v8/uX45NBwY8rp65F6Glur8I/mlVNgF6W/qTI37m40",
+        );
+        assert_eq!(dnskey_tag(&key), 10771);
+        assert!(ds_matches(
+            &ds(
+                "example.net",
+                10771,
+                14,
+                4,
+                "72d7b62976ce06438e9c0bf319013cf801f09ecc84b8d7e9495f27e305c6a9b0563a9b5f4d28840\
+                 5c3008a946df983d6",
+            ),
+            &key,
+            "example.net",
+        ));
+
+        // 20100909102025 20100812102025
+        let sig = rrsig(
+            "www.example.net",
+            QueryType::A,
+            14,
+            3,
+            (1284027625, 1281608425),
+            (10771, "example.net"),
+            "/L5hDKIvGDyI1fcARX3z65qrmPsVz73QD1Mr5CEqOiLP95hxQouuroGCeZOvzFaxsT8Glr74hbavRKay
+             JNuydCuzWTSSPdz7wnqXL5bdcJzusdnI0RSMROxxwGipWcJm",
+        );
+        let records = [a("www.example.net", Ipv4Addr::new(192, 0, 2, 1))];
+        assert!(verifies(&key, &records, &sig));
+    }
+
+    #[test]
+    fn ed25519_rfc8080() {
+        let key = dnskey(
+            "example.com",
+            257,
+            15,
+            "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=",
+        );
+        assert_eq!(dnskey_tag(&key), 3613);
+
+        let digest = "3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b";
+        assert!(ds_matches(&ds("example.com", 3613, 15, 2, digest), &key, "example.com"));
+        // Owner names are compared case-insensitively
+        assert!(ds_matches(&ds("EXAMPLE.com", 3613, 15, 2, digest), &key, "EXAMPLE.com"));
+        assert!(!ds_matches(&ds("example.com", 3614, 15, 2, digest), &key, "example.com"));
+        assert!(!ds_matches(&ds("example.com", 3613, 15, 2, digest), &key, "example.net"));
+
+        let sig = rrsig(
+            "example.com",
+            QueryType::MX,
+            15,
+            2,
+            (1440021600, 1438207200),
+            (3613, "example.com"),
+            "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngw
+             crqNAg==",
+        );
+        let records = [DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 3600,
+        }];
+        assert!(verifies(&key, &records, &sig));
+    }
+
+    #[test]
+    fn revoked_keys_are_not_zone_keys() {
+        let public_key = "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=";
+        let key = dnskey("example.com", 257, 15, public_key);
+        assert!(is_zone_key(&key));
+
+        let revoked = dnskey("example.com", 257 | REVOKE, 15, public_key);
+        assert!(!is_zone_key(&revoked));
+    }
+
+    #[test]
+    fn short_rrsig_fails_verification() {
+        // A signature that can't be encoded leaves nothing to strip it from
+        let mut sig = rrsig("example.com", QueryType::MX, 15, 2, (0, 0), (3613, "example.com"), "");
+        if let DnsRecord::RRSIG {
+            ref mut signature,
+            ..
+        } = sig
+        {
+            *signature = vec![0; 0x10000];
+        }
+        assert_eq!(signed_data("example.com", &[], &sig), None);
+    }
+
+    #[test]
+    fn trust_anchor_rejects_malformed_digests() {
+        assert!(trust_anchor("", 20326, 8, 2, "E06D44B80B8F1D39").is_ok());
+        assert!(trust_anchor("", 20326, 8, 2, "E06D44B80B8F1D3").is_err());
+        assert!(trust_anchor("", 20326, 8, 2, "E06D44B80B8F1DXX").is_err());
+        assert!(trust_anchor("", 20326, 8, 2, "").is_err());
+        assert_eq!(root_trust_anchors().len(), 2);
+    }
+
+    #[test]
+    fn nsec3_hash_rfc5155() {
+        let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+        let hashes = [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("ai.example", "gjeqe526plbf1g8mklp59enfd789njgi"),
+            ("ns1.example", "2t7b4g4vsa5smi47k61mv5bv1a22bojr"),
+            ("ns2.example", "q04jkcevqvmu85r014c7dkba38o0ji5r"),
+            ("w.example", "k8udemvp1j2f7eg6jebps17vp3n8i58h"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("x.w.example", "b4um86eghhds6nea196smvmlo4ors995"),
+            ("y.w.example", "ji6neoaepv8b5o6k4ev33abha8ht9fgc"),
+            ("x.y.w.example", "2vptu5timamqttgl4luu9kg21e0aor3s"),
+            ("xx.example", "t644ebqk9bibcna874givr6joj62mlhv"),
+            ("XX.Example", "t644ebqk9bibcna874givr6joj62mlhv"),
+        ];
+        for &(name, hash) in &hashes {
+            assert_eq!(nsec3_hash(name, &salt, 12), base32hex_decode(hash).unwrap(), "{}", name);
+        }
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: owner.to_string(),
+            next_domain: next.to_string(),
+            types: types.to_vec(),
+            ttl: 3600,
+        }
+    }
+
+    /// The NSEC chain of the example zone in RFC 4035 appendix A.
+    fn rfc4035_chain() -> Vec<DnsRecord> {
+        use query_type::QueryType::*;
+        let hinfo = UNKNOWN(13);
+        vec![
+            nsec("example", "a.example", &[NS, SOA, MX, RRSIG, NSEC, DNSKEY]),
+            nsec("a.example", "ai.example", &[NS, DS, RRSIG, NSEC]),
+            nsec("ai.example", "b.example", &[A, hinfo, AAAA, RRSIG, NSEC]),
+            nsec("b.example", "ns1.example", &[NS, RRSIG, NSEC]),
+            nsec("ns1.example", "ns2.example", &[A, RRSIG, NSEC]),
+            nsec("ns2.example", "*.w.example", &[A, RRSIG, NSEC]),
+            nsec("*.w.example", "x.w.example", &[MX, RRSIG, NSEC]),
+            nsec("x.w.example", "x.y.w.example", &[MX, RRSIG, NSEC]),
+            nsec("x.y.w.example", "xx.example", &[MX, RRSIG, NSEC]),
+            nsec("xx.example", "example", &[A, hinfo, AAAA, RRSIG, NSEC]),
+        ]
+    }
+
+    fn nsecs(owners: &[&str]) -> Vec<DnsRecord> {
+        rfc4035_chain()
+            .into_iter()
+            .filter(|rec| owners.contains(&rec.get_domain()))
+            .collect()
+    }
+
+    #[test]
+    fn nsec_nxdomain_rfc4035() {
+        // B.2: ml.example
+        let proofs = nsecs(&["b.example", "example"]);
+        assert_eq!(prove_nxdomain("ml.example", &proofs), Security::Secure);
+
+        // Without the wildcard proof
+        let proofs = nsecs(&["b.example"]);
+        assert!(prove_nxdomain("ml.example", &proofs) != Security::Secure);
+
+        // Names below a delegation aren't the parent's to deny
+        let proofs = nsecs(&["a.example", "example"]);
+        assert!(prove_nxdomain("b.a.example", &proofs) != Security::Secure);
+    }
+
+    #[test]
+    fn nsec_nodata_rfc4035() {
+        // B.3: ns1.example MX
+        let proofs = nsecs(&["ns1.example"]);
+        assert_eq!(prove_nodata("ns1.example", QueryType::MX, &proofs), Security::Secure);
+        assert!(prove_nodata("ns1.example", QueryType::A, &proofs) != Security::Secure);
+
+        // B.5: the DS of the unsigned delegation b.example
+        let proofs = nsecs(&["b.example"]);
+        assert_eq!(prove_nodata("b.example", QueryType::DS, &proofs), Security::Secure);
+        assert!(is_delegation("b.example", &proofs));
+
+        // The parent side of a delegation says nothing about the child's data
+        assert!(prove_nodata("b.example", QueryType::A, &proofs) != Security::Secure);
+
+        // Nor the child's apex about the DS in the parent
+        let proofs = nsecs(&["example"]);
+        assert!(prove_nodata("example", QueryType::DS, &proofs) != Security::Secure);
+
+        // Names below a DNAME are redirected
+        let proofs = vec![nsec("d.example", "ns1.example", &[QueryType::DNAME, QueryType::NSEC])];
+        assert!(prove_nodata("d.example", QueryType::A, &proofs) != Security::Secure);
+        assert!(prove_nxdomain("x.d.example", &proofs) != Security::Secure);
+    }
+
+    #[test]
+    fn nsec_wildcard_rfc4035() {
+        // B.6: a.z.w.example MX expanded from *.w.example
+        let proofs = nsecs(&["x.y.w.example"]);
+        assert_eq!(prove_wildcard_expansion("a.z.w.example", 2, &proofs), Security::Secure);
+        assert!(prove_wildcard_expansion("a.z.w.example", 2, &[]) != Security::Secure);
+
+        // B.7: a.z.w.example AAAA, no data at the wildcard
+        let proofs = nsecs(&["x.y.w.example", "*.w.example"]);
+        assert_eq!(prove_nodata("a.z.w.example", QueryType::AAAA, &proofs), Security::Secure);
+        assert!(prove_nodata("a.z.w.example", QueryType::MX, &proofs) != Security::Secure);
+    }
+
+    /// The NSEC3 chain of the example zone in RFC 5155 appendix A, with the
+    /// given flags.
+    fn rfc5155_chain(flags: u8) -> Vec<DnsRecord> {
+        use query_type::QueryType::*;
+        let hinfo = UNKNOWN(13);
+        let nsec3param = UNKNOWN(51);
+        let names: Vec<(&str, Vec<QueryType>)> = vec![
+            ("example", vec![NS, SOA, MX, RRSIG, DNSKEY, nsec3param]),
+            ("a.example", vec![NS, DS, RRSIG]),
+            ("ai.example", vec![A, hinfo, AAAA, RRSIG]),
+            ("ns1.example", vec![A, RRSIG]),
+            ("ns2.example", vec![A, RRSIG]),
+            ("w.example", vec![]),
+            ("*.w.example", vec![MX, RRSIG]),
+            ("x.w.example", vec![MX, RRSIG]),
+            ("y.w.example", vec![]),
+            ("x.y.w.example", vec![MX, RRSIG]),
+            ("xx.example", vec![A, hinfo, AAAA, RRSIG]),
+        ];
+
+        let salt = vec![0xaa, 0xbb, 0xcc, 0xdd];
+        let mut hashed = names
+            .into_iter()
+            .map(|(name, types)| (nsec3_hash(name, &salt, 12), types))
+            .collect::<Vec<_>>();
+        hashed.sort();
+
+        (0..hashed.len())
+            .map(|i| {
+                let (ref hash, ref types) = hashed[i];
+                DnsRecord::NSEC3 {
+                    domain: format!("{}.example", base32hex(hash)),
+                    hash_algorithm: 1,
+                    flags: flags,
+                    iterations: 12,
+                    salt: salt.clone(),
+                    next_hashed: hashed[(i + 1) % hashed.len()].0.clone(),
+                    types: types.clone(),
+                    ttl: 3600,
+                }
+            })
+            .collect()
+    }
+
+    fn base32hex(data: &[u8]) -> String {
+        let alphabet = b"0123456789abcdefghijklmnopqrstuv";
+        let mut res = String::new();
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+        for b in data {
+            acc = (acc << 8) | *b as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                res.push(alphabet[(acc >> bits) as usize & 0x1F] as char);
+            }
+        }
+        if bits > 0 {
+            res.push(alphabet[(acc << (5 - bits)) as usize & 0x1F] as char);
+        }
+        res
+    }
+
+    fn nsec3s(flags: u8, names: &[&str]) -> Vec<DnsRecord> {
+        let owners = names
+            .iter()
+            .map(|name| base32hex(&nsec3_hash(name, &[0xaa, 0xbb, 0xcc, 0xdd], 12)))
+            .collect::<Vec<String>>();
+        rfc5155_chain(flags)
+            .into_iter()
+            .filter(|rec| owners.iter().any(|owner| rec.get_domain().starts_with(&owner[..])))
+            .collect()
+    }
+
+    #[test]
+    fn nsec3_nxdomain_rfc5155() {
+        // B.1: a.c.x.w.example, proven by the closest encloser x.w.example,
+        // the next closer name c.x.w.example and the wildcard *.x.w.example
+        let names = ["x.w.example", "example", "a.example"];
+        let proofs = nsec3s(0, &names);
+        assert_eq!(prove_nxdomain("a.c.x.w.example", &proofs), Security::Secure);
+
+        // With opt-out, an unsigned delegation could hide in the span
+        let proofs = nsec3s(1, &names);
+        assert_eq!(prove_nxdomain("a.c.x.w.example", &proofs), Security::Insecure);
+
+        // Without the wildcard proof
+        let proofs = nsec3s(0, &["x.w.example", "example"]);
+        assert!(prove_nxdomain("a.c.x.w.example", &proofs) != Security::Secure);
+
+        // A delegation can't be the closest encloser
+        let proofs = rfc5155_chain(0);
+        assert!(prove_nxdomain("b.a.example", &proofs) != Security::Secure);
+    }
+
+    #[test]
+    fn nsec3_nodata_rfc5155() {
+        // B.2: ns1.example MX
+        let proofs = nsec3s(1, &["ns1.example"]);
+        assert_eq!(prove_nodata("ns1.example", QueryType::MX, &proofs), Security::Secure);
+        assert!(prove_nodata("ns1.example", QueryType::A, &proofs) != Security::Secure);
+
+        // B.2.1: the empty non-terminal y.w.example
+        let proofs = nsec3s(1, &["y.w.example"]);
+        assert_eq!(prove_nodata("y.w.example", QueryType::A, &proofs), Security::Secure);
+
+        // B.3: the DS of c.example, an unsigned delegation in an opt-out span
+        let proofs = nsec3s(1, &["example", "a.example"]);
+        assert_eq!(prove_nodata("c.example", QueryType::DS, &proofs), Security::Insecure);
+
+        // The parent side of the signed delegation a.example
+        let proofs = nsec3s(1, &["a.example"]);
+        assert!(prove_nodata("a.example", QueryType::A, &proofs) != Security::Secure);
+    }
+
+    #[test]
+    fn nsec3_wildcard_rfc5155() {
+        // B.4: a.z.w.example MX expanded from *.w.example; the next closer
+        // name z.w.example is covered by the NSEC3 of ns2.example
+        let proofs = nsec3s(0, &["ns2.example"]);
+        assert_eq!(prove_wildcard_expansion("a.z.w.example", 2, &proofs), Security::Secure);
+        assert!(prove_wildcard_expansion("a.z.w.example", 2, &[]) != Security::Secure);
+
+        // B.5: a.z.w.example AAAA, no data at the wildcard
+        let proofs = nsec3s(1, &["w.example", "ns2.example", "*.w.example"]);
+        assert_eq!(prove_nodata("a.z.w.example", QueryType::AAAA, &proofs), Security::Secure);
+        assert!(prove_nodata("a.z.w.example", QueryType::MX, &proofs) != Security::Secure);
+    }
+
+    #[test]
+    fn nsec3_excessive_iterations_are_insecure() {
+        let mut proofs = nsec3s(0, &["ns1.example"]);
+        if let DnsRecord::NSEC3 {
+            ref mut iterations,
+            ..
+        } = proofs[0]
+        {
+            *iterations = MAX_NSEC3_ITERATIONS + 1;
+        }
+        assert_eq!(prove_nodata("ns1.example", QueryType::MX, &proofs), Security::Insecure);
+    }
+
+    #[test]
+    fn dname_synthesized_cname() {
+        let answers = group_rrsets(&[
+            DnsRecord::DNAME {
+                domain: "example".to_string(),
+                host: "example.net".to_string(),
+                ttl: 3600,
+            },
+            DnsRecord::CNAME {
+                domain: "www.example".to_string(),
+                host: "www.example.net".to_string(),
+                ttl: 3600,
+            },
+            DnsRecord::CNAME {
+                domain: "ftp.example".to_string(),
+                host: "evil.example.org".to_string(),
+                ttl: 3600,
+            },
+        ]);
+        assert!(synthesized_from_dname(&answers[1], &answers));
+        assert!(!synthesized_from_dname(&answers[2], &answers));
+    }
+}
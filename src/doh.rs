@@ -0,0 +1,617 @@
+//! DNS over HTTPS (RFC 8484): messages sent as the body of a POST, or
+//! base64url-encoded in the `dns` parameter of a GET, over HTTP/2 or
+//! HTTP/1.1. We answer both, and forward queries as POSTs over HTTP/2.
+//! GETs with a `name` parameter instead are answered in JSON, as Google's
+//! and Cloudflare's JSON APIs do.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection};
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use http::{self, Handler, Request, Response};
+use http2;
+use json::{self, DnsJson};
+use presentation;
+use query_type::QueryType;
+use resolver;
+use serde_json;
+use tls::{self, TlsWriter};
+use zone_parser;
+
+pub const PATH: &str = "/dns-query";
+pub const CONTENT_TYPE: &str = "application/dns-message";
+// What we offer in the TLS handshake, best first
+pub const ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Answers a DNS message from a client, or returns None if it isn't one.
+pub type MessageHandler = Arc<dyn Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Answers the DoH request `request` from `client` with `handler`.
+pub fn respond(request: Request, client: SocketAddr, handler: &MessageHandler) -> Response {
+    if request.route() != PATH {
+        return Response::new(404);
+    }
+    let in_json = request.method == "GET" && request.query("dns").is_none();
+    let message = match request.method.as_str() {
+        "GET" if in_json => match json_query(&request) {
+            Some(x) => x,
+            None => return Response::new(400),
+        },
+        "GET" => match request.query("dns").and_then(|dns| parse_base64url(&dns)) {
+            Some(x) => x,
+            None => return Response::new(400),
+        },
+        "POST" if request.header("content-type") == Some(CONTENT_TYPE) => request.body,
+        "POST" => return Response::new(415),
+        _ => {
+            let mut response = Response::new(405);
+            response.add_header("allow", "GET, POST");
+            return response;
+        }
+    };
+
+    let answer = match handler(&message, client) {
+        Some(x) => x,
+        None => return Response::new(400),
+    };
+    let mut response = Response::new(200);
+    if let Some(ttl) = min_ttl(&answer) {
+        response.add_header("cache-control", &format!("max-age={}", ttl));
+    }
+    if in_json {
+        response.body = match to_json(&answer) {
+            Some(x) => x,
+            None => return Response::new(500),
+        };
+        response.add_header("content-type", json::CONTENT_TYPE);
+    } else {
+        response.add_header("content-type", CONTENT_TYPE);
+        response.body = answer;
+    }
+    response
+}
+
+/// The query a JSON API request asks, for the `name` and `type` (A by
+/// default) in its parameters, with the `cd` and `do` flags set if they're
+/// 1 or true.
+fn json_query(request: &Request) -> Option<Vec<u8>> {
+    let name = match request.query("name").map(|name| zone_parser::resolve_name(&name, "")) {
+        Some(Ok(x)) => x,
+        _ => return None,
+    };
+    let qtype = match request.query("type") {
+        Some(qtype) => match qtype.parse::<u16>() {
+            Ok(num) => QueryType::from_num(num),
+            Err(_) => match QueryType::from_name(&qtype) {
+                Some(x) => x,
+                None => return None,
+            },
+        },
+        None => QueryType::A,
+    };
+    let flag = |name| request.query(name).is_some_and(|value| value == "1" || value == "true");
+
+    let mut packet = DnsPacket::new();
+    packet.header.recursion_desired = true;
+    packet.header.checking_disabled = flag("cd");
+    packet.questions.push(DnsQuestion::new(name, qtype));
+    if flag("do") {
+        packet.set_edns(resolver::EDNS_PAYLOAD_SIZE, true);
+    }
+    let mut buffer = BytePacketBuffer::new();
+    match packet.write(&mut buffer) {
+        Ok(_) => Some(buffer.buf[..buffer.pos()].to_vec()),
+        Err(_) => None,
+    }
+}
+
+/// `answer` in the JSON APIs' format.
+fn to_json(answer: &[u8]) -> Option<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::with_size(answer.len());
+    buffer.buf.copy_from_slice(answer);
+    let packet = match DnsPacket::from_buffer(&mut buffer) {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+    serde_json::to_vec(&DnsJson(packet)).ok()
+}
+
+/// base64url without padding, as GET requests carry messages in.
+fn parse_base64url(text: &str) -> Option<Vec<u8>> {
+    if text.contains(&['+', '/', '='][..]) {
+        return None;
+    }
+    let mut text = text.replace('-', "+").replace('_', "/");
+    while text.len() % 4 != 0 {
+        text.push('=');
+    }
+    presentation::parse_base64(&text)
+}
+
+/// The lowest TTL in an answer, which is as long as it may be cached
+/// (RFC 8484 section 5.1).
+fn min_ttl(answer: &[u8]) -> Option<u32> {
+    let mut buffer = BytePacketBuffer::with_size(answer.len());
+    buffer.buf.copy_from_slice(answer);
+    let packet = match DnsPacket::from_buffer(&mut buffer) {
+        Ok(x) => x,
+        Err(_) => return None,
+    };
+    packet
+        .answers
+        .iter()
+        .chain(packet.authorities.iter())
+        .filter(|rec| rec.get_querytype() != QueryType::OPT)
+        .map(|rec| rec.get_ttl())
+        .min()
+}
+
+/// Serves DoH on `stream` until the client closes it or goes quiet, in
+/// HTTP/2 if the client starts with its preface and in HTTP/1.1 otherwise.
+pub fn handle_connection(
+    stream: TcpStream,
+    tls: Arc<ServerConfig>,
+    handler: MessageHandler,
+) -> Result<(), Error> {
+    let client = try!(stream.peer_addr());
+    let connection = try!(ServerConnection::new(tls)
+        .map_err(|e| Error::other(e.to_string())));
+    let (mut reader, mut writer) = try!(tls::split(connection, stream));
+
+    let http_handler: Handler = Arc::new(move |request| respond(request, client, &handler));
+    let mut start = vec![0u8; 4];
+    try!(reader.read_exact(&mut start));
+    let is_http2 = http2::PREFACE.starts_with(&start);
+    let reader = Cursor::new(start).chain(reader);
+    if is_http2 {
+        http2::serve(reader, writer, http_handler)
+    } else {
+        try!(http::serve(reader, &mut writer, &http_handler));
+        // The client may be gone already
+        let _ = writer.close();
+        Ok(())
+    }
+}
+
+/// A DoH resolver to forward to, from its URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DohUpstream {
+    // What its certificate has to be for
+    pub host: String,
+    // The host with the port, if it isn't 443
+    pub authority: String,
+    pub path: String,
+    // Where it's reached: its own address, or the bootstrap addresses
+    // given for its name so that it needn't be resolved
+    pub addrs: Vec<SocketAddr>,
+}
+
+impl fmt::Display for DohUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "https://{}{}", self.authority, self.path)
+    }
+}
+
+impl DohUpstream {
+    /// The resolver at `url`, such as "https://dns.example/dns-query", at
+    /// one of `bootstrap` if it's given by name. The path defaults to
+    /// /dns-query, and a trailing {?dns} template is dropped.
+    pub fn parse(url: &str, bootstrap: &[IpAddr]) -> Result<DohUpstream, String> {
+        if !url.starts_with("https://") {
+            return Err("expected an https:// URL".to_string());
+        }
+        let rest = &url["https://".len()..];
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches("{?dns}")),
+            None => (rest, PATH),
+        };
+
+        // The port follows the last colon, unless it's inside an IPv6
+        // address's brackets
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                match authority[i + 1..].parse::<u16>() {
+                    Ok(port) => (&authority[..i], port),
+                    Err(_) => return Err(format!("invalid port in {}", url)),
+                }
+            }
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("no host in {}", url));
+        }
+
+        let addrs = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) if bootstrap.is_empty() => {
+                return Err(format!("a bootstrap address is needed to reach {}", host))
+            }
+            Err(_) => bootstrap.iter().map(|&ip| SocketAddr::new(ip, port)).collect(),
+        };
+        Ok(DohUpstream {
+            host: host.to_string(),
+            authority: authority.to_string(),
+            path: path.to_string(),
+            addrs: addrs,
+        })
+    }
+}
+
+type Connection = http2::Connection<TlsWriter>;
+type Slot = Mutex<Option<Arc<Connection>>>;
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// HTTP/2 connections to DoH upstreams, one to each address, shared by all
+/// the queries sent there.
+pub struct DohClient {
+    config: Arc<ClientConfig>,
+    connections: Mutex<HashMap<(String, SocketAddr), Arc<Slot>>>,
+}
+
+impl DohClient {
+    /// A client trusting upstreams as `TlsClient` does.
+    pub fn new(pins: Vec<Vec<u8>>, ca_file: Option<&str>) -> Result<DohClient, Error> {
+        Ok(DohClient {
+            config: try!(tls::client_config(pins, ca_file, &[b"h2"])),
+            connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends the message `request` to `upstream` and returns its response,
+    /// trying each of its addresses in turn.
+    pub fn exchange(
+        &self,
+        upstream: &DohUpstream,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let mut last = Err(Error::other("no address for the upstream"));
+        for &addr in &upstream.addrs {
+            last = self.exchange_with(upstream, addr, request, timeout);
+            if last.is_ok() {
+                break;
+            }
+        }
+        last
+    }
+
+    fn exchange_with(
+        &self,
+        upstream: &DohUpstream,
+        addr: SocketAddr,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let key = (upstream.host.clone(), addr);
+        let slot = lock(&self.connections)
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone();
+        let connection = try!(self.connection(&slot, &upstream.host, addr, timeout));
+        match post(&connection, upstream, request, timeout) {
+            // The server may have closed it as we asked, so a new one is
+            // made
+            Err(_) if connection.is_closed() => {
+                let connection = try!(self.connection(&slot, &upstream.host, addr, timeout));
+                post(&connection, upstream, request, timeout)
+            }
+            result => result,
+        }
+    }
+
+    /// The open connection in `slot`, or a new one put there. Queries
+    /// waiting on the same connection wait for its handshake together.
+    fn connection(
+        &self,
+        slot: &Slot,
+        host: &str,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Arc<Connection>, Error> {
+        let mut slot = lock(slot);
+        if let Some(ref connection) = *slot {
+            if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+        let connection = try!(self.connect(host, addr, timeout));
+        *slot = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Arc<Connection>, Error> {
+        let name = match host.parse::<IpAddr>() {
+            Ok(ip) => ServerName::IpAddress(ip.into()),
+            Err(_) => try!(ServerName::try_from(host.to_string()).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, format!("{}: {}", host, e))
+            })),
+        };
+        let mut connection = try!(ClientConnection::new(self.config.clone(), name)
+            .map_err(|e| Error::other(e.to_string())));
+        let mut stream = try!(TcpStream::connect_timeout(&addr, timeout));
+        try!(stream.set_nodelay(true));
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+        while connection.is_handshaking() {
+            try!(connection.complete_io(&mut stream));
+        }
+        if connection.alpn_protocol() != Some(b"h2") {
+            return Err(Error::other(format!("{} doesn't speak HTTP/2", addr)));
+        }
+
+        // Responses are waited on with their own timeouts
+        try!(stream.set_read_timeout(None));
+        let (reader, writer) = try!(tls::split(connection, stream));
+        http2::Connection::new(reader, writer)
+    }
+}
+
+fn post(
+    connection: &Connection,
+    upstream: &DohUpstream,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    let headers = [("content-type", CONTENT_TYPE), ("accept", CONTENT_TYPE)];
+    let response = try!(connection.request(
+        "POST",
+        &upstream.authority,
+        &upstream.path,
+        &headers,
+        request,
+        timeout
+    ));
+    if response.status != 200 {
+        return Err(Error::other(
+            format!("{} answered with HTTP status {}", upstream.host, response.status),
+        ));
+    }
+    Ok(response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_question::DnsQuestion;
+    use dns_record::DnsRecord;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::convert::TryFrom;
+    use std::io::Write;
+    use resolver;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tls::test_support::Certificate;
+
+    fn query() -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 0;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::with_size(512);
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos()].to_vec()
+    }
+
+    /// Answers every query with two A records, the shorter-lived for 60s.
+    fn handler() -> MessageHandler {
+        Arc::new(|message: &[u8], _: SocketAddr| {
+            let mut buffer = BytePacketBuffer::with_size(message.len());
+            buffer.buf.copy_from_slice(message);
+            let mut packet = match DnsPacket::from_buffer(&mut buffer) {
+                Ok(x) => x,
+                Err(_) => return None,
+            };
+            packet.header.response = true;
+            for &ttl in &[300, 60] {
+                packet.answers.push(DnsRecord::A {
+                    domain: "example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: ttl,
+                });
+            }
+            let mut buffer = BytePacketBuffer::with_size(512);
+            packet.write(&mut buffer).unwrap();
+            Some(buffer.buf[..buffer.pos()].to_vec())
+        })
+    }
+
+    fn request(method: &str, path: &str, content_type: Option<&str>, body: Vec<u8>) -> Request {
+        let mut headers = Vec::new();
+        if let Some(content_type) = content_type {
+            headers.push(("content-type".to_string(), content_type.to_string()));
+        }
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: headers,
+            body: body,
+        }
+    }
+
+    fn base64url(data: &[u8]) -> String {
+        presentation::base64(data)
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_")
+    }
+
+    #[test]
+    fn answers_get_and_post() {
+        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 50000);
+        let handler = handler();
+
+        let path = format!("{}?dns={}", PATH, base64url(&query()));
+        let get = respond(request("GET", &path, None, Vec::new()), client, &handler);
+        assert_eq!(get.status, 200);
+        assert!(get.headers.contains(&("cache-control".to_string(), "max-age=60".to_string())));
+        assert!(get.headers.contains(&("content-type".to_string(), CONTENT_TYPE.to_string())));
+
+        let post = respond(request("POST", PATH, Some(CONTENT_TYPE), query()), client, &handler);
+        assert_eq!(post.status, 200);
+        assert_eq!(post.body, get.body);
+
+        let path = format!("{}?name=example.com&type=A&do=1", PATH);
+        let json = respond(request("GET", &path, None, Vec::new()), client, &handler);
+        assert_eq!(json.status, 200);
+        let content_type = ("content-type".to_string(), json::CONTENT_TYPE.to_string());
+        assert!(json.headers.contains(&content_type));
+        let DnsJson(packet) = serde_json::from_slice(&json.body).unwrap();
+        assert_eq!(packet.questions[0].name, "example.com");
+        assert_eq!(packet.answers.len(), 2);
+        assert!(String::from_utf8(json.body).unwrap().contains(r#""data":"192.0.2.1""#));
+
+        let cases = vec![
+            (request("GET", "/dns-query?name=example.com&type=BOGUS", None, Vec::new()), 400),
+            (request("GET", &format!("{}?name=a..b", PATH), None, Vec::new()), 400),
+            (request("GET", PATH, None, Vec::new()), 400),
+            (request("POST", PATH, Some("text/plain"), query()), 415),
+            (request("GET", "/other", None, Vec::new()), 404),
+            (request("PUT", PATH, Some(CONTENT_TYPE), query()), 405),
+            (request("GET", "/dns-query?dns=AA+B", None, Vec::new()), 400),
+            (request("POST", PATH, Some(CONTENT_TYPE), vec![1, 2, 3]), 400),
+        ];
+        for (request, status) in cases {
+            assert_eq!(respond(request, client, &handler).status, status);
+        }
+    }
+
+    /// A DoH server for `name`, answering with `handler`, its certificate
+    /// to be trusted, and the number of connections made to it.
+    fn https_stub(test: &str, name: &str) -> (SocketAddr, Certificate, Arc<AtomicUsize>) {
+        let cert = Certificate::new(test, name);
+        let tls = cert.server_config(&ALPN);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let tls = tls.clone();
+                thread::spawn(move || handle_connection(stream.unwrap(), tls, handler()));
+            }
+        });
+        (addr, cert, connections)
+    }
+
+    #[test]
+    fn serves_both_http_versions_over_tls() {
+        let (addr, cert, _) = https_stub("doh-server", "localhost");
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der.clone()).unwrap();
+        let config = Arc::new(ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth());
+        let connect = || {
+            let name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(config.clone(), name).unwrap();
+            StreamOwned::new(connection, TcpStream::connect(addr).unwrap())
+        };
+
+        let mut stream = connect();
+        let message = query();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            PATH,
+            CONTENT_TYPE,
+            message.len()
+        ).unwrap();
+        stream.write_all(&message).unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let text = String::from_utf8_lossy(&response);
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("cache-control: max-age=60\r\n"));
+
+        let mut stream = connect();
+        stream.write_all(http2::PREFACE).unwrap();
+        http2::settings(&[]).write(&mut stream).unwrap();
+        let path = format!("{}?dns={}", PATH, base64url(&message));
+        let block = ::hpack::encode(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", &path),
+            (":authority", "localhost"),
+        ]);
+        let flags = http2::END_STREAM | http2::END_HEADERS;
+        http2::Frame::new(http2::HEADERS, flags, 1, block).write(&mut stream).unwrap();
+        let mut body = Vec::new();
+        loop {
+            let frame = http2::Frame::read(&mut stream).unwrap();
+            if frame.kind == http2::DATA && frame.stream == 1 {
+                body.extend_from_slice(&frame.payload);
+                if frame.flags & http2::END_STREAM != 0 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(body, handler()(&message, addr).unwrap());
+    }
+
+    #[test]
+    fn forwards_over_one_connection() {
+        let (addr, cert, connections) = https_stub("doh-client", "doh.test");
+        let url = format!("https://doh.test:{}/dns-query", addr.port());
+        let upstream = DohUpstream::parse(&url, &[addr.ip()]).unwrap();
+        let client = Arc::new(DohClient::new(Vec::new(), Some(&cert.cert_file)).unwrap());
+        let timeout = Duration::from_secs(5);
+
+        let message = query();
+        let threads = (0..8)
+            .map(|_| {
+                let (client, upstream, message) = (client.clone(), upstream.clone(), message.clone());
+                thread::spawn(move || client.exchange(&upstream, &message, timeout).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let expected = handler()(&message, addr).unwrap();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), expected);
+        }
+
+        let response = resolver::lookup_https(
+            &client,
+            "example.com",
+            QueryType::A,
+            &upstream,
+            timeout,
+            false,
+            true,
+        ).unwrap();
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // The certificate is for doh.test alone
+        let url = format!("https://127.0.0.1:{}/dns-query", addr.port());
+        let upstream = DohUpstream::parse(&url, &[]).unwrap();
+        assert!(client.exchange(&upstream, &message, timeout).is_err());
+    }
+}
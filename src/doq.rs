@@ -0,0 +1,904 @@
+//! DNS over QUIC (RFC 9250): each query and its response on a stream of
+//! their own, prefixed with their length as over TCP. QUIC itself is
+//! quinn-proto's, driven here over a plain UDP socket by a thread that
+//! receives on it and fires timers; answers are sent from whichever thread
+//! has them.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use quinn_proto::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn_proto::{ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig, Event,
+                  ReadError, StreamEvent, StreamId, VarInt};
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_packet::DnsPacket;
+use doh::MessageHandler;
+use opcode::Opcode;
+use query_type::QueryType;
+use tls;
+
+pub const ALPN: &[u8] = b"doq";
+
+// Error codes (RFC 9250 section 4.3)
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+const DOQ_EXCESSIVE_LOAD: u32 = 0x4;
+
+// The longest message, and its length prefix
+const MAX_MESSAGE: usize = 2 + 0xFFFF;
+// The most a connection's unfinished messages may hold between them
+const MAX_BUFFERED: usize = 1 << 20;
+// The longest a driver sleeps between looking at its timers, which may
+// have moved earlier in the meantime
+const MAX_WAIT: Duration = Duration::from_millis(20);
+
+pub type QuicConfig = quinn_proto::ServerConfig;
+
+fn invalid<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn code(value: u32) -> VarInt {
+    VarInt::from_u32(value)
+}
+
+/// `msg` with its length prefixed.
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(msg.len() + 2);
+    data.extend_from_slice(&[(msg.len() >> 8) as u8, msg.len() as u8]);
+    data.extend_from_slice(msg);
+    data
+}
+
+/// The message in a stream's data, which has to be exactly one.
+fn unframe(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < 2 || ((data[0] as usize) << 8 | data[1] as usize) != data.len() - 2 {
+        return None;
+    }
+    Some(&data[2..])
+}
+
+/// Whether answering `msg` twice does no harm, so that it may be answered
+/// from 0-RTT data an attacker could replay (RFC 9250 section 4.5).
+fn is_replay_safe(msg: &[u8]) -> bool {
+    let mut buffer = BytePacketBuffer::with_size(msg.len());
+    buffer.buf.copy_from_slice(msg);
+    match DnsPacket::from_buffer(&mut buffer) {
+        Ok(packet) => {
+            packet.header.opcode == Opcode::QUERY
+                && packet.questions.iter().all(|q| match q.qtype {
+                    QueryType::AXFR | QueryType::IXFR => false,
+                    _ => true,
+                })
+        }
+        Err(_) => false,
+    }
+}
+
+type Reply = mpsc::Sender<Result<Vec<u8>, Error>>;
+
+/// A query of ours, and who is waiting on its response.
+struct Query {
+    token: u64,
+    data: Vec<u8>,
+    reply: Reply,
+}
+
+/// A QUIC connection and the DoQ streams on it.
+struct Conn {
+    connection: quinn_proto::Connection,
+    // What's been read so far of each stream's message
+    incoming: HashMap<StreamId, Vec<u8>>,
+    // What flow control has held back so far of each stream's message
+    outgoing: HashMap<StreamId, Vec<u8>>,
+    // As a client: queries sent, and those waiting on a stream to open
+    sent: HashMap<StreamId, Query>,
+    unsent: Vec<Query>,
+    // As a server: queries that can't be answered before the handshake is
+    // done
+    deferred: Vec<(StreamId, Vec<u8>)>,
+}
+
+impl Conn {
+    fn new(connection: quinn_proto::Connection) -> Conn {
+        Conn {
+            connection: connection,
+            incoming: HashMap::new(),
+            outgoing: HashMap::new(),
+            sent: HashMap::new(),
+            unsent: Vec::new(),
+            deferred: Vec::new(),
+        }
+    }
+
+    fn close(&mut self, error: u32) {
+        self.connection.close(Instant::now(), code(error), Bytes::new());
+    }
+
+    /// Gives up on sending on `stream`, leaving the connection's other
+    /// streams be.
+    fn reset(&mut self, stream: StreamId, error: u32) {
+        self.outgoing.remove(&stream);
+        let _ = self.connection.send_stream(stream).reset(code(error));
+    }
+
+    /// Sends `data` on `stream` and ends it, as far as flow control allows
+    /// for now.
+    fn send(&mut self, stream: StreamId, data: Vec<u8>) {
+        self.outgoing.insert(stream, data);
+        self.send_more(stream);
+    }
+
+    fn send_more(&mut self, stream: StreamId) {
+        let data = match self.outgoing.remove(&stream) {
+            Some(x) => x,
+            None => return,
+        };
+        let mut send = self.connection.send_stream(stream);
+        match send.write(&data) {
+            Ok(len) if len == data.len() => {
+                let _ = send.finish();
+            }
+            Ok(len) => {
+                self.outgoing.insert(stream, data[len..].to_vec());
+            }
+            Err(quinn_proto::WriteError::Blocked) => {
+                self.outgoing.insert(stream, data);
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Reads what's arrived on `stream`, returning its data once the peer
+    /// has ended it.
+    fn read(&mut self, stream: StreamId) -> Option<Vec<u8>> {
+        if !self.incoming.contains_key(&stream) {
+            return None;
+        }
+        let others = self
+            .incoming
+            .iter()
+            .filter(|&(&id, _)| id != stream)
+            .map(|(_, data)| data.len())
+            .sum::<usize>();
+        let mut recv = self.connection.recv_stream(stream);
+        let mut chunks = match recv.read(true) {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        let mut finished = false;
+        let mut failed = None;
+        {
+            let data = self.incoming.get_mut(&stream).unwrap();
+            loop {
+                match chunks.next(MAX_MESSAGE) {
+                    Ok(Some(chunk)) => {
+                        data.extend_from_slice(&chunk.bytes);
+                        if data.len() > MAX_MESSAGE {
+                            failed = Some(DOQ_PROTOCOL_ERROR);
+                            break;
+                        }
+                        if others + data.len() > MAX_BUFFERED {
+                            failed = Some(DOQ_EXCESSIVE_LOAD);
+                            break;
+                        }
+                    }
+                    Ok(None) => {
+                        finished = true;
+                        break;
+                    }
+                    Err(ReadError::Blocked) => break,
+                    Err(ReadError::Reset(_)) => {
+                        failed = Some(DOQ_PROTOCOL_ERROR);
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = chunks.finalize();
+
+        if let Some(error) = failed {
+            self.incoming.remove(&stream);
+            let _ = self.connection.recv_stream(stream).stop(code(error));
+            return None;
+        }
+        if finished {
+            return self.incoming.remove(&stream);
+        }
+        None
+    }
+
+    /// Opens streams for the queries waiting on them, as far as the
+    /// server's stream limit allows.
+    fn send_queries(&mut self) {
+        while !self.unsent.is_empty() {
+            let stream = match self.connection.streams().open(Dir::Bi) {
+                Some(x) => x,
+                None => return,
+            };
+            let query = self.unsent.remove(0);
+            self.incoming.insert(stream, Vec::new());
+            self.send(stream, query.data.clone());
+            self.sent.insert(stream, query);
+        }
+    }
+
+    fn answer(&mut self, stream: StreamId, result: Result<Vec<u8>, Error>) {
+        self.incoming.remove(&stream);
+        if let Some(query) = self.sent.remove(&stream) {
+            let _ = query.reply.send(result);
+        }
+    }
+
+    /// Handles what's happened on a client connection.
+    fn poll_client(&mut self) {
+        while let Some(event) = self.connection.poll() {
+            match event {
+                Event::Connected => {
+                    // The server turned down our 0-RTT data, so the
+                    // queries in it are sent again
+                    if self.connection.has_0rtt() && !self.connection.accepted_0rtt() {
+                        let mut sent = self.sent.drain().collect::<Vec<_>>();
+                        sent.sort_by_key(|&(stream, _)| stream.index());
+                        let mut unsent = sent.into_iter().map(|(_, query)| query).collect::<Vec<_>>();
+                        unsent.extend(self.unsent.drain(..));
+                        self.unsent = unsent;
+                        self.outgoing.clear();
+                        self.incoming.clear();
+                    }
+                    self.send_queries();
+                }
+                Event::Stream(StreamEvent::Available { dir: Dir::Bi }) => self.send_queries(),
+                Event::Stream(StreamEvent::Writable { id }) => self.send_more(id),
+                Event::Stream(StreamEvent::Readable { id }) => {
+                    if let Some(data) = self.read(id) {
+                        let result = match unframe(&data) {
+                            Some(msg) => Ok(msg.to_vec()),
+                            None => Err(invalid("malformed DoQ response")),
+                        };
+                        self.answer(id, result);
+                    } else if self.sent.contains_key(&id) && !self.incoming.contains_key(&id) {
+                        // Reset by the server, or too long
+                        self.answer(id, Err(Error::new(ErrorKind::ConnectionReset, "stream reset")));
+                    }
+                }
+                Event::Stream(StreamEvent::Stopped { id, .. }) => {
+                    self.answer(id, Err(Error::new(ErrorKind::ConnectionReset, "stream stopped")))
+                }
+                Event::ConnectionLost { reason } => {
+                    let queries = self.sent.drain().map(|(_, query)| query);
+                    for query in queries.chain(self.unsent.drain(..)) {
+                        let _ = query.reply.send(Err(Error::new(
+                            ErrorKind::ConnectionAborted,
+                            format!("DoQ connection lost: {}", reason),
+                        )));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handles what's happened on a server connection, returning the
+    /// queries that can be answered now.
+    fn poll_server(&mut self) -> Vec<(StreamId, Vec<u8>)> {
+        let mut queries = Vec::new();
+        while let Some(event) = self.connection.poll() {
+            let stream = match event {
+                Event::Connected => {
+                    queries.extend(self.deferred.drain(..));
+                    continue;
+                }
+                Event::Stream(StreamEvent::Opened { dir: Dir::Bi }) => {
+                    while let Some(stream) = self.connection.streams().accept(Dir::Bi) {
+                        self.incoming.insert(stream, Vec::new());
+                    }
+                    let streams = self.incoming.keys().cloned().collect::<Vec<_>>();
+                    for stream in streams {
+                        if let Some(data) = self.read(stream) {
+                            queries.push((stream, data));
+                        }
+                    }
+                    continue;
+                }
+                // Only clients open streams, and only bidirectional ones
+                Event::Stream(StreamEvent::Opened { dir: Dir::Uni }) => {
+                    self.close(DOQ_PROTOCOL_ERROR);
+                    continue;
+                }
+                Event::Stream(StreamEvent::Readable { id }) => id,
+                Event::Stream(StreamEvent::Writable { id }) => {
+                    self.send_more(id);
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(data) = self.read(stream) {
+                queries.push((stream, data));
+            }
+        }
+
+        let mut ready = Vec::new();
+        for (stream, data) in queries {
+            let msg = match unframe(&data) {
+                // Queries have to have an ID of 0 (RFC 9250 section 4.2.1)
+                Some(msg) if msg.len() >= 12 && msg[0] == 0 && msg[1] == 0 => msg.to_vec(),
+                _ => {
+                    self.close(DOQ_PROTOCOL_ERROR);
+                    return Vec::new();
+                }
+            };
+            if self.connection.is_handshaking() && !is_replay_safe(&msg) {
+                self.deferred.push((stream, data));
+            } else {
+                ready.push((stream, msg));
+            }
+        }
+        ready
+    }
+}
+
+/// A QUIC endpoint, its connections and the socket they share.
+struct Driver {
+    socket: UdpSocket,
+    endpoint: Endpoint,
+    conns: HashMap<ConnectionHandle, Conn>,
+    buf: Vec<u8>,
+}
+
+impl Driver {
+    fn new(socket: UdpSocket, server: Option<Arc<QuicConfig>>) -> Driver {
+        let endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), server, true, None);
+        Driver {
+            socket: socket,
+            endpoint: endpoint,
+            conns: HashMap::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// How long to wait for a datagram before the next timer is due.
+    fn wait(&mut self, now: Instant) -> Duration {
+        let next = self
+            .conns
+            .values_mut()
+            .filter_map(|conn| conn.connection.poll_timeout())
+            .min();
+        match next {
+            Some(next) if next <= now => Duration::from_millis(1),
+            Some(next) => (next - now).min(MAX_WAIT).max(Duration::from_millis(1)),
+            None => MAX_WAIT,
+        }
+    }
+
+    fn receive(&mut self, now: Instant, from: SocketAddr, data: &[u8]) {
+        self.buf.clear();
+        let event = self.endpoint.handle(now, from, None, None, BytesMut::from(data), &mut self.buf);
+        match event {
+            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                if let Some(conn) = self.conns.get_mut(&handle) {
+                    conn.connection.handle_event(event);
+                }
+            }
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                let mut buf = Vec::new();
+                match self.endpoint.accept(incoming, now, &mut buf, None) {
+                    Ok((handle, connection)) => {
+                        self.conns.insert(handle, Conn::new(connection));
+                    }
+                    Err(e) => {
+                        debug!("Refused QUIC connection from {}: {}", from, e.cause);
+                        if let Some(transmit) = e.response {
+                            let _ = self.socket.send_to(&buf[..transmit.size], transmit.destination);
+                        }
+                    }
+                }
+            }
+            Some(DatagramEvent::Response(transmit)) => {
+                let _ = self.socket.send_to(&self.buf[..transmit.size], transmit.destination);
+            }
+            None => {}
+        }
+    }
+
+    fn handle_timeouts(&mut self, now: Instant) {
+        for conn in self.conns.values_mut() {
+            match conn.connection.poll_timeout() {
+                Some(timeout) if timeout <= now => conn.connection.handle_timeout(now),
+                _ => {}
+            }
+        }
+    }
+
+    /// Passes events between the connections and the endpoint, sends what
+    /// they have to send, and forgets those that are done with.
+    fn flush(&mut self, now: Instant) {
+        let mut failed = false;
+        for (&handle, conn) in self.conns.iter_mut() {
+            while let Some(event) = conn.connection.poll_endpoint_events() {
+                if let Some(event) = self.endpoint.handle_event(handle, event) {
+                    conn.connection.handle_event(event);
+                }
+            }
+            loop {
+                self.buf.clear();
+                let transmit = match conn.connection.poll_transmit(now, 1, &mut self.buf) {
+                    Some(x) => x,
+                    None => break,
+                };
+                if let Err(e) = self.socket.send_to(&self.buf[..transmit.size], transmit.destination)
+                {
+                    debug!("Failed to send QUIC datagram to {}: {:?}", transmit.destination, e);
+                    failed = true;
+                }
+            }
+        }
+        self.conns.retain(|_, conn| !conn.connection.is_drained());
+
+        // The network may have changed under a client, which carries on
+        // from a new socket
+        if failed && self.endpoint.open_connections() > 0 && self.is_client() {
+            let _ = self.rebind();
+        }
+    }
+
+    fn is_client(&self) -> bool {
+        self.conns
+            .values()
+            .all(|conn| conn.connection.side() == quinn_proto::Side::Client)
+    }
+
+    /// Moves over to a new socket, and the connections with it.
+    fn rebind(&mut self) -> Result<(), Error> {
+        let local = try!(self.socket.local_addr());
+        let unspecified: IpAddr = match local {
+            SocketAddr::V4(_) => [0, 0, 0, 0].into(),
+            SocketAddr::V6(_) => [0u16; 8].into(),
+        };
+        self.socket = try!(UdpSocket::bind(SocketAddr::new(unspecified, 0)));
+        Ok(())
+    }
+}
+
+/// Receives datagrams for `driver` and fires its timers, handing each
+/// round's connections to `poll` before sending what they have to send.
+/// Returns once `poll` says there's nothing left to drive.
+fn drive<F>(driver: &Mutex<Driver>, mut poll: F)
+where
+    F: FnMut(&mut Driver) -> bool,
+{
+    let mut data = vec![0u8; 65535];
+    loop {
+        let socket = {
+            let mut driver = lock(driver);
+            let wait = driver.wait(Instant::now());
+            driver
+                .socket
+                .try_clone()
+                .and_then(|socket| socket.set_read_timeout(Some(wait)).map(|_| socket))
+        };
+        let received = match socket.and_then(|socket| socket.recv_from(&mut data)) {
+            Ok(x) => Some(x),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                None
+            }
+            Err(e) => {
+                debug!("Failed to receive QUIC datagram: {:?}", e);
+                None
+            }
+        };
+
+        let now = Instant::now();
+        let mut driver = lock(driver);
+        if let Some((len, from)) = received {
+            driver.receive(now, from, &data[..len]);
+        }
+        driver.handle_timeouts(now);
+        let more = poll(&mut driver);
+        driver.flush(now);
+        if !more {
+            return;
+        }
+    }
+}
+
+/// A QUIC server config from `tls`, which has to offer DoQ by ALPN.
+pub fn server_config(tls: &rustls::ServerConfig) -> Result<Arc<QuicConfig>, Error> {
+    let mut tls = tls.clone();
+    // 0-RTT, for the queries it's safe for
+    tls.max_early_data_size = u32::max_value();
+    let crypto = try!(QuicServerConfig::try_from(tls).map_err(invalid));
+    Ok(Arc::new(QuicConfig::with_crypto(Arc::new(crypto))))
+}
+
+/// Answers DoQ queries arriving on `socket` with `handler`, each on a
+/// thread of its own.
+pub fn serve(socket: UdpSocket, config: Arc<QuicConfig>, handler: MessageHandler) {
+    let driver = Arc::new(Mutex::new(Driver::new(socket, Some(config))));
+    drive(&driver.clone(), |state| {
+        for (&handle, conn) in state.conns.iter_mut() {
+            let client = conn.connection.remote_address();
+            for (stream, msg) in conn.poll_server() {
+                let driver = driver.clone();
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    let answer = handler(&msg, client);
+                    let mut driver = lock(&driver);
+                    if let Some(conn) = driver.conns.get_mut(&handle) {
+                        // A query we can't make sense of fails alone (RFC
+                        // 9250 section 4.3.3)
+                        match answer {
+                            Some(answer) => conn.send(stream, frame(&answer)),
+                            None => conn.reset(stream, DOQ_PROTOCOL_ERROR),
+                        }
+                    }
+                    driver.flush(Instant::now());
+                });
+            }
+        }
+        true
+    });
+}
+
+/// A connection to a DoQ upstream, and the driver behind it.
+struct Upstream {
+    driver: Arc<Mutex<Driver>>,
+    handle: ConnectionHandle,
+}
+
+impl Upstream {
+    fn is_closed(&self) -> bool {
+        lock(&self.driver)
+            .conns
+            .get(&self.handle)
+            .is_none_or(|conn| conn.connection.is_closed())
+    }
+}
+
+/// Connections to DoQ upstreams, one to each, shared by all the queries
+/// sent there. Session tickets from one let the next send its first
+/// queries in 0-RTT.
+pub struct DoqClient {
+    config: quinn_proto::ClientConfig,
+    // The name upstream certificates have to be for, rather than their IP
+    name: Option<String>,
+    upstreams: Mutex<HashMap<SocketAddr, Arc<Upstream>>>,
+    next_token: Mutex<u64>,
+}
+
+impl DoqClient {
+    /// A client trusting upstreams as `TlsClient` does.
+    pub fn new(
+        name: Option<String>,
+        pins: Vec<Vec<u8>>,
+        ca_file: Option<&str>,
+    ) -> Result<DoqClient, Error> {
+        let mut tls = (*try!(tls::client_config(pins, ca_file, &[ALPN]))).clone();
+        tls.enable_early_data = true;
+        let crypto = try!(QuicClientConfig::try_from(tls).map_err(invalid));
+        Ok(DoqClient {
+            config: quinn_proto::ClientConfig::new(Arc::new(crypto)),
+            name: name,
+            upstreams: Mutex::new(HashMap::new()),
+            next_token: Mutex::new(0),
+        })
+    }
+
+    /// Sends the message `request` to `server` and returns its response.
+    pub fn exchange(
+        &self,
+        server: SocketAddr,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let upstream = try!(self.upstream(server));
+        let token = {
+            let mut next_token = lock(&self.next_token);
+            *next_token += 1;
+            *next_token
+        };
+        let (reply, response) = mpsc::channel();
+        {
+            let mut driver = lock(&upstream.driver);
+            match driver.conns.get_mut(&upstream.handle) {
+                Some(conn) => {
+                    conn.unsent.push(Query {
+                        token: token,
+                        data: frame(request),
+                        reply: reply,
+                    });
+                    conn.send_queries();
+                }
+                None => return Err(Error::new(ErrorKind::BrokenPipe, "DoQ connection closed")),
+            }
+            driver.flush(Instant::now());
+        }
+
+        match response.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                let mut driver = lock(&upstream.driver);
+                if let Some(conn) = driver.conns.get_mut(&upstream.handle) {
+                    conn.unsent.retain(|query| query.token != token);
+                    let stream = conn
+                        .sent
+                        .iter()
+                        .find(|&(_, query)| query.token == token)
+                        .map(|(&stream, _)| stream);
+                    if let Some(stream) = stream {
+                        conn.sent.remove(&stream);
+                        conn.outgoing.remove(&stream);
+                        let _ = conn.connection.send_stream(stream).reset(code(DOQ_REQUEST_CANCELLED));
+                        let _ = conn.connection.recv_stream(stream).stop(code(DOQ_REQUEST_CANCELLED));
+                    }
+                }
+                driver.flush(Instant::now());
+                Err(Error::new(ErrorKind::TimedOut, "timed out waiting for a DoQ response"))
+            }
+        }
+    }
+
+    /// The open connection to `server`, or a new one.
+    fn upstream(&self, server: SocketAddr) -> Result<Arc<Upstream>, Error> {
+        let mut upstreams = lock(&self.upstreams);
+        if let Some(upstream) = upstreams.get(&server) {
+            if !upstream.is_closed() {
+                return Ok(upstream.clone());
+            }
+        }
+
+        let local: SocketAddr = if server.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        };
+        let mut driver = Driver::new(try!(UdpSocket::bind(local)), None);
+        let name = match self.name {
+            Some(ref name) => name.clone(),
+            None => server.ip().to_string(),
+        };
+        let (handle, connection) = try!(driver
+            .endpoint
+            .connect(Instant::now(), self.config.clone(), server, &name)
+            .map_err(|e| Error::other(e.to_string())));
+        driver.conns.insert(handle, Conn::new(connection));
+
+        let upstream = Arc::new(Upstream {
+            driver: Arc::new(Mutex::new(driver)),
+            handle: handle,
+        });
+        let driver = upstream.driver.clone();
+        thread::spawn(move || {
+            drive(&driver, |state| {
+                for conn in state.conns.values_mut() {
+                    conn.poll_client();
+                }
+                !state.conns.is_empty()
+            })
+        });
+        upstreams.insert(server, upstream.clone());
+        Ok(upstream)
+    }
+
+    /// Moves the connections to all upstreams over to new sockets, as if
+    /// the network had changed.
+    pub fn rebind(&self) -> Result<(), Error> {
+        for upstream in lock(&self.upstreams).values() {
+            try!(lock(&upstream.driver).rebind());
+        }
+        Ok(())
+    }
+
+    /// Closes the connections to all upstreams.
+    pub fn close(&self) {
+        for upstream in lock(&self.upstreams).values() {
+            let mut driver = lock(&upstream.driver);
+            for conn in driver.conns.values_mut() {
+                conn.close(DOQ_NO_ERROR);
+            }
+            driver.flush(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_question::DnsQuestion;
+    use tls::test_support::Certificate;
+
+    fn query(name: &str, qtype: QueryType) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 0;
+        packet.questions.push(DnsQuestion::new(name.to_string(), qtype));
+        let mut buffer = BytePacketBuffer::with_size(512);
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos()].to_vec()
+    }
+
+    /// Answers every query with its own question, a little later.
+    fn handler() -> MessageHandler {
+        Arc::new(|message: &[u8], _: SocketAddr| {
+            let mut buffer = BytePacketBuffer::with_size(message.len());
+            buffer.buf.copy_from_slice(message);
+            let mut packet = match DnsPacket::from_buffer(&mut buffer) {
+                Ok(x) => x,
+                Err(_) => return None,
+            };
+            packet.header.response = true;
+            thread::sleep(Duration::from_millis(10));
+            let mut buffer = BytePacketBuffer::with_size(512);
+            packet.write(&mut buffer).unwrap();
+            Some(buffer.buf[..buffer.pos()].to_vec())
+        })
+    }
+
+    /// A DoQ server for `doq.test`, and a client trusting it.
+    fn doq_stub(test: &str) -> (SocketAddr, DoqClient) {
+        let cert = Certificate::new(test, "doq.test");
+        let tls = cert.server_config(&[ALPN]);
+        let client = DoqClient::new(
+            Some("doq.test".to_string()),
+            Vec::new(),
+            Some(&cert.cert_file),
+        ).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = server_config(&tls).unwrap();
+        thread::spawn(move || serve(socket, config, handler()));
+        (addr, client)
+    }
+
+    fn ask(client: &DoqClient, server: SocketAddr, name: &str) -> DnsPacket {
+        let response = client
+            .exchange(server, &query(name, QueryType::A), Duration::from_secs(5))
+            .unwrap();
+        let mut buffer = BytePacketBuffer::with_size(response.len());
+        buffer.buf.copy_from_slice(&response);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(packet.header.response);
+        assert_eq!(packet.questions[0].name, name);
+        packet
+    }
+
+    fn connection<F, T>(client: &DoqClient, server: SocketAddr, f: F) -> T
+    where
+        F: FnOnce(&quinn_proto::Connection) -> T,
+    {
+        let upstream = lock(&client.upstreams)[&server].clone();
+        let driver = lock(&upstream.driver);
+        f(&driver.conns[&upstream.handle].connection)
+    }
+
+    #[test]
+    fn answers_queries_on_streams_of_their_own() {
+        let (server, client) = doq_stub("doq-streams");
+        let client = Arc::new(client);
+        let threads = (0..8)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    ask(&client, server, &format!("host{}.example.com", i));
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(lock(&client.upstreams).len(), 1);
+        let streams = connection(&client, server, |c| c.stats().frame_tx.stream);
+        assert!(streams >= 8);
+
+        // Connections stay with their client when it moves
+        let upstream = lock(&client.upstreams)[&server].clone();
+        let local = lock(&upstream.driver).socket.local_addr().unwrap();
+        client.rebind().unwrap();
+        ask(&client, server, "moved.example.com");
+        assert!(Arc::ptr_eq(&upstream, &lock(&client.upstreams)[&server]));
+        assert!(!upstream.is_closed());
+        assert!(lock(&upstream.driver).socket.local_addr().unwrap() != local);
+    }
+
+    #[test]
+    fn resumes_in_0rtt() {
+        let (server, client) = doq_stub("doq-0rtt");
+        ask(&client, server, "first.example.com");
+        assert!(!connection(&client, server, |c| c.accepted_0rtt()));
+
+        client.close();
+        let upstream = lock(&client.upstreams)[&server].clone();
+        while !upstream.is_closed() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        ask(&client, server, "second.example.com");
+        assert!(connection(&client, server, |c| c.accepted_0rtt()));
+    }
+
+    #[test]
+    fn resets_only_streams_it_cant_answer() {
+        let (server, client) = doq_stub("doq-reset");
+        ask(&client, server, "first.example.com");
+
+        // An ID of 0, but five questions that aren't there
+        let garbage = [0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0];
+        let error = client.exchange(server, &garbage, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+
+        let upstream = lock(&client.upstreams)[&server].clone();
+        ask(&client, server, "second.example.com");
+        assert!(Arc::ptr_eq(&upstream, &lock(&client.upstreams)[&server]));
+    }
+
+    #[test]
+    fn caps_what_unfinished_queries_hold() {
+        let (server, client) = doq_stub("doq-buffered");
+        ask(&client, server, "first.example.com");
+
+        // Streams the server has to hold on to, as they never end
+        let upstream = lock(&client.upstreams)[&server].clone();
+        let streams = {
+            let mut driver = lock(&upstream.driver);
+            let streams = {
+                let conn = driver.conns.get_mut(&upstream.handle).unwrap();
+                (0..20)
+                    .map(|_| {
+                        let stream = conn.connection.streams().open(Dir::Bi).unwrap();
+                        let data = vec![0; 60000];
+                        let written = conn.connection.send_stream(stream).write(&data);
+                        assert_eq!(written, Ok(data.len()));
+                        stream
+                    })
+                    .collect::<Vec<_>>()
+            };
+            driver.flush(Instant::now());
+            streams
+        };
+
+        let stopped = || {
+            let mut driver = lock(&upstream.driver);
+            let conn = driver.conns.get_mut(&upstream.handle).unwrap();
+            streams
+                .iter()
+                .filter(|&&stream| {
+                    let stopped = conn.connection.send_stream(stream).stopped();
+                    stopped == Ok(Some(code(DOQ_EXCESSIVE_LOAD)))
+                })
+                .count()
+        };
+        // Those the others leave no room for are turned away, but there's
+        // still room for a query
+        let expected = 20 - MAX_BUFFERED / 60000;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stopped() < expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(stopped(), expected);
+        ask(&client, server, "second.example.com");
+    }
+
+    #[test]
+    fn holds_back_unsafe_0rtt_queries() {
+        assert!(is_replay_safe(&query("example.com", QueryType::A)));
+        assert!(!is_replay_safe(&query("example.com", QueryType::AXFR)));
+        assert!(!is_replay_safe(&[0, 0, 1]));
+        assert_eq!(unframe(&frame(&[1, 2, 3])), Some(&[1u8, 2, 3][..]));
+        assert_eq!(unframe(&[0, 4, 1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,441 @@
+//! HPACK (RFC 7541), the header compression of HTTP/2. The headers we send
+//! are never added to the dynamic table, so only decoding keeps any state.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+
+// The most the dynamic table can hold, which we never raise in SETTINGS
+pub const MAX_TABLE_SIZE: usize = 4096;
+
+pub type Header = (String, String);
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// The length in bits of the Huffman code for each byte (Appendix B). The
+// code is canonical, so the codes themselves follow from these. EOS, which
+// only pads, is 30 bits long.
+const HUFFMAN_CODE_LENGTHS: [u8; 256] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+];
+const EOS: usize = 256;
+const EOS_LENGTH: usize = 30;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("HPACK: {}", msg))
+}
+
+/// Reads an integer with an `prefix`-bit prefix (section 5.1).
+fn read_integer(block: &[u8], pos: &mut usize, prefix: u32) -> Result<usize, Error> {
+    let mask = (1usize << prefix) - 1;
+    let mut value = match block.get(*pos) {
+        Some(byte) => *byte as usize & mask,
+        None => return Err(invalid("truncated integer")),
+    };
+    *pos += 1;
+    if value < mask {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let byte = match block.get(*pos) {
+            Some(x) => *x,
+            None => return Err(invalid("truncated integer")),
+        };
+        *pos += 1;
+        value += (byte as usize & 0x7F) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        if shift > 28 {
+            return Err(invalid("integer too large"));
+        }
+    }
+}
+
+fn write_integer(out: &mut Vec<u8>, flags: u8, prefix: u32, value: usize) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    let mut value = value - mask;
+    while value >= 0x80 {
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The canonical Huffman code, worked out from the code lengths once, as
+/// the crate is compiled.
+struct HuffmanCode {
+    // Codes of the same length are consecutive, in the order of the bytes
+    // they stand for
+    symbols: [u16; EOS + 1],
+    // By length: how many codes there are, the first of them, and where it
+    // is in `symbols`
+    counts: [usize; EOS_LENGTH + 1],
+    first_codes: [usize; EOS_LENGTH + 1],
+    first_indexes: [usize; EOS_LENGTH + 1],
+}
+
+impl HuffmanCode {
+    const fn new() -> HuffmanCode {
+        let mut huffman = HuffmanCode {
+            symbols: [0; EOS + 1],
+            counts: [0; EOS_LENGTH + 1],
+            first_codes: [0; EOS_LENGTH + 1],
+            first_indexes: [0; EOS_LENGTH + 1],
+        };
+        let (mut code, mut index) = (0, 0);
+        let mut len = 1;
+        while len <= EOS_LENGTH {
+            huffman.first_codes[len] = code;
+            huffman.first_indexes[len] = index;
+            let mut symbol = 0;
+            while symbol <= EOS {
+                let symbol_len = match symbol {
+                    EOS => EOS_LENGTH,
+                    _ => HUFFMAN_CODE_LENGTHS[symbol] as usize,
+                };
+                if symbol_len == len {
+                    huffman.symbols[index] = symbol as u16;
+                    huffman.counts[len] += 1;
+                    index += 1;
+                }
+                symbol += 1;
+            }
+            code = (code + huffman.counts[len]) << 1;
+            len += 1;
+        }
+        huffman
+    }
+}
+
+static HUFFMAN: HuffmanCode = HuffmanCode::new();
+
+/// Decodes a Huffman coded string, which may end with at most 7 bits of
+/// padding taken from the start of EOS.
+fn decode_huffman(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let HuffmanCode {
+        ref symbols,
+        ref counts,
+        ref first_codes,
+        ref first_indexes,
+    } = HUFFMAN;
+    let mut res = Vec::new();
+    let (mut code, mut len) = (0usize, 0usize);
+    for byte in data {
+        for i in (0..8).rev() {
+            code = (code << 1) | (*byte as usize >> i & 1);
+            len += 1;
+            if len > EOS_LENGTH {
+                return Err(invalid("invalid Huffman code"));
+            }
+            if code >= first_codes[len] && code - first_codes[len] < counts[len] {
+                match symbols[first_indexes[len] + code - first_codes[len]] as usize {
+                    EOS => return Err(invalid("EOS in Huffman coded string")),
+                    symbol => res.push(symbol as u8),
+                }
+                code = 0;
+                len = 0;
+            }
+        }
+    }
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(invalid("invalid Huffman padding"));
+    }
+    Ok(res)
+}
+
+fn read_string(block: &[u8], pos: &mut usize) -> Result<String, Error> {
+    let huffman = block.get(*pos).is_some_and(|byte| byte & 0x80 != 0);
+    let len = try!(read_integer(block, pos, 7));
+    if block.len() - *pos < len {
+        return Err(invalid("truncated string"));
+    }
+    let data = &block[*pos..*pos + len];
+    *pos += len;
+
+    let data = if huffman {
+        try!(decode_huffman(data))
+    } else {
+        data.to_vec()
+    };
+    String::from_utf8(data).map_err(|_| invalid("header isn't UTF-8"))
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+    write_integer(out, 0, 7, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// `headers` as a header block, each indexed from the static table where
+/// it's there, and a literal that isn't to be indexed otherwise.
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(name, value) in headers {
+        if let Some(i) = STATIC_TABLE.iter().position(|&(n, v)| n == name && v == value) {
+            write_integer(&mut out, 0x80, 7, i + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => write_integer(&mut out, 0, 4, i + 1),
+            None => {
+                out.push(0);
+                write_string(&mut out, name);
+            }
+        }
+        write_string(&mut out, value);
+    }
+    out
+}
+
+/// Decodes the header blocks of one direction of a connection, keeping the
+/// dynamic table they build up.
+pub struct Decoder {
+    // Newest first, as they're indexed
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: MAX_TABLE_SIZE,
+        }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Header>, Error> {
+        let mut headers = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            if byte & 0x80 != 0 {
+                let index = try!(read_integer(block, &mut pos, 7));
+                headers.push(try!(self.get(index)));
+            } else if byte & 0x40 != 0 {
+                let header = try!(self.read_literal(block, &mut pos, 6));
+                self.insert(header.clone());
+                headers.push(header);
+            } else if byte & 0x20 != 0 {
+                let size = try!(read_integer(block, &mut pos, 5));
+                if size > MAX_TABLE_SIZE {
+                    return Err(invalid("table size over the limit"));
+                }
+                self.max_size = size;
+                self.evict();
+            } else {
+                // Whether or not it's never to be indexed, it isn't by us
+                headers.push(try!(self.read_literal(block, &mut pos, 4)));
+            }
+        }
+        Ok(headers)
+    }
+
+    fn get(&self, index: usize) -> Result<Header, Error> {
+        if index == 0 {
+            return Err(invalid("index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_string(), value.to_string()));
+        }
+        match self.table.get(index - STATIC_TABLE.len() - 1) {
+            Some(header) => Ok(header.clone()),
+            None => Err(invalid("index past the dynamic table")),
+        }
+    }
+
+    fn read_literal(&self, block: &[u8], pos: &mut usize, prefix: u32) -> Result<Header, Error> {
+        let index = try!(read_integer(block, pos, prefix));
+        let name = if index == 0 {
+            try!(read_string(block, pos))
+        } else {
+            try!(self.get(index)).0
+        };
+        let value = try!(read_string(block, pos));
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, header: Header) {
+        self.size += entry_size(&header);
+        self.table.push_front(header);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some(header) => self.size -= entry_size(&header),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+fn entry_size(header: &Header) -> usize {
+    header.0.len() + header.1.len() + 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<Header> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // The requests of RFC 7541 appendix C.3, and C.4 with Huffman coding
+    fn decodes_requests(blocks: &[&str]) {
+        let mut decoder = Decoder::new();
+        let first = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), headers(&first));
+        assert_eq!(decoder.size, 57);
+
+        let mut second = first.to_vec();
+        second.push(("cache-control", "no-cache"));
+        assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), headers(&second));
+
+        let third = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), headers(&third));
+        assert_eq!(decoder.size, 164);
+        assert_eq!(decoder.table[0], ("custom-key".to_string(), "custom-value".to_string()));
+    }
+
+    #[test]
+    fn decodes_literal_requests() {
+        decodes_requests(&[
+            "828684410f7777772e6578616d706c652e636f6d",
+            "828684be58086e6f2d6361636865",
+            "828785bf400a637573746f6d2d6b65790c637573746f6d2d76616c7565",
+        ]);
+    }
+
+    #[test]
+    fn decodes_huffman_coded_requests() {
+        decodes_requests(&[
+            "828684418cf1e3c2e5f23a6ba0ab90f4ff",
+            "828684be5886a8eb10649cbf",
+            "828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf",
+        ]);
+        assert!(decode_huffman(&[0xFF, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn encodes_what_it_decodes() {
+        let sent = [
+            (":status", "200"),
+            ("content-type", "application/dns-message"),
+            ("cache-control", "max-age=300"),
+            ("x-long", &"a".repeat(300)),
+        ];
+        let block = encode(&sent);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::new().decode(&block).unwrap(), headers(&sent));
+    }
+}
@@ -0,0 +1,277 @@
+//! HTTP requests and responses, and reading and writing them in HTTP/1.1
+//! (RFC 9112). Only what DNS over HTTPS needs is supported: bodies have to
+//! come with a Content-Length, and no larger than a DNS message.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+// The longest body taken, that of the largest DNS message
+pub const MAX_BODY: usize = 0xFFFF;
+const MAX_LINE: usize = 8192;
+const MAX_HEADERS: usize = 100;
+
+/// Answers requests, on whichever connection they came in on.
+pub type Handler = Arc<dyn Fn(Request) -> Response + Send + Sync>;
+
+pub struct Request {
+    pub method: String,
+    // The path with the query, if any
+    pub path: String,
+    // Names in lower case, and in the order they came in
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// The value of `name` in the query part of the path, percent-decoded.
+    pub fn query(&self, name: &str) -> Option<String> {
+        let query = match self.path.find('?') {
+            Some(i) => &self.path[i + 1..],
+            None => return None,
+        };
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(n), Some(value)) if n == name => Some(percent_decode(value)),
+                    (Some(n), None) if n == name => Some(String::new()),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// The path without the query.
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or("")
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = text.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                res.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                res.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// A line less its CRLF, or None at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, Error> {
+    let mut line = Vec::new();
+    try!(reader.take(MAX_LINE as u64).read_until(b'\n', &mut line));
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("line too long"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("line isn't UTF-8"))
+}
+
+/// The next request on `reader`, or None if the client closed the
+/// connection instead of sending one.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, Error> {
+    let line = match try!(read_line(reader)) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+        return Err(invalid("malformed request line"));
+    }
+    let mut request = Request {
+        method: parts[0].to_string(),
+        path: parts[1].to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    loop {
+        let line = match try!(read_line(reader)) {
+            Some(x) => x,
+            None => return Err(invalid("connection closed in the headers")),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if request.headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => request
+                .headers
+                .push((name.to_ascii_lowercase(), value.trim().to_string())),
+            _ => return Err(invalid("malformed header")),
+        }
+    }
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(invalid("only bodies with a Content-Length are supported"));
+    }
+    let len = match request.header("content-length").map(|len| len.parse::<usize>()) {
+        Some(Ok(len)) if len <= MAX_BODY => len,
+        Some(Ok(_)) => return Err(invalid("body too large")),
+        Some(Err(_)) => return Err(invalid("malformed Content-Length")),
+        None => 0,
+    };
+    request.body = vec![0; len];
+    try!(reader.read_exact(&mut request.body));
+
+    Ok(Some(request))
+}
+
+pub fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<(), Error> {
+    let mut out = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for &(ref name, ref value) in &response.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!("content-length: {}\r\n\r\n", response.body.len()));
+
+    let mut data = out.into_bytes();
+    data.extend_from_slice(&response.body);
+    try!(writer.write_all(&data));
+    writer.flush()
+}
+
+/// Answers the requests on an HTTP/1.1 connection in turn, until the client
+/// closes it, asks for it to be closed or goes quiet.
+pub fn serve<R: Read, W: Write>(reader: R, mut writer: W, handler: &Handler) -> Result<(), Error> {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                let mut response = Response::new(400);
+                response.add_header("connection", "close");
+                return write_response(&mut writer, &response);
+            }
+            Err(_) => return Ok(()),
+        };
+
+        let close = request
+            .header("connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        let mut response = handler(request);
+        if close {
+            response.add_header("connection", "close");
+        }
+        try!(write_response(&mut writer, &response));
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_requests() {
+        let text = "GET /dns-query?dns=AAAB&x=%41%2 HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                    POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\n\
+                    Content-Length: 3\r\n\r\nabc";
+        let mut reader = Cursor::new(text.as_bytes());
+
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.route(), "/dns-query");
+        assert_eq!(request.query("dns"), Some("AAAB".to_string()));
+        assert_eq!(request.query("x"), Some("A%2".to_string()));
+        assert_eq!(request.header("host"), Some("example.com"));
+
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("content-type"), Some("application/dns-message"));
+        assert_eq!(request.body, b"abc".to_vec());
+
+        assert!(read_request(&mut reader).unwrap().is_none());
+        let mut chunked = Cursor::new("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(read_request(&mut chunked).is_err());
+    }
+
+    #[test]
+    fn serves_until_asked_to_close() {
+        let text = "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n\
+                    GET /c HTTP/1.1\r\n\r\n";
+        let handler: Handler = Arc::new(|request: Request| {
+            let mut response = Response::new(200);
+            response.body = request.path.into_bytes();
+            response
+        });
+        let mut out = Vec::new();
+        serve(text.as_bytes(), &mut out, &handler).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n/a\
+             HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 2\r\n\r\n/b"
+        );
+    }
+}
@@ -0,0 +1,1254 @@
+//! HTTP/2 (RFC 9113), as much of it as DNS over HTTPS needs. Each request
+//! is answered on a thread of its own, so that one waiting on a slow
+//! upstream doesn't hold up the others on its connection; and as a client,
+//! requests from any number of threads share one connection.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hpack;
+use http::{self, Handler, Request, Response};
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+// Flags
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY: u8 = 0x20;
+
+// Settings
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+const MAX_WINDOW_SIZE: i64 = 0x7FFF_FFFF;
+// The largest frame we take, and send until the peer allows larger
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+// Requests answered at once on a connection
+const MAX_STREAMS: u32 = 100;
+const MAX_STREAM_ID: u32 = 0x7FFF_FFFF;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("HTTP/2: {}", msg))
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind: kind,
+            flags: flags,
+            stream: stream,
+            payload: payload,
+        }
+    }
+
+    /// Reads a frame. Running out of time before any of it arrives gives
+    /// the reader's timeout error, and reading may carry on; running out
+    /// partway through leaves the connection out of step, so it's an error
+    /// of its own.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Frame, Error> {
+        let mut header = [0u8; 9];
+        try!(read_part(reader, &mut header, true));
+        let len = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(invalid("frame too large"));
+        }
+        let stream = ((header[5] as u32) << 24 | (header[6] as u32) << 16
+            | (header[7] as u32) << 8 | header[8] as u32) & 0x7FFF_FFFF;
+
+        let mut payload = vec![0; len];
+        try!(read_part(reader, &mut payload, false));
+        Ok(Frame::new(header[3], header[4], stream, payload))
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let len = self.payload.len();
+        let mut data = Vec::with_capacity(9 + len);
+        data.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        data.push(self.kind);
+        data.push(self.flags);
+        data.extend_from_slice(&u32_bytes(self.stream));
+        data.extend_from_slice(&self.payload);
+        try!(writer.write_all(&data));
+        writer.flush()
+    }
+
+    /// The payload of a DATA or HEADERS frame, less any padding and
+    /// priority fields.
+    pub fn data(&self) -> Result<&[u8], Error> {
+        let mut data = &self.payload[..];
+        let mut padding = 0;
+        if self.flags & PADDED != 0 {
+            match data.split_first() {
+                Some((len, rest)) => {
+                    padding = *len as usize;
+                    data = rest;
+                }
+                None => return Err(invalid("missing padding length")),
+            }
+        }
+        if self.kind == HEADERS && self.flags & PRIORITY != 0 {
+            if data.len() < 5 {
+                return Err(invalid("missing priority"));
+            }
+            data = &data[5..];
+        }
+        if padding > data.len() {
+            return Err(invalid("padding longer than the frame"));
+        }
+        Ok(&data[..data.len() - padding])
+    }
+}
+
+/// Fills `buf` with the next part of a frame, the first if `first`.
+fn read_part<R: Read>(reader: &mut R, buf: &mut [u8], first: bool) -> Result<(), Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+            Ok(len) => filled += len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(ref e) if is_timeout(e) && (filled > 0 || !first) => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "timed out within a frame"))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn u32_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+pub fn settings(values: &[(u16, u32)]) -> Frame {
+    let mut payload = Vec::new();
+    for &(id, value) in values {
+        payload.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+        payload.extend_from_slice(&u32_bytes(value));
+    }
+    Frame::new(SETTINGS, 0, 0, payload)
+}
+
+fn goaway(last_stream: u32, code: u32) -> Frame {
+    let mut payload = u32_bytes(last_stream).to_vec();
+    payload.extend_from_slice(&u32_bytes(code));
+    Frame::new(GOAWAY, 0, 0, payload)
+}
+
+fn rst_stream(stream: u32, code: u32) -> Frame {
+    Frame::new(RST_STREAM, 0, stream, u32_bytes(code).to_vec())
+}
+
+fn window_update(stream: u32, increment: usize) -> Frame {
+    Frame::new(WINDOW_UPDATE, 0, stream, u32_bytes(increment as u32).to_vec())
+}
+
+/// How much more we may send, by the windows the peer has given us.
+struct Windows {
+    connection: i64,
+    // Streams we've yet to finish sending on
+    streams: HashMap<u32, i64>,
+    initial: i64,
+    max_frame_size: usize,
+    // How many streams the peer lets us open at once
+    max_streams: usize,
+    // No more updates are coming, since the connection is gone
+    closed: bool,
+}
+
+impl Windows {
+    fn new() -> Windows {
+        Windows {
+            connection: DEFAULT_WINDOW_SIZE,
+            streams: HashMap::new(),
+            initial: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_streams: usize::max_value(),
+            closed: false,
+        }
+    }
+
+    /// Applies a SETTINGS frame from the peer.
+    fn settings(&mut self, payload: &[u8]) -> Result<(), u32> {
+        if payload.len() % 6 != 0 {
+            return Err(FRAME_SIZE_ERROR);
+        }
+        for setting in payload.chunks(6) {
+            let value = read_u32(&setting[2..]) as i64;
+            match (setting[0] as u16) << 8 | setting[1] as u16 {
+                SETTINGS_INITIAL_WINDOW_SIZE if value > MAX_WINDOW_SIZE => {
+                    return Err(FLOW_CONTROL_ERROR)
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    for window in self.streams.values_mut() {
+                        *window += value - self.initial;
+                    }
+                    self.initial = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE if !(16384..=0xFF_FFFF).contains(&value) => {
+                    return Err(PROTOCOL_ERROR)
+                }
+                SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                SETTINGS_MAX_CONCURRENT_STREAMS => self.max_streams = value as usize,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The two halves of a connection: where frames go out, shared by the
+/// threads sending on it, and the windows they're sent in.
+pub struct Sender<W: Write> {
+    writer: Mutex<W>,
+    windows: Mutex<Windows>,
+    updated: Condvar,
+}
+
+impl<W: Write> Sender<W> {
+    pub fn new(writer: W) -> Sender<W> {
+        Sender {
+            writer: Mutex::new(writer),
+            windows: Mutex::new(Windows::new()),
+            updated: Condvar::new(),
+        }
+    }
+
+    pub fn send(&self, frame: &Frame) -> Result<(), Error> {
+        frame.write(&mut *lock(&self.writer))
+    }
+
+    /// Sends the header block `block` on `stream`, ending it if there's no
+    /// body to follow.
+    pub fn send_headers(&self, stream: u32, block: Vec<u8>, end_stream: bool) -> Result<(), Error> {
+        let flags = if end_stream { END_STREAM | END_HEADERS } else { END_HEADERS };
+        if block.len() > lock(&self.windows).max_frame_size {
+            return Err(invalid("header block too large"));
+        }
+        self.send(&Frame::new(HEADERS, flags, stream, block))
+    }
+
+    /// Sends `body` on `stream` and ends it, waiting for the peer to open
+    /// its windows as needed.
+    pub fn send_body(&self, stream: u32, body: &[u8]) -> Result<(), Error> {
+        let mut sent = 0;
+        loop {
+            let len = {
+                let mut windows = lock(&self.windows);
+                loop {
+                    if windows.closed {
+                        return Err(Error::new(ErrorKind::BrokenPipe, "connection closed"));
+                    }
+                    let stream_window = match windows.streams.get(&stream) {
+                        Some(x) => *x,
+                        None => return Err(Error::other("stream reset")),
+                    };
+                    let window = stream_window.min(windows.connection);
+                    if window > 0 || sent == body.len() {
+                        let len = (body.len() - sent)
+                            .min(window.max(0) as usize)
+                            .min(windows.max_frame_size);
+                        windows.connection -= len as i64;
+                        if let Some(window) = windows.streams.get_mut(&stream) {
+                            *window -= len as i64;
+                        }
+                        break len;
+                    }
+                    windows = match self.updated.wait(windows) {
+                        Ok(x) => x,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                }
+            };
+
+            let end = sent + len == body.len();
+            let flags = if end { END_STREAM } else { 0 };
+            try!(self.send(&Frame::new(DATA, flags, stream, body[sent..sent + len].to_vec())));
+            sent += len;
+            if end {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn open(&self, stream: u32) {
+        let mut windows = lock(&self.windows);
+        let initial = windows.initial;
+        windows.streams.insert(stream, initial);
+    }
+
+    /// Opens `stream` once the peer's limit on streams open at once allows,
+    /// waiting up to `timeout` for others to finish.
+    pub fn open_within(&self, stream: u32, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut windows = lock(&self.windows);
+        while windows.streams.len() >= windows.max_streams {
+            let now = Instant::now();
+            if windows.closed {
+                return Err(Error::new(ErrorKind::BrokenPipe, "connection closed"));
+            }
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "timed out waiting for a stream"));
+            }
+            windows = match self.updated.wait_timeout(windows, deadline - now) {
+                Ok((x, _)) => x,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        let initial = windows.initial;
+        windows.streams.insert(stream, initial);
+        Ok(())
+    }
+
+    pub fn finish(&self, stream: u32) {
+        lock(&self.windows).streams.remove(&stream);
+        self.updated.notify_all();
+    }
+
+    pub fn open_streams(&self) -> usize {
+        lock(&self.windows).streams.len()
+    }
+
+    /// Handles the frames that change the windows, returning the error code
+    /// to end the connection with if the peer got them wrong, or else the
+    /// stream reset for it if only that stream is affected.
+    pub fn handle_frame(&self, frame: &Frame) -> Result<Option<u32>, u32> {
+        let mut windows = lock(&self.windows);
+        match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => try!(windows.settings(&frame.payload)),
+            WINDOW_UPDATE => {
+                if frame.payload.len() != 4 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                let increment = (read_u32(&frame.payload) & 0x7FFF_FFFF) as i64;
+                // Section 6.9: an increment of 0 is an error
+                if increment == 0 && frame.stream == 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if increment == 0 {
+                    windows.streams.remove(&frame.stream);
+                    drop(windows);
+                    self.updated.notify_all();
+                    let _ = self.send(&rst_stream(frame.stream, PROTOCOL_ERROR));
+                    return Ok(Some(frame.stream));
+                }
+                let window = match frame.stream {
+                    0 => Some(&mut windows.connection),
+                    stream => windows.streams.get_mut(&stream),
+                };
+                if let Some(window) = window {
+                    *window += increment;
+                    if *window > MAX_WINDOW_SIZE {
+                        return Err(FLOW_CONTROL_ERROR);
+                    }
+                }
+            }
+            RST_STREAM => {
+                windows.streams.remove(&frame.stream);
+            }
+            _ => return Ok(None),
+        }
+        self.updated.notify_all();
+        Ok(None)
+    }
+
+    /// Wakes whoever is waiting on a window, as none will open now.
+    pub fn close(&self) {
+        lock(&self.windows).closed = true;
+        self.updated.notify_all();
+    }
+}
+
+/// A request whose header block or body is still coming in.
+struct Incoming {
+    block: Vec<u8>,
+    request: Option<Request>,
+    end_stream: bool,
+}
+
+/// Serves an HTTP/2 connection whose client sends on `reader` and receives
+/// on `writer`, answering each request with `handler`. Returns once the
+/// client closes the connection, or has been quiet for as long as `reader`
+/// waits with nothing in flight.
+pub fn serve<R, W>(mut reader: R, writer: W, handler: Handler) -> Result<(), Error>
+where
+    R: Read,
+    W: Write + Send + 'static,
+{
+    let mut preface = [0u8; 24];
+    try!(reader.read_exact(&mut preface));
+    if &preface[..] != PREFACE {
+        return Err(invalid("missing connection preface"));
+    }
+
+    let sender = Arc::new(Sender::new(writer));
+    try!(sender.send(&settings(&[(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS)])));
+    let mut last_stream = 0;
+    let result = read_requests(&mut reader, &sender, &handler, &mut last_stream);
+    sender.close();
+
+    let code = match result {
+        Ok(()) => NO_ERROR,
+        Err(code) => code,
+    };
+    let _ = sender.send(&goaway(last_stream, code));
+    if code == NO_ERROR {
+        Ok(())
+    } else {
+        Err(invalid(&format!("connection ended with error {}", code)))
+    }
+}
+
+fn read_requests<R, W>(
+    reader: &mut R,
+    sender: &Arc<Sender<W>>,
+    handler: &Handler,
+    last_stream: &mut u32,
+) -> Result<(), u32>
+where
+    R: Read,
+    W: Write + Send + 'static,
+{
+    let mut decoder = hpack::Decoder::new();
+    let mut incoming: HashMap<u32, Incoming> = HashMap::new();
+    // The stream whose header block is being continued, if any
+    let mut continued: Option<u32> = None;
+
+    loop {
+        let frame = match Frame::read(reader) {
+            Ok(x) => x,
+            // A timeout with answers still to send isn't the client going
+            // quiet
+            Err(ref e) if is_timeout(e) && sender.open_streams() > 0 => continue,
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => return Err(FRAME_SIZE_ERROR),
+            Err(_) => return Ok(()),
+        };
+        if continued.is_some() && (frame.kind != CONTINUATION || continued != Some(frame.stream)) {
+            return Err(PROTOCOL_ERROR);
+        }
+        if let Some(stream) = try!(sender.handle_frame(&frame)) {
+            incoming.remove(&stream);
+            continue;
+        }
+
+        match frame.kind {
+            HEADERS => {
+                let data = try!(frame.data().map_err(|_| PROTOCOL_ERROR)).to_vec();
+                if let Some(mut request) = incoming.remove(&frame.stream) {
+                    // Trailers, which end the stream
+                    request.block = data;
+                    request.end_stream = true;
+                    incoming.insert(frame.stream, request);
+                } else if frame.stream % 2 == 1 && frame.stream > *last_stream {
+                    *last_stream = frame.stream;
+                    incoming.insert(
+                        frame.stream,
+                        Incoming {
+                            block: data,
+                            request: None,
+                            end_stream: frame.flags & END_STREAM != 0,
+                        },
+                    );
+                } else {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if frame.flags & END_HEADERS == 0 {
+                    continued = Some(frame.stream);
+                    continue;
+                }
+            }
+            CONTINUATION => match incoming.get_mut(&frame.stream) {
+                Some(request) if continued == Some(frame.stream) => {
+                    request.block.extend_from_slice(&frame.payload);
+                    if frame.flags & END_HEADERS == 0 {
+                        continue;
+                    }
+                    continued = None;
+                }
+                _ => return Err(PROTOCOL_ERROR),
+            },
+            DATA => {
+                let data = try!(frame.data().map_err(|_| PROTOCOL_ERROR));
+                // Whatever comes in is taken off our windows, so give it
+                // back straight away
+                if !frame.payload.is_empty() {
+                    let _ = sender.send(&window_update(0, frame.payload.len()));
+                }
+                let request = match incoming.get_mut(&frame.stream) {
+                    Some(&mut Incoming { request: Some(ref mut request), .. }) => request,
+                    _ => continue,
+                };
+                if request.body.len() + data.len() > http::MAX_BODY {
+                    incoming.remove(&frame.stream);
+                    sender.finish(frame.stream);
+                    let _ = sender.send(&rst_stream(frame.stream, REFUSED_STREAM));
+                    continue;
+                }
+                request.body.extend_from_slice(data);
+                if frame.flags & END_STREAM == 0 {
+                    if !frame.payload.is_empty() {
+                        let _ = sender.send(&window_update(frame.stream, frame.payload.len()));
+                    }
+                    continue;
+                }
+                if let Some(request) = incoming.get_mut(&frame.stream) {
+                    request.end_stream = true;
+                }
+            }
+            SETTINGS if frame.flags & ACK == 0 => {
+                let _ = sender.send(&Frame::new(SETTINGS, ACK, 0, Vec::new()));
+                continue;
+            }
+            PING if frame.flags & ACK == 0 => {
+                let _ = sender.send(&Frame::new(PING, ACK, 0, frame.payload.clone()));
+                continue;
+            }
+            RST_STREAM => {
+                incoming.remove(&frame.stream);
+                continue;
+            }
+            PUSH_PROMISE => return Err(PROTOCOL_ERROR),
+            GOAWAY => return Ok(()),
+            _ => continue,
+        }
+
+        // A header block is complete, or the body is
+        let stream = frame.stream;
+        let ready = match incoming.get_mut(&stream) {
+            Some(request) => {
+                if !request.block.is_empty() {
+                    let headers = try!(decoder.decode(&request.block)
+                        .map_err(|_| COMPRESSION_ERROR));
+                    request.block.clear();
+                    if request.request.is_none() {
+                        request.request = Some(try!(to_request(headers)));
+                        if sender.open_streams() >= MAX_STREAMS as usize {
+                            let _ = sender.send(&rst_stream(stream, REFUSED_STREAM));
+                            request.request = None;
+                            request.end_stream = true;
+                        } else {
+                            sender.open(stream);
+                        }
+                    }
+                }
+                request.end_stream
+            }
+            None => false,
+        };
+        if ready {
+            let request = incoming.remove(&stream).and_then(|incoming| incoming.request);
+            if let Some(request) = request {
+                answer(sender.clone(), handler.clone(), stream, request);
+            }
+        }
+    }
+}
+
+fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// A request from its decoded headers, which have to include a method and
+/// path.
+fn to_request(headers: Vec<hpack::Header>) -> Result<Request, u32> {
+    let mut request = Request {
+        method: String::new(),
+        path: String::new(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    for (name, value) in headers {
+        match name.as_str() {
+            ":method" => request.method = value,
+            ":path" => request.path = value,
+            ":scheme" | ":authority" => {}
+            _ if name.starts_with(':') => return Err(PROTOCOL_ERROR),
+            _ => request.headers.push((name, value)),
+        }
+    }
+    if request.method.is_empty() || request.path.is_empty() {
+        return Err(PROTOCOL_ERROR);
+    }
+    Ok(request)
+}
+
+/// Answers `request` on a thread of its own.
+fn answer<W>(sender: Arc<Sender<W>>, handler: Handler, stream: u32, request: Request)
+where
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let response = handler(request);
+        let status = response.status.to_string();
+        let len = response.body.len().to_string();
+        let mut headers = vec![(":status", status.as_str())];
+        for &(ref name, ref value) in &response.headers {
+            headers.push((name.as_str(), value.as_str()));
+        }
+        headers.push(("content-length", len.as_str()));
+
+        let block = hpack::encode(&headers);
+        let sent = sender
+            .send_headers(stream, block, response.body.is_empty())
+            .and_then(|_| match response.body.is_empty() {
+                true => Ok(()),
+                false => sender.send_body(stream, &response.body),
+            });
+        if let Err(e) = sent {
+            debug!("Failed to send HTTP/2 response: {:?}", e);
+        }
+        sender.finish(stream);
+    });
+}
+
+type Reply = mpsc::Sender<Result<Response, Error>>;
+
+/// A connection to an HTTPS server, which any number of threads can make
+/// requests on at once. A thread of its own reads the responses.
+pub struct Connection<W: Write> {
+    sender: Sender<W>,
+    // The next stream to open. It's held while the request's headers are
+    // sent, since streams have to be opened in order.
+    next_stream: Mutex<u32>,
+    // Those waiting on a response, by stream
+    waiting: Mutex<HashMap<u32, Reply>>,
+    closed: AtomicBool,
+}
+
+impl<W: Write + Send + 'static> Connection<W> {
+    /// Starts a connection to the server that sends on `reader` and
+    /// receives on `writer`.
+    pub fn new<R: Read + Send + 'static>(reader: R, mut writer: W) -> Result<Arc<Self>, Error> {
+        try!(writer.write_all(PREFACE));
+        let connection = Arc::new(Connection {
+            sender: Sender::new(writer),
+            next_stream: Mutex::new(1),
+            waiting: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        try!(connection.sender.send(&settings(&[(SETTINGS_ENABLE_PUSH, 0)])));
+
+        let reading = connection.clone();
+        thread::spawn(move || {
+            let mut reader = reader;
+            let code = match reading.read_responses(&mut reader) {
+                Ok(()) => NO_ERROR,
+                Err(code) => code,
+            };
+            reading.close(code);
+        });
+        Ok(connection)
+    }
+
+    /// Whether the connection is gone, or going, so that new requests have
+    /// to go over another.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Makes a request for `path` on the server `authority` with `headers`
+    /// and `body`, and waits up to `timeout` for the response.
+    pub fn request(
+        &self,
+        method: &str,
+        authority: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<Response, Error> {
+        let (reply, response) = mpsc::channel();
+        let stream = {
+            let mut next_stream = lock(&self.next_stream);
+            if self.is_closed() || *next_stream > MAX_STREAM_ID {
+                self.closed.store(true, Ordering::SeqCst);
+                return Err(Error::new(ErrorKind::BrokenPipe, "connection closed"));
+            }
+            let stream = *next_stream;
+            *next_stream += 2;
+
+            let len = body.len().to_string();
+            let mut all = vec![
+                (":method", method),
+                (":scheme", "https"),
+                (":authority", authority),
+                (":path", path),
+            ];
+            all.extend_from_slice(headers);
+            all.push(("content-length", len.as_str()));
+
+            // Streams are only opened as the server allows
+            try!(self.sender.open_within(stream, timeout));
+            lock(&self.waiting).insert(stream, reply);
+            if let Err(e) = self.sender.send_headers(stream, hpack::encode(&all), body.is_empty()) {
+                self.forget(stream);
+                return Err(e);
+            }
+            stream
+        };
+
+        if !body.is_empty() {
+            if let Err(e) = self.sender.send_body(stream, body) {
+                self.forget(stream);
+                return Err(e);
+            }
+        }
+        match response.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                self.forget(stream);
+                let _ = self.sender.send(&rst_stream(stream, CANCEL));
+                Err(Error::new(ErrorKind::TimedOut, "timed out waiting for a response"))
+            }
+        }
+    }
+
+    fn forget(&self, stream: u32) -> Option<Reply> {
+        self.sender.finish(stream);
+        lock(&self.waiting).remove(&stream)
+    }
+
+    fn reply(&self, stream: u32, result: Result<Response, Error>) {
+        if let Some(reply) = self.forget(stream) {
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Hands responses to those waiting on them, until the server closes
+    /// the connection.
+    fn read_responses<R: Read>(&self, reader: &mut R) -> Result<(), u32> {
+        let mut decoder = hpack::Decoder::new();
+        // Responses still coming in, and the stream whose header block is
+        // being continued, if any
+        let mut incoming: HashMap<u32, Response> = HashMap::new();
+        let mut block = Vec::new();
+        let mut continued: Option<u32> = None;
+        // Whether the stream ends with the header block being read
+        let mut ending = false;
+
+        loop {
+            let frame = match Frame::read(reader) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => return Err(FRAME_SIZE_ERROR),
+                Err(_) => return Ok(()),
+            };
+            let within_block = frame.kind == CONTINUATION && continued == Some(frame.stream);
+            if continued.is_some() && !within_block {
+                return Err(PROTOCOL_ERROR);
+            }
+            if let Some(stream) = try!(self.sender.handle_frame(&frame)) {
+                incoming.remove(&stream);
+                let reset = Error::new(ErrorKind::ConnectionReset, "stream reset");
+                self.reply(stream, Err(reset));
+                continue;
+            }
+
+            let end_stream = match frame.kind {
+                HEADERS | CONTINUATION => {
+                    if frame.kind == HEADERS {
+                        block = try!(frame.data().map_err(|_| PROTOCOL_ERROR)).to_vec();
+                        ending = frame.flags & END_STREAM != 0;
+                    } else if continued.is_some() {
+                        block.extend_from_slice(&frame.payload);
+                    } else {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    if frame.flags & END_HEADERS == 0 {
+                        continued = Some(frame.stream);
+                        continue;
+                    }
+                    continued = None;
+                    let headers = try!(decoder.decode(&block).map_err(|_| COMPRESSION_ERROR));
+                    block.clear();
+
+                    // Trailers and informational responses are skipped
+                    let response = incoming.entry(frame.stream).or_insert_with(|| Response::new(0));
+                    let status = headers
+                        .iter()
+                        .find(|&&(ref name, _)| name == ":status")
+                        .and_then(|&(_, ref value)| value.parse::<u16>().ok());
+                    match status {
+                        Some(status) if status >= 200 && response.status == 0 => {
+                            response.status = status;
+                            response.headers = headers
+                                .into_iter()
+                                .filter(|&(ref name, _)| !name.starts_with(':'))
+                                .collect();
+                        }
+                        _ => {}
+                    }
+                    ending
+                }
+                DATA => {
+                    let data = try!(frame.data().map_err(|_| PROTOCOL_ERROR));
+                    if !frame.payload.is_empty() {
+                        let _ = self.sender.send(&window_update(0, frame.payload.len()));
+                    }
+                    let too_large = match incoming.get_mut(&frame.stream) {
+                        Some(response) => {
+                            response.body.extend_from_slice(data);
+                            response.body.len() > http::MAX_BODY
+                        }
+                        None => continue,
+                    };
+                    if too_large {
+                        incoming.remove(&frame.stream);
+                        let _ = self.sender.send(&rst_stream(frame.stream, CANCEL));
+                        self.reply(frame.stream, Err(invalid("response too large")));
+                        continue;
+                    }
+                    let end_stream = frame.flags & END_STREAM != 0;
+                    if !end_stream && !frame.payload.is_empty() {
+                        let _ = self.sender.send(&window_update(frame.stream, frame.payload.len()));
+                    }
+                    end_stream
+                }
+                SETTINGS if frame.flags & ACK == 0 => {
+                    let _ = self.sender.send(&Frame::new(SETTINGS, ACK, 0, Vec::new()));
+                    continue;
+                }
+                PING if frame.flags & ACK == 0 => {
+                    let _ = self.sender.send(&Frame::new(PING, ACK, 0, frame.payload.clone()));
+                    continue;
+                }
+                RST_STREAM => {
+                    incoming.remove(&frame.stream);
+                    let reset = Error::new(ErrorKind::ConnectionReset, "stream reset");
+                    self.reply(frame.stream, Err(reset));
+                    continue;
+                }
+                GOAWAY if frame.payload.len() >= 8 => {
+                    // Streams after the last will never be answered, but
+                    // the rest still may be
+                    self.closed.store(true, Ordering::SeqCst);
+                    let last_stream = read_u32(&frame.payload) & MAX_STREAM_ID;
+                    let streams = lock(&self.waiting)
+                        .keys()
+                        .cloned()
+                        .filter(|&stream| stream > last_stream)
+                        .collect::<Vec<u32>>();
+                    for stream in streams {
+                        let refused = Error::new(ErrorKind::ConnectionAborted, "request refused");
+                        self.reply(stream, Err(refused));
+                    }
+                    continue;
+                }
+                PUSH_PROMISE => return Err(PROTOCOL_ERROR),
+                _ => continue,
+            };
+
+            if end_stream {
+                if let Some(response) = incoming.remove(&frame.stream) {
+                    self.reply(frame.stream, Ok(response));
+                }
+            }
+        }
+    }
+
+    /// Fails whatever is still waiting, once the connection is gone.
+    fn close(&self, code: u32) {
+        self.closed.store(true, Ordering::SeqCst);
+        if code != NO_ERROR {
+            let _ = self.sender.send(&goaway(0, code));
+        }
+        self.sender.close();
+        let waiting = lock(&self.waiting).drain().collect::<Vec<_>>();
+        for (_, reply) in waiting {
+            let _ = reply.send(Err(Error::new(ErrorKind::BrokenPipe, "connection closed")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+
+    fn request(stream: u32, path: &str, body: &[u8]) -> Vec<Frame> {
+        let method = if body.is_empty() { "GET" } else { "POST" };
+        let block = hpack::encode(&[
+            (":method", method),
+            (":scheme", "https"),
+            (":path", path),
+            (":authority", "localhost"),
+        ]);
+        let flags = if body.is_empty() { END_STREAM } else { 0 };
+        let mut frames = vec![Frame::new(HEADERS, flags | END_HEADERS, stream, block)];
+        if !body.is_empty() {
+            frames.push(Frame::new(DATA, END_STREAM, stream, body.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn answers_interleaved_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            let handler: Handler = Arc::new(|request: Request| {
+                let mut response = Response::new(200);
+                response.add_header("x-path", &request.path);
+                response.body = request.body;
+                response
+            });
+            serve(stream.try_clone().unwrap(), stream, handler).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(PREFACE).unwrap();
+        // Only 3 bytes of response body at a time on the first stream
+        settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 3)]).write(&mut client).unwrap();
+        for frame in request(1, "/one", b"abcdefg").iter().chain(&request(3, "/two", b"")) {
+            frame.write(&mut client).unwrap();
+        }
+
+        let mut decoder = hpack::Decoder::new();
+        let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut ended = 0;
+        while ended < 2 {
+            let frame = Frame::read(&mut client).unwrap();
+            match frame.kind {
+                HEADERS => {
+                    let headers = decoder.decode(frame.data().unwrap()).unwrap();
+                    assert_eq!(headers[0], (":status".to_string(), "200".to_string()));
+                    let path = if frame.stream == 1 { "/one" } else { "/two" };
+                    assert_eq!(headers[1], ("x-path".to_string(), path.to_string()));
+                }
+                DATA => {
+                    assert!(frame.payload.len() <= 3);
+                    bodies.entry(frame.stream).or_insert_with(Vec::new)
+                        .extend_from_slice(&frame.payload);
+                    window_update(frame.stream, frame.payload.len()).write(&mut client).unwrap();
+                }
+                _ => {}
+            }
+            if frame.flags & END_STREAM != 0 && (frame.kind == DATA || frame.kind == HEADERS) {
+                ended += 1;
+            }
+        }
+        assert_eq!(bodies[&1], b"abcdefg".to_vec());
+        assert!(!bodies.contains_key(&3));
+    }
+
+    /// A client connected to `serve` on a socket that waits up to `timeout`
+    /// for frames, with the preface and settings sent.
+    fn serving(handler: Handler, timeout: Duration) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            stream.set_read_timeout(Some(timeout)).unwrap();
+            let _ = serve(stream.try_clone().unwrap(), stream, handler);
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(PREFACE).unwrap();
+        settings(&[]).write(&mut client).unwrap();
+        client
+    }
+
+    fn echo() -> Handler {
+        Arc::new(|request: Request| {
+            let mut response = Response::new(200);
+            response.add_header("x-path", &request.path);
+            response.body = request.body;
+            response
+        })
+    }
+
+    /// The next frame of `kind`, skipping the rest.
+    fn next(client: &mut TcpStream, kind: u8) -> Frame {
+        loop {
+            let frame = Frame::read(client).unwrap();
+            if frame.kind == kind {
+                return frame;
+            }
+        }
+    }
+
+    /// The error code of the server's GOAWAY, checking the connection ends
+    /// with it.
+    fn goaway_code(client: &mut TcpStream) -> u32 {
+        let frame = next(client, GOAWAY);
+        assert!(Frame::read(client).is_err());
+        read_u32(&frame.payload[4..])
+    }
+
+    /// The body of the response on `stream`, checking its path.
+    fn response(client: &mut TcpStream, stream: u32, path: &str) -> Vec<u8> {
+        let mut decoder = hpack::Decoder::new();
+        let mut body = Vec::new();
+        loop {
+            let frame = Frame::read(client).unwrap();
+            if frame.stream != stream {
+                continue;
+            }
+            match frame.kind {
+                HEADERS => {
+                    let headers = decoder.decode(frame.data().unwrap()).unwrap();
+                    assert_eq!(headers[1], ("x-path".to_string(), path.to_string()));
+                }
+                DATA => body.extend_from_slice(frame.data().unwrap()),
+                kind => panic!("unexpected frame {} on stream {}", kind, stream),
+            }
+            if frame.flags & END_STREAM != 0 {
+                return body;
+            }
+        }
+    }
+
+    #[test]
+    fn waits_on_slow_answers_but_not_partial_frames() {
+        let handler: Handler = Arc::new(|request: Request| {
+            thread::sleep(Duration::from_millis(300));
+            echo()(request)
+        });
+        let mut client = serving(handler, Duration::from_millis(50));
+        for frame in request(1, "/slow", b"") {
+            frame.write(&mut client).unwrap();
+        }
+        assert_eq!(response(&mut client, 1, "/slow"), Vec::<u8>::new());
+
+        // Half a frame header, then nothing, goes away without waiting
+        for frame in request(3, "/slow", b"") {
+            frame.write(&mut client).unwrap();
+        }
+        client.write_all(&[0, 0, 4, WINDOW_UPDATE]).unwrap();
+        let frame = next(&mut client, GOAWAY);
+        assert_eq!(read_u32(&frame.payload), 3);
+        assert_eq!(response(&mut client, 3, "/slow"), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn continues_header_blocks() {
+        let mut client = serving(echo(), Duration::from_secs(5));
+        let block = request(1, "/continued", b"").remove(0).payload;
+        let (first, rest) = block.split_at(5);
+        Frame::new(HEADERS, END_STREAM, 1, first.to_vec()).write(&mut client).unwrap();
+        Frame::new(CONTINUATION, 0, 1, rest[..3].to_vec()).write(&mut client).unwrap();
+        Frame::new(CONTINUATION, END_HEADERS, 1, rest[3..].to_vec()).write(&mut client).unwrap();
+        assert_eq!(response(&mut client, 1, "/continued"), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn rejects_other_frames_within_a_header_block() {
+        for &kind in &[HEADERS, CONTINUATION, DATA] {
+            let mut client = serving(echo(), Duration::from_secs(5));
+            let block = request(1, "/", b"").remove(0).payload;
+            Frame::new(HEADERS, END_STREAM, 1, block[..5].to_vec()).write(&mut client).unwrap();
+            Frame::new(kind, 0, 3, block[5..].to_vec()).write(&mut client).unwrap();
+            assert_eq!(goaway_code(&mut client), PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn strips_padding() {
+        let mut client = serving(echo(), Duration::from_secs(5));
+        let padded = |frame: &Frame, padding: usize, flags: u8| {
+            let mut payload = vec![padding as u8];
+            payload.extend_from_slice(&frame.payload);
+            payload.extend_from_slice(&vec![0; padding]);
+            Frame::new(frame.kind, frame.flags | flags, frame.stream, payload)
+        };
+        for frame in request(1, "/padded", b"body") {
+            padded(&frame, 7, PADDED).write(&mut client).unwrap();
+        }
+        assert_eq!(response(&mut client, 1, "/padded"), b"body".to_vec());
+
+        // More padding than the frame holds
+        let frame = &request(3, "/", b"").remove(0);
+        let mut frame = padded(frame, 0, PADDED);
+        frame.payload[0] = 255;
+        frame.write(&mut client).unwrap();
+        assert_eq!(goaway_code(&mut client), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn rejects_window_updates_of_nothing() {
+        let mut client = serving(echo(), Duration::from_secs(5));
+        // On a stream, only that stream goes
+        request(1, "/", b"body")[0].write(&mut client).unwrap();
+        window_update(1, 0).write(&mut client).unwrap();
+        let reset = next(&mut client, RST_STREAM);
+        assert_eq!((reset.stream, read_u32(&reset.payload)), (1, PROTOCOL_ERROR));
+        for frame in request(3, "/after", b"") {
+            frame.write(&mut client).unwrap();
+        }
+        assert_eq!(response(&mut client, 3, "/after"), Vec::<u8>::new());
+
+        // On the connection, the connection goes
+        window_update(0, 0).write(&mut client).unwrap();
+        assert_eq!(goaway_code(&mut client), PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn drops_requests_the_client_resets() {
+        let answered = Arc::new(AtomicUsize::new(0));
+        let counted = answered.clone();
+        let handler: Handler = Arc::new(move |request: Request| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            echo()(request)
+        });
+        let mut client = serving(handler, Duration::from_secs(5));
+        let frames = request(1, "/reset", b"body");
+        frames[0].write(&mut client).unwrap();
+        rst_stream(1, CANCEL).write(&mut client).unwrap();
+        frames[1].write(&mut client).unwrap();
+        for frame in request(3, "/kept", b"") {
+            frame.write(&mut client).unwrap();
+        }
+        assert_eq!(response(&mut client, 3, "/kept"), Vec::<u8>::new());
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+
+        // Going away ends the connection
+        goaway(3, NO_ERROR).write(&mut client).unwrap();
+        assert_eq!(goaway_code(&mut client), NO_ERROR);
+    }
+
+    /// A connection to a server that `respond` plays, given each frame the
+    /// client sends.
+    fn connect<F>(respond: F) -> Arc<Connection<TcpStream>>
+    where
+        F: Fn(&mut TcpStream, Frame) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut preface = [0u8; 24];
+            stream.read_exact(&mut preface).unwrap();
+            while let Ok(frame) = Frame::read(&mut stream) {
+                respond(&mut stream, frame);
+            }
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        Connection::new(stream.try_clone().unwrap(), stream).unwrap()
+    }
+
+    fn ok(stream: &mut TcpStream, id: u32) {
+        let block = hpack::encode(&[(":status", "200")]);
+        Frame::new(HEADERS, END_HEADERS | END_STREAM, id, block).write(stream).unwrap();
+    }
+
+    fn get(connection: &Connection<TcpStream>) -> Result<Response, Error> {
+        connection.request("GET", "localhost", "/", &[], b"", Duration::from_secs(5))
+    }
+
+    #[test]
+    fn fails_requests_the_server_resets() {
+        let connection = connect(|stream, frame| match (frame.kind, frame.stream) {
+            (HEADERS, 1) => rst_stream(1, REFUSED_STREAM).write(stream).unwrap(),
+            (HEADERS, id) => ok(stream, id),
+            _ => {}
+        });
+        assert_eq!(get(&connection).err().unwrap().kind(), ErrorKind::ConnectionReset);
+        assert_eq!(get(&connection).unwrap().status, 200);
+    }
+
+    #[test]
+    fn stops_at_the_servers_goaway() {
+        // Stream 1 is answered after going away, but 3 is past the last
+        let connection = connect(|stream, frame| match (frame.kind, frame.stream) {
+            (HEADERS, 1) => {
+                goaway(1, NO_ERROR).write(stream).unwrap();
+                ok(stream, 1);
+            }
+            _ => {}
+        });
+        assert_eq!(get(&connection).unwrap().status, 200);
+        assert!(connection.is_closed());
+        assert_eq!(get(&connection).err().unwrap().kind(), ErrorKind::BrokenPipe);
+
+        let connection = connect(|stream, frame| {
+            if frame.kind == HEADERS {
+                goaway(0, NO_ERROR).write(stream).unwrap();
+            }
+        });
+        assert_eq!(get(&connection).err().unwrap().kind(), ErrorKind::ConnectionAborted);
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn keeps_to_the_servers_stream_limit() {
+        let (requests, requested) = mpsc::channel();
+        let requests = Mutex::new(requests);
+        let connection = connect(move |stream, frame| match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                settings(&[(SETTINGS_MAX_CONCURRENT_STREAMS, 1)]).write(stream).unwrap();
+            }
+            // Stream 3 is only answered once pinged
+            HEADERS => {
+                lock(&requests).send(frame.stream).unwrap();
+                if frame.stream != 3 {
+                    ok(stream, frame.stream);
+                }
+            }
+            PING => ok(stream, 3),
+            _ => {}
+        });
+        // Once the first is answered, the settings are in place
+        assert_eq!(get(&connection).unwrap().status, 200);
+        assert_eq!(requested.recv().unwrap(), 1);
+
+        let first = connection.clone();
+        let first = thread::spawn(move || get(&first).unwrap().status);
+        assert_eq!(requested.recv().unwrap(), 3);
+        let second = connection.clone();
+        let second = thread::spawn(move || get(&second).unwrap().status);
+        assert!(requested.recv_timeout(Duration::from_millis(200)).is_err());
+
+        connection.sender.send(&Frame::new(PING, 0, 0, vec![0; 8])).unwrap();
+        assert_eq!(requested.recv().unwrap(), 5);
+        assert_eq!((first.join().unwrap(), second.join().unwrap()), (200, 200));
+    }
+}
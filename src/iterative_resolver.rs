@@ -0,0 +1,348 @@
+use std::cmp;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use dns_name;
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use dnssec;
+use qname_minimisation::QnameMinimisation;
+use query_type::QueryType;
+use resolver::lookup_authoritative;
+use result_code::ResultCode;
+
+// Queries spent on a single name before giving up
+const MAX_QUERIES: usize = 32;
+// Nested resolutions of name server addresses and CNAME targets
+const MAX_DEPTH: usize = 6;
+// RFC 9156 section 2.3: minimised queries per name before the rest of it is
+// revealed at once, and how many of those reveal a single label
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+/// The IPv4 addresses of the root servers, a to m.
+pub fn root_servers() -> Vec<SocketAddr> {
+    vec![
+        SocketAddr::from(([198, 41, 0, 4], 53)),
+        SocketAddr::from(([170, 247, 170, 2], 53)),
+        SocketAddr::from(([192, 33, 4, 12], 53)),
+        SocketAddr::from(([199, 7, 91, 13], 53)),
+        SocketAddr::from(([192, 203, 230, 10], 53)),
+        SocketAddr::from(([192, 5, 5, 241], 53)),
+        SocketAddr::from(([192, 112, 36, 4], 53)),
+        SocketAddr::from(([198, 97, 190, 53], 53)),
+        SocketAddr::from(([192, 36, 148, 17], 53)),
+        SocketAddr::from(([192, 58, 128, 30], 53)),
+        SocketAddr::from(([193, 0, 14, 129], 53)),
+        SocketAddr::from(([199, 7, 83, 42], 53)),
+        SocketAddr::from(([202, 12, 27, 33], 53)),
+    ]
+}
+
+/// Resolves names by following referrals down from the root servers.
+pub struct IterativeResolver<'a> {
+    root_servers: &'a [SocketAddr],
+    timeout: Duration,
+    minimisation: QnameMinimisation,
+    dnssec: bool,
+    randomise_case: bool,
+}
+
+impl<'a> IterativeResolver<'a> {
+    pub fn new(
+        root_servers: &'a [SocketAddr],
+        timeout: Duration,
+        minimisation: QnameMinimisation,
+        dnssec: bool,
+        randomise_case: bool,
+    ) -> IterativeResolver<'a> {
+        IterativeResolver {
+            root_servers: root_servers,
+            timeout: timeout,
+            minimisation: minimisation,
+            dnssec: dnssec,
+            randomise_case: randomise_case,
+        }
+    }
+
+    pub fn resolve(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket, Error> {
+        self.resolve_depth(qname, qtype, 0)
+    }
+
+    fn resolve_depth(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        depth: usize,
+    ) -> Result<DnsPacket, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::other("Resolution nested too deeply"));
+        }
+
+        let mut response = try!(self.resolve_name(qname, qtype, depth));
+
+        // Authoritative servers leave CNAMEs into other zones to us
+        let target = dnssec::follow_cnames(qname, &response.answers);
+        let answered = response.answers.iter().any(|rec| {
+            rec.get_querytype() == qtype && dns_name::names_equal(rec.get_domain(), &target)
+        });
+        if response.header.rescode == ResultCode::NOERROR && qtype != QueryType::CNAME
+            && !answered && !dns_name::names_equal(&target, qname)
+        {
+            let rest = try!(self.resolve_depth(&target, qtype, depth + 1));
+            response.header.rescode = rest.header.rescode;
+            response.answers.extend(rest.answers);
+            response.authorities = rest.authorities;
+        }
+
+        Ok(response)
+    }
+
+    fn resolve_name(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        depth: usize,
+    ) -> Result<DnsPacket, Error> {
+        let total = dns_name::label_count(qname);
+        let mut zone = String::new();
+        let mut servers = self.root_servers.to_vec();
+        let mut minimise = self.minimisation != QnameMinimisation::Off;
+        let mut revealed = 0;
+        let mut minimised_queries = 0;
+
+        for _ in 0..MAX_QUERIES {
+            let labels = if minimise {
+                next_revealed(
+                    dns_name::label_count(&zone),
+                    revealed,
+                    total,
+                    minimised_queries,
+                )
+            } else {
+                total
+            };
+
+            // RFC 9156 section 2.1: hide the type along with the name
+            let minimised = labels < total;
+            let (name, query_type) = if minimised {
+                minimised_queries += 1;
+                (dns_name::suffix(qname, labels), QueryType::A)
+            } else {
+                (qname.to_string(), qtype)
+            };
+
+            let response = try!(self.query_servers(&name, query_type, &servers));
+
+            if let Some(cut) = referral(&response, &zone, &name) {
+                // The parent answers for the DS at a zone cut
+                if !(qtype == QueryType::DS && !minimised && dns_name::names_equal(&cut, qname)) {
+                    servers = try!(self.server_addresses(&response, &zone, &cut, depth));
+                    revealed = dns_name::label_count(&cut);
+                    zone = cut;
+                    continue;
+                }
+            }
+
+            if !minimised {
+                return Ok(response);
+            }
+
+            match response.header.rescode {
+                // Not a zone cut, so reveal more of the name to the same servers
+                ResultCode::NOERROR => revealed = labels,
+                // RFC 8020: nothing exists below a name that doesn't exist
+                _ if self.minimisation == QnameMinimisation::Strict => {
+                    return Ok(with_question(response, qname, qtype));
+                }
+                // Some servers answer NXDOMAIN for empty non-terminals, or
+                // choke on the minimised query
+                _ => minimise = false,
+            }
+        }
+
+        Err(Error::other("Too many referrals"))
+    }
+
+    /// Asks each of `servers` in turn until one gives a usable answer.
+    fn query_servers(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        servers: &[SocketAddr],
+    ) -> Result<DnsPacket, Error> {
+        let mut last = Err(Error::other("No name servers"));
+        for server in servers {
+            last = lookup_authoritative(
+                qname,
+                qtype,
+                *server,
+                self.timeout,
+                self.dnssec,
+                self.randomise_case,
+            );
+            match last {
+                Ok(ref response) => match response.header.rescode {
+                    ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP => continue,
+                    _ => break,
+                },
+                Err(_) => continue,
+            }
+        }
+        last
+    }
+
+    /// The addresses of the name servers `response` delegates `cut` to, from
+    /// glue within `zone` or else by resolving their names.
+    fn server_addresses(
+        &self,
+        response: &DnsPacket,
+        zone: &str,
+        cut: &str,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let hosts = response
+            .authorities
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::NS {
+                    ref domain,
+                    ref host,
+                    ..
+                } if dns_name::names_equal(domain, cut) => Some(host.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let glue = response
+            .resources
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::A {
+                    ref domain,
+                    addr,
+                    ..
+                } if dns_name::is_subdomain(domain, zone)
+                    && hosts.iter().any(|host| dns_name::names_equal(host, domain)) =>
+                {
+                    Some(SocketAddr::from((addr, 53)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<SocketAddr>>();
+        if !glue.is_empty() {
+            return Ok(glue);
+        }
+
+        for host in &hosts {
+            let response = match self.resolve_depth(host, QueryType::A, depth + 1) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let addrs = response
+                .answers
+                .iter()
+                .filter_map(|rec| match *rec {
+                    DnsRecord::A { addr, .. } => Some(SocketAddr::from((addr, 53))),
+                    _ => None,
+                })
+                .collect::<Vec<SocketAddr>>();
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+        }
+
+        Err(Error::other("No reachable name servers"))
+    }
+}
+
+/// How many labels of a `total` label name the next minimised query reveals,
+/// given the current zone cut, the labels revealed so far and the number of
+/// minimised queries already sent (RFC 9156 section 2.3).
+fn next_revealed(zone_labels: usize, revealed: usize, total: usize, queries: usize) -> usize {
+    if queries >= MAX_MINIMISE_COUNT {
+        return total;
+    }
+
+    let current = cmp::max(zone_labels, revealed);
+    let step = if queries < MINIMISE_ONE_LAB {
+        1
+    } else {
+        cmp::max(1, (total - current) / (MAX_MINIMISE_COUNT - queries))
+    };
+    cmp::min(current + step, total)
+}
+
+/// The zone cut `response` refers us to, if it's a referral for `name` to a
+/// zone below `zone`.
+fn referral(response: &DnsPacket, zone: &str, name: &str) -> Option<String> {
+    if response.header.rescode != ResultCode::NOERROR || !response.answers.is_empty() {
+        return None;
+    }
+
+    response
+        .authorities
+        .iter()
+        .filter(|rec| rec.get_querytype() == QueryType::NS)
+        .map(|rec| rec.get_domain())
+        .find(|cut| {
+            dns_name::is_subdomain(name, cut) && dns_name::is_subdomain(cut, zone)
+                && dns_name::label_count(cut) > dns_name::label_count(zone)
+        })
+        .map(|cut| cut.to_string())
+}
+
+/// `response` to a minimised query, presented as the answer to the full one.
+fn with_question(mut response: DnsPacket, qname: &str, qtype: QueryType) -> DnsPacket {
+    response.questions = vec![DnsQuestion::new(qname.to_string(), qtype)];
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reveals_one_label_at_a_time() {
+        // www.example.com from the root, then com, then example.com
+        assert_eq!(next_revealed(0, 0, 3, 0), 1);
+        assert_eq!(next_revealed(1, 1, 3, 1), 2);
+        assert_eq!(next_revealed(2, 2, 3, 2), 3);
+
+        // example.com turned out not to be a zone cut
+        assert_eq!(next_revealed(1, 2, 3, 2), 3);
+    }
+
+    #[test]
+    fn bounds_minimised_queries() {
+        // Larger steps once MINIMISE_ONE_LAB labels went one by one
+        assert_eq!(next_revealed(4, 4, 40, 4), 10);
+        // And the full name once the budget is spent
+        assert_eq!(next_revealed(9, 9, 40, MAX_MINIMISE_COUNT), 40);
+    }
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn detects_referrals() {
+        let mut response = DnsPacket::new();
+        response.authorities.push(ns("com", "a.gtld-servers.net"));
+        assert_eq!(referral(&response, "", "com"), Some("com".to_string()));
+        assert_eq!(referral(&response, "", "www.example.com"), Some("com".to_string()));
+
+        // Referrals sideways or back up the tree lead nowhere
+        assert_eq!(referral(&response, "com", "www.example.com"), None);
+        assert_eq!(referral(&response, "", "www.example.net"), None);
+
+        response.header.rescode = ResultCode::NXDOMAIN;
+        assert_eq!(referral(&response, "", "com"), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Zone journals: the changes dynamic updates made to a zone, kept next to
+//! its zone file so they survive restarts. Each change is the old SOA and
+//! the removed records, each line starting with `-`, then the new SOA and
+//! the added records, each starting with `+`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use query_type::QueryType;
+use zone::Zone;
+use zone_diff::ZoneDiff;
+use zone_parser::ZoneParser;
+
+/// Where the journal for the zone file `file` is kept.
+pub fn path(file: &str) -> String {
+    format!("{}.jnl", file)
+}
+
+/// Adds `diff` to the end of the journal at `path`.
+pub fn append(path: &str, diff: &ZoneDiff) -> Result<(), Error> {
+    let mut text = String::new();
+    for record in &diff.removed {
+        text.push_str(&format!("-{}\n", record));
+    }
+    for record in &diff.added {
+        text.push_str(&format!("+{}\n", record));
+    }
+
+    let mut file = try!(OpenOptions::new().create(true).append(true).open(path));
+    file.write_all(text.as_bytes())
+}
+
+/// The changes in the journal at `path`, oldest first. There are none if
+/// it doesn't exist.
+pub fn read(path: &str, origin: &str) -> Result<Vec<ZoneDiff>, Error> {
+    if !Path::new(path).exists() {
+        return Ok(Vec::new());
+    }
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+
+    // The records are parsed as one zone file, so that errors point at
+    // the right line
+    let mut signs = Vec::new();
+    let mut body = String::new();
+    for line in text.lines() {
+        if !line.is_empty() {
+            let (sign, rest) = line.split_at(1);
+            signs.push(sign);
+            body.push_str(rest);
+        }
+        body.push('\n');
+    }
+    let mut parser = ZoneParser::new(origin);
+    try!(parser.parse_str(&body, path));
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+    if parser.records.len() != signs.len() {
+        return Err(invalid("expected one record per line"));
+    }
+
+    let mut diffs = Vec::new();
+    let mut current: Option<ZoneDiff> = None;
+    for (sign, record) in signs.into_iter().zip(parser.records) {
+        let is_soa = record.get_querytype() == QueryType::SOA;
+        if sign == "-" && is_soa {
+            diffs.extend(current.take());
+            current = Some(ZoneDiff {
+                removed: vec![record],
+                added: Vec::new(),
+            });
+            continue;
+        }
+        match (sign, current.as_mut()) {
+            ("-", Some(diff)) if diff.added.is_empty() => diff.removed.push(record),
+            ("+", Some(diff)) if is_soa == diff.added.is_empty() => diff.added.push(record),
+            _ => return Err(invalid(&format!("{} is out of place", record))),
+        }
+    }
+    diffs.extend(current);
+    Ok(diffs)
+}
+
+/// `zone` with the changes in `diffs` that follow on from its version made
+/// to it. Older changes, and ones from before the zone file was last
+/// edited, don't apply and are skipped.
+pub fn replay(zone: Zone, diffs: Vec<ZoneDiff>) -> Result<Zone, Error> {
+    let mut zone = zone;
+    for diff in diffs {
+        if diff.added.is_empty() || diff.from_serial() != zone.serial() {
+            continue;
+        }
+
+        let mut records = zone.records();
+        for record in &diff.removed {
+            if let Some(i) = records.iter().position(|r| r == record) {
+                records.remove(i);
+            }
+        }
+        records.extend(diff.added.iter().cloned());
+        let mut next = try!(Zone::new(&zone.name, records));
+        next.follow(&zone);
+        zone = next;
+    }
+    Ok(zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_record::DnsRecord;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn zone(serial: u32, hosts: &[&str]) -> Zone {
+        let mut text = format!("$TTL 60\n@ SOA ns1 hostmaster {} 2 3 4 5\n@ NS ns1\n", serial);
+        for host in hosts {
+            text.push_str(&format!("{} TXT \"{} here\"\n", host, host));
+        }
+        let mut parser = ZoneParser::new("example.com");
+        parser.parse_str(&text, "test.zone").unwrap();
+        Zone::new("example.com", parser.records).unwrap()
+    }
+
+    fn sorted(mut records: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        records.sort();
+        records
+    }
+
+    #[test]
+    fn replays_journalled_changes() {
+        let path = env::temp_dir().join(format!("dns-journal-{}.jnl", process::id()));
+        let path = path.to_str().unwrap();
+
+        let v1 = zone(1, &["a", "b"]);
+        let v2 = zone(2, &["a", "c"]);
+        let v3 = zone(3, &["c", "d"]);
+        append(path, &ZoneDiff::new(&v1, &v2)).unwrap();
+        append(path, &ZoneDiff::new(&v2, &v3)).unwrap();
+
+        let diffs = read(path, "example.com").unwrap();
+        assert_eq!(diffs.len(), 2);
+        let replayed = replay(zone(1, &["a", "b"]), diffs.clone()).unwrap();
+        assert_eq!(replayed.serial(), 3);
+        assert_eq!(sorted(replayed.records()), sorted(v3.records()));
+        assert_eq!(replayed.journal.len(), 2);
+
+        // A zone file edited since has nothing to replay
+        let edited = replay(zone(5, &["e"]), diffs).unwrap();
+        assert_eq!(sorted(edited.records()), sorted(zone(5, &["e"]).records()));
+
+        fs::remove_file(path).unwrap();
+    }
+}
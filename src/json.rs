@@ -0,0 +1,634 @@
+//! DNS messages as JSON, through serde: in the format of RFC 8427, whose
+//! member names the impls here follow, and as `DnsJson` in the
+//! `application/dns-json` format of Google's and Cloudflare's JSON APIs.
+//!
+//! Records carry their data both as hex and in presentation format, and
+//! may be read back from either.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use serde::de::{self, Deserialize, Deserializer, Unexpected, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, Serializer};
+use serde_json::Value;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_header::DnsHeader;
+use dns_name;
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use opcode::Opcode;
+use presentation;
+use query_type::QueryType;
+use result_code::ResultCode;
+use zone_parser::{self, ZoneParser};
+
+pub const CONTENT_TYPE: &str = "application/dns-json";
+
+// Room for any one record: its owner, fixed fields and data
+const MAX_RECORD: usize = 255 + 10 + 0xFFFF;
+
+/// The class, TTL and data of `record` as they go on the wire, which for
+/// OPT records hold its EDNS fields.
+fn wire_fields(record: &DnsRecord) -> Result<(u16, u32, Vec<u8>), Error> {
+    let mut buffer = BytePacketBuffer::with_size(MAX_RECORD);
+    try!(record.write(&mut buffer));
+    buffer.pos = dns_name::to_wire(record.get_domain()).len() + 2;
+    let class = try!(buffer.read_u16());
+    let ttl = try!(buffer.read_u32());
+    let len = try!(buffer.read_u16());
+    let data = try!(buffer.read_bytes(len as usize));
+    Ok((class, ttl, data))
+}
+
+/// The record with these fields, read as if off the wire.
+fn read_record(name: &str, qtype: u16, class: u16, ttl: u32, data: &[u8]) -> Result<DnsRecord, Error> {
+    if data.len() > 0xFFFF {
+        return Err(Error::new(ErrorKind::InvalidData, "record data is too long"));
+    }
+    let mut buffer = BytePacketBuffer::with_size(MAX_RECORD);
+    try!(buffer.write_qname(name));
+    try!(buffer.write_u16(qtype));
+    try!(buffer.write_u16(class));
+    try!(buffer.write_u32(ttl));
+    try!(buffer.write_u16(data.len() as u16));
+    try!(buffer.write_bytes(data));
+    buffer.pos = 0;
+    DnsRecord::read(&mut buffer)
+}
+
+/// The record with data `text` in presentation format.
+fn parse_record(name: &str, qtype: QueryType, ttl: u32, text: &str) -> Result<DnsRecord, Error> {
+    // One line, so that the data can't bring in directives
+    if text.contains(&['\n', '\r'][..]) {
+        return Err(Error::new(ErrorKind::InvalidData, "record data has a line break"));
+    }
+    let line = format!("{} {} IN {} {}", presentation::name(name), ttl, qtype, text);
+    let mut parser = ZoneParser::new("");
+    try!(parser.parse_str(&line, "JSON"));
+    match parser.records.pop() {
+        Some(ref record) if record.get_querytype() != qtype => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("expected {} record data", qtype),
+        )),
+        Some(record) => Ok(record),
+        None => Err(Error::new(ErrorKind::InvalidData, "no record data")),
+    }
+}
+
+fn parse_name<E: de::Error>(text: &str) -> Result<String, E> {
+    zone_parser::resolve_name(text, "").map_err(E::custom)
+}
+
+fn class_in() -> u16 {
+    1
+}
+
+/// A flag given as a JSON boolean, or as 0 or 1.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    struct FlagVisitor;
+
+    impl<'de> Visitor<'de> for FlagVisitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a boolean, or 0 or 1")
+        }
+
+        fn visit_bool<E: de::Error>(self, value: bool) -> Result<bool, E> {
+            Ok(value)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<bool, E> {
+            match value {
+                0 => Ok(false),
+                1 => Ok(true),
+                _ => Err(E::invalid_value(Unexpected::Unsigned(value), &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_any(FlagVisitor)
+}
+
+fn rescode<E: de::Error>(value: u8) -> Result<ResultCode, E> {
+    if value > ResultCode::NOTZONE as u8 {
+        return Err(E::custom(format!("unknown RCODE {}", value)));
+    }
+    Ok(ResultCode::from_num(value))
+}
+
+fn write_header<M: SerializeMap>(map: &mut M, header: &DnsHeader) -> Result<(), M::Error> {
+    try!(map.serialize_entry("ID", &header.id));
+    try!(map.serialize_entry("QR", &header.response));
+    try!(map.serialize_entry("Opcode", &header.opcode.to_num()));
+    try!(map.serialize_entry("AA", &header.authoritative_answer));
+    try!(map.serialize_entry("TC", &header.truncated_message));
+    try!(map.serialize_entry("RD", &header.recursion_desired));
+    try!(map.serialize_entry("RA", &header.recursion_available));
+    try!(map.serialize_entry("AD", &header.authed_data));
+    try!(map.serialize_entry("CD", &header.checking_disabled));
+    try!(map.serialize_entry("RCODE", &(header.rescode as u8)));
+    try!(map.serialize_entry("QDCOUNT", &header.questions));
+    try!(map.serialize_entry("ANCOUNT", &header.answers));
+    try!(map.serialize_entry("NSCOUNT", &header.authoritative_entries));
+    map.serialize_entry("ARCOUNT", &header.resource_entries)
+}
+
+impl Serialize for DnsHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = try!(serializer.serialize_map(None));
+        try!(write_header(&mut map, self));
+        map.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(rename = "ID", default)]
+    id: u16,
+    #[serde(rename = "QR", default, deserialize_with = "flag")]
+    response: bool,
+    #[serde(rename = "Opcode", default)]
+    opcode: u8,
+    #[serde(rename = "AA", default, deserialize_with = "flag")]
+    authoritative_answer: bool,
+    #[serde(rename = "TC", default, deserialize_with = "flag")]
+    truncated_message: bool,
+    #[serde(rename = "RD", default, deserialize_with = "flag")]
+    recursion_desired: bool,
+    #[serde(rename = "RA", default, deserialize_with = "flag")]
+    recursion_available: bool,
+    #[serde(rename = "AD", default, deserialize_with = "flag")]
+    authed_data: bool,
+    #[serde(rename = "CD", default, deserialize_with = "flag")]
+    checking_disabled: bool,
+    #[serde(rename = "RCODE", default)]
+    rescode: u8,
+}
+
+impl<'de> Deserialize<'de> for DnsHeader {
+    /// The header's flags and codes. Its counts are left for writing the
+    /// message to fill in.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DnsHeader, D::Error> {
+        let fields = try!(Header::deserialize(deserializer));
+        if fields.opcode > 0x0F {
+            return Err(de::Error::custom(format!("unknown Opcode {}", fields.opcode)));
+        }
+        let mut header = DnsHeader::new();
+        header.id = fields.id;
+        header.response = fields.response;
+        header.opcode = Opcode::from_num(fields.opcode);
+        header.authoritative_answer = fields.authoritative_answer;
+        header.truncated_message = fields.truncated_message;
+        header.recursion_desired = fields.recursion_desired;
+        header.recursion_available = fields.recursion_available;
+        header.authed_data = fields.authed_data;
+        header.checking_disabled = fields.checking_disabled;
+        header.rescode = try!(rescode(fields.rescode));
+        Ok(header)
+    }
+}
+
+impl Serialize for DnsQuestion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = try!(serializer.serialize_map(None));
+        try!(map.serialize_entry("QNAME", &presentation::name(&self.name)));
+        try!(map.serialize_entry("QTYPE", &self.qtype.to_num()));
+        try!(map.serialize_entry("QTYPEname", &self.qtype.to_string()));
+        try!(map.serialize_entry("QCLASS", &self.class));
+        try!(map.serialize_entry("QCLASSname", &presentation::class_name(self.class)));
+        map.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct Question {
+    #[serde(rename = "QNAME", alias = "NAME")]
+    name: String,
+    #[serde(rename = "QTYPE", alias = "TYPE")]
+    qtype: u16,
+    #[serde(rename = "QCLASS", alias = "CLASS", default = "class_in")]
+    class: u16,
+}
+
+impl<'de> Deserialize<'de> for DnsQuestion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DnsQuestion, D::Error> {
+        let fields = try!(Question::deserialize(deserializer));
+        Ok(DnsQuestion {
+            name: try!(parse_name(&fields.name)),
+            qtype: QueryType::from_num(fields.qtype),
+            class: fields.class,
+        })
+    }
+}
+
+impl Serialize for DnsRecord {
+    /// The record's fields, its data as hex, and its data in presentation
+    /// format as `rdata` followed by the type, for types we know.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (class, ttl, data) = try!(wire_fields(self).map_err(ser::Error::custom));
+        let qtype = self.get_querytype();
+        let mut map = try!(serializer.serialize_map(None));
+        try!(map.serialize_entry("NAME", &presentation::name(self.get_domain())));
+        try!(map.serialize_entry("TYPE", &qtype.to_num()));
+        try!(map.serialize_entry("TYPEname", &qtype.to_string()));
+        try!(map.serialize_entry("CLASS", &class));
+        if qtype != QueryType::OPT {
+            try!(map.serialize_entry("CLASSname", &presentation::class_name(class)));
+        }
+        try!(map.serialize_entry("TTL", &ttl));
+        try!(map.serialize_entry("RDLENGTH", &data.len()));
+        try!(map.serialize_entry("RDATAHEX", &presentation::hex(&data)));
+        match qtype {
+            QueryType::UNKNOWN(_) | QueryType::OPT => {}
+            _ => try!(map.serialize_entry(&format!("rdata{}", qtype), &self.rdata_string())),
+        }
+        map.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct Record {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    qtype: u16,
+    #[serde(rename = "CLASS", default = "class_in")]
+    class: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    #[serde(rename = "RDATAHEX")]
+    data: Option<String>,
+    // Where the data in presentation format is looked for
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+impl<'de> Deserialize<'de> for DnsRecord {
+    /// A record from its data as hex if given, and in presentation format
+    /// otherwise, which only class IN records can be read from.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DnsRecord, D::Error> {
+        let fields = try!(Record::deserialize(deserializer));
+        let name = try!(parse_name(&fields.name));
+        if let Some(hex) = fields.data {
+            let data = try!(presentation::parse_hex(&hex)
+                .ok_or_else(|| de::Error::custom("expected hex for RDATAHEX")));
+            return read_record(&name, fields.qtype, fields.class, fields.ttl, &data)
+                .map_err(de::Error::custom);
+        }
+
+        let qtype = QueryType::from_num(fields.qtype);
+        let member = format!("rdata{}", qtype);
+        match fields.other.get(&member) {
+            Some(&Value::String(ref text)) if fields.class == 1 => {
+                parse_record(&name, qtype, fields.ttl, text).map_err(de::Error::custom)
+            }
+            Some(_) if fields.class == 1 => Err(de::Error::custom(format!("expected a string for {}", member))),
+            _ => Err(de::Error::custom(format!("expected RDATAHEX or {}", member))),
+        }
+    }
+}
+
+impl Serialize for DnsPacket {
+    /// The header's fields, then the sections that have records in them.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = try!(serializer.serialize_map(None));
+        try!(write_header(&mut map, &self.header));
+        if !self.questions.is_empty() {
+            try!(map.serialize_entry("questionRRs", &self.questions));
+        }
+        if !self.answers.is_empty() {
+            try!(map.serialize_entry("answerRRs", &self.answers));
+        }
+        if !self.authorities.is_empty() {
+            try!(map.serialize_entry("authorityRRs", &self.authorities));
+        }
+        if !self.resources.is_empty() {
+            try!(map.serialize_entry("additionalRRs", &self.resources));
+        }
+        map.end()
+    }
+}
+
+#[derive(Deserialize)]
+struct Message {
+    #[serde(flatten)]
+    header: DnsHeader,
+    // A single question may be given among the header's fields instead
+    #[serde(rename = "QNAME")]
+    qname: Option<String>,
+    #[serde(rename = "QTYPE")]
+    qtype: Option<u16>,
+    #[serde(rename = "QCLASS")]
+    qclass: Option<u16>,
+    #[serde(rename = "questionRRs", default)]
+    questions: Vec<DnsQuestion>,
+    #[serde(rename = "answerRRs", default)]
+    answers: Vec<DnsRecord>,
+    #[serde(rename = "authorityRRs", default)]
+    authorities: Vec<DnsRecord>,
+    #[serde(rename = "additionalRRs", default)]
+    resources: Vec<DnsRecord>,
+}
+
+impl<'de> Deserialize<'de> for DnsPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DnsPacket, D::Error> {
+        let fields = try!(Message::deserialize(deserializer));
+        let mut packet = DnsPacket::new();
+        packet.header = fields.header;
+        if let Some(qname) = fields.qname {
+            let qtype = try!(fields.qtype.ok_or_else(|| de::Error::missing_field("QTYPE")));
+            packet.questions.push(DnsQuestion {
+                name: try!(parse_name(&qname)),
+                qtype: QueryType::from_num(qtype),
+                class: fields.qclass.unwrap_or(1),
+            });
+        }
+        packet.questions.extend(fields.questions);
+        packet.answers = fields.answers;
+        packet.authorities = fields.authorities;
+        packet.resources = fields.resources;
+        Ok(packet)
+    }
+}
+
+/// A response in the `application/dns-json` format, with record data in
+/// presentation format only, and without the OPT record.
+pub struct DnsJson(pub DnsPacket);
+
+#[derive(Serialize, Deserialize)]
+struct JsonQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonMessage {
+    #[serde(rename = "Status", default)]
+    status: u8,
+    #[serde(rename = "TC", default, deserialize_with = "flag")]
+    truncated_message: bool,
+    #[serde(rename = "RD", default, deserialize_with = "flag")]
+    recursion_desired: bool,
+    #[serde(rename = "RA", default, deserialize_with = "flag")]
+    recursion_available: bool,
+    #[serde(rename = "AD", default, deserialize_with = "flag")]
+    authed_data: bool,
+    #[serde(rename = "CD", default, deserialize_with = "flag")]
+    checking_disabled: bool,
+    #[serde(rename = "Question", default)]
+    questions: Vec<JsonQuestion>,
+    #[serde(rename = "Answer", default, skip_serializing_if = "Vec::is_empty")]
+    answers: Vec<JsonRecord>,
+    #[serde(rename = "Authority", default, skip_serializing_if = "Vec::is_empty")]
+    authorities: Vec<JsonRecord>,
+    #[serde(rename = "Additional", default, skip_serializing_if = "Vec::is_empty")]
+    resources: Vec<JsonRecord>,
+}
+
+fn json_records(records: &[DnsRecord]) -> Vec<JsonRecord> {
+    records
+        .iter()
+        .filter(|rec| rec.get_querytype() != QueryType::OPT)
+        .map(|rec| JsonRecord {
+            name: presentation::name(rec.get_domain()),
+            qtype: rec.get_querytype().to_num(),
+            ttl: rec.get_ttl(),
+            data: rec.rdata_string(),
+        })
+        .collect()
+}
+
+fn read_json_records<E: de::Error>(records: Vec<JsonRecord>) -> Result<Vec<DnsRecord>, E> {
+    records
+        .into_iter()
+        .map(|rec| {
+            let name = try!(parse_name(&rec.name));
+            parse_record(&name, QueryType::from_num(rec.qtype), rec.ttl, &rec.data)
+                .map_err(E::custom)
+        })
+        .collect()
+}
+
+impl Serialize for DnsJson {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let packet = &self.0;
+        JsonMessage {
+            status: packet.header.rescode as u8,
+            truncated_message: packet.header.truncated_message,
+            recursion_desired: packet.header.recursion_desired,
+            recursion_available: packet.header.recursion_available,
+            authed_data: packet.header.authed_data,
+            checking_disabled: packet.header.checking_disabled,
+            questions: packet
+                .questions
+                .iter()
+                .map(|q| JsonQuestion {
+                    name: presentation::name(&q.name),
+                    qtype: q.qtype.to_num(),
+                })
+                .collect(),
+            answers: json_records(&packet.answers),
+            authorities: json_records(&packet.authorities),
+            resources: json_records(&packet.resources),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsJson {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DnsJson, D::Error> {
+        let fields = try!(JsonMessage::deserialize(deserializer));
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.rescode = try!(rescode(fields.status));
+        packet.header.truncated_message = fields.truncated_message;
+        packet.header.recursion_desired = fields.recursion_desired;
+        packet.header.recursion_available = fields.recursion_available;
+        packet.header.authed_data = fields.authed_data;
+        packet.header.checking_disabled = fields.checking_disabled;
+        for question in fields.questions {
+            let name = try!(parse_name(&question.name));
+            packet
+                .questions
+                .push(DnsQuestion::new(name, QueryType::from_num(question.qtype)));
+        }
+        packet.answers = try!(read_json_records(fields.answers));
+        packet.authorities = try!(read_json_records(fields.authorities));
+        packet.resources = try!(read_json_records(fields.resources));
+        Ok(DnsJson(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use std::net::Ipv4Addr;
+
+    fn wire(packet: &DnsPacket) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::with_size(4096);
+        packet.clone().write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos()].to_vec()
+    }
+
+    fn response() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4660;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        });
+        packet.answers.push(DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec![b"v=spf1 -all".to_vec(), b"a \"quoted\" string".to_vec()],
+            ttl: 60,
+        });
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 2024010101,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        });
+        packet.resources.push(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65,
+            data: vec![0, 1, 0],
+            ttl: 300,
+        });
+        packet.set_edns(1232, true);
+        packet
+    }
+
+    #[test]
+    fn round_trips_rfc8427_messages() {
+        let packet = response();
+        let json = serde_json::to_value(&packet).unwrap();
+        assert_eq!(json["ID"], 4660);
+        assert_eq!(json["QR"], true);
+        assert_eq!(json["questionRRs"][0]["QNAME"], "example.com.");
+        assert_eq!(json["questionRRs"][0]["QTYPEname"], "A");
+        let a = &json["answerRRs"][0];
+        assert_eq!(a["NAME"], "example.com.");
+        assert_eq!(a["TYPE"], 1);
+        assert_eq!(a["CLASSname"], "IN");
+        assert_eq!(a["TTL"], 300);
+        assert_eq!(a["RDLENGTH"], 4);
+        assert_eq!(a["RDATAHEX"], "C0000201");
+        assert_eq!(a["rdataA"], "192.0.2.1");
+        let opt = &json["additionalRRs"][1];
+        assert_eq!(opt["TYPEname"], "OPT");
+        assert_eq!(opt["CLASS"], 1232);
+        assert!(opt.get("rdataOPT").is_none());
+
+        let read: DnsPacket = serde_json::from_value(json).unwrap();
+        assert_eq!(wire(&read), wire(&packet));
+
+        // Without the hex, the data is read in presentation format
+        let mut json = serde_json::to_value(&packet).unwrap();
+        for rec in json["answerRRs"].as_array_mut().unwrap() {
+            rec.as_object_mut().unwrap().remove("RDATAHEX");
+        }
+        json["authorityRRs"][0].as_object_mut().unwrap().remove("RDATAHEX");
+        let read: DnsPacket = serde_json::from_value(json).unwrap();
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(read.authorities, packet.authorities);
+    }
+
+    #[test]
+    fn reads_the_rfc8427_examples() {
+        // Section 5.1: a query, with its question among the header's fields
+        let query: DnsPacket = serde_json::from_str(
+            r#"{ "ID": 19678, "QR": 0, "Opcode": 0,
+                 "AA": 0, "TC": 0, "RD": 0, "RA": 0, "AD": 0, "CD": 0, "RCODE": 0,
+                 "QDCOUNT": 1, "ANCOUNT": 0, "NSCOUNT": 0, "ARCOUNT": 0,
+                 "QNAME": "example.com", "QTYPE": 1, "QCLASS": 1 }"#,
+        ).unwrap();
+        assert_eq!(query.header.id, 19678);
+        assert!(!query.header.response);
+        assert_eq!(
+            query.questions,
+            vec![DnsQuestion::new("example.com".to_string(), QueryType::A)]
+        );
+
+        // Section 5.2: a response
+        let response: DnsPacket = serde_json::from_str(
+            r#"{ "ID": 32784, "QR": 1, "AA": 1, "RCODE": 0,
+                 "QDCOUNT": 1, "ANCOUNT": 1, "NSCOUNT": 1, "ARCOUNT": 0,
+                 "answerRRs": [ { "NAME": "example.com.", "TYPE": 1, "CLASS": 1,
+                                  "TTL": 3600, "RDATAHEX": "C0020202" } ],
+                 "authorityRRs": [ { "NAME": "ns.example.com.", "TYPE": 1, "CLASS": 1,
+                                     "TTL": 28800, "RDATAHEX": "CB007181" } ] }"#,
+        ).unwrap();
+        assert!(response.header.authoritative_answer);
+        assert_eq!(
+            response.authorities,
+            vec![DnsRecord::A {
+                domain: "ns.example.com".to_string(),
+                addr: Ipv4Addr::new(203, 0, 113, 129),
+                ttl: 28800,
+            }]
+        );
+
+        let error = |json: &str| serde_json::from_str::<DnsPacket>(json).err().unwrap().to_string();
+        assert!(error(r#"{ "RCODE": 99 }"#).starts_with("unknown RCODE 99"));
+        assert!(error(r#"{ "QR": 2 }"#).contains("expected a boolean, or 0 or 1"));
+        let record = r#"{ "answerRRs": [ { "NAME": "a.", "TYPE": 1 %s } ] }"#;
+        assert!(error(&record.replace("%s", "")).starts_with("expected RDATAHEX or rdataA"));
+        assert!(error(&record.replace("%s", r#", "RDATAHEX": "C00""#)).starts_with("expected hex"));
+        assert!(error(&record.replace("%s", r#", "rdataA": "2001:db8::1""#)).contains("JSON:1"));
+        assert!(error(&record.replace("%s", r#", "rdataA": "1.2.3.4\n$INCLUDE /etc/passwd""#))
+            .starts_with("record data has a line break"));
+    }
+
+    #[test]
+    fn writes_and_reads_dns_json() {
+        let json = serde_json::to_value(&DnsJson(response())).unwrap();
+        assert_eq!(
+            json,
+            serde_json::from_str::<Value>(
+                r#"{ "Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+                     "Question": [ { "name": "example.com.", "type": 1 } ],
+                     "Answer": [
+                       { "name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1" },
+                       { "name": "example.com.", "type": 16, "TTL": 60,
+                         "data": "\"v=spf1 -all\" \"a \\\"quoted\\\" string\"" } ],
+                     "Authority": [ { "name": "example.com.", "type": 6, "TTL": 3600,
+                       "data": "ns.example.com. hostmaster.example.com. 2024010101 3600 600 86400 300" } ],
+                     "Additional": [
+                       { "name": "example.com.", "type": 65, "TTL": 300, "data": "\\# 3 000100" } ] }"#
+            ).unwrap()
+        );
+
+        let DnsJson(read) = serde_json::from_value(json).unwrap();
+        let mut expected = response();
+        expected.header.id = 0;
+        expected.resources.pop();
+        assert_eq!(wire(&read), wire(&expected));
+    }
+}
@@ -0,0 +1,59 @@
+extern crate bytes;
+extern crate libc;
+extern crate quinn_proto;
+extern crate ring;
+extern crate rustls;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+
+#[cfg(test)]
+extern crate rcgen;
+
+#[macro_use]
+pub mod log_level;
+pub mod byte_packet_buffer;
+pub mod result_code;
+pub mod opcode;
+pub mod dns_header;
+pub mod query_type;
+pub mod dns_question;
+pub mod dns_record;
+pub mod dns_packet;
+pub mod dns_name;
+pub mod presentation;
+pub mod zone_parser;
+pub mod json;
+pub mod zone;
+pub mod zone_diff;
+pub mod journal;
+pub mod transfer;
+pub mod notify;
+pub mod update;
+pub mod update_script;
+pub mod tsig;
+pub mod tls;
+pub mod hpack;
+pub mod http;
+pub mod http2;
+pub mod doh;
+pub mod doq;
+pub mod network;
+pub mod zone_config;
+pub mod authority;
+pub mod secondary;
+pub mod dnssec;
+pub mod cache;
+pub mod qname_minimisation;
+pub mod iterative_resolver;
+pub mod query_coalescer;
+pub mod query_log;
+pub mod metrics;
+pub mod resolver;
+pub mod server;
+pub mod config;
+pub mod command_line;
+pub mod query_command_line;
+pub mod daemon;
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How much the server logs, from least to most.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(2); // Info
+
+impl LogLevel {
+    pub fn from_name(level: &str) -> Option<LogLevel> {
+        match level {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    /// Sets the level messages have to be at or below to be logged.
+    pub fn set(level: LogLevel) {
+        LEVEL.store(level as usize, Ordering::Relaxed);
+    }
+
+    pub fn enabled(level: LogLevel) -> bool {
+        level as usize <= LEVEL.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs a line to standard output. Unlike `println!`, doesn't panic when
+/// that's gone, as it is once a daemon's terminal closes.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log_level::LogLevel::enabled($level) {
+            let line = format!($($arg)*);
+            let _ = ::std::io::Write::write_all(&mut ::std::io::stdout(), (line + "\n").as_bytes());
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::log_level::LogLevel::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::log_level::LogLevel::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::log_level::LogLevel::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::log_level::LogLevel::Debug, $($arg)*) };
+}
@@ -1,121 +1,662 @@
-mod byte_packet_buffer;
-mod result_code;
-mod dns_header;
-mod query_type;
-mod dns_question;
-mod dns_record;
-mod dns_packet;
-
-use std::net::UdpSocket;
-use std::io::Error;
-use query_type::QueryType;
-use dns_packet::DnsPacket;
-use dns_question::DnsQuestion;
-use byte_packet_buffer::BytePacketBuffer;
-use result_code::ResultCode;
-
-fn lookup(qname: &str, qtype: QueryType, server: (&str, u16)) -> Result<DnsPacket, Error> {
-    let socket = try!(UdpSocket::bind(("0.0.0.0", 43210)));
-    let mut packet = DnsPacket::new();
-    packet.header.id = 6666;
-    packet.header.questions = 1;
-    packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
-
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer).unwrap();
-
-    try!(socket.send_to(&req_buffer.buf[0..req_buffer.pos], server));
-
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf).unwrap();
-
-    DnsPacket::from_buffer(&mut res_buffer)
+#[macro_use]
+extern crate dns;
+extern crate rustls;
+
+use std::cmp;
+use std::env;
+use std::io::{Error, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use dns::dns_packet::DnsPacket;
+use dns::query_type::QueryType;
+use dns::authority::Authority;
+use dns::byte_packet_buffer::BytePacketBuffer;
+use dns::command_line::CommandLine;
+use dns::config::Config;
+use dns::doh::MessageHandler;
+use dns::http::{self, Handler};
+use dns::log_level::LogLevel;
+use dns::metrics::{self, InFlight, METRICS};
+use dns::opcode::Opcode;
+use dns::query_log::{Entry, QueryLog, Transport};
+use dns::resolver::{AnswerSource, Resolver, EDNS_PAYLOAD_SIZE};
+use dns::secondary::Secondary;
+use dns::server::Server;
+use dns::tsig::{self, Signer};
+use dns::update::Update;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+// Queries resolved at once; beyond this the receiving loop waits its turn
+const MAX_CONCURRENT_QUERIES: usize = 256;
+// How long an idle TCP connection is kept open
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The responses to `request` from `client`, or to `update` if it's one,
+/// and where they came from. Requests whose TSIG signature `signer` found
+/// fault with are turned away.
+fn respond(
+    server: &Server,
+    request: &DnsPacket,
+    update: Option<&Update>,
+    signer: Option<&Signer>,
+    client: IpAddr,
+    tcp: bool,
+) -> (Vec<DnsPacket>, AnswerSource) {
+    let key = signer.map(|s| s.key.name.as_str());
+    match (signer, update) {
+        (Some(signer), _) if signer.error != 0 => {
+            warn!("Rejected request from {} signed with key {}", client, signer.key.name);
+            (vec![server.handle_bad_signature(request)], AnswerSource::default())
+        }
+        (_, Some(update)) => {
+            (vec![server.handle_update(update, client, key)], AnswerSource::default())
+        }
+        (_, None) if tcp => server.answer_tcp_query(request, client, key),
+        (_, None) => {
+            let (packet, source) = server.answer_query(request, client, key);
+            (vec![packet], source)
+        }
+    }
 }
 
-fn main() {
-    let server = ("8.8.8.8", 53);
-    let socket = UdpSocket::bind(("0.0.0.0", 2053)).unwrap();
+/// A query that's arrived, counted as in flight until it's answered.
+struct Received {
+    transport: Transport,
+    // Its query log entry, if queries are logged
+    entry: Option<Entry>,
+    _in_flight: InFlight<'static>,
+}
+
+/// Notes the arrival of `query` from `client` over `transport`.
+fn receive(server: &Server, client: SocketAddr, transport: Transport, query: &[u8]) -> Received {
+    Received {
+        transport: transport,
+        entry: server.query_log.as_ref().map(|_| Entry::start(client, transport, query)),
+        _in_flight: METRICS.start_query(),
+    }
+}
+
+/// Counts the answer `response`, sent as `answer`, to the `request` that
+/// was `received`, and logs it if queries are logged.
+fn answered(
+    server: &Server,
+    received: Received,
+    request: &DnsPacket,
+    response: &DnsPacket,
+    answer: &[u8],
+    source: AnswerSource,
+) {
+    let qtype = request.questions.first().map(|question| question.qtype);
+    METRICS.answered(received.transport, qtype, response.header.rescode);
+    if let (Some(log), Some(entry)) = (server.query_log.as_ref(), received.entry) {
+        log.log(entry, request, response, answer, source);
+    }
+}
+
+/// `packet` written out in at most `size` bytes, and signed if the request
+/// it answers was.
+fn write_response(
+    packet: &mut DnsPacket,
+    signer: Option<&mut Signer>,
+    size: usize,
+) -> Result<BytePacketBuffer, Error> {
+    let mut buffer = BytePacketBuffer::with_size(size);
+    try!(packet.write(&mut buffer));
+    if let Some(signer) = signer {
+        try!(signer.sign(&mut buffer, tsig::now()));
+    }
+    Ok(buffer)
+}
+
+/// The request in the first `size` bytes of `buffer`, the update it makes
+/// if it's one, and what its TSIG signature, if any, says of it.
+fn read_request(
+    server: &Server,
+    buffer: &mut BytePacketBuffer,
+    size: usize,
+) -> Result<(DnsPacket, Option<Update>, Option<Signer>), Error> {
+    let request = try!(DnsPacket::from_buffer(buffer));
+    let signer = try!(Signer::check_request(&server.keys, &buffer.buf[..size], tsig::now()));
+    // Updates are read again, keeping the classes of their records
+    let update = if request.header.opcode == Opcode::UPDATE {
+        buffer.pos = 0;
+        Some(try!(Update::read(buffer)))
+    } else {
+        None
+    };
+    Ok((request, update, signer))
+}
+
+fn handle_request(
+    server: &Arc<Server>,
+    socket: &UdpSocket,
+    request: DnsPacket,
+    update: Option<Update>,
+    mut signer: Option<Signer>,
+    src: SocketAddr,
+    received: Received,
+) {
+    let client = src.ip();
+    let (mut packets, source) =
+        respond(server, &request, update.as_ref(), signer.as_ref(), client, false);
+    let mut packet = packets.remove(0);
+
+    // Clients without EDNS only accept 512 bytes over UDP
+    let max_size = match request.edns_payload_size() {
+        Some(size) => cmp::min(cmp::max(size, 512), EDNS_PAYLOAD_SIZE),
+        None => 512,
+    };
+
+    let mut res_buffer = match write_response(&mut packet, signer.as_mut(), max_size as usize) {
+        Ok(x) => x,
+        Err(_) => {
+            packet.header.truncated_message = true;
+            packet.answers.clear();
+            packet.authorities.clear();
+            packet
+                .resources
+                .retain(|rec| rec.get_querytype() == QueryType::OPT);
+
+            match write_response(&mut packet, signer.as_mut(), max_size as usize) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to encode UDP response packet: {:?}", e);
+                    return;
+                }
+            }
+        }
+    };
+
+    let len = res_buffer.pos();
+    let data = match res_buffer.get_range(0, len) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to retrieve response buffer: {:?}", e);
+            return;
+        }
+    };
+
+    match socket.send_to(data, src) {
+        Ok(_) => {}
+        Err(e) => {
+            warn!("Failed to send response buffer: {:?}", e);
+        }
+    };
+    answered(server, received, &request, &packet, data, source);
+}
+
+fn serve_udp(server: Arc<Server>, socket: UdpSocket) {
+    let socket = Arc::new(socket);
+    let active = Arc::new(AtomicUsize::new(0));
     loop {
         let mut req_buffer = BytePacketBuffer::new();
-        let (_size, src) = match socket.recv_from(&mut req_buffer.buf) {
+        let (size, src) = match socket.recv_from(&mut req_buffer.buf) {
             Ok(x) => x,
             Err(e) => {
-                println!("Failed to read from UDP socket: {:?}", e);
+                warn!("Failed to read from UDP socket: {:?}", e);
                 continue;
             }
         };
 
-        let request = match DnsPacket::from_buffer(&mut req_buffer) {
+        let received = receive(&server, src, Transport::Udp, &req_buffer.buf[..size]);
+        let (request, update, signer) = match read_request(&server, &mut req_buffer, size) {
             Ok(x) => x,
             Err(e) => {
-                println!("Failed to parse UDP query packet: {:?}", e);
+                warn!("Failed to parse UDP request packet: {:?}", e);
+                METRICS.parse_failure(Transport::Udp);
                 continue;
             }
         };
 
-        let mut packet = DnsPacket::new();
-        packet.header.id = request.header.id;
-        packet.header.recursion_desired = true;
-        packet.header.recursion_available = true;
-        packet.header.response = true;
-
-        if request.questions.is_empty() {
-            packet.header.rescode = ResultCode::FORMERR;
-        } else {
-            let question = &request.questions[0];
-            println!("Recursive query: {:?}", question);
-
-            if let Ok(result) = lookup(&question.name, question.qtype, server) {
-                packet.questions.push(question.clone());
-                packet.header.rescode = result.header.rescode;
-
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    packet.answers.push(rec);
-                }
+        if active.load(Ordering::SeqCst) >= MAX_CONCURRENT_QUERIES {
+            handle_request(&server, &socket, request, update, signer, src, received);
+            continue;
+        }
 
-                for rec in result.authorities {
-                    println!("Authority: {:?}", rec);
-                    packet.authorities.push(rec);
-                }
+        active.fetch_add(1, Ordering::SeqCst);
+        let server = server.clone();
+        let socket = socket.clone();
+        let active = active.clone();
+        thread::spawn(move || {
+            handle_request(&server, &socket, request, update, signer, src, received);
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
 
-                for rec in result.resources {
-                    println!("Resource: {:?}", rec);
-                    packet.resources.push(rec);
-                }
-            } else {
-                packet.header.rescode = ResultCode::SERVFAIL;
+/// Answers the length-prefixed queries on `stream`, from `client` over
+/// `transport`, until the client closes it or goes quiet.
+fn serve_stream<S: Read + Write>(
+    server: &Arc<Server>,
+    stream: &mut S,
+    client: SocketAddr,
+    transport: Transport,
+) -> Result<(), Error> {
+    loop {
+        let mut len_buf = [0u8; 2];
+        if stream.read_exact(&mut len_buf).is_err() {
+            return Ok(());
+        }
+        let len = ((len_buf[0] as usize) << 8) | len_buf[1] as usize;
+
+        let mut req_buffer = BytePacketBuffer::with_size(len);
+        try!(stream.read_exact(&mut req_buffer.buf));
+        let mut received = Some(receive(server, client, transport, &req_buffer.buf));
+        let read = read_request(server, &mut req_buffer, len);
+        if read.is_err() {
+            METRICS.parse_failure(transport);
+        }
+        let (request, update, mut signer) = try!(read);
+
+        let (responses, source) =
+            respond(server, &request, update.as_ref(), signer.as_ref(), client.ip(), true);
+        for mut packet in responses {
+            let mut res_buffer = try!(write_response(&mut packet, signer.as_mut(), 0xFFFF));
+
+            let len = res_buffer.pos();
+            let answer = try!(res_buffer.get_range(0, len));
+            try!(stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8]));
+            try!(stream.write_all(answer));
+            // Zone transfers count with their first message
+            if let Some(received) = received.take() {
+                answered(server, received, &request, &packet, answer, source.clone());
+            }
+        }
+        try!(stream.flush());
+    }
+}
+
+fn handle_tcp_connection(server: &Arc<Server>, mut stream: TcpStream) -> Result<(), Error> {
+    try!(stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT)));
+    try!(stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT)));
+    let client = try!(stream.peer_addr());
+    serve_stream(server, &mut stream, client, Transport::Tcp)
+}
+
+/// Like `handle_tcp_connection`, with the messages sent over TLS.
+fn handle_tls_connection(
+    server: &Arc<Server>,
+    stream: TcpStream,
+    tls: Arc<ServerConfig>,
+) -> Result<(), Error> {
+    try!(stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT)));
+    try!(stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT)));
+    let client = try!(stream.peer_addr());
+    let connection = try!(ServerConnection::new(tls)
+        .map_err(|e| Error::other(e.to_string())));
+
+    let mut stream = StreamOwned::new(connection, stream);
+    try!(serve_stream(server, &mut stream, client, Transport::Tls));
+    // The client may be gone already
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    Ok(())
+}
+
+fn serve_tcp(server: Arc<Server>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept TCP connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_tcp_connection(&server, stream) {
+                warn!("Failed to handle TCP connection: {:?}", e);
+            }
+        });
+    }
+}
+
+fn serve_tls(server: Arc<Server>, listener: TcpListener, tls: Arc<ServerConfig>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept TLS connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        let tls = tls.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_tls_connection(&server, stream, tls) {
+                warn!("Failed to handle TLS connection: {:?}", e);
+            }
+        });
+    }
+}
+
+/// The response to the DoH or DoQ message `msg` from `client`, or None if
+/// it isn't a DNS message.
+fn answer_message(
+    server: &Server,
+    msg: &[u8],
+    client: SocketAddr,
+    transport: Transport,
+) -> Option<Vec<u8>> {
+    let received = receive(server, client, transport, msg);
+    let mut req_buffer = BytePacketBuffer::with_size(msg.len());
+    req_buffer.buf.copy_from_slice(msg);
+    let (request, update, mut signer) = match read_request(server, &mut req_buffer, msg.len()) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to parse DoH or DoQ request packet: {:?}", e);
+            METRICS.parse_failure(transport);
+            return None;
+        }
+    };
+
+    let (mut packets, source) =
+        respond(server, &request, update.as_ref(), signer.as_ref(), client.ip(), false);
+    let mut packet = packets.remove(0);
+    let mut res_buffer = match write_response(&mut packet, signer.as_mut(), 0xFFFF) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to encode DoH or DoQ response packet: {:?}", e);
+            return None;
+        }
+    };
+    let len = res_buffer.pos();
+    let answer = match res_buffer.get_range(0, len) {
+        Ok(x) => x.to_vec(),
+        Err(_) => return None,
+    };
+    answered(server, received, &request, &packet, &answer, source);
+    Some(answer)
+}
+
+fn serve_quic(server: Arc<Server>, socket: UdpSocket, config: Arc<dns::doq::QuicConfig>) {
+    let handler: MessageHandler =
+        Arc::new(move |msg: &[u8], client| answer_message(&server, msg, client, Transport::Quic));
+    dns::doq::serve(socket, config, handler);
+}
+
+fn serve_https(server: Arc<Server>, listener: TcpListener, tls: Arc<ServerConfig>) {
+    let handler: MessageHandler =
+        Arc::new(move |msg: &[u8], client| answer_message(&server, msg, client, Transport::Https));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept HTTPS connection: {:?}", e);
+                continue;
             }
+        };
 
-            let mut res_buffer = BytePacketBuffer::new();
+        let handler = handler.clone();
+        let tls = tls.clone();
+        thread::spawn(move || {
+            let result = stream
+                .set_read_timeout(Some(TCP_IDLE_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT)))
+                .and_then(|_| dns::doh::handle_connection(stream, tls, handler));
+            if let Err(e) = result {
+                debug!("Failed to handle HTTPS connection: {:?}", e);
+            }
+        });
+    }
+}
 
-            match packet.write(&mut res_buffer) {
-                Ok(_) => {}
+fn serve_metrics(listener: TcpListener) {
+    let handler: Handler = Arc::new(metrics::respond);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let result = stream
+                .set_read_timeout(Some(TCP_IDLE_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT)))
+                .and_then(|_| http::serve(&stream, &stream, &handler));
+            if let Err(e) = result {
+                debug!("Failed to handle metrics connection: {:?}", e);
+            }
+        });
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let cli = match CommandLine::parse(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}\nRun with --help for usage.", e);
+            process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", dns::command_line::USAGE);
+        return;
+    }
+
+    let config = match cli.config {
+        Some(ref path) => Config::load(path),
+        None => Ok(Config::new()),
+    };
+    let mut config = match config {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    if !cli.listen.is_empty() {
+        config.listen = cli.listen;
+    }
+    if !cli.upstreams.is_empty() {
+        config.resolver.upstreams = cli.upstreams;
+    }
+    if let Some(level) = cli.log_level {
+        config.log_level = level;
+    }
+
+    LogLevel::set(config.log_level);
+
+    let authority = match Authority::load(&config.zones) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            error!("Failed to load zones: {}", e);
+            process::exit(1);
+        }
+    };
+    // DoT, DoH and DoQ present the same certificate, but only DoH and DoQ
+    // have ALPN
+    let (tls, https, quic) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (&Some(ref cert), &Some(ref key)) => {
+            let configs = dns::tls::server_config(cert, key, &[]).and_then(|tls| {
+                let https = try!(dns::tls::server_config(cert, key, &dns::doh::ALPN));
+                let quic = try!(dns::tls::server_config(cert, key, &[dns::doq::ALPN]));
+                Ok((tls, https, try!(dns::doq::server_config(&quic))))
+            });
+            match configs {
+                Ok((tls, https, quic)) => (Some(tls), Some(https), Some(quic)),
                 Err(e) => {
-                    println!("Failed to encode UDP response packet: {:?}", e);
-                    continue;
+                    error!("Failed to load the TLS certificate: {}", e);
+                    process::exit(1);
                 }
-            };
+            }
+        }
+        _ => (None, None, None),
+    };
+    if cli.check_config {
+        println!("Configuration OK");
+        return;
+    }
 
-            let len = res_buffer.pos();
-            let data = match res_buffer.get_range(0, len) {
-                Ok(x) => x,
+    // Bind before detaching so that failures reach the terminal
+    let mut udp_sockets = Vec::new();
+    let mut tcp_listeners = Vec::new();
+    for addr in &config.listen {
+        if config.udp {
+            match UdpSocket::bind(addr) {
+                Ok(x) => udp_sockets.push(x),
                 Err(e) => {
-                    println!("Failed to retrieve response buffer: {:?}", e);
-                    continue;
+                    error!("Failed to bind UDP socket on {}: {:?}", addr, e);
+                    process::exit(1);
                 }
-            };
-
-            match socket.send_to(data, src) {
-                Ok(_) => {}
+            }
+        }
+        if config.tcp {
+            match TcpListener::bind(addr) {
+                Ok(x) => tcp_listeners.push(x),
                 Err(e) => {
-                    println!("Failed to send response buffer: {:?}", e);
-                    continue;
+                    error!("Failed to bind TCP socket on {}: {:?}", addr, e);
+                    process::exit(1);
                 }
-            };
+            }
+        }
+        info!("Listening on {}", addr);
+    }
+    let mut tls_listeners = Vec::new();
+    for addr in &config.tls_listen {
+        match TcpListener::bind(addr) {
+            Ok(x) => tls_listeners.push(x),
+            Err(e) => {
+                error!("Failed to bind TLS socket on {}: {:?}", addr, e);
+                process::exit(1);
+            }
+        }
+        info!("Listening on {} over TLS", addr);
+    }
+    let mut https_listeners = Vec::new();
+    for addr in &config.https_listen {
+        match TcpListener::bind(addr) {
+            Ok(x) => https_listeners.push(x),
+            Err(e) => {
+                error!("Failed to bind HTTPS socket on {}: {:?}", addr, e);
+                process::exit(1);
+            }
+        }
+        info!("Listening on {} over HTTPS", addr);
+    }
+    let mut quic_sockets = Vec::new();
+    for addr in &config.quic_listen {
+        match UdpSocket::bind(addr) {
+            Ok(x) => quic_sockets.push(x),
+            Err(e) => {
+                error!("Failed to bind QUIC socket on {}: {:?}", addr, e);
+                process::exit(1);
+            }
         }
+        info!("Listening on {} over QUIC", addr);
+    }
+    let mut metrics_listeners = Vec::new();
+    for addr in &config.metrics_listen {
+        match TcpListener::bind(addr) {
+            Ok(x) => metrics_listeners.push(x),
+            Err(e) => {
+                error!("Failed to bind metrics socket on {}: {:?}", addr, e);
+                process::exit(1);
+            }
+        }
+        info!("Serving metrics on http://{}{}", addr, metrics::PATH);
+    }
+
+    if !cli.foreground {
+        if let Err(e) = dns::daemon::daemonize() {
+            error!("Failed to run in the background: {:?}", e);
+            process::exit(1);
+        }
+    }
+    if let Some(ref path) = cli.pid_file {
+        if let Err(e) = dns::daemon::write_pid_file(path) {
+            error!("Failed to write pid file {}: {:?}", path, e);
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = dns::daemon::catch_hangup() {
+        error!("Failed to install the SIGHUP handler: {:?}", e);
+        process::exit(1);
+    }
+    {
+        let authority = authority.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if dns::daemon::take_hangup() {
+                info!("Reloading zones");
+                authority.reload();
+            }
+        });
+    }
+
+    let resolver = if config.recursion {
+        match Resolver::new(config.resolver) {
+            Ok(x) => Some(Arc::new(x)),
+            Err(e) => {
+                error!("Failed to set up the resolver: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let mut server = Server::new(authority.clone(), resolver);
+    server.keys = config.keys.clone();
+    server.query_log = match QueryLog::open(&config.query_log) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to open the query log: {}", e);
+            process::exit(1);
+        }
+    };
+    for config in config.zones.iter().filter(|z| z.is_secondary()) {
+        let secondary = Arc::new(Secondary::new(config.clone(), authority.clone()));
+        server.secondaries.push(secondary.clone());
+        thread::spawn(move || secondary.run());
+    }
+    let server = Arc::new(server);
+    let mut servers = Vec::new();
+    for socket in udp_sockets {
+        let server = server.clone();
+        servers.push(thread::spawn(move || serve_udp(server, socket)));
+    }
+    for listener in tcp_listeners {
+        let server = server.clone();
+        servers.push(thread::spawn(move || serve_tcp(server, listener)));
+    }
+    if let Some(tls) = tls {
+        for listener in tls_listeners {
+            let server = server.clone();
+            let tls = tls.clone();
+            servers.push(thread::spawn(move || serve_tls(server, listener, tls)));
+        }
+    }
+    if let Some(https) = https {
+        for listener in https_listeners {
+            let server = server.clone();
+            let https = https.clone();
+            servers.push(thread::spawn(move || serve_https(server, listener, https)));
+        }
+    }
+    if let Some(quic) = quic {
+        for socket in quic_sockets {
+            let server = server.clone();
+            let quic = quic.clone();
+            servers.push(thread::spawn(move || serve_quic(server, socket, quic)));
+        }
+    }
+    for listener in metrics_listeners {
+        servers.push(thread::spawn(move || serve_metrics(listener)));
+    }
+
+    for server in servers {
+        let _ = server.join();
     }
 }
@@ -0,0 +1,339 @@
+//! Counters of what the server is doing, served in the Prometheus text
+//! format at /metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use http::{Request, Response};
+use query_log::Transport;
+use query_type::QueryType;
+use result_code::ResultCode;
+
+pub const PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// Upper bounds of the upstream latency buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// What the server counts, from wherever it's counted.
+pub static METRICS: Metrics = Metrics::new();
+
+struct Histogram {
+    // Observations in each bucket alone, and then above the last
+    counts: [u64; 13],
+    sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: [0; 13],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = BUCKETS.iter().position(|&le| value <= le).unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+struct Counters {
+    // By transport, query type and response code
+    queries: BTreeMap<(&'static str, String, String), u64>,
+    // By transport
+    parse_failures: BTreeMap<&'static str, u64>,
+    // By upstream
+    upstream_latency: BTreeMap<String, Histogram>,
+    upstream_timeouts: BTreeMap<String, u64>,
+}
+
+pub struct Metrics {
+    in_flight: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    coalescer_exchanges: AtomicU64,
+    coalesced_queries: AtomicU64,
+    counters: Mutex<Counters>,
+}
+
+/// A query being answered, counted as in flight until this is dropped.
+pub struct InFlight<'a>(&'a Metrics);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            in_flight: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            coalescer_exchanges: AtomicU64::new(0),
+            coalesced_queries: AtomicU64::new(0),
+            counters: Mutex::new(Counters {
+                queries: BTreeMap::new(),
+                parse_failures: BTreeMap::new(),
+                upstream_latency: BTreeMap::new(),
+                upstream_timeouts: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Counts a query as in flight until the result is dropped.
+    pub fn start_query(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    /// Counts a query of type `qtype`, if it had a question, answered with
+    /// `rcode`.
+    pub fn answered(&self, transport: Transport, qtype: Option<QueryType>, rcode: ResultCode) {
+        let qtype = qtype.map(|qtype| qtype.to_string()).unwrap_or_default();
+        let key = (transport.name(), qtype, format!("{:?}", rcode));
+        *self.lock().queries.entry(key).or_insert(0) += 1;
+    }
+
+    /// Counts a request that couldn't be read.
+    pub fn parse_failure(&self, transport: Transport) {
+        *self.lock().parse_failures.entry(transport.name()).or_insert(0) += 1;
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_evictions(&self, count: usize) {
+        self.cache_evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Counts an exchange that identical queries arriving meanwhile share.
+    pub fn coalescer_exchange(&self) {
+        self.coalescer_exchanges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a query that shared another's exchange.
+    pub fn query_merged(&self) {
+        self.coalesced_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long `upstream` took to respond.
+    pub fn upstream_responded(&self, upstream: &str, latency: Duration) {
+        let mut counters = self.lock();
+        if !counters.upstream_latency.contains_key(upstream) {
+            counters.upstream_latency.insert(upstream.to_string(), Histogram::new());
+        }
+        if let Some(histogram) = counters.upstream_latency.get_mut(upstream) {
+            histogram.observe(latency.as_secs_f64());
+        }
+    }
+
+    /// Counts a query to `upstream` that went unanswered.
+    pub fn upstream_timed_out(&self, upstream: &str) {
+        let mut counters = self.lock();
+        if let Some(count) = counters.upstream_timeouts.get_mut(upstream) {
+            *count += 1;
+            return;
+        }
+        counters.upstream_timeouts.insert(upstream.to_string(), 1);
+    }
+
+    /// Everything counted, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = self.lock();
+
+        header(&mut out, "dns_queries_total", "counter", "Queries answered.");
+        for (&(transport, ref qtype, ref rcode), count) in &counters.queries {
+            let labels = [("transport", transport), ("type", qtype), ("rcode", rcode)];
+            sample(&mut out, "dns_queries_total", &labels, *count as f64);
+        }
+        let name = "dns_queries_in_flight";
+        header(&mut out, name, "gauge", "Queries being answered.");
+        sample(&mut out, name, &[], self.in_flight.load(Ordering::Relaxed) as f64);
+        let name = "dns_parse_failures_total";
+        header(&mut out, name, "counter", "Requests that couldn't be read.");
+        for (&transport, count) in &counters.parse_failures {
+            sample(&mut out, name, &[("transport", transport)], *count as f64);
+        }
+
+        let totals = [
+            ("dns_cache_hits_total", "Answers found in the cache.", &self.cache_hits),
+            ("dns_cache_misses_total", "Answers not found in the cache.", &self.cache_misses),
+            ("dns_cache_evictions_total", "Answers dropped to make room.", &self.cache_evictions),
+            (
+                "dns_coalescer_exchanges_total",
+                "Resolutions run for queries not already in flight.",
+                &self.coalescer_exchanges,
+            ),
+            (
+                "dns_coalesced_queries_total",
+                "Queries merged into an identical one in flight.",
+                &self.coalesced_queries,
+            ),
+        ];
+        for &(name, help, value) in &totals {
+            header(&mut out, name, "counter", help);
+            sample(&mut out, name, &[], value.load(Ordering::Relaxed) as f64);
+        }
+
+        let name = "dns_upstream_response_seconds";
+        header(&mut out, name, "histogram", "How long upstreams took to respond.");
+        for (upstream, histogram) in &counters.upstream_latency {
+            let mut total = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                total += count;
+                let le = BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+                let labels = [("upstream", upstream.as_str()), ("le", le.as_str())];
+                sample(&mut out, "dns_upstream_response_seconds_bucket", &labels, total as f64);
+            }
+            let labels = [("upstream", upstream.as_str())];
+            sample(&mut out, "dns_upstream_response_seconds_sum", &labels, histogram.sum);
+            sample(&mut out, "dns_upstream_response_seconds_count", &labels, total as f64);
+        }
+        let name = "dns_upstream_timeouts_total";
+        header(&mut out, name, "counter", "Queries servers didn't answer in time.");
+        for (upstream, count) in &counters.upstream_timeouts {
+            sample(&mut out, name, &[("upstream", upstream)], *count as f64);
+        }
+
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<Counters> {
+        match self.counters.lock() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// Answers a scrape of `METRICS`.
+pub fn respond(request: Request) -> Response {
+    if request.route() != PATH {
+        return Response::new(404);
+    }
+    if request.method != "GET" {
+        let mut response = Response::new(405);
+        response.add_header("allow", "GET");
+        return response;
+    }
+    let mut response = Response::new(200);
+    response.add_header("content-type", CONTENT_TYPE);
+    response.body = METRICS.render().into_bytes();
+    response
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|&(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect::<Vec<_>>();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_format() {
+        let metrics = Metrics::new();
+        {
+            let _in_flight = metrics.start_query();
+            // Dropped at once
+            let _ = metrics.start_query();
+            metrics.answered(Transport::Udp, Some(QueryType::A), ResultCode::NOERROR);
+            metrics.answered(Transport::Udp, Some(QueryType::A), ResultCode::NOERROR);
+            metrics.answered(Transport::Https, None, ResultCode::FORMERR);
+            assert!(metrics.render().contains("\ndns_queries_in_flight 1\n"));
+        }
+        metrics.parse_failure(Transport::Tcp);
+        metrics.cache_hit();
+        metrics.cache_miss();
+        metrics.cache_evictions(3);
+        metrics.coalescer_exchange();
+        metrics.query_merged();
+        metrics.query_merged();
+        metrics.upstream_responded("192.0.2.1:53", Duration::from_millis(20));
+        metrics.upstream_responded("192.0.2.1:53", Duration::from_secs(10));
+        metrics.upstream_timed_out("https://dns.example/\"q\"");
+
+        let text = metrics.render();
+        for line in &[
+            "# TYPE dns_queries_total counter",
+            "dns_queries_total{transport=\"udp\",type=\"A\",rcode=\"NOERROR\"} 2",
+            "dns_queries_total{transport=\"https\",type=\"\",rcode=\"FORMERR\"} 1",
+            "dns_queries_in_flight 0",
+            "dns_parse_failures_total{transport=\"tcp\"} 1",
+            "dns_cache_hits_total 1",
+            "dns_cache_misses_total 1",
+            "dns_cache_evictions_total 3",
+            "dns_coalescer_exchanges_total 1",
+            "dns_coalesced_queries_total 2",
+            "# TYPE dns_upstream_response_seconds histogram",
+            "dns_upstream_response_seconds_bucket{upstream=\"192.0.2.1:53\",le=\"0.01\"} 0",
+            "dns_upstream_response_seconds_bucket{upstream=\"192.0.2.1:53\",le=\"0.025\"} 1",
+            "dns_upstream_response_seconds_bucket{upstream=\"192.0.2.1:53\",le=\"5\"} 1",
+            "dns_upstream_response_seconds_bucket{upstream=\"192.0.2.1:53\",le=\"+Inf\"} 2",
+            "dns_upstream_response_seconds_sum{upstream=\"192.0.2.1:53\"} 10.02",
+            "dns_upstream_response_seconds_count{upstream=\"192.0.2.1:53\"} 2",
+            "dns_upstream_timeouts_total{upstream=\"https://dns.example/\\\"q\\\"\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "no {} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let request = |method: &str, path: &str| Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        let response = respond(request("GET", PATH));
+        assert_eq!(response.status, 200);
+        assert!(response.headers.contains(&("content-type".to_string(), CONTENT_TYPE.to_string())));
+        assert!(String::from_utf8(response.body).unwrap().contains("# TYPE dns_cache_hits_total"));
+        assert_eq!(respond(request("POST", PATH)).status, 405);
+        assert_eq!(respond(request("GET", "/")).status, 404);
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+
+/// An IP network in CIDR notation, such as `192.0.2.0/24`, as used in access
+/// control lists.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+/// `addr` with IPv4-mapped IPv6 addresses turned back into IPv4 ones.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xFFFF {
+                let octets = v6.octets();
+                return IpAddr::from([octets[12], octets[13], octets[14], octets[15]]);
+            }
+            addr
+        }
+        _ => addr,
+    }
+}
+
+fn bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl Network {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Network {
+        Network {
+            addr: canonical(addr),
+            prefix_len: prefix_len,
+        }
+    }
+
+    /// The network holding just `addr`.
+    pub fn host(addr: IpAddr) -> Network {
+        let addr = canonical(addr);
+        Network::new(addr, bits(addr).1)
+    }
+
+    /// Parses `address/prefix`, or a bare address for a single host.
+    pub fn parse(text: &str) -> Option<Network> {
+        let mut parts = text.splitn(2, '/');
+        let addr = match parts.next().and_then(|x| x.parse::<IpAddr>().ok()) {
+            Some(x) => canonical(x),
+            None => return None,
+        };
+        let max = bits(addr).1;
+        let prefix_len = match parts.next() {
+            Some(len) => match len.parse::<u8>() {
+                Ok(x) if x <= max => x,
+                _ => return None,
+            },
+            None => max,
+        };
+        Some(Network::new(addr, prefix_len))
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (network, len) = bits(self.addr);
+        let (addr, addr_len) = bits(canonical(addr));
+        if len != addr_len {
+            return false;
+        }
+        if self.prefix_len == 0 {
+            return true;
+        }
+
+        let shift = (len - self.prefix_len) as u32;
+        network >> shift == addr >> shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, addr: &str) -> bool {
+        Network::parse(network)
+            .unwrap()
+            .contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_addresses_in_the_network() {
+        assert!(contains("192.0.2.0/24", "192.0.2.200"));
+        assert!(!contains("192.0.2.0/24", "192.0.3.1"));
+        assert!(contains("192.0.2.7", "192.0.2.7"));
+        assert!(!contains("192.0.2.7", "192.0.2.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+        assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+        assert!(!contains("2001:db8::/32", "192.0.2.1"));
+        assert!(contains("127.0.0.0/8", "::ffff:127.0.0.1"));
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        assert_eq!(Network::parse("192.0.2.0/33"), None);
+        assert_eq!(Network::parse("example.com"), None);
+        assert_eq!(Network::parse("192.0.2.0/"), None);
+    }
+}
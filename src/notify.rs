@@ -0,0 +1,289 @@
+//! Outgoing NOTIFY messages (RFC 1996), telling secondaries that a zone
+//! changed so they don't have to wait for its refresh timer.
+
+use std::io::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use opcode::Opcode;
+use query_type::QueryType;
+use resolver;
+use result_code::ResultCode;
+
+// Sends per server, each waiting a second longer for the response
+const ATTEMPTS: u32 = 5;
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Tells each of `servers` in the background that the zone `name` now has
+/// `soa`, trying again while they don't respond.
+pub fn send(name: &str, soa: &DnsRecord, servers: &[SocketAddr]) {
+    for &server in servers {
+        let name = name.to_string();
+        let soa = soa.clone();
+        thread::spawn(move || notify_with_retries(&name, &soa, server, BACKOFF));
+    }
+}
+
+/// Sends NOTIFY to `server` until it's acknowledged, waiting `backoff`
+/// longer for each response than for the last. Tells whether it was.
+fn notify_with_retries(
+    name: &str,
+    soa: &DnsRecord,
+    server: SocketAddr,
+    backoff: Duration,
+) -> bool {
+    for attempt in 1..ATTEMPTS + 1 {
+        match notify(name, soa, server, backoff * attempt) {
+            Ok(()) => {
+                debug!("{} acknowledged NOTIFY for {}", server, name);
+                return true;
+            }
+            Err(e) => debug!("NOTIFY for {} to {} failed: {}", name, server, e),
+        }
+    }
+    warn!("Gave up sending NOTIFY for {} to {}", name, server);
+    false
+}
+
+/// Sends one NOTIFY for the zone `name` to `server`, and waits up to
+/// `timeout` for the response.
+pub fn notify(
+    name: &str,
+    soa: &DnsRecord,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let local: SocketAddr = if server.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(timeout)));
+
+    let mut request = DnsPacket::new();
+    request.header.id = resolver::random_id();
+    request.header.opcode = Opcode::NOTIFY;
+    request.header.authoritative_answer = true;
+    request
+        .questions
+        .push(DnsQuestion::new(name.to_string(), QueryType::SOA));
+    // The new SOA, as a hint (RFC 1996 section 3.7)
+    request.answers.push(soa.clone());
+
+    let mut req_buffer = BytePacketBuffer::new();
+    try!(request.write(&mut req_buffer));
+    try!(socket.send_to(&req_buffer.buf[0..req_buffer.pos], server));
+
+    loop {
+        let mut res_buffer = BytePacketBuffer::new();
+        let (_, src) = try!(socket.recv_from(&mut res_buffer.buf));
+        if src != server {
+            continue;
+        }
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if !response.header.response || response.header.id != request.header.id
+            || response.header.opcode != Opcode::NOTIFY
+        {
+            continue;
+        }
+
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(Error::other(
+                format!("answered with {:?}", response.header.rescode),
+            ));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use authority::Authority;
+    use secondary::Secondary;
+    use server::Server;
+    use zone::Zone;
+    use zone_config::ZoneConfig;
+
+    /// A server with a secondary zone `example.com` whose primary is
+    /// `primary`, answering one message.
+    fn secondary_server(primary: SocketAddr) -> SocketAddr {
+        let mut config = ZoneConfig::new("example.com");
+        config.primaries.push(primary);
+        let authority = Arc::new(Authority::new(vec![config.clone()]));
+        let mut server = Server::new(authority.clone(), None);
+        server
+            .secondaries
+            .push(Arc::new(Secondary::new(config, authority)));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let mut response = server.handle_query(&request, src.ip(), None);
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[..buffer.pos], src).unwrap();
+        });
+        addr
+    }
+
+    fn soa() -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 2,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn notifies_secondaries() {
+        let timeout = Duration::from_secs(2);
+        let server = secondary_server("127.0.0.1:53".parse().unwrap());
+        assert!(notify("example.com", &soa(), server, timeout).is_ok());
+
+        // Only the primaries are listened to
+        let server = secondary_server("192.0.2.1:53".parse().unwrap());
+        let e = notify("example.com", &soa(), server, timeout).unwrap_err();
+        assert_eq!(e.to_string(), "answered with REFUSED");
+    }
+
+    /// A server that hands over each NOTIFY it gets, with when it got it,
+    /// and answers with whatever `respond` makes of it.
+    fn stub<F>(respond: F) -> (SocketAddr, mpsc::Receiver<(DnsPacket, Instant)>)
+    where
+        F: Fn(&UdpSocket, &DnsPacket, SocketAddr) + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            if sender.send((request.clone(), Instant::now())).is_err() {
+                return;
+            }
+            respond(&socket, &request, src);
+        });
+        (addr, receiver)
+    }
+
+    fn respond(
+        socket: &UdpSocket,
+        request: &DnsPacket,
+        id: u16,
+        rescode: ResultCode,
+        to: SocketAddr,
+    ) {
+        let mut response = DnsPacket::new();
+        response.header.id = id;
+        response.header.response = true;
+        response.header.opcode = request.header.opcode;
+        response.header.rescode = rescode;
+        response.questions = request.questions.clone();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos], to).unwrap();
+    }
+
+    #[test]
+    fn backs_off_until_acknowledged() {
+        let backoff = Duration::from_millis(40);
+        let (silent, requests) = stub(|_, _, _| {});
+        assert!(!notify_with_retries("example.com", &soa(), silent, backoff));
+        let times = requests.try_iter().map(|(_, time)| time).collect::<Vec<_>>();
+        assert_eq!(times.len(), ATTEMPTS as usize);
+        for (i, pair) in times.windows(2).enumerate() {
+            assert!(pair[1] - pair[0] >= backoff * (i as u32 + 1));
+        }
+
+        // Answered on the third try
+        let tries = AtomicUsize::new(0);
+        let (server, requests) = stub(move |socket, request, src| {
+            if tries.fetch_add(1, Ordering::SeqCst) == 2 {
+                respond(socket, request, request.header.id, ResultCode::NOERROR, src);
+            }
+        });
+        assert!(notify_with_retries("example.com", &soa(), server, backoff));
+        assert_eq!(requests.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn ignores_responses_it_didnt_ask_for() {
+        let (server, _requests) = stub(|socket, request, src| {
+            let id = request.header.id;
+            // Each of these refuses, and would fail the NOTIFY if heeded
+            respond(socket, request, id.wrapping_add(1), ResultCode::REFUSED, src);
+            let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+            respond(&other, request, id, ResultCode::REFUSED, src);
+            let mut query = request.clone();
+            query.header.opcode = Opcode::QUERY;
+            respond(socket, &query, id, ResultCode::REFUSED, src);
+
+            respond(socket, request, id, ResultCode::NOERROR, src);
+        });
+        assert!(notify("example.com", &soa(), server, Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn notifies_every_configured_server() {
+        let acknowledge = |socket: &UdpSocket, request: &DnsPacket, src| {
+            respond(socket, request, request.header.id, ResultCode::NOERROR, src)
+        };
+        let (first, first_requests) = stub(acknowledge);
+        let (second, second_requests) = stub(acknowledge);
+        let mut config = ZoneConfig::new("example.com");
+        config.notify = vec![first, second];
+        let authority = Authority::new(vec![config]);
+
+        let zone = |serial| {
+            let mut soa = soa();
+            if let DnsRecord::SOA { serial: ref mut x, .. } = soa {
+                *x = serial;
+            }
+            Zone::new("example.com", vec![soa]).unwrap()
+        };
+        assert!(authority.insert(zone(1)));
+        assert!(authority.insert(zone(2)));
+        // Nothing changed, so there's nothing to tell
+        assert!(!authority.insert(zone(2)));
+
+        for requests in &[first_requests, second_requests] {
+            let timeout = Duration::from_secs(2);
+            for serial in 1..3 {
+                let (request, _) = requests.recv_timeout(timeout).unwrap();
+                assert_eq!(request.header.opcode, Opcode::NOTIFY);
+                assert!(request.header.authoritative_answer);
+                assert_eq!(request.questions[0].name, "example.com");
+                assert_eq!(request.questions[0].qtype, QueryType::SOA);
+                match request.answers[0] {
+                    DnsRecord::SOA { serial: x, .. } => assert_eq!(x, serial),
+                    _ => panic!("expected the new SOA"),
+                }
+            }
+            assert!(requests.recv_timeout(Duration::from_millis(100)).is_err());
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// The kind of message, from the header's 4-bit OPCODE field.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Opcode {
+    UNKNOWN(u8),
+    QUERY,  // 0
+    IQUERY, // 1
+    STATUS, // 2
+    NOTIFY, // 4, RFC 1996
+    UPDATE, // 5, RFC 2136
+}
+
+impl Opcode {
+    pub fn to_num(&self) -> u8 {
+        match *self {
+            Opcode::UNKNOWN(x) => x & 0x0F,
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(num),
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Opcode::UNKNOWN(x) => write!(f, "OPCODE{}", x),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
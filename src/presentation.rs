@@ -0,0 +1,310 @@
+// Helpers for the RFC 1035 presentation format used by zone files and dig.
+
+/// `name` as an absolute domain name, with special characters escaped.
+pub fn name(name: &str) -> String {
+    let mut res = String::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        for &b in label.as_bytes() {
+            match b {
+                b'"' | b'(' | b')' | b';' | b'\\' | b'@' | b'$' => {
+                    res.push('\\');
+                    res.push(b as char);
+                }
+                0x21..=0x7E => res.push(b as char),
+                _ => res.push_str(&format!("\\{:03}", b)),
+            }
+        }
+        res.push('.');
+    }
+
+    if res.is_empty() {
+        res.push('.');
+    }
+    res
+}
+
+/// `data` as a quoted character-string.
+pub fn character_string(data: &[u8]) -> String {
+    let mut res = String::from("\"");
+    for &b in data {
+        match b {
+            b'"' | b'\\' => {
+                res.push('\\');
+                res.push(b as char);
+            }
+            0x20..=0x7E => res.push(b as char),
+            _ => res.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut res = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Base 32 with the extended hex alphabet and no padding, as NSEC3 owner
+/// names use (RFC 5155 section 3.3).
+pub fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+    let mut res = String::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &b in data {
+        bits = (bits << 8) | b as u32;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            res.push(ALPHABET[(bits >> count & 0x1F) as usize] as char);
+        }
+    }
+    if count > 0 {
+        res.push(ALPHABET[(bits << (5 - count) & 0x1F) as usize] as char);
+    }
+    res
+}
+
+/// A time in seconds since the epoch as YYYYMMDDHHmmSS (RFC 4034 section 3.2).
+pub fn timestamp(secs: u32) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil from days, counting in 400 year eras that begin on March 1st
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+fn decode_bits(text: &str, alphabet: &[u8], bits_per_char: u32) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = match alphabet.iter().position(|&a| a == c.to_ascii_uppercase()) {
+            Some(x) => x as u32,
+            None => return None,
+        };
+        bits = (bits << bits_per_char) | value;
+        count += bits_per_char;
+        if count >= 8 {
+            count -= 8;
+            res.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // Leftover bits are padding and have to be zero
+    if bits != 0 {
+        return None;
+    }
+    Some(res)
+}
+
+pub fn parse_base64(text: &str) -> Option<Vec<u8>> {
+    let data = text.trim_end_matches('=');
+    if text.len() % 4 != 0 || text.len() - data.len() > 2 {
+        return None;
+    }
+
+    let mut res = Vec::new();
+    for chunk in data.as_bytes().chunks(4) {
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            res.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(res)
+}
+
+pub fn parse_base32hex(text: &str) -> Option<Vec<u8>> {
+    decode_bits(text, b"0123456789ABCDEFGHIJKLMNOPQRSTUV", 5)
+}
+
+/// Parses a YYYYMMDDHHmmSS time, or a plain number of seconds since the
+/// epoch (RFC 4034 section 3.2).
+pub fn parse_timestamp(text: &str) -> Option<u32> {
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if text.len() != 14 {
+        return text.parse::<u32>().ok();
+    }
+
+    let field = |start: usize, len: usize| text[start..start + len].parse::<i64>().unwrap_or(0);
+    let (year, month, day) = (field(0, 4), field(4, 2), field(6, 2));
+    let (hour, minute, second) = (field(8, 2), field(10, 2), field(12, 2));
+    if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // Days from civil, the inverse of `timestamp`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    if secs < 0 || secs > u32::max_value() as i64 {
+        return None;
+    }
+    Some(secs as u32)
+}
+
+pub fn class_name(class: u16) -> String {
+    match class {
+        1 => "IN".to_string(),
+        3 => "CH".to_string(),
+        4 => "HS".to_string(),
+        254 => "NONE".to_string(),
+        255 => "ANY".to_string(),
+        _ => format!("CLASS{}", class),
+    }
+}
+
+/// Parses a class mnemonic, or the generic `CLASS<n>` form (RFC 3597).
+pub fn parse_class(class: &str) -> Option<u16> {
+    let class = class.to_ascii_uppercase();
+    match class.as_str() {
+        "IN" => Some(1),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        "NONE" => Some(254),
+        "ANY" => Some(255),
+        _ if class.starts_with("CLASS") => class[5..].parse::<u16>().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_names() {
+        assert_eq!(name(""), ".");
+        assert_eq!(name("www.example.com"), "www.example.com.");
+        assert_eq!(name("a b;c.example"), "a\\032b\\;c.example.");
+        assert_eq!(name("\"q\\@$(x)\""), "\\\"q\\\\\\@\\$\\(x\\)\\\".");
+    }
+
+    #[test]
+    fn escapes_character_strings() {
+        assert_eq!(character_string(b"v=spf1 -all"), "\"v=spf1 -all\"");
+        assert_eq!(character_string(b"say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(character_string(b"tab\there\x00\xFF"), "\"tab\\009here\\000\\255\"");
+    }
+
+    #[test]
+    fn encodes_binary_data() {
+        assert_eq!(hex(&[0x00, 0xAB, 0x1F]), "00AB1F");
+        // RFC 4648 section 10
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base32hex(b"f"), "CO");
+        assert_eq!(base32hex(b"foobar"), "CPNMUOJ1E8");
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(0), "19700101000000");
+        // RFC 8080 section 6.1 example validity period
+        assert_eq!(timestamp(1440021600), "20150819220000");
+        assert_eq!(timestamp(1709164800), "20240229000000");
+    }
+
+    #[test]
+    fn decodes_binary_data() {
+        assert_eq!(parse_hex("00ab1F"), Some(vec![0x00, 0xAB, 0x1F]));
+        assert_eq!(parse_hex("0"), None);
+        assert_eq!(parse_base64("Zm9vYmFy"), Some(b"foobar".to_vec()));
+        assert_eq!(parse_base64("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(parse_base64("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(parse_base64("Zg="), None);
+        assert_eq!(parse_base64("Z!=="), None);
+        assert_eq!(parse_base32hex("cpnmuoj1e8"), Some(b"foobar".to_vec()));
+        assert_eq!(parse_base32hex("CO"), Some(b"f".to_vec()));
+        assert_eq!(parse_base32hex("CP"), None);
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        for &secs in &[0, 1440021600, 1709164800, u32::max_value()] {
+            assert_eq!(parse_timestamp(&timestamp(secs)), Some(secs));
+        }
+        assert_eq!(parse_timestamp("1440021600"), Some(1440021600));
+        assert_eq!(parse_timestamp("20151319220000"), None);
+        assert_eq!(parse_timestamp("2015081922000x"), None);
+    }
+
+    #[test]
+    fn parses_classes() {
+        assert_eq!(parse_class("in"), Some(1));
+        assert_eq!(parse_class("CLASS42"), Some(42));
+        assert_eq!(parse_class("CLASSY"), None);
+        assert_eq!(class_name(42), "CLASS42");
+    }
+}
@@ -0,0 +1,13 @@
+/// How much of the query name iterative resolution reveals to the servers
+/// above the zone that holds it (RFC 9156).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QnameMinimisation {
+    /// Every server sees the full name
+    Off,
+    /// Falls back to the full name when a server fails a minimised query or
+    /// answers NXDOMAIN for an empty non-terminal
+    Relaxed,
+    /// Takes NXDOMAIN for a minimised name to cover everything below it
+    /// (RFC 8020) and never falls back
+    Strict,
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use dns_packet::DnsPacket;
+use dnssec::Security;
+use metrics::METRICS;
+use query_type::QueryType;
+
+/// Name (lowercased), type, class, DO and CD bits of a client query.
+pub type QueryKey = (String, QueryType, u16, bool, bool);
+
+/// An answer, how secure it is, and the upstream that gave it, if one did.
+pub type Resolved = (DnsPacket, Security, Option<String>);
+
+type Outcome = Result<Resolved, (ErrorKind, String)>;
+
+struct Exchange {
+    outcome: Mutex<Option<Outcome>>,
+    done: Condvar,
+}
+
+/// Finishes the exchange for `key` when dropped: with `outcome`, or with an
+/// error if the leader unwound before it had one.
+struct Leader<'a> {
+    coalescer: &'a QueryCoalescer,
+    key: &'a QueryKey,
+    exchange: Arc<Exchange>,
+    outcome: Option<Outcome>,
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        lock(&self.coalescer.pending).remove(self.key);
+        let outcome = self
+            .outcome
+            .take()
+            .unwrap_or_else(|| Err((ErrorKind::Other, "In-flight query failed".to_string())));
+        *lock(&self.exchange.outcome) = Some(outcome);
+        self.exchange.done.notify_all();
+    }
+}
+
+/// Lets concurrent identical queries share a single upstream exchange.
+pub struct QueryCoalescer {
+    pending: Mutex<HashMap<QueryKey, Arc<Exchange>>>,
+    exchanges: AtomicUsize,
+    merged: AtomicUsize,
+}
+
+impl QueryCoalescer {
+    pub fn new() -> QueryCoalescer {
+        QueryCoalescer {
+            pending: Mutex::new(HashMap::new()),
+            exchanges: AtomicUsize::new(0),
+            merged: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many exchanges have been run.
+    pub fn exchanges(&self) -> usize {
+        self.exchanges.load(Ordering::Relaxed)
+    }
+
+    /// How many queries have waited on another's exchange instead.
+    pub fn merged(&self) -> usize {
+        self.merged.load(Ordering::Relaxed)
+    }
+
+    /// Runs `resolve` for `key`, unless another thread already is, in which
+    /// case this waits for and returns its result.
+    pub fn run<F>(&self, key: QueryKey, resolve: F) -> Result<Resolved, Error>
+    where
+        F: FnOnce() -> Result<Resolved, Error>,
+    {
+        let (exchange, leader) = {
+            let mut pending = lock(&self.pending);
+            match pending.get(&key) {
+                Some(exchange) => (exchange.clone(), false),
+                None => {
+                    let exchange = Arc::new(Exchange {
+                        outcome: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    pending.insert(key.clone(), exchange.clone());
+                    (exchange, true)
+                }
+            }
+        };
+
+        if leader {
+            self.exchanges.fetch_add(1, Ordering::Relaxed);
+            METRICS.coalescer_exchange();
+            let mut leader = Leader {
+                coalescer: self,
+                key: &key,
+                exchange: exchange,
+                outcome: None,
+            };
+            let outcome = resolve().map_err(|e| (e.kind(), e.to_string()));
+            leader.outcome = Some(outcome.clone());
+            drop(leader);
+            return outcome.map_err(|(kind, msg)| Error::new(kind, msg));
+        }
+
+        let merged = self.merged.fetch_add(1, Ordering::Relaxed) + 1;
+        METRICS.query_merged();
+        debug!(
+            "Merged query for {} {:?} into one in flight ({} merged, {} exchanges)",
+            key.0,
+            key.1,
+            merged,
+            self.exchanges.load(Ordering::Relaxed)
+        );
+
+        let mut outcome = lock(&exchange.outcome);
+        while outcome.is_none() {
+            outcome = match exchange.done.wait(outcome) {
+                Ok(x) => x,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+        match *outcome {
+            Some(Ok(ref res)) => Ok(res.clone()),
+            Some(Err((kind, ref msg))) => Err(Error::new(kind, msg.clone())),
+            None => Err(Error::other("Lost in-flight query")),
+        }
+    }
+}
+
+impl Default for QueryCoalescer {
+    fn default() -> QueryCoalescer {
+        QueryCoalescer::new()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> ::std::sync::MutexGuard<T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn merges_concurrent_queries() {
+        let coalescer = Arc::new(QueryCoalescer::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let threads = (0..8)
+            .map(|_| {
+                let coalescer = coalescer.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    let key = ("example.com".to_string(), QueryType::A, 1, false, false);
+                    coalescer.run(key, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(200));
+                        Ok((DnsPacket::new(), Security::Insecure, None))
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in threads {
+            assert!(t.join().unwrap().is_ok());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!((coalescer.exchanges(), coalescer.merged()), (1, 7));
+    }
+
+    #[test]
+    fn shares_errors_and_forgets_finished_queries() {
+        let coalescer = QueryCoalescer::new();
+        let key = ("example.com".to_string(), QueryType::A, 1, false, false);
+
+        let res = coalescer.run(key.clone(), || Err(Error::new(ErrorKind::TimedOut, "timeout")));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+
+        // The next query starts a new exchange
+        assert!(coalescer.run(key, || Ok((DnsPacket::new(), Security::Secure, None))).is_ok());
+        assert_eq!((coalescer.exchanges(), coalescer.merged()), (2, 0));
+    }
+
+    #[test]
+    fn releases_followers_when_the_leader_panics() {
+        let coalescer = Arc::new(QueryCoalescer::new());
+        let key = ("example.com".to_string(), QueryType::A, 1, false, false);
+
+        let leader = {
+            let coalescer = coalescer.clone();
+            let key = key.clone();
+            thread::spawn(move || {
+                coalescer.run(key, || {
+                    thread::sleep(Duration::from_millis(200));
+                    panic!("resolver bug")
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        let res = coalescer.run(key.clone(), || Ok((DnsPacket::new(), Security::Secure, None)));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Other);
+        assert!(leader.join().is_err());
+        assert_eq!(coalescer.merged(), 1);
+
+        // Nothing is left waiting on the failed exchange
+        assert!(coalescer.run(key, || Ok((DnsPacket::new(), Security::Secure, None))).is_ok());
+        assert_eq!(coalescer.exchanges(), 2);
+    }
+}
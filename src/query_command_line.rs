@@ -0,0 +1,254 @@
+use std::net::IpAddr;
+
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use presentation;
+use query_type::QueryType;
+use tsig::TsigKey;
+
+pub const USAGE: &str = "Usage: dnsq [@server] [-p port] name [type] [class] [+options]
+
+Sends a DNS query and prints the response.
+
+  @server      Server to ask, by default the first nameserver in
+               /etc/resolv.conf
+  -p port      Port to send to (53)
+  -y [alg:]name:secret
+               Sign the query with a TSIG key, hmac-sha256 unless another
+               algorithm is given, and check the response's signature
+  type         Record type, such as A, MX or TYPE65 (A)
+  class        IN, CH, HS, ANY or CLASS<n> (IN)
+
+Options:
+  +[no]tcp      Query over TCP instead of UDP
+  +[no]recurse  Ask the server to recurse (on)
+  +[no]dnssec   Request DNSSEC records (off)
+  +edns=size    Advertise an EDNS buffer size (1232)
+  +noedns       Leave out EDNS
+  -h, --help    Show this help";
+
+/// The arguments of dnsq, which sends a query and prints the response.
+#[derive(Debug, PartialEq)]
+pub struct QueryCommandLine {
+    pub server: Option<IpAddr>,
+    pub port: u16,
+    pub name: Option<String>,
+    pub qtype: QueryType,
+    pub class: u16,
+    pub tcp: bool,
+    pub recurse: bool,
+    pub dnssec: bool,
+    pub edns: Option<u16>,
+    pub key: Option<TsigKey>,
+}
+
+impl QueryCommandLine {
+    pub fn new() -> QueryCommandLine {
+        QueryCommandLine {
+            server: None,
+            port: 53,
+            name: None,
+            qtype: QueryType::A,
+            class: 1,
+            tcp: false,
+            recurse: true,
+            dnssec: false,
+            edns: Some(1232),
+            key: None,
+        }
+    }
+
+    /// Parses `args`, less the program name. The name comes first, then
+    /// the type and class in either order.
+    pub fn parse(args: &[String]) -> Result<QueryCommandLine, String> {
+        let mut query = QueryCommandLine::new();
+        let mut has_type = false;
+        let mut has_class = false;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg.starts_with('@') {
+                query.server = Some(try!(arg[1..]
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("Invalid server {}", &arg[1..]))));
+            } else if arg == "-p" {
+                let port = try!(args.next().ok_or("-p needs a port".to_string()));
+                query.port = try!(port
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid port {}", port)));
+            } else if arg == "-y" {
+                let key = try!(args.next().ok_or("-y needs a key".to_string()));
+                query.key = Some(try!(parse_key(key)));
+            } else if arg.starts_with('+') {
+                try!(query.set_option(&arg[1..]));
+            } else if arg.starts_with('-') {
+                return Err(format!("Unknown option {}", arg));
+            } else if query.name.is_none() {
+                query.name = Some(arg.trim_end_matches('.').to_string());
+            } else if let (false, Some(qtype)) = (has_type, QueryType::from_name(arg)) {
+                query.qtype = qtype;
+                has_type = true;
+            } else if let (false, Some(class)) = (has_class, presentation::parse_class(arg)) {
+                query.class = class;
+                has_class = true;
+            } else {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+        }
+
+        if query.name.is_none() {
+            return Err("No name to query".to_string());
+        }
+        Ok(query)
+    }
+
+    fn set_option(&mut self, option: &str) -> Result<(), String> {
+        if option.starts_with("edns=") {
+            let size = &option[5..];
+            self.edns = Some(try!(size
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid EDNS size {}", size))));
+            return Ok(());
+        }
+
+        match option {
+            "tcp" => self.tcp = true,
+            "notcp" => self.tcp = false,
+            "recurse" => self.recurse = true,
+            "norecurse" => self.recurse = false,
+            "dnssec" => self.dnssec = true,
+            "nodnssec" => self.dnssec = false,
+            "edns" => self.edns = Some(1232),
+            "noedns" => self.edns = None,
+            _ => return Err(format!("Unknown option +{}", option)),
+        }
+        Ok(())
+    }
+
+    /// The query to send, with ID `id`.
+    pub fn packet(&self, id: u16) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.recursion_desired = self.recurse;
+
+        let mut question = DnsQuestion::new(self.name.clone().unwrap_or_default(), self.qtype);
+        question.class = self.class;
+        packet.questions.push(question);
+
+        // DNSSEC needs EDNS to ask for signatures
+        if self.edns.is_some() || self.dnssec {
+            packet.set_edns(self.edns.unwrap_or(1232), self.dnssec);
+        }
+        packet
+    }
+}
+
+impl Default for QueryCommandLine {
+    fn default() -> QueryCommandLine {
+        QueryCommandLine::new()
+    }
+}
+
+/// A TSIG key given as `[algorithm:]name:secret`, the secret in base64.
+fn parse_key(text: &str) -> Result<TsigKey, String> {
+    let fields = text.split(':').collect::<Vec<&str>>();
+    let (algorithm, name, secret) = match fields.len() {
+        2 => ("hmac-sha256", fields[0], fields[1]),
+        3 => (fields[0], fields[1], fields[2]),
+        _ => return Err(format!("Invalid key {}", text)),
+    };
+    if !TsigKey::is_supported(algorithm) {
+        return Err(format!("Unsupported TSIG algorithm {}", algorithm));
+    }
+    let secret = try!(presentation::parse_base64(secret)
+        .ok_or_else(|| format!("Invalid base64 secret for key {}", name)));
+    Ok(TsigKey::new(name, algorithm, secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<QueryCommandLine, String> {
+        QueryCommandLine::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let query = parse(&[
+            "@192.0.2.53",
+            "-p",
+            "5353",
+            "example.com.",
+            "CH",
+            "TXT",
+            "+tcp",
+            "+norecurse",
+            "+dnssec",
+            "+edns=4096",
+            "-y",
+            "hmac-sha512:query.key:c2VjcmV0",
+        ]).unwrap();
+
+        assert_eq!(query.server, Some("192.0.2.53".parse().unwrap()));
+        assert_eq!(query.port, 5353);
+        assert_eq!(query.name, Some("example.com".to_string()));
+        assert_eq!(query.qtype, QueryType::TXT);
+        assert_eq!(query.class, 3);
+        assert!(query.tcp && !query.recurse && query.dnssec);
+        assert_eq!(query.edns, Some(4096));
+        assert_eq!(query.key, Some(TsigKey::new("query.key", "hmac-sha512", b"secret".to_vec())));
+
+        // Type and class either way round, and later flags win
+        let query = parse(&["example.com", "TXT", "CH", "+tcp", "+notcp", "+noedns"]).unwrap();
+        assert_eq!((query.qtype, query.class), (QueryType::TXT, 3));
+        assert!(!query.tcp);
+        assert_eq!(query.edns, None);
+
+        let mut expected = QueryCommandLine::new();
+        expected.name = Some("example.com".to_string());
+        assert_eq!(parse(&["example.com"]), Ok(expected));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse(&[]), Err("No name to query".to_string()));
+        assert_eq!(
+            parse(&["@dns.example", "example.com"]),
+            Err("Invalid server dns.example".to_string())
+        );
+        assert_eq!(parse(&["-p"]), Err("-p needs a port".to_string()));
+        assert_eq!(parse(&["-p", "domain"]), Err("Invalid port domain".to_string()));
+        assert_eq!(parse(&["-y", "query.key"]), Err("Invalid key query.key".to_string()));
+        assert_eq!(
+            parse(&["-y", "md4:query.key:c2VjcmV0"]),
+            Err("Unsupported TSIG algorithm md4".to_string())
+        );
+        assert_eq!(parse(&["+edns=big"]), Err("Invalid EDNS size big".to_string()));
+        assert_eq!(parse(&["+json"]), Err("Unknown option +json".to_string()));
+        assert_eq!(parse(&["-x"]), Err("Unknown option -x".to_string()));
+        assert_eq!(
+            parse(&["example.com", "A", "IN", "MX"]),
+            Err("Unexpected argument MX".to_string())
+        );
+    }
+
+    #[test]
+    fn builds_the_query() {
+        let packet = parse(&["example.com", "MX", "+norecurse"]).unwrap().packet(7);
+        assert_eq!(packet.header.id, 7);
+        assert!(!packet.header.recursion_desired);
+        assert_eq!(packet.questions[0].name, "example.com");
+        assert_eq!(packet.questions[0].qtype, QueryType::MX);
+        assert_eq!(packet.edns_payload_size(), Some(1232));
+        assert!(!packet.dnssec_ok());
+
+        // DNSSEC brings EDNS back
+        let packet = parse(&["example.com", "+noedns", "+dnssec"]).unwrap().packet(7);
+        assert_eq!(packet.edns_payload_size(), Some(1232));
+        assert!(packet.dnssec_ok());
+
+        let packet = parse(&["example.com", "+noedns"]).unwrap().packet(7);
+        assert_eq!(packet.get_edns(), None);
+    }
+}
@@ -0,0 +1,674 @@
+//! A log of the queries answered: who asked what over which transport, the
+//! outcome, how long it took and where the answer came from. Entries are
+//! written on a thread of their own as JSON lines and as dnstap, Frame
+//! Streams of protobuf messages (https://dnstap.info), to a file or to a
+//! reader listening on a Unix socket.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use presentation;
+use resolver::AnswerSource;
+use result_code::ResultCode;
+
+// Entries waiting to be written, beyond which they're dropped rather than
+// hold up answers
+const QUEUE: usize = 10000;
+// How often a dnstap socket that's gone away is tried again
+const RECONNECT: Duration = Duration::from_secs(5);
+// How long a dnstap reader has to answer its control frames
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+// Control frames are short; anything longer isn't one
+const MAX_CONTROL: usize = 512;
+
+// dnstap.proto's Message.Type values
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+
+/// How a query reached us.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+            Transport::Quic => "quic",
+        }
+    }
+
+    /// Its dnstap SocketProtocol.
+    fn protocol(&self) -> u64 {
+        match *self {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+            Transport::Tls => 3,
+            Transport::Https => 4,
+            Transport::Quic => 7,
+        }
+    }
+}
+
+/// Where query log entries go. Each is optional.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryLogConfig {
+    // A file JSON lines are appended to
+    pub file: Option<String>,
+    // A file dnstap is written to, replacing what it held
+    pub dnstap_file: Option<String>,
+    // The Unix socket a dnstap reader listens on
+    pub dnstap_socket: Option<String>,
+}
+
+impl QueryLogConfig {
+    pub fn new() -> QueryLogConfig {
+        QueryLogConfig {
+            file: None,
+            dnstap_file: None,
+            dnstap_socket: None,
+        }
+    }
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> QueryLogConfig {
+        QueryLogConfig::new()
+    }
+}
+
+/// A query as it's answered, for the log.
+pub struct Entry {
+    client: SocketAddr,
+    transport: Transport,
+    time: SystemTime,
+    started: Instant,
+    latency: Duration,
+    question: Option<DnsQuestion>,
+    rcode: ResultCode,
+    source: AnswerSource,
+    query: Vec<u8>,
+    response: Vec<u8>,
+}
+
+impl Entry {
+    /// An entry for the query `query` from `client`, which has just arrived.
+    pub fn start(client: SocketAddr, transport: Transport, query: &[u8]) -> Entry {
+        Entry {
+            client: client,
+            transport: transport,
+            time: SystemTime::now(),
+            started: Instant::now(),
+            latency: Duration::from_secs(0),
+            question: None,
+            rcode: ResultCode::NOERROR,
+            source: AnswerSource::default(),
+            query: query.to_vec(),
+            response: Vec::new(),
+        }
+    }
+
+    fn json(&self) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            time: String,
+            client: String,
+            transport: &'static str,
+            question: Option<&'a DnsQuestion>,
+            rcode: String,
+            latency_ms: f64,
+            cache_hit: bool,
+            upstream: Option<&'a str>,
+        }
+
+        let line = Line {
+            time: rfc3339(self.time),
+            client: self.client.to_string(),
+            transport: self.transport.name(),
+            question: self.question.as_ref(),
+            rcode: format!("{:?}", self.rcode),
+            latency_ms: (self.latency.as_secs_f64() * 1e6).round() / 1e3,
+            cache_hit: self.source.cache_hit,
+            upstream: self.source.upstream.as_ref().map(|x| x.as_str()),
+        };
+        serde_json::to_string(&line).unwrap_or_default()
+    }
+
+    /// The dnstap CLIENT_QUERY or CLIENT_RESPONSE message for it.
+    fn dnstap(&self, kind: u64) -> Vec<u8> {
+        let mut message = Vec::new();
+        put_varint_field(&mut message, 1, kind);
+        let (family, address) = match self.client.ip() {
+            IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        put_varint_field(&mut message, 2, family);
+        put_varint_field(&mut message, 3, self.transport.protocol());
+        put_bytes_field(&mut message, 4, &address);
+        put_varint_field(&mut message, 6, u64::from(self.client.port()));
+
+        let since = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_varint_field(&mut message, 8, since.as_secs());
+        put_fixed32_field(&mut message, 9, since.subsec_nanos());
+        if kind == CLIENT_QUERY {
+            put_bytes_field(&mut message, 10, &self.query);
+        } else {
+            let since = since + self.latency;
+            put_varint_field(&mut message, 12, since.as_secs());
+            put_fixed32_field(&mut message, 13, since.subsec_nanos());
+            put_bytes_field(&mut message, 14, &self.response);
+        }
+
+        let mut dnstap = Vec::new();
+        put_bytes_field(&mut dnstap, 2, concat!("dns ", env!("CARGO_PKG_VERSION")).as_bytes());
+        if kind == CLIENT_RESPONSE {
+            // What dnstap has no field for
+            let extra = serde_json::json!({
+                "cache_hit": self.source.cache_hit,
+                "upstream": self.source.upstream,
+            });
+            put_bytes_field(&mut dnstap, 3, extra.to_string().as_bytes());
+        }
+        put_bytes_field(&mut dnstap, 14, &message);
+        // Type MESSAGE
+        put_varint_field(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+/// Writes entries to the configured outputs on a thread of its own, which
+/// finishes them off when this is dropped.
+pub struct QueryLog {
+    sender: Option<SyncSender<Entry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl QueryLog {
+    /// The log `config` asks for, or None if it asks for none.
+    pub fn open(config: &QueryLogConfig) -> Result<Option<QueryLog>, Error> {
+        let mut outputs = Vec::new();
+        if let Some(ref path) = config.file {
+            let file = try!(OpenOptions::new().create(true).append(true).open(path));
+            outputs.push(Output::Json(BufWriter::new(file)));
+        }
+        if let Some(ref path) = config.dnstap_file {
+            let mut file = BufWriter::new(try!(File::create(path)));
+            try!(file.write_all(&control_frame(CONTROL_START)));
+            outputs.push(Output::DnstapFile(file));
+        }
+        if let Some(ref path) = config.dnstap_socket {
+            // The reader may not be up yet, and is tried again later
+            let stream = match connect(path) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    warn!("Failed to connect to dnstap socket {}: {}", path, e);
+                    None
+                }
+            };
+            outputs.push(Output::DnstapSocket(path.clone(), stream, Instant::now()));
+        }
+        if outputs.is_empty() {
+            return Ok(None);
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        let writer = thread::spawn(move || write_entries(receiver, outputs));
+        Ok(Some(QueryLog {
+            sender: Some(sender),
+            writer: Some(writer),
+        }))
+    }
+
+    /// Logs the answer `response`, sent as `answer`, to the query `request`
+    /// that `entry` was started for.
+    pub fn log(
+        &self,
+        mut entry: Entry,
+        request: &DnsPacket,
+        response: &DnsPacket,
+        answer: &[u8],
+        source: AnswerSource,
+    ) {
+        entry.latency = entry.started.elapsed();
+        entry.question = request.questions.first().cloned();
+        entry.rcode = response.header.rescode;
+        entry.source = source;
+        entry.response = answer.to_vec();
+
+        if let Some(ref sender) = self.sender {
+            if let Err(TrySendError::Full(_)) = sender.try_send(entry) {
+                debug!("Query log is behind, dropped an entry");
+            }
+        }
+    }
+}
+
+impl Drop for QueryLog {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Output {
+    Json(BufWriter<File>),
+    DnstapFile(BufWriter<File>),
+    // The socket's path, the connection to it if there is one, and when
+    // it was last connected to
+    DnstapSocket(String, Option<UnixStream>, Instant),
+}
+
+impl Output {
+    fn write(&mut self, entry: &Entry) -> Result<(), Error> {
+        match *self {
+            Output::Json(ref mut file) => {
+                try!(file.write_all(entry.json().as_bytes()));
+                file.write_all(b"\n")
+            }
+            Output::DnstapFile(ref mut file) => write_dnstap(file, entry),
+            Output::DnstapSocket(ref path, ref mut stream, ref mut tried) => {
+                if stream.is_none() && tried.elapsed() >= RECONNECT {
+                    *tried = Instant::now();
+                    *stream = Some(try!(connect(path)));
+                    info!("Connected to dnstap socket {}", path);
+                }
+                let written = match *stream {
+                    Some(ref mut stream) => write_dnstap(stream, entry),
+                    None => return Ok(()),
+                };
+                if written.is_err() {
+                    *stream = None;
+                }
+                written
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            Output::Json(ref mut file) | Output::DnstapFile(ref mut file) => file.flush(),
+            Output::DnstapSocket(..) => Ok(()),
+        }
+    }
+
+    /// Ends the frame stream, and waits for the reader to acknowledge it.
+    fn close(&mut self) -> Result<(), Error> {
+        match *self {
+            Output::Json(ref mut file) => file.flush(),
+            Output::DnstapFile(ref mut file) => {
+                try!(file.write_all(&control_frame(CONTROL_STOP)));
+                file.flush()
+            }
+            Output::DnstapSocket(_, ref mut stream, _) => match stream.take() {
+                Some(mut stream) => {
+                    try!(stream.write_all(&control_frame(CONTROL_STOP)));
+                    expect_control(&mut stream, CONTROL_FINISH)
+                }
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// Writes what arrives on `receiver` to `outputs`, flushing whenever it
+/// catches up, until the log is dropped.
+fn write_entries(receiver: Receiver<Entry>, mut outputs: Vec<Output>) {
+    // Failures are reported once until the output recovers
+    let mut failing = vec![false; outputs.len()];
+    loop {
+        let entry = match receiver.try_recv() {
+            Ok(x) => x,
+            Err(TryRecvError::Empty) => {
+                for output in outputs.iter_mut() {
+                    let _ = output.flush();
+                }
+                match receiver.recv() {
+                    Ok(x) => x,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        for (output, failing) in outputs.iter_mut().zip(failing.iter_mut()) {
+            match output.write(&entry) {
+                Ok(()) => *failing = false,
+                Err(ref e) if !*failing => {
+                    warn!("Failed to write query log: {}", e);
+                    *failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    for output in outputs.iter_mut() {
+        if let Err(e) = output.close() {
+            warn!("Failed to close query log: {}", e);
+        }
+    }
+}
+
+/// Writes the query and response messages for `entry` as data frames.
+fn write_dnstap<W: Write>(writer: &mut W, entry: &Entry) -> Result<(), Error> {
+    let mut frames = Vec::new();
+    for &kind in &[CLIENT_QUERY, CLIENT_RESPONSE] {
+        let message = entry.dnstap(kind);
+        frames.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&message);
+    }
+    writer.write_all(&frames)
+}
+
+/// Connects to the dnstap reader at `path`, starting a bidirectional frame
+/// stream with it.
+fn connect(path: &str) -> Result<UnixStream, Error> {
+    let mut stream = try!(UnixStream::connect(path));
+    try!(stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)));
+    try!(stream.write_all(&control_frame(CONTROL_READY)));
+    try!(expect_control(&mut stream, CONTROL_ACCEPT));
+    try!(stream.write_all(&control_frame(CONTROL_START)));
+    Ok(stream)
+}
+
+/// A control frame: an escape of a zero length, the frame's length, its
+/// type and, for all but STOP and FINISH, our content type.
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut body = kind.to_be_bytes().to_vec();
+    if kind != CONTROL_STOP && kind != CONTROL_FINISH {
+        body.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = vec![0; 4];
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Reads a control frame, which has to be of type `kind`.
+fn expect_control<R: Read>(reader: &mut R, kind: u32) -> Result<(), Error> {
+    let body = try!(read_control(reader));
+    if body.len() < 4 || body[..4] != kind.to_be_bytes() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("expected control frame {}", kind),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a control frame's body.
+fn read_control<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut header = [0u8; 8];
+    try!(reader.read_exact(&mut header));
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if header[..4] != [0; 4] || len > MAX_CONTROL {
+        return Err(Error::new(ErrorKind::InvalidData, "expected a control frame"));
+    }
+    let mut body = vec![0; len];
+    try!(reader.read_exact(&mut body));
+    Ok(body)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+/// `time` as RFC 3339, in UTC to the millisecond.
+fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let t = presentation::timestamp(since.as_secs() as u32);
+    format!(
+        "{}-{}-{}T{}:{}:{}.{:03}Z",
+        &t[0..4],
+        &t[4..6],
+        &t[6..8],
+        &t[8..10],
+        &t[10..12],
+        &t[12..14],
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+
+    use serde_json::Value;
+
+    use query_type::QueryType;
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("dns-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Logs an answered query for example.com.
+    fn log_query(log: &QueryLog) {
+        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 5353);
+        let entry = Entry::start(client, Transport::Tls, b"query");
+
+        let mut request = DnsPacket::new();
+        request.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        let mut response = request.clone();
+        response.header.response = true;
+        response.header.rescode = ResultCode::NXDOMAIN;
+        let source = AnswerSource {
+            cache_hit: false,
+            upstream: Some("192.0.2.53:53".to_string()),
+        };
+        log.log(entry, &request, &response, b"response", source);
+    }
+
+    type Fields = HashMap<u64, Vec<u8>>;
+
+    /// The fields of a protobuf message, each the last of its number.
+    fn fields(mut buf: &[u8]) -> Fields {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            let mut shift = 0;
+            loop {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= u64::from(byte & 0x7F) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        let mut fields = HashMap::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let value = match key & 7 {
+                0 => varint(&mut buf).to_be_bytes().to_vec(),
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let value = buf[..len].to_vec();
+                    buf = &buf[len..];
+                    value
+                }
+                5 => {
+                    let value = buf[..4].to_vec();
+                    buf = &buf[4..];
+                    value
+                }
+                _ => panic!("unexpected wire type"),
+            };
+            fields.insert(key >> 3, value);
+        }
+        fields
+    }
+
+    fn number(fields: &Fields, field: u64) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&fields[&field]);
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Reads a data frame, checking it's a client message of type `kind`
+    /// over TLS from 192.0.2.1. Returns the fields of the dnstap wrapper
+    /// and of the message.
+    fn read_message<R: Read>(reader: &mut R, kind: u64) -> (Fields, Fields) {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut frame).unwrap();
+
+        let dnstap = fields(&frame);
+        assert_eq!(number(&dnstap, 15), 1);
+        let message = fields(&dnstap[&14]);
+        assert_eq!(number(&message, 1), kind);
+        assert_eq!(number(&message, 2), 1);
+        assert_eq!(number(&message, 3), 3);
+        assert_eq!(message[&4], vec![192, 0, 2, 1]);
+        assert_eq!(number(&message, 6), 5353);
+        assert!(number(&message, 8) > 0);
+        (dnstap, message)
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let file = path("queries.jsonl");
+        let mut config = QueryLogConfig::new();
+        config.file = Some(file.clone());
+        let log = QueryLog::open(&config).unwrap().unwrap();
+        log_query(&log);
+        log_query(&log);
+        drop(log);
+
+        let text = fs::read_to_string(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+
+        let line: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["client"], "192.0.2.1:5353");
+        assert_eq!(line["transport"], "tls");
+        assert_eq!(line["question"]["QNAME"], "example.com.");
+        assert_eq!(line["question"]["QTYPEname"], "A");
+        assert_eq!(line["rcode"], "NXDOMAIN");
+        assert_eq!(line["cache_hit"], false);
+        assert_eq!(line["upstream"], "192.0.2.53:53");
+        assert!(line["latency_ms"].as_f64().unwrap() >= 0.0);
+        let time = line["time"].as_str().unwrap();
+        assert_eq!((time.len(), &time[10..11], &time[23..]), (24, "T", "Z"));
+    }
+
+    #[test]
+    fn writes_dnstap_files() {
+        let file = path("queries.dnstap");
+        let mut config = QueryLogConfig::new();
+        config.dnstap_file = Some(file.clone());
+        let log = QueryLog::open(&config).unwrap().unwrap();
+        log_query(&log);
+        drop(log);
+
+        let mut reader = Cursor::new(fs::read(&file).unwrap());
+        fs::remove_file(&file).unwrap();
+        let start = read_control(&mut reader).unwrap();
+        assert_eq!(start[..4], CONTROL_START.to_be_bytes());
+        assert!(start.ends_with(CONTENT_TYPE));
+
+        let (_, query) = read_message(&mut reader, CLIENT_QUERY);
+        assert_eq!(query[&10], b"query");
+        assert!(!query.contains_key(&14));
+        let (dnstap, response) = read_message(&mut reader, CLIENT_RESPONSE);
+        assert_eq!(response[&14], b"response");
+        assert!(number(&response, 12) >= number(&query, 8));
+        let extra: Value = serde_json::from_slice(&dnstap[&3]).unwrap();
+        assert_eq!(extra["upstream"], "192.0.2.53:53");
+
+        expect_control(&mut reader, CONTROL_STOP).unwrap();
+        assert_eq!(reader.position() as usize, reader.get_ref().len());
+    }
+
+    #[test]
+    fn streams_dnstap_to_sockets() {
+        let socket = path("dnstap.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let reader = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let ready = read_control(&mut stream).unwrap();
+            assert_eq!(ready[..4], CONTROL_READY.to_be_bytes());
+            assert!(ready.ends_with(CONTENT_TYPE));
+            stream.write_all(&control_frame(CONTROL_ACCEPT)).unwrap();
+            expect_control(&mut stream, CONTROL_START).unwrap();
+
+            let (_, query) = read_message(&mut stream, CLIENT_QUERY);
+            assert_eq!(query[&10], b"query");
+            let (_, response) = read_message(&mut stream, CLIENT_RESPONSE);
+            assert_eq!(response[&14], b"response");
+
+            expect_control(&mut stream, CONTROL_STOP).unwrap();
+            stream.write_all(&control_frame(CONTROL_FINISH)).unwrap();
+        });
+
+        let mut config = QueryLogConfig::new();
+        config.dnstap_socket = Some(socket.clone());
+        let log = QueryLog::open(&config).unwrap().unwrap();
+        log_query(&log);
+        drop(log);
+
+        reader.join().unwrap();
+        fs::remove_file(&socket).unwrap();
+    }
+}
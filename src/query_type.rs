@@ -1,22 +1,50 @@
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
 pub enum QueryType {
     UNKNOWN(u16),
-    A,     // 1
-    NS,    // 2
-    CNAME, // 5
-    MX,    // 15
-    AAAA,  // 28
+    A,      // 1
+    NS,     // 2
+    CNAME,  // 5
+    SOA,    // 6
+    PTR,    // 12
+    MX,     // 15
+    TXT,    // 16
+    AAAA,   // 28
+    DNAME,  // 39
+    OPT,    // 41
+    DS,     // 43
+    RRSIG,  // 46
+    NSEC,   // 47
+    DNSKEY, // 48
+    NSEC3,  // 50
 }
 
 impl QueryType {
+    // Types that only appear in questions, and TSIG's meta type
+    pub const TSIG: QueryType = QueryType::UNKNOWN(250);
+    pub const IXFR: QueryType = QueryType::UNKNOWN(251);
+    pub const AXFR: QueryType = QueryType::UNKNOWN(252);
+    pub const ANY: QueryType = QueryType::UNKNOWN(255);
+
     pub fn to_num(&self) -> u16 {
         match self {
             &QueryType::UNKNOWN(x) => x,
             &QueryType::A => 1,
             &QueryType::NS => 2,
             &QueryType::CNAME => 5,
+            &QueryType::SOA => 6,
+            &QueryType::PTR => 12,
             &QueryType::MX => 15,
+            &QueryType::TXT => 16,
             &QueryType::AAAA => 28,
+            &QueryType::DNAME => 39,
+            &QueryType::OPT => 41,
+            &QueryType::DS => 43,
+            &QueryType::RRSIG => 46,
+            &QueryType::NSEC => 47,
+            &QueryType::DNSKEY => 48,
+            &QueryType::NSEC3 => 50,
         }
     }
 
@@ -25,9 +53,64 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
             _ => QueryType::UNKNOWN(num),
         }
     }
+
+    /// Parses a type mnemonic such as `AAAA`, or the generic `TYPE28` form
+    /// (RFC 3597).
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        let name = name.to_ascii_uppercase();
+        if name.starts_with("TYPE") {
+            return name[4..].parse::<u16>().ok().map(QueryType::from_num);
+        }
+
+        match name.as_str() {
+            "A" => Some(QueryType::A),
+            "NS" => Some(QueryType::NS),
+            "CNAME" => Some(QueryType::CNAME),
+            "SOA" => Some(QueryType::SOA),
+            "PTR" => Some(QueryType::PTR),
+            "MX" => Some(QueryType::MX),
+            "TXT" => Some(QueryType::TXT),
+            "AAAA" => Some(QueryType::AAAA),
+            "DNAME" => Some(QueryType::DNAME),
+            "OPT" => Some(QueryType::OPT),
+            "DS" => Some(QueryType::DS),
+            "RRSIG" => Some(QueryType::RRSIG),
+            "NSEC" => Some(QueryType::NSEC),
+            "DNSKEY" => Some(QueryType::DNSKEY),
+            "NSEC3" => Some(QueryType::NSEC3),
+            "TSIG" => Some(QueryType::TSIG),
+            "IXFR" => Some(QueryType::IXFR),
+            "AXFR" => Some(QueryType::AXFR),
+            "ANY" => Some(QueryType::ANY),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryType::TSIG => write!(f, "TSIG"),
+            QueryType::IXFR => write!(f, "IXFR"),
+            QueryType::AXFR => write!(f, "AXFR"),
+            QueryType::ANY => write!(f, "ANY"),
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+            _ => write!(f, "{:?}", self),
+        }
+    }
 }
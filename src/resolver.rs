@@ -0,0 +1,880 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use byte_packet_buffer::BytePacketBuffer;
+use cache::{Cache, CacheKey, CacheLookup};
+use dns_name;
+use dnssec;
+use dnssec::{KeyCache, Security, Validator};
+use iterative_resolver;
+use iterative_resolver::IterativeResolver;
+use metrics::METRICS;
+use qname_minimisation::QnameMinimisation;
+use query_coalescer::{QueryCoalescer, Resolved};
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use doh::{DohClient, DohUpstream};
+use doq::DoqClient;
+use query_type::QueryType;
+use result_code::ResultCode;
+use tls::TlsClient;
+use tsig::{self, Signer, TsigKey};
+
+// Payload size we advertise in EDNS, and the largest response we'll send
+pub const EDNS_PAYLOAD_SIZE: u16 = 4096;
+// Most prefetches running at once. Answers due one past that wait for a
+// later lookup to ask again.
+const MAX_PREFETCHES: usize = 8;
+
+pub struct ResolverConfig {
+    // Tried in turn until one answers
+    pub upstreams: Vec<SocketAddr>,
+    pub upstream_timeout: Duration,
+    // Forwarded to over TLS instead of `upstreams` if there are any,
+    // trusting certificates for `tls_name` (or their address) from a CA in
+    // `tls_ca_file` or the system's, or whose key matches a pin
+    pub tls_upstreams: Vec<SocketAddr>,
+    pub tls_name: Option<String>,
+    pub tls_pins: Vec<Vec<u8>>,
+    pub tls_ca_file: Option<String>,
+    // Forwarded to over HTTPS instead of either if there are any, trusted
+    // by the same pins or CAs
+    pub https_upstreams: Vec<DohUpstream>,
+    // Forwarded to over QUIC instead of TLS if there are any, trusted as
+    // TLS upstreams are
+    pub quic_upstreams: Vec<SocketAddr>,
+    // Resolve from the root servers instead of forwarding to `upstream`
+    pub iterative: bool,
+    pub root_servers: Vec<SocketAddr>,
+    pub qname_minimisation: QnameMinimisation,
+    // Randomise the case of outgoing names and insist on getting it back
+    pub case_randomisation: bool,
+    pub dnssec: bool,
+    pub trust_anchors: Vec<DnsRecord>,
+    pub cache_size: usize,
+    // RFC 8767: how long past their TTL answers are kept for when upstreams
+    // fail, the TTL they're served with, and how long a client waits for a
+    // fresh answer before getting the stale one
+    pub max_stale: u32,
+    pub stale_answer_ttl: u32,
+    pub client_response_timeout: Duration,
+    // Answers looked up this many times are refreshed in the background
+    // once they're within the last `prefetch_percent` of their TTL
+    pub prefetch_hits: u32,
+    pub prefetch_percent: u32,
+}
+
+impl ResolverConfig {
+    pub fn new() -> ResolverConfig {
+        ResolverConfig {
+            upstreams: vec![SocketAddr::from(([8, 8, 8, 8], 53))],
+            upstream_timeout: Duration::from_secs(2),
+            tls_upstreams: Vec::new(),
+            tls_name: None,
+            tls_pins: Vec::new(),
+            tls_ca_file: None,
+            https_upstreams: Vec::new(),
+            quic_upstreams: Vec::new(),
+            iterative: false,
+            root_servers: iterative_resolver::root_servers(),
+            qname_minimisation: QnameMinimisation::Relaxed,
+            case_randomisation: false,
+            dnssec: true,
+            trust_anchors: dnssec::root_trust_anchors(),
+            cache_size: 10000,
+            max_stale: 86400,
+            stale_answer_ttl: 30,
+            client_response_timeout: Duration::from_millis(1800),
+            prefetch_hits: 5,
+            prefetch_percent: 10,
+        }
+    }
+}
+
+impl Default for ResolverConfig {
+    fn default() -> ResolverConfig {
+        ResolverConfig::new()
+    }
+}
+
+pub fn random_id() -> u16 {
+    let mut bytes = [0u8; 2];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(_) => ((bytes[0] as u16) << 8) | bytes[1] as u16,
+        Err(_) => 6666,
+    }
+}
+
+/// DNS 0x20: flips the case of each letter of `name` at random.
+fn randomise_case(name: &str) -> String {
+    let mut bits = vec![0u8; name.len()];
+    if SystemRandom::new().fill(&mut bits).is_err() {
+        return name.to_string();
+    }
+
+    name.chars()
+        .zip(bits)
+        .map(|(c, bit)| {
+            if bit & 1 == 1 {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn is_reply_to(request: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.response && response.header.id == request.header.id
+        && response.questions.len() == 1
+        && response.questions[0].qtype == request.questions[0].qtype
+        && dns_name::names_equal(&response.questions[0].name, &request.questions[0].name)
+}
+
+/// A reply whose question differs from ours only in case has been forged by
+/// someone who guessed the name but not the case we sent it in.
+fn is_spoofed(request: &DnsPacket, response: &DnsPacket) -> bool {
+    response.questions[0].name != request.questions[0].name
+}
+
+fn lookup_tcp(
+    request: &DnsPacket,
+    server: SocketAddr,
+    timeout: Duration,
+    randomise_case: bool,
+    key: Option<&TsigKey>,
+) -> Result<DnsPacket, Error> {
+    let mut req_buffer = BytePacketBuffer::with_size(0xFFFF);
+    let mut packet = request.clone();
+    try!(packet.write(&mut req_buffer));
+    let mut signer = key.map(|key| Signer::new(key.clone()));
+    if let Some(ref mut signer) = signer {
+        try!(signer.sign(&mut req_buffer, tsig::now()));
+    }
+
+    let mut stream = try!(TcpStream::connect_timeout(&server, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+
+    let len = req_buffer.pos();
+    try!(stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8]));
+    try!(stream.write_all(&req_buffer.buf[0..len]));
+
+    let mut len_buf = [0u8; 2];
+    try!(stream.read_exact(&mut len_buf));
+    let len = ((len_buf[0] as usize) << 8) | len_buf[1] as usize;
+
+    let mut res_buffer = BytePacketBuffer::with_size(len);
+    try!(stream.read_exact(&mut res_buffer.buf));
+
+    let response = try!(DnsPacket::from_buffer(&mut res_buffer));
+    if !is_reply_to(request, &response) || randomise_case && is_spoofed(request, &response) {
+        return Err(Error::new(ErrorKind::InvalidData, "Mismatched TCP response"));
+    }
+    if let Some(ref mut signer) = signer {
+        try!(signer.verify(&res_buffer.buf, tsig::now()));
+    }
+    Ok(response)
+}
+
+/// Asks the recursive resolver at `server`, leaving validation to us if
+/// `dnssec` is set.
+pub fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    dnssec: bool,
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let packet = query_packet(qname, qtype, true, dnssec, randomise_case);
+    let result = send_query(qname, &packet, server, timeout, randomise_case, None);
+    count_timeout(&server, result)
+}
+
+/// Asks the authoritative server `server`, requesting signatures if `dnssec`
+/// is set.
+pub fn lookup_authoritative(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    dnssec: bool,
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let packet = query_packet(qname, qtype, false, dnssec, randomise_case);
+    let result = send_query(qname, &packet, server, timeout, randomise_case, None);
+    count_timeout(&server, result)
+}
+
+/// Asks the authoritative server `server` with a query signed with `key`,
+/// which the response has to be signed with too (RFC 8945).
+pub fn lookup_signed(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    key: &TsigKey,
+) -> Result<DnsPacket, Error> {
+    let packet = query_packet(qname, qtype, false, false, false);
+    let result = send_query(qname, &packet, server, timeout, false, Some(key));
+    count_timeout(&server, result)
+}
+
+/// `result`, counting it against `server` if it's a timeout.
+fn count_timeout<T, S: fmt::Display>(server: &S, result: Result<T, Error>) -> Result<T, Error> {
+    if let Err(ref e) = result {
+        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut {
+            METRICS.upstream_timed_out(&server.to_string());
+        }
+    }
+    result
+}
+
+fn query_packet(
+    qname: &str,
+    qtype: QueryType,
+    recursive: bool,
+    dnssec: bool,
+    randomise_case: bool,
+) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = random_id();
+    packet.header.questions = 1;
+    packet.header.recursion_desired = recursive;
+    // We validate ourselves, so have the upstream hand over bogus data too
+    packet.header.checking_disabled = recursive && dnssec;
+    let sent_name = if randomise_case {
+        self::randomise_case(qname)
+    } else {
+        qname.to_string()
+    };
+    packet.questions.push(DnsQuestion::new(sent_name, qtype));
+    packet.set_edns(EDNS_PAYLOAD_SIZE, dnssec);
+    packet
+}
+
+/// Asks the recursive resolver at `server` over TLS (RFC 7858), as `lookup`
+/// does over UDP.
+pub fn lookup_tls(
+    tls: &TlsClient,
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    dnssec: bool,
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let packet = query_packet(qname, qtype, true, dnssec, randomise_case);
+    let mut req_buffer = BytePacketBuffer::new();
+    try!(packet.clone().write(&mut req_buffer));
+    let data = try!(count_timeout(
+        &server,
+        tls.exchange(server, &req_buffer.buf[0..req_buffer.pos], timeout)
+    ));
+    read_reply(&packet, qname, &data, randomise_case)
+}
+
+/// Asks the recursive resolver `upstream` over HTTPS (RFC 8484), as `lookup`
+/// does over UDP.
+pub fn lookup_https(
+    doh: &DohClient,
+    qname: &str,
+    qtype: QueryType,
+    upstream: &DohUpstream,
+    timeout: Duration,
+    dnssec: bool,
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let mut packet = query_packet(qname, qtype, true, dnssec, randomise_case);
+    // HTTP matches up responses already, and the same ID for every query
+    // lets caches along the way share them
+    packet.header.id = 0;
+    let mut req_buffer = BytePacketBuffer::new();
+    try!(packet.clone().write(&mut req_buffer));
+    let data = try!(count_timeout(
+        upstream,
+        doh.exchange(upstream, &req_buffer.buf[0..req_buffer.pos], timeout)
+    ));
+    read_reply(&packet, qname, &data, randomise_case)
+}
+
+/// Asks the recursive resolver at `server` over QUIC (RFC 9250), as `lookup`
+/// does over UDP.
+pub fn lookup_quic(
+    doq: &DoqClient,
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    timeout: Duration,
+    dnssec: bool,
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let mut packet = query_packet(qname, qtype, true, dnssec, randomise_case);
+    // Each query has a stream of its own, and RFC 9250 has its ID be 0
+    packet.header.id = 0;
+    let mut req_buffer = BytePacketBuffer::new();
+    try!(packet.clone().write(&mut req_buffer));
+    let data = try!(count_timeout(
+        &server,
+        doq.exchange(server, &req_buffer.buf[0..req_buffer.pos], timeout)
+    ));
+    read_reply(&packet, qname, &data, randomise_case)
+}
+
+/// The response to `request` in `data`, with the question as asked.
+fn read_reply(
+    request: &DnsPacket,
+    qname: &str,
+    data: &[u8],
+    randomise_case: bool,
+) -> Result<DnsPacket, Error> {
+    let mut res_buffer = BytePacketBuffer::with_size(data.len());
+    res_buffer.buf.copy_from_slice(data);
+    let mut response = try!(DnsPacket::from_buffer(&mut res_buffer));
+    if !is_reply_to(request, &response) || randomise_case && is_spoofed(request, &response) {
+        return Err(Error::new(ErrorKind::InvalidData, "Mismatched response"));
+    }
+    response.questions[0].name = qname.to_string();
+    Ok(response)
+}
+
+/// Sends `packet`, the query for `qname`, to `server` over UDP, retrying over
+/// TCP if the response is truncated.
+fn send_query(
+    qname: &str,
+    packet: &DnsPacket,
+    server: SocketAddr,
+    timeout: Duration,
+    randomise_case: bool,
+    key: Option<&TsigKey>,
+) -> Result<DnsPacket, Error> {
+    let local: SocketAddr = if server.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(timeout)));
+
+    let mut req_buffer = BytePacketBuffer::new();
+    try!(packet.clone().write(&mut req_buffer));
+    let mut signer = key.map(|key| Signer::new(key.clone()));
+    if let Some(ref mut signer) = signer {
+        try!(signer.sign(&mut req_buffer, tsig::now()));
+    }
+
+    try!(socket.send_to(&req_buffer.buf[0..req_buffer.pos], server));
+
+    // Anything that isn't the reply to this query, from this server, is
+    // dropped until the timeout fires
+    loop {
+        let mut res_buffer = BytePacketBuffer::with_size(EDNS_PAYLOAD_SIZE as usize);
+        let (size, src) = try!(socket.recv_from(&mut res_buffer.buf));
+        if src != server {
+            continue;
+        }
+
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if !is_reply_to(packet, &response) {
+            continue;
+        }
+        if randomise_case && is_spoofed(packet, &response) {
+            warn!(
+                "Dropped reply for {} from {} with the wrong case, possible spoofing attempt",
+                packet.questions[0].name, src
+            );
+            continue;
+        }
+
+        let mut response = if response.header.truncated_message {
+            try!(lookup_tcp(packet, server, timeout, randomise_case, key))
+        } else {
+            if let Some(ref mut signer) = signer {
+                try!(signer.verify(&res_buffer.buf[..size], tsig::now()));
+            }
+            response
+        };
+        response.questions[0].name = qname.to_string();
+        return Ok(response);
+    }
+}
+
+/// Whether `packet` settles the question, rather than the upstream failing.
+fn is_answer(packet: &DnsPacket) -> bool {
+    match packet.header.rescode {
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => true,
+        _ => false,
+    }
+}
+
+fn is_dnssec_type(qtype: QueryType) -> bool {
+    match qtype {
+        QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3 => true,
+        _ => false,
+    }
+}
+
+/// The first answer from `upstreams`, asked in turn with `ask`, along with
+/// the upstream that gave it, or the last failure.
+fn first_answer<T, F>(upstreams: &[T], ask: F) -> Result<(DnsPacket, Option<String>), Error>
+where
+    T: fmt::Display,
+    F: Fn(&T) -> Result<DnsPacket, Error>,
+{
+    let mut last = Err(Error::other("No upstream servers"));
+    for upstream in upstreams {
+        let name = upstream.to_string();
+        let started = Instant::now();
+        // Timeouts are counted by the lookups, for every server asked
+        last = ask(upstream).map(|response| {
+            METRICS.upstream_responded(&name, started.elapsed());
+            (response, Some(name))
+        });
+        match last {
+            Ok((ref response, _)) if is_answer(response) => break,
+            _ => continue,
+        }
+    }
+    last
+}
+
+/// Where the resolver got an answer from, as the query log records it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnswerSource {
+    // Whether it came from the cache, fresh or stale
+    pub cache_hit: bool,
+    // The upstream that gave it, when one was asked
+    pub upstream: Option<String>,
+}
+
+impl AnswerSource {
+    fn cache() -> AnswerSource {
+        AnswerSource {
+            cache_hit: true,
+            upstream: None,
+        }
+    }
+
+    fn upstream(upstream: Option<String>) -> AnswerSource {
+        AnswerSource {
+            cache_hit: false,
+            upstream: upstream,
+        }
+    }
+}
+
+pub struct Resolver {
+    pub config: ResolverConfig,
+    tls: Option<TlsClient>,
+    doh: Option<DohClient>,
+    doq: Option<DoqClient>,
+    keys: Mutex<KeyCache>,
+    cache: Mutex<Cache>,
+    in_flight: QueryCoalescer,
+    prefetches: AtomicUsize,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Result<Resolver, Error> {
+        let tls = if config.tls_upstreams.is_empty() {
+            None
+        } else {
+            Some(try!(TlsClient::new(
+                config.tls_name.clone(),
+                config.tls_pins.clone(),
+                config.tls_ca_file.as_ref().map(|path| path.as_str()),
+            )))
+        };
+
+        let doh = if config.https_upstreams.is_empty() {
+            None
+        } else {
+            Some(try!(DohClient::new(
+                config.tls_pins.clone(),
+                config.tls_ca_file.as_ref().map(|path| path.as_str()),
+            )))
+        };
+
+        let doq = if config.quic_upstreams.is_empty() {
+            None
+        } else {
+            Some(try!(DoqClient::new(
+                config.tls_name.clone(),
+                config.tls_pins.clone(),
+                config.tls_ca_file.as_ref().map(|path| path.as_str()),
+            )))
+        };
+
+        Ok(Resolver {
+            tls: tls,
+            doh: doh,
+            doq: doq,
+            cache: Mutex::new(Cache::new(
+                config.cache_size,
+                config.max_stale,
+                config.prefetch_hits,
+                config.prefetch_percent,
+            )),
+            config: config,
+            keys: Mutex::new(KeyCache::new()),
+            in_flight: QueryCoalescer::new(),
+            prefetches: AtomicUsize::new(0),
+        })
+    }
+
+    /// Forwards the question upstream, along with which one answered, or
+    /// resolves it from the root servers.
+    fn query(&self, qname: &str, qtype: QueryType) -> Result<(DnsPacket, Option<String>), Error> {
+        if self.config.iterative {
+            let resolver = IterativeResolver::new(
+                &self.config.root_servers,
+                self.config.upstream_timeout,
+                self.config.qname_minimisation,
+                self.config.dnssec,
+                self.config.case_randomisation,
+            );
+            return resolver.resolve(qname, qtype).map(|packet| (packet, None));
+        }
+
+        let config = &self.config;
+        let timeout = config.upstream_timeout;
+        if let Some(ref doh) = self.doh {
+            return first_answer(&config.https_upstreams, |upstream| {
+                lookup_https(
+                    doh,
+                    qname,
+                    qtype,
+                    upstream,
+                    timeout,
+                    config.dnssec,
+                    config.case_randomisation,
+                )
+            });
+        }
+        if let Some(ref doq) = self.doq {
+            return first_answer(&config.quic_upstreams, |upstream| {
+                lookup_quic(
+                    doq,
+                    qname,
+                    qtype,
+                    *upstream,
+                    timeout,
+                    config.dnssec,
+                    config.case_randomisation,
+                )
+            });
+        }
+        match self.tls {
+            Some(ref tls) => first_answer(&config.tls_upstreams, |upstream| {
+                lookup_tls(
+                    tls,
+                    qname,
+                    qtype,
+                    *upstream,
+                    timeout,
+                    config.dnssec,
+                    config.case_randomisation,
+                )
+            }),
+            None => first_answer(&config.upstreams, |upstream| {
+                lookup(
+                    qname,
+                    qtype,
+                    *upstream,
+                    timeout,
+                    config.dnssec,
+                    config.case_randomisation,
+                )
+            }),
+        }
+    }
+
+    pub fn resolve(
+        &self,
+        question: &DnsQuestion,
+        checking_disabled: bool,
+    ) -> Result<Resolved, Error> {
+        let (mut result, upstream) = try!(self.query(&question.name, question.qtype));
+
+        if !self.config.dnssec || checking_disabled {
+            return Ok((result, Security::Insecure, upstream));
+        }
+
+        match result.header.rescode {
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+            _ => return Ok((result, Security::Insecure, upstream)),
+        }
+
+        let mut keys = lock(&self.keys);
+        let mut validator = Validator::new(&self.config.trust_anchors, &mut keys, |qname, qtype| {
+            self.query(qname, qtype).map(|(packet, _)| packet)
+        });
+        let security = validator.validate(&question.name, question.qtype, &mut result);
+
+        Ok((result, security, upstream))
+    }
+
+    /// Resolves the question through the in-flight queries, caching what
+    /// comes back.
+    fn refresh(
+        &self,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        checking_disabled: bool,
+    ) -> Result<Resolved, Error> {
+        let key = cache_key(question, checking_disabled);
+
+        // Identical queries arriving while this one is resolved wait for it
+        let in_flight = (key.0.clone(), key.1, key.2, dnssec_ok, checking_disabled);
+        self.in_flight.run(in_flight, || {
+            let resolved = self.resolve(question, checking_disabled);
+            let mut cache = lock(&self.cache);
+            match resolved {
+                Ok((ref packet, security, _)) if is_answer(packet) => {
+                    cache.insert(key, packet, security)
+                }
+                Ok(_) | Err(_) => cache.mark_failed(&key),
+            }
+            resolved
+        })
+    }
+
+    /// Refreshes a popular answer in the background, unless `MAX_PREFETCHES`
+    /// are running already.
+    fn prefetch(
+        self: &Arc<Self>,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        checking_disabled: bool,
+    ) {
+        if self.prefetches.fetch_add(1, Ordering::SeqCst) >= MAX_PREFETCHES {
+            self.prefetches.fetch_sub(1, Ordering::SeqCst);
+            let key = cache_key(question, checking_disabled);
+            lock(&self.cache).cancel_prefetch(&key);
+            return;
+        }
+
+        debug!("Prefetching {}", question);
+        let resolver = self.clone();
+        let question = question.clone();
+        thread::spawn(move || {
+            let _running = Prefetch(&resolver.prefetches);
+            let _ = resolver.refresh(&question, dnssec_ok, checking_disabled);
+        });
+    }
+
+    /// Answers from the cache where possible. Expired answers are refreshed,
+    /// but served stale if that fails or takes too long (RFC 8767).
+    fn answer(
+        self: &Arc<Self>,
+        question: &DnsQuestion,
+        dnssec_ok: bool,
+        checking_disabled: bool,
+    ) -> Result<(DnsPacket, Security, AnswerSource), Error> {
+        let key = cache_key(question, checking_disabled);
+        let cached = lock(&self.cache).get(&key, self.config.stale_answer_ttl);
+        match cached {
+            CacheLookup::Miss => METRICS.cache_miss(),
+            _ => METRICS.cache_hit(),
+        }
+        let (stale, security) = match cached {
+            CacheLookup::Fresh(packet, security, prefetch) => {
+                if prefetch {
+                    self.prefetch(question, dnssec_ok, checking_disabled);
+                }
+                return Ok((packet, security, AnswerSource::cache()));
+            }
+            CacheLookup::Miss => {
+                let resolved = self.refresh(question, dnssec_ok, checking_disabled);
+                return resolved.map(|(packet, security, upstream)| {
+                    (packet, security, AnswerSource::upstream(upstream))
+                });
+            }
+            CacheLookup::Stale(packet, security, true) => {
+                info!("Serving stale answer for {}, refresh failed recently", question);
+                return Ok((packet, security, AnswerSource::cache()));
+            }
+            CacheLookup::Stale(packet, security, false) => (packet, security),
+        };
+
+        // The refresh carries on after we give up waiting, so the cache is
+        // up to date for the next client
+        let (sender, receiver) = mpsc::channel();
+        let resolver = self.clone();
+        let refreshed = question.clone();
+        thread::spawn(move || {
+            let resolved = resolver.refresh(&refreshed, dnssec_ok, checking_disabled);
+            let _ = sender.send(resolved);
+        });
+
+        match receiver.recv_timeout(self.config.client_response_timeout) {
+            Ok(Ok((packet, security, upstream))) if is_answer(&packet) => {
+                Ok((packet, security, AnswerSource::upstream(upstream)))
+            }
+            Ok(Ok(_)) | Ok(Err(_)) => {
+                info!("Serving stale answer for {}, refresh failed", question);
+                Ok((stale, security, AnswerSource::cache()))
+            }
+            Err(_) => {
+                info!("Serving stale answer for {}, refresh is taking too long", question);
+                Ok((stale, security, AnswerSource::cache()))
+            }
+        }
+    }
+
+    /// Answers `request` recursively, along with where the answer came from.
+    pub fn answer_query(self: &Arc<Self>, request: &DnsPacket) -> (DnsPacket, AnswerSource) {
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+        packet.header.response = true;
+        packet.header.checking_disabled = request.header.checking_disabled;
+
+        let dnssec_ok = request.dnssec_ok();
+        if request.get_edns().is_some() {
+            packet.set_edns(EDNS_PAYLOAD_SIZE, dnssec_ok);
+        }
+
+        if request.questions.is_empty() {
+            packet.header.rescode = ResultCode::FORMERR;
+            return (packet, AnswerSource::default());
+        }
+
+        let question = &request.questions[0];
+        info!("Recursive query: {}", question);
+        packet.questions.push(question.clone());
+
+        let resolved = self.answer(question, dnssec_ok, request.header.checking_disabled);
+        let (result, security, source) = match resolved {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to resolve {}: {:?}", question, e);
+                packet.header.rescode = ResultCode::SERVFAIL;
+                return (packet, AnswerSource::default());
+            }
+        };
+
+        if let Security::Bogus(reason) = security {
+            warn!("Bogus answer for {}: {}", question, reason);
+            packet.header.rescode = ResultCode::SERVFAIL;
+            return (packet, source);
+        }
+
+        packet.header.rescode = result.header.rescode;
+        // RFC 6840 section 5.8: only tell clients that asked for it
+        packet.header.authed_data =
+            security == Security::Secure && (dnssec_ok || request.header.authed_data);
+
+        let wanted = |rec: &DnsRecord| {
+            let qtype = rec.get_querytype();
+            qtype != QueryType::OPT
+                && (dnssec_ok || !is_dnssec_type(qtype) || qtype == question.qtype)
+        };
+
+        for rec in result.answers {
+            if wanted(&rec) {
+                debug!("Answer: {}", rec);
+                packet.answers.push(rec);
+            }
+        }
+
+        for rec in result.authorities {
+            if wanted(&rec) {
+                debug!("Authority: {}", rec);
+                packet.authorities.push(rec);
+            }
+        }
+
+        for rec in result.resources {
+            if wanted(&rec) {
+                debug!("Resource: {}", rec);
+                packet.resources.push(rec);
+            }
+        }
+
+        (packet, source)
+    }
+}
+
+fn cache_key(question: &DnsQuestion, checking_disabled: bool) -> CacheKey {
+    (
+        question.name.to_ascii_lowercase(),
+        question.qtype,
+        question.class,
+        checking_disabled,
+    )
+}
+
+/// Holds one of the `MAX_PREFETCHES` slots until dropped.
+struct Prefetch<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Prefetch<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_timeouts_for_every_server_asked() {
+        // Forwarded and iterative queries alike
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap();
+        let timeout = Duration::from_millis(50);
+        assert!(lookup("example.com", QueryType::A, server, timeout, false, false).is_err());
+        assert!(lookup_authoritative("example.com", QueryType::A, server, timeout, false, false)
+            .is_err());
+
+        let sample = format!("dns_upstream_timeouts_total{{upstream=\"{}\"}} 2", server);
+        assert!(METRICS.render().lines().any(|line| line == sample));
+    }
+
+    #[test]
+    fn randomised_case_keeps_the_name() {
+        let name = "www-1.example.com";
+        let randomised = randomise_case(name);
+        assert!(dns_name::names_equal(&randomised, name));
+        assert_eq!(randomised.to_ascii_lowercase(), name);
+    }
+
+    #[test]
+    fn detects_case_mismatch() {
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new("wWw.ExAmPle.com".to_string(), QueryType::A));
+
+        let mut response = request.clone();
+        response.header.response = true;
+        assert!(is_reply_to(&request, &response));
+        assert!(!is_spoofed(&request, &response));
+
+        response.questions[0].name = "www.example.com".to_string();
+        assert!(is_reply_to(&request, &response));
+        assert!(is_spoofed(&request, &response));
+    }
+}
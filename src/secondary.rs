@@ -0,0 +1,403 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use authority::Authority;
+use dns_record::DnsRecord;
+use query_type::QueryType;
+use resolver;
+use result_code::ResultCode;
+use transfer;
+use tsig::TsigKey;
+use zone::{self, Zone};
+use zone_config::ZoneConfig;
+
+// How long to wait for a primary to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+// How often to try the primaries while we don't have the zone at all
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// A zone we serve a copy of. Its primaries' SOA is checked on the refresh
+/// and retry timers of the zone (RFC 1035 section 4.3.5) or when one of
+/// them sends a NOTIFY, and newer versions are pulled with IXFR or AXFR.
+/// The zone stops being served once it has gone unrefreshed for its expire
+/// interval.
+pub struct Secondary {
+    pub config: ZoneConfig,
+    authority: Arc<Authority>,
+    // Set by NOTIFY to check before the next timer fires
+    notified: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl Secondary {
+    pub fn new(config: ZoneConfig, authority: Arc<Authority>) -> Secondary {
+        Secondary {
+            config: config,
+            authority: authority,
+            notified: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Keeps the zone up to date, never returning.
+    pub fn run(&self) {
+        // A copy loaded from the zone file counts as fresh
+        let mut expires = self.authority
+            .get(&self.config.name)
+            .map(|zone| Instant::now() + timers(&zone).2);
+
+        loop {
+            let wait = match self.refresh() {
+                Ok(()) => {
+                    let zone = self.authority.get(&self.config.name);
+                    expires = zone.as_ref().map(|zone| Instant::now() + timers(zone).2);
+                    zone.map(|zone| timers(&zone).0).unwrap_or(INITIAL_RETRY)
+                }
+                Err(e) => {
+                    warn!("Failed to refresh zone {}: {}", self.config.name, e);
+                    match self.authority.get(&self.config.name) {
+                        Some(zone) => timers(&zone).1,
+                        None => INITIAL_RETRY,
+                    }
+                }
+            };
+
+            let wait = match expires {
+                Some(at) if at <= Instant::now() => {
+                    warn!(
+                        "Zone {} expired without reaching its primaries",
+                        self.config.name
+                    );
+                    self.authority.remove(&self.config.name);
+                    expires = None;
+                    INITIAL_RETRY
+                }
+                Some(at) => cmp::min(wait, at - Instant::now()),
+                None => wait,
+            };
+            self.wait(wait);
+        }
+    }
+
+    /// Has the zone checked now rather than when its timer fires.
+    pub fn notify(&self) {
+        *lock(&self.notified) = true;
+        self.wakeup.notify_all();
+    }
+
+    /// Checks the primaries in turn for a newer version of the zone, and
+    /// transfers it from the first one that answers.
+    pub fn refresh(&self) -> Result<(), Error> {
+        let mut last_error = Error::other("no primaries");
+        for primary in &self.config.primaries {
+            match self.refresh_from(*primary) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    debug!(
+                        "Failed to refresh zone {} from {}: {}",
+                        self.config.name, primary, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn refresh_from(&self, primary: SocketAddr) -> Result<(), Error> {
+        let name = &self.config.name;
+        let current = self.authority.get(name);
+
+        if let Some(ref zone) = current {
+            let serial = try!(primary_serial(name, primary, self.config.key.as_ref()));
+            if !zone::serial_newer(serial, zone.serial()) {
+                debug!("Zone {} is up to date with serial {}", name, zone.serial());
+                return Ok(());
+            }
+        }
+
+        let soa = current.as_ref().map(|zone| zone.soa());
+        let key = self.config.key.as_ref();
+        let records = try!(transfer::fetch(name, soa, primary, TIMEOUT, key));
+        if records.len() == 1 {
+            return Ok(());
+        }
+        let records = match transfer::apply(current.as_ref().map(|zone| &**zone), &records) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(
+                    "Failed to apply IXFR of {} from {}, transferring all of it: {}",
+                    name, primary, e
+                );
+                let records = try!(transfer::fetch(name, None, primary, TIMEOUT, key));
+                try!(transfer::apply(None, &records))
+            }
+        };
+
+        let zone = try!(Zone::new(name, records));
+        info!(
+            "Transferred zone {} with serial {} from {}",
+            name,
+            zone.serial(),
+            primary
+        );
+        if let Some(ref file) = self.config.file {
+            if let Err(e) = save(&zone, file) {
+                warn!("Failed to save zone {} to {}: {}", name, file, e);
+            }
+        }
+        self.authority.insert(zone);
+        Ok(())
+    }
+
+    /// Sleeps for `duration`, or until a NOTIFY comes in.
+    fn wait(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        let mut notified = lock(&self.notified);
+        while !*notified {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            notified = match self.wakeup.wait_timeout(notified, deadline - now) {
+                Ok((x, _)) => x,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        *notified = false;
+    }
+}
+
+/// The serial of the zone `name` on `primary`, asking with a query signed
+/// with `key` if there is one.
+fn primary_serial(name: &str, primary: SocketAddr, key: Option<&TsigKey>) -> Result<u32, Error> {
+    let response = try!(match key {
+        Some(key) => resolver::lookup_signed(name, QueryType::SOA, primary, TIMEOUT, key),
+        None => {
+            resolver::lookup_authoritative(name, QueryType::SOA, primary, TIMEOUT, false, false)
+        }
+    });
+    if response.header.rescode != ResultCode::NOERROR {
+        return Err(Error::other(
+            format!("SOA query answered with {:?}", response.header.rescode),
+        ));
+    }
+    response
+        .answers
+        .iter()
+        .filter_map(|r| match *r {
+            DnsRecord::SOA { serial, .. } => Some(serial),
+            _ => None,
+        })
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no SOA in the answer"))
+}
+
+/// The refresh, retry and expire intervals of `zone`.
+fn timers(zone: &Zone) -> (Duration, Duration, Duration) {
+    match *zone.soa() {
+        DnsRecord::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        } => (
+            Duration::from_secs(refresh as u64),
+            Duration::from_secs(retry as u64),
+            Duration::from_secs(expire as u64),
+        ),
+        _ => (INITIAL_RETRY, INITIAL_RETRY, INITIAL_RETRY),
+    }
+}
+
+/// Writes `zone` to `path` as a zone file, replacing the old one in one go.
+fn save(zone: &Zone, path: &str) -> Result<(), Error> {
+    let tmp = format!("{}.tmp", path);
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(writeln!(file, "; Zone {} with serial {}", zone.name, zone.serial()));
+        for record in zone.records() {
+            try!(writeln!(file, "{}", record));
+        }
+    }
+    fs::rename(&tmp, path)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+    use std::net::{TcpListener, UdpSocket};
+    use std::process;
+    use std::thread;
+    use byte_packet_buffer::BytePacketBuffer;
+    use dns_packet::DnsPacket;
+    use network::Network;
+    use server::Server;
+    use tsig::{self, Signer};
+    use zone_parser::ZoneParser;
+
+    fn zone(serial: u32, hosts: &[&str]) -> Zone {
+        let mut text = format!("$TTL 60\n@ SOA ns1 hostmaster {} 2 3 4 5\n@ NS ns1\n", serial);
+        for host in hosts {
+            text.push_str(&format!("{} A 192.0.2.1\n", host));
+        }
+        let mut parser = ZoneParser::new("example.com");
+        parser.parse_str(&text, "test.zone").unwrap();
+        Zone::new("example.com", parser.records).unwrap()
+    }
+
+    fn sorted(mut records: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        records.sort_by_key(|r| r.to_string());
+        records
+    }
+
+    /// A primary for `zone` on a local port, over UDP and TCP. With a `key`,
+    /// transfers have to be signed with it.
+    fn primary(zone: Zone, key: Option<TsigKey>) -> (Arc<Authority>, SocketAddr) {
+        let mut config = ZoneConfig::new("example.com");
+        match key {
+            Some(ref key) => config.transfer_keys.push(key.name.clone()),
+            None => config.allow_transfer.push(Network::parse("127.0.0.1").unwrap()),
+        }
+        let authority = Arc::new(Authority::new(vec![config]));
+        authority.insert(zone);
+        let mut server = Server::new(authority.clone(), None);
+        server.keys.extend(key);
+        let server = Arc::new(server);
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).unwrap();
+
+        let udp_server = server.clone();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (size, src) = udp.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let mut signer = answer(&udp_server, &buffer.buf[..size]);
+            let key = signer.as_ref().map(|s| s.key.name.clone());
+            let key = key.as_ref().map(String::as_str);
+            let mut response = udp_server.handle_query(&request, src.ip(), key);
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            if let Some(ref mut signer) = signer {
+                signer.sign(&mut buffer, tsig::now()).unwrap();
+            }
+            udp.send_to(&buffer.buf[..buffer.pos], src).unwrap();
+        });
+        thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let len = ((len[0] as usize) << 8) | len[1] as usize;
+                let mut buffer = BytePacketBuffer::with_size(len);
+                stream.read_exact(&mut buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+                let mut signer = answer(&server, &buffer.buf);
+                let key = signer.as_ref().map(|s| s.key.name.clone());
+
+                let key = key.as_ref().map(String::as_str);
+
+                let client = stream.peer_addr().unwrap().ip();
+                for mut response in server.handle_tcp_query(&request, client, key) {
+                    let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+                    response.write(&mut buffer).unwrap();
+                    if let Some(ref mut signer) = signer {
+                        signer.sign(&mut buffer, tsig::now()).unwrap();
+                    }
+                    let len = buffer.pos;
+                    stream.write_all(&[(len >> 8) as u8, len as u8]).unwrap();
+                    stream.write_all(&buffer.buf[..len]).unwrap();
+                }
+            }
+        });
+
+        (authority, addr)
+    }
+
+    /// What to sign the response to the request `msg` with, if it was
+    /// signed with a key the primary knows.
+    fn answer(server: &Server, msg: &[u8]) -> Option<Signer> {
+        Signer::check_request(&server.keys, msg, tsig::now())
+            .unwrap()
+            .filter(|s| s.error == 0)
+    }
+
+    #[test]
+    fn follows_its_primary() {
+        let (primary_zones, addr) = primary(zone(1, &["a", "b"]), None);
+        let path = env::temp_dir().join(format!("dns-secondary-{}.zone", process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut config = ZoneConfig::new("example.com");
+        config.primaries.push(addr);
+        config.file = Some(path.clone());
+        let authority = Arc::new(Authority::new(vec![config.clone()]));
+        let secondary = Secondary::new(config, authority.clone());
+
+        secondary.refresh().unwrap();
+        assert_eq!(authority.get("example.com").unwrap().serial(), 1);
+        // Nothing to do while the primary is unchanged
+        secondary.refresh().unwrap();
+
+        primary_zones.insert(zone(2, &["a", "c"]));
+        secondary.refresh().unwrap();
+        let copy = authority.get("example.com").unwrap();
+        let original = primary_zones.get("example.com").unwrap();
+        assert_eq!(copy.serial(), 2);
+        assert_eq!(sorted(copy.records()), sorted(original.records()));
+        assert_eq!(copy.journal.len(), 1);
+
+        let saved = ZoneParser::load(&path, "example.com").unwrap();
+        assert_eq!(sorted(saved), sorted(original.records()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn signs_its_requests() {
+        let key = TsigKey::new("transfer", "hmac-sha256", b"secret".to_vec());
+        let (_, addr) = primary(zone(1, &["a"]), Some(key.clone()));
+
+        let mut config = ZoneConfig::new("example.com");
+        config.primaries.push(addr);
+        let authority = Arc::new(Authority::new(vec![config.clone()]));
+        let secondary = Secondary::new(config.clone(), authority.clone());
+        assert!(secondary.refresh().is_err());
+        assert!(authority.get("example.com").is_none());
+
+        config.key = Some(key);
+        let secondary = Secondary::new(config, authority.clone());
+        secondary.refresh().unwrap();
+        assert_eq!(authority.get("example.com").unwrap().serial(), 1);
+        secondary.refresh().unwrap();
+    }
+
+    #[test]
+    fn wakes_up_on_notify() {
+        let authority = Arc::new(Authority::new(Vec::new()));
+        let secondary = Secondary::new(ZoneConfig::new("example.com"), authority);
+        secondary.notify();
+
+        let start = Instant::now();
+        secondary.wait(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // The NOTIFY is used up
+        let start = Instant::now();
+        secondary.wait(Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
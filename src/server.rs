@@ -0,0 +1,512 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use authority::Authority;
+use dns_packet::DnsPacket;
+use journal;
+use opcode::Opcode;
+use query_log::QueryLog;
+use query_type::QueryType;
+use resolver::{AnswerSource, Resolver, EDNS_PAYLOAD_SIZE};
+use result_code::ResultCode;
+use secondary::Secondary;
+use transfer;
+use tsig::TsigKey;
+use update::Update;
+use zone::Zone;
+
+/// Answers queries for our own zones authoritatively, and everything else
+/// through the resolver when recursion is enabled.
+pub struct Server {
+    pub authority: Arc<Authority>,
+    pub resolver: Option<Arc<Resolver>>,
+    pub secondaries: Vec<Arc<Secondary>>,
+    // What signed requests may be signed with
+    pub keys: Vec<TsigKey>,
+    // Where the queries answered are logged, if anywhere
+    pub query_log: Option<QueryLog>,
+    // Held while an update is made, so that they're made one at a time
+    updating: Mutex<()>,
+}
+
+impl Server {
+    pub fn new(authority: Arc<Authority>, resolver: Option<Arc<Resolver>>) -> Server {
+        Server {
+            authority: authority,
+            resolver: resolver,
+            secondaries: Vec::new(),
+            keys: Vec::new(),
+            query_log: None,
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// Answers `request` from `client`, which may be a zone transfer that
+    /// takes several messages.
+    pub fn handle_tcp_query(
+        &self,
+        request: &DnsPacket,
+        client: IpAddr,
+        key: Option<&str>,
+    ) -> Vec<DnsPacket> {
+        self.answer_tcp_query(request, client, key).0
+    }
+
+    /// Answers `request` from `client`. `key` is what it was signed with, if
+    /// it was, which zone transfers may be allowed for.
+    pub fn handle_query(
+        &self,
+        request: &DnsPacket,
+        client: IpAddr,
+        key: Option<&str>,
+    ) -> DnsPacket {
+        self.answer_query(request, client, key).0
+    }
+
+    /// As `handle_tcp_query`, along with where the answer came from.
+    pub fn answer_tcp_query(
+        &self,
+        request: &DnsPacket,
+        client: IpAddr,
+        key: Option<&str>,
+    ) -> (Vec<DnsPacket>, AnswerSource) {
+        if is_transfer(request) {
+            return (self.transfer(request, client, key, true), AnswerSource::default());
+        }
+        let (packet, source) = self.answer_query(request, client, key);
+        (vec![packet], source)
+    }
+
+    /// As `handle_query`, along with where the answer came from.
+    pub fn answer_query(
+        &self,
+        request: &DnsPacket,
+        client: IpAddr,
+        key: Option<&str>,
+    ) -> (DnsPacket, AnswerSource) {
+        let local = AnswerSource::default();
+        let mut packet = self.response(request);
+
+        if request.questions.len() != 1 {
+            packet.header.rescode = ResultCode::FORMERR;
+            return (packet, local);
+        }
+        if request.header.opcode == Opcode::NOTIFY {
+            return (self.notify(request, client), local);
+        }
+        if request.header.opcode != Opcode::QUERY {
+            packet.header.rescode = ResultCode::NOTIMP;
+            return (packet, local);
+        }
+
+        if is_transfer(request) {
+            return (self.transfer(request, client, key, false).remove(0), local);
+        }
+
+        let question = &request.questions[0];
+        // Zones only hold class IN data
+        let zone = match question.class {
+            1 | 255 => self.authority.find(&question.name, question.qtype),
+            _ => None,
+        };
+
+        if let Some(zone) = zone {
+            info!("Authoritative query: {}", question);
+            let dnssec_ok = request.dnssec_ok();
+            let answer = zone.answer(&question.name, question.qtype, dnssec_ok);
+            packet.header.authoritative_answer = answer.header.authoritative_answer;
+            packet.header.rescode = answer.header.rescode;
+            packet.answers = answer.answers;
+            packet.authorities = answer.authorities;
+            packet.resources = answer.resources;
+            if request.get_edns().is_some() {
+                packet.set_edns(EDNS_PAYLOAD_SIZE, dnssec_ok);
+            }
+            return (packet, local);
+        }
+        // A secondary zone we don't have a current copy of
+        if self.authority.configured(&question.name).is_some() {
+            packet.header.rescode = ResultCode::SERVFAIL;
+            return (packet, local);
+        }
+
+        match self.resolver {
+            Some(ref resolver) => resolver.answer_query(request),
+            None => {
+                packet.header.rescode = ResultCode::REFUSED;
+                (packet, local)
+            }
+        }
+    }
+
+    /// Answers AXFR and IXFR requests from clients the zone's ACL allows.
+    /// Over UDP only IXFR is possible, and only with the SOA: that tells a
+    /// secondary to come back over TCP if it's behind (RFC 1995 section 2).
+    fn transfer(
+        &self,
+        request: &DnsPacket,
+        client: IpAddr,
+        key: Option<&str>,
+        tcp: bool,
+    ) -> Vec<DnsPacket> {
+        let mut packet = self.response(request);
+        let question = &request.questions[0];
+
+        let zone = match self.authority.get(&question.name) {
+            Some(x) => x,
+            None => {
+                packet.header.rescode = ResultCode::REFUSED;
+                return vec![packet];
+            }
+        };
+        if !self.authority.allows_transfer(&zone.name, client, key) {
+            warn!("Refused {} of {} to {}", question.qtype, zone.name, client);
+            packet.header.rescode = ResultCode::REFUSED;
+            return vec![packet];
+        }
+
+        let records = if question.qtype == QueryType::IXFR {
+            let serial = match transfer::requested_serial(request) {
+                Some(x) => x,
+                None => {
+                    packet.header.rescode = ResultCode::FORMERR;
+                    return vec![packet];
+                }
+            };
+            if tcp {
+                transfer::ixfr_records(&zone, serial)
+            } else {
+                vec![zone.soa().clone()]
+            }
+        } else {
+            // AXFR is only defined over TCP (RFC 5936 section 4.2)
+            if !tcp {
+                packet.header.rescode = ResultCode::FORMERR;
+                return vec![packet];
+            }
+            transfer::axfr_records(&zone)
+        };
+
+        info!(
+            "{} of {} with serial {} to {}",
+            question.qtype,
+            zone.name,
+            zone.serial(),
+            client
+        );
+        match transfer::messages(request, records) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to build {} of {}: {:?}", question.qtype, zone.name, e);
+                packet.header.rescode = ResultCode::SERVFAIL;
+                vec![packet]
+            }
+        }
+    }
+
+    /// Makes the changes in `update` from `client` to one of our zones, if
+    /// its prerequisites hold (RFC 2136). The new version is written to the
+    /// zone's journal. `key` is what the update was signed with, if it was.
+    pub fn handle_update(&self, update: &Update, client: IpAddr, key: Option<&str>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = update.header.id;
+        packet.header.opcode = Opcode::UPDATE;
+        packet.header.response = true;
+        packet.questions = update.zone.clone();
+
+        if update.zone.len() != 1 || update.zone[0].qtype != QueryType::SOA {
+            packet.header.rescode = ResultCode::FORMERR;
+            return packet;
+        }
+        let name = &update.zone[0].name;
+        let config = match self.authority.config(name) {
+            Some(x) => x,
+            None => {
+                packet.header.rescode = ResultCode::NOTAUTH;
+                return packet;
+            }
+        };
+        // Updates would have to be passed on to the primary
+        if config.is_secondary() {
+            packet.header.rescode = ResultCode::NOTIMP;
+            return packet;
+        }
+        if !config.allows_update(client, key) {
+            warn!("Refused update of {} from {}", config.name, client);
+            packet.header.rescode = ResultCode::REFUSED;
+            return packet;
+        }
+
+        let _updating = match self.updating.lock() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let zone = match self.authority.get(name) {
+            Some(x) => x,
+            None => {
+                packet.header.rescode = ResultCode::SERVFAIL;
+                return packet;
+            }
+        };
+        let records = match update.check(&zone).and_then(|_| update.apply(&zone)) {
+            Ok(Some(x)) => x,
+            Ok(None) => return packet,
+            Err(rescode) => {
+                debug!("Update of {} from {} failed with {:?}", zone.name, client, rescode);
+                packet.header.rescode = rescode;
+                return packet;
+            }
+        };
+        let updated = match Zone::new(&zone.name, records) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Update of {} from {} broke the zone: {}", zone.name, client, e);
+                packet.header.rescode = ResultCode::SERVFAIL;
+                return packet;
+            }
+        };
+
+        info!(
+            "Updated zone {} to serial {} for {}",
+            zone.name,
+            updated.serial(),
+            client
+        );
+        self.authority.insert(updated);
+        if let (Some(file), Some(zone)) = (config.file.as_ref(), self.authority.get(name)) {
+            if let Some(diff) = zone.journal.last() {
+                if let Err(e) = journal::append(&journal::path(file), diff) {
+                    error!("Failed to write the journal of zone {}: {}", zone.name, e);
+                }
+            }
+        }
+        packet
+    }
+
+    /// Has a secondary zone checked for changes when one of its primaries,
+    /// or another server allowed to, sends a NOTIFY (RFC 1996).
+    fn notify(&self, request: &DnsPacket, client: IpAddr) -> DnsPacket {
+        let mut packet = self.response(request);
+        let name = request.questions[0].name.trim_end_matches('.');
+
+        let secondary = self.secondaries
+            .iter()
+            .find(|s| s.config.name.eq_ignore_ascii_case(name));
+        match secondary {
+            Some(secondary) if secondary.config.allows_notify(client) => {
+                info!("NOTIFY for {} from {}", secondary.config.name, client);
+                secondary.notify();
+                packet.header.authoritative_answer = true;
+            }
+            _ => {
+                warn!("Refused NOTIFY for {} from {}", name, client);
+                packet.header.rescode = ResultCode::REFUSED;
+            }
+        }
+        packet
+    }
+
+    /// Turns away `request`, whose TSIG signature didn't check out. The
+    /// reason goes in the TSIG record of the response (RFC 8945 section 5.2).
+    pub fn handle_bad_signature(&self, request: &DnsPacket) -> DnsPacket {
+        let mut packet = self.response(request);
+        packet.header.rescode = ResultCode::NOTAUTH;
+        packet
+    }
+
+    /// An empty response to `request`.
+    fn response(&self, request: &DnsPacket) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.opcode = request.header.opcode;
+        packet.header.response = true;
+        packet.header.recursion_desired = request.header.recursion_desired;
+        packet.header.recursion_available = self.resolver.is_some();
+        packet.header.checking_disabled = request.header.checking_disabled;
+        packet.questions = request.questions.clone();
+
+        if request.get_edns().is_some() {
+            packet.set_edns(EDNS_PAYLOAD_SIZE, request.dnssec_ok());
+        }
+        packet
+    }
+}
+
+fn is_transfer(request: &DnsPacket) -> bool {
+    request.header.opcode == Opcode::QUERY
+        && request.questions.len() == 1
+        && (request.questions[0].qtype == QueryType::AXFR
+            || request.questions[0].qtype == QueryType::IXFR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byte_packet_buffer::BytePacketBuffer;
+    use dns_question::DnsQuestion;
+    use dns_record::DnsRecord;
+    use network::Network;
+    use update;
+    use zone::Zone;
+    use zone_config::ZoneConfig;
+    use zone_parser::ZoneParser;
+
+    fn server() -> Server {
+        let mut parser = ZoneParser::new("example.com");
+        parser
+            .parse_str(
+                "$TTL 60\n@ SOA ns1 hostmaster 1 2 3 4 5\n@ NS ns1\nns1 A 192.0.2.1\n",
+                "test.zone",
+            )
+            .unwrap();
+        let mut config = ZoneConfig::new("example.com");
+        config.allow_transfer.push(Network::parse("192.0.2.0/24").unwrap());
+        config.allow_update.push(Network::parse("192.0.2.53").unwrap());
+        let authority = Authority::new(vec![config]);
+        authority.insert(Zone::new("example.com", parser.records).unwrap());
+        Server::new(Arc::new(authority), None)
+    }
+
+    fn query(name: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype));
+        packet
+    }
+
+    fn client() -> IpAddr {
+        "192.0.2.53".parse().unwrap()
+    }
+
+    /// `packet` as a client would read it.
+    fn round_trip(mut packet: DnsPacket) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn answers_for_its_zones_authoritatively() {
+        let request = query("ns1.example.com", QueryType::A);
+        let response = round_trip(server().handle_query(&request, client(), None));
+        assert_eq!(response.header.id, 1234);
+        assert!(response.header.response);
+        assert!(response.header.authoritative_answer);
+        assert!(!response.header.recursion_available);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn refuses_other_zones_without_recursion() {
+        let request = query("example.org", QueryType::A);
+        let response = server().handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+        assert!(!response.header.authoritative_answer);
+        assert_eq!(response.questions.len(), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        let mut request = query("example.com", QueryType::A);
+        request.header.opcode = Opcode::STATUS;
+        let response = server().handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+
+        request.questions.clear();
+        let response = server().handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    }
+
+    #[test]
+    fn transfers_zones_to_allowed_clients() {
+        let server = server();
+        let request = query("example.com", QueryType::AXFR);
+        let messages = server.handle_tcp_query(&request, client(), None);
+        assert_eq!(messages.len(), 1);
+        let response = round_trip(messages.into_iter().next().unwrap());
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.answers.len(), 4);
+        assert_eq!(response.answers[0].get_querytype(), QueryType::SOA);
+        assert_eq!(response.answers[3].get_querytype(), QueryType::SOA);
+
+        let other = "198.51.100.1".parse().unwrap();
+        let messages = server.handle_tcp_query(&request, other, None);
+        assert_eq!(messages[0].header.rescode, ResultCode::REFUSED);
+        assert!(messages[0].answers.is_empty());
+
+        // Only over TCP
+        let response = server.handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    }
+
+    #[test]
+    fn answers_ixfr_over_udp_with_the_soa() {
+        let server = server();
+        let mut request = query("example.com", QueryType::IXFR);
+        let response = server.handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+
+        let soa = server.authority.get("example.com").unwrap().soa().clone();
+        let mut old = soa.clone();
+        if let DnsRecord::SOA { ref mut serial, .. } = old {
+            *serial = 0;
+        }
+        request.authorities.push(old);
+        let response = server.handle_query(&request, client(), None);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers, vec![soa]);
+    }
+
+    #[test]
+    fn accepts_notify_from_primaries() {
+        let mut server = server();
+        let mut config = ZoneConfig::new("example.org");
+        config.primaries.push("192.0.2.53:53".parse().unwrap());
+        let secondary = Secondary::new(config, server.authority.clone());
+        server.secondaries.push(Arc::new(secondary));
+
+        let mut request = query("example.org", QueryType::SOA);
+        request.header.opcode = Opcode::NOTIFY;
+        let response = round_trip(server.handle_query(&request, client(), None));
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.header.opcode, Opcode::NOTIFY);
+        assert!(response.header.authoritative_answer);
+
+        let other = "198.51.100.1".parse().unwrap();
+        let response = server.handle_query(&request, other, None);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+    }
+
+    #[test]
+    fn applies_updates_from_allowed_clients() {
+        let server = server();
+        let mut update = Update::new("example.com");
+        update.header.id = 4321;
+        let www = DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "192.0.2.80".parse().unwrap(),
+            ttl: 60,
+        };
+        update.updates.push((www.clone(), update::CLASS_IN));
+
+        let response = server.handle_update(&update, client(), None);
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(response.header.opcode, Opcode::UPDATE);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(server.authority.get("example.com").unwrap().serial(), 2);
+        let request = query("www.example.com", QueryType::A);
+        let response = server.handle_query(&request, client(), None);
+        assert_eq!(response.answers, vec![www]);
+
+        let other = "192.0.2.54".parse().unwrap();
+        let response = server.handle_update(&update, other, None);
+        assert_eq!(response.header.rescode, ResultCode::REFUSED);
+
+        let response = server.handle_update(&Update::new("example.org"), client(), None);
+        assert_eq!(response.header.rescode, ResultCode::NOTAUTH);
+    }
+}
@@ -0,0 +1,532 @@
+//! DNS over TLS (RFC 7858): the certificate we serve it with, and the
+//! connections we forward queries over. Messages on a TLS connection are
+//! framed as over TCP, each prefixed with its length.
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::{ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore,
+             ServerConfig, SignatureScheme, StreamOwned};
+
+// Where the system keeps its CA certificates, on the common distributions
+const SYSTEM_CA_FILES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+// Connections kept open for reuse, across all upstreams
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn invalid<E: Display>(what: &str, e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", what, e))
+}
+
+/// A server config presenting the certificate chain in the PEM file
+/// `cert_file`, with the private key in `key_file`, and offering the
+/// application protocols in `alpn`.
+pub fn server_config(
+    cert_file: &str,
+    key_file: &str,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>, Error> {
+    let certs = try!(read_certs(cert_file));
+    if certs.is_empty() {
+        return Err(invalid(cert_file, "no certificates found"));
+    }
+    let key = try!(PrivateKeyDer::from_pem_file(key_file).map_err(|e| invalid(key_file, e)));
+
+    let config = try!(ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(cert_file, e)));
+    let mut config = try!(config
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(cert_file, e)));
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut text = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut text)));
+    CertificateDer::pem_slice_iter(&text)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))
+}
+
+/// The SHA-256 digest of a certificate's public key, as pinned by
+/// `upstream.tls_pins` (RFC 7858 section 4.2).
+pub fn spki_pin(cert: &CertificateDer) -> Result<Vec<u8>, Error> {
+    let cert = try!(ParsedCertificate::try_from(cert).map_err(|e| invalid("certificate", e)));
+    let spki = cert.subject_public_key_info();
+    Ok(digest::digest(&digest::SHA256, spki.as_ref()).as_ref().to_vec())
+}
+
+/// Accepts certificates whose public key is one of `pins`, checking the
+/// chain and name too if there are CAs to check them against.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<Vec<u8>>,
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(ref webpki) = self.webpki {
+            try!(webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now
+            ));
+        }
+        let pin = try!(spki_pin(end_entity).map_err(|e| rustls::Error::General(e.to_string())));
+        if !self.pins.contains(&pin) {
+            return Err(rustls::Error::General(
+                "certificate doesn't match any pinned key".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// A client config trusting servers whose key is one of `pins`, or whose
+/// certificate a CA in `ca_file` issued, or the system's CAs without either;
+/// and asking for the application protocols in `alpn`.
+pub fn client_config(
+    pins: Vec<Vec<u8>>,
+    ca_file: Option<&str>,
+    alpn: &[&[u8]],
+) -> Result<Arc<ClientConfig>, Error> {
+    let provider = provider();
+    let ca_file = match ca_file {
+        Some(path) => Some(path),
+        None if pins.is_empty() => match SYSTEM_CA_FILES.iter().find(|f| Path::new(f).exists()) {
+            Some(path) => Some(*path),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    "no system CA certificates found, a CA file or pins are needed",
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let webpki = match ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(try!(read_certs(path)));
+            if added == 0 {
+                return Err(invalid(path, "no CA certificates found"));
+            }
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build();
+            Some(try!(verifier.map_err(|e| invalid(path, e))))
+        }
+        None => None,
+    };
+
+    let builder = try!(ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid("TLS", e)));
+    let mut config = match webpki {
+        Some(webpki) if pins.is_empty() => builder
+            .with_webpki_verifier(webpki)
+            .with_no_client_auth(),
+        webpki => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                pins: pins,
+                webpki: webpki,
+                algorithms: provider.signature_verification_algorithms,
+            }))
+            .with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Connections to DoT upstreams. Each is kept open after a query so that
+/// the next one skips the handshake.
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    // The name upstream certificates have to be for, rather than their IP
+    name: Option<String>,
+    idle: Mutex<Vec<(SocketAddr, TlsStream)>>,
+}
+
+impl TlsClient {
+    /// A client trusting upstreams whose key is one of `pins`, or whose
+    /// certificate a CA in `ca_file` issued. Without either, the system's
+    /// CAs are trusted.
+    pub fn new(
+        name: Option<String>,
+        pins: Vec<Vec<u8>>,
+        ca_file: Option<&str>,
+    ) -> Result<TlsClient, Error> {
+        Ok(TlsClient {
+            config: try!(client_config(pins, ca_file, &[])),
+            name: name,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Sends the message `request` to `server` and returns its response,
+    /// over a connection left open by an earlier exchange if there is one.
+    pub fn exchange(
+        &self,
+        server: SocketAddr,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        // The server may well have closed an idle connection since, in
+        // which case a new one is made
+        if let Some(mut stream) = self.take_idle(server) {
+            if let Ok(response) = exchange_on(&mut stream, request, timeout) {
+                self.put_idle(server, stream);
+                return Ok(response);
+            }
+        }
+
+        let mut stream = try!(self.connect(server, timeout));
+        let response = try!(exchange_on(&mut stream, request, timeout));
+        self.put_idle(server, stream);
+        Ok(response)
+    }
+
+    fn connect(&self, server: SocketAddr, timeout: Duration) -> Result<TlsStream, Error> {
+        let name = match self.name {
+            Some(ref name) => try!(ServerName::try_from(name.clone())
+                .map_err(|e| invalid(name, e))),
+            None => ServerName::IpAddress(server.ip().into()),
+        };
+        let connection = try!(ClientConnection::new(self.config.clone(), name)
+            .map_err(|e| invalid(&server.to_string(), e)));
+        let stream = try!(TcpStream::connect_timeout(&server, timeout));
+        try!(stream.set_nodelay(true));
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    fn take_idle(&self, server: SocketAddr) -> Option<TlsStream> {
+        let mut idle = match self.idle.lock() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let i = idle.iter().position(|&(addr, _)| addr == server);
+        i.map(|i| idle.remove(i).1)
+    }
+
+    fn put_idle(&self, server: SocketAddr, stream: TlsStream) {
+        let mut idle = match self.idle.lock() {
+            Ok(x) => x,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if idle.len() >= MAX_IDLE_CONNECTIONS {
+            idle.remove(0);
+        }
+        idle.push((server, stream));
+    }
+}
+
+fn exchange_on(
+    stream: &mut TlsStream,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    try!(stream.sock.set_read_timeout(Some(timeout)));
+    try!(stream.sock.set_write_timeout(Some(timeout)));
+
+    let len = request.len();
+    let mut message = Vec::with_capacity(len + 2);
+    message.extend_from_slice(&[(len >> 8) as u8, (len & 0xFF) as u8]);
+    message.extend_from_slice(request);
+    try!(stream.write_all(&message));
+    try!(stream.flush());
+
+    let mut len_buf = [0u8; 2];
+    try!(stream.read_exact(&mut len_buf));
+    let mut response = vec![0u8; ((len_buf[0] as usize) << 8) | len_buf[1] as usize];
+    try!(stream.read_exact(&mut response));
+    Ok(response)
+}
+
+/// Splits a TLS connection over `tcp` into halves that can be read and
+/// written from different threads at once, as HTTP/2 needs.
+pub fn split<C: Into<Connection>>(
+    connection: C,
+    tcp: TcpStream,
+) -> Result<(TlsReader, TlsWriter), Error> {
+    let connection = Arc::new(Mutex::new(connection.into()));
+    let reader = TlsReader {
+        connection: connection.clone(),
+        tcp: try!(tcp.try_clone()),
+    };
+    let writer = TlsWriter {
+        connection: connection,
+        tcp: tcp,
+    };
+    Ok((reader, writer))
+}
+
+fn lock<'a>(connection: &'a Mutex<Connection>) -> MutexGuard<'a, Connection> {
+    match connection.lock() {
+        Ok(x) => x,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Sends whatever TLS records are waiting to go.
+fn send_records(connection: &mut Connection, tcp: &mut TcpStream) -> Result<(), Error> {
+    while connection.wants_write() {
+        try!(connection.write_tls(tcp));
+    }
+    Ok(())
+}
+
+pub struct TlsReader {
+    connection: Arc<Mutex<Connection>>,
+    tcp: TcpStream,
+}
+
+impl TlsReader {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.tcp.set_read_timeout(timeout)
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut records = [0u8; 16384];
+        loop {
+            {
+                let mut connection = lock(&self.connection);
+                match connection.reader().read(buf) {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                // Our half of the handshake, say
+                try!(send_records(&mut connection, &mut self.tcp));
+            }
+
+            // Waiting on the socket mustn't keep the writer from sending
+            let len = try!(self.tcp.read(&mut records));
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut connection = lock(&self.connection);
+            let mut data = &records[..len];
+            while !data.is_empty() {
+                try!(connection.read_tls(&mut data));
+                try!(connection
+                    .process_new_packets()
+                    .map_err(|e| invalid("TLS", e)));
+            }
+            try!(send_records(&mut connection, &mut self.tcp));
+        }
+    }
+}
+
+pub struct TlsWriter {
+    connection: Arc<Mutex<Connection>>,
+    tcp: TcpStream,
+}
+
+impl TlsWriter {
+    /// Tells the peer we're done, and closes our side of the connection.
+    pub fn close(&mut self) -> Result<(), Error> {
+        {
+            let mut connection = lock(&self.connection);
+            connection.send_close_notify();
+            try!(send_records(&mut connection, &mut self.tcp));
+        }
+        self.tcp.shutdown(Shutdown::Write)
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut connection = lock(&self.connection);
+        let len = try!(connection.writer().write(buf));
+        try!(send_records(&mut connection, &mut self.tcp));
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut connection = lock(&self.connection);
+        send_records(&mut connection, &mut self.tcp)
+    }
+}
+
+/// What the tests of the TLS, DoH and DoQ transports share.
+#[cfg(test)]
+pub mod test_support {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    use rcgen;
+    use rustls::pki_types::CertificateDer;
+    use rustls::ServerConfig;
+
+    /// A self-signed certificate, written out with its key for servers to
+    /// present and clients to trust. The files go when it's dropped.
+    pub struct Certificate {
+        pub cert_file: String,
+        pub key_file: String,
+        pub der: CertificateDer<'static>,
+    }
+
+    impl Certificate {
+        /// A certificate for `name`, in files named after `test` so that
+        /// tests running at once don't share them.
+        pub fn new(test: &str, name: &str) -> Certificate {
+            let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+            let path = |ext| {
+                let file = env::temp_dir().join(format!("dns-{}-{}.{}", test, process::id(), ext));
+                file.to_str().unwrap().to_string()
+            };
+            let certificate = Certificate {
+                cert_file: path("crt"),
+                key_file: path("key"),
+                der: cert.cert.der().clone(),
+            };
+            fs::write(&certificate.cert_file, cert.cert.pem()).unwrap();
+            fs::write(&certificate.key_file, cert.key_pair.serialize_pem()).unwrap();
+            certificate
+        }
+
+        /// A server config presenting it, offering `alpn`.
+        pub fn server_config(&self, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+            super::server_config(&self.cert_file, &self.key_file, alpn).unwrap()
+        }
+    }
+
+    impl Drop for Certificate {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.cert_file);
+            let _ = fs::remove_file(&self.key_file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::Certificate;
+    use super::*;
+    use rustls::{ServerConnection, StreamOwned};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// A server sending every message back over TLS, and the number of
+    /// connections made to it.
+    fn echo_server(config: Arc<ServerConfig>) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let connection = ServerConnection::new(config.clone()).unwrap();
+                let mut stream = StreamOwned::new(connection, stream.unwrap());
+                thread::spawn(move || loop {
+                    let mut len_buf = [0u8; 2];
+                    if stream.read_exact(&mut len_buf).is_err() {
+                        return;
+                    }
+                    let mut msg = vec![0u8; ((len_buf[0] as usize) << 8) | len_buf[1] as usize];
+                    stream.read_exact(&mut msg).unwrap();
+                    stream.write_all(&len_buf).unwrap();
+                    stream.write_all(&msg).unwrap();
+                    stream.flush().unwrap();
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    #[test]
+    fn reuses_connections_to_pinned_servers() {
+        let cert = Certificate::new("pinned", "localhost");
+        let (addr, connections) = echo_server(cert.server_config(&[]));
+        let timeout = Duration::from_secs(5);
+
+        let client = TlsClient::new(None, vec![spki_pin(&cert.der).unwrap()], None).unwrap();
+        assert_eq!(client.exchange(addr, b"first", timeout).unwrap(), b"first".to_vec());
+        assert_eq!(client.exchange(addr, b"second", timeout).unwrap(), b"second".to_vec());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let other = TlsClient::new(None, vec![vec![0; 32]], None).unwrap();
+        assert!(other.exchange(addr, b"first", timeout).is_err());
+    }
+
+    #[test]
+    fn checks_names_against_ca_file() {
+        let cert = Certificate::new("ca", "localhost");
+        let (addr, _) = echo_server(cert.server_config(&[]));
+        let timeout = Duration::from_secs(5);
+
+        let name = Some("localhost".to_string());
+        let client = TlsClient::new(name, Vec::new(), Some(&cert.cert_file)).unwrap();
+        assert_eq!(client.exchange(addr, b"query", timeout).unwrap(), b"query".to_vec());
+
+        let name = Some("dns.example".to_string());
+        let client = TlsClient::new(name, Vec::new(), Some(&cert.cert_file)).unwrap();
+        assert!(client.exchange(addr, b"query", timeout).is_err());
+    }
+}
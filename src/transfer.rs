@@ -0,0 +1,348 @@
+//! Zone transfers, both ways: AXFR (RFC 5936) and IXFR (RFC 1995).
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_packet::DnsPacket;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use query_type::QueryType;
+use resolver;
+use result_code::ResultCode;
+use tsig::{self, Signer, TsigKey};
+use zone::{self, Zone};
+
+/// Roughly how many bytes of records go in each message of a transfer.
+const MESSAGE_SIZE: usize = 16 * 1024;
+
+/// The whole zone, starting and ending with its SOA.
+pub fn axfr_records(zone: &Zone) -> Vec<DnsRecord> {
+    let soa = zone.soa().clone();
+    let mut records = vec![soa.clone()];
+    records.extend(
+        zone.records()
+            .into_iter()
+            .filter(|r| r.get_querytype() != QueryType::SOA),
+    );
+    records.push(soa);
+    records
+}
+
+/// What a secondary at version `serial` needs: just the SOA when it's up to
+/// date, the journalled changes when there are any, or else the whole zone.
+pub fn ixfr_records(zone: &Zone, serial: u32) -> Vec<DnsRecord> {
+    let soa = zone.soa().clone();
+    if !zone::serial_newer(zone.serial(), serial) {
+        return vec![soa];
+    }
+
+    let changes = match zone.changes_since(serial) {
+        Some(x) => x,
+        None => return axfr_records(zone),
+    };
+    let mut records = vec![soa.clone()];
+    for diff in changes {
+        records.extend(diff.removed.iter().cloned());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa);
+    records
+}
+
+/// The serial in the SOA an IXFR request carries in its authority section.
+pub fn requested_serial(request: &DnsPacket) -> Option<u32> {
+    request
+        .authorities
+        .iter()
+        .filter_map(|r| match *r {
+            DnsRecord::SOA { serial, .. } => Some(serial),
+            _ => None,
+        })
+        .next()
+}
+
+/// Spreads `records` over as many responses to `request` as they need. Only
+/// the first one repeats the question.
+pub fn messages(request: &DnsPacket, records: Vec<DnsRecord>) -> Result<Vec<DnsPacket>, Error> {
+    let mut messages = Vec::new();
+    let mut current = response(request, true);
+    let mut size = 0;
+
+    for record in records {
+        let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+        let len = try!(record.write(&mut buffer));
+        if size + len > MESSAGE_SIZE && !current.answers.is_empty() {
+            messages.push(current);
+            current = response(request, false);
+            size = 0;
+        }
+        current.answers.push(record);
+        size += len;
+    }
+
+    messages.push(current);
+    Ok(messages)
+}
+
+/// Transfers the zone `name` from `server`: with IXFR when we already have a
+/// version of it, given by its SOA, or else with AXFR. Returns the records
+/// of every message, from the opening SOA up to the closing one. A lone SOA
+/// means our version is current. With a `key`, the request is signed and
+/// so must the messages be.
+pub fn fetch(
+    name: &str,
+    current: Option<&DnsRecord>,
+    server: SocketAddr,
+    timeout: Duration,
+    key: Option<&TsigKey>,
+) -> Result<Vec<DnsRecord>, Error> {
+    let qtype = match current {
+        Some(_) => QueryType::IXFR,
+        None => QueryType::AXFR,
+    };
+    let mut request = DnsPacket::new();
+    request.header.id = resolver::random_id();
+    request
+        .questions
+        .push(DnsQuestion::new(name.to_string(), qtype));
+    if let Some(soa) = current {
+        request.authorities.push(soa.clone());
+    }
+
+    let mut req_buffer = BytePacketBuffer::with_size(0xFFFF);
+    try!(request.clone().write(&mut req_buffer));
+    let mut signer = key.map(|key| Signer::new(key.clone()));
+    if let Some(ref mut signer) = signer {
+        try!(signer.sign(&mut req_buffer, tsig::now()));
+    }
+
+    let mut stream = try!(TcpStream::connect_timeout(&server, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+
+    let len = req_buffer.pos();
+    try!(stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8]));
+    try!(stream.write_all(&req_buffer.buf[0..len]));
+
+    let mut records = Vec::new();
+    loop {
+        let mut len_buf = [0u8; 2];
+        try!(stream.read_exact(&mut len_buf));
+        let len = ((len_buf[0] as usize) << 8) | len_buf[1] as usize;
+
+        let mut res_buffer = BytePacketBuffer::with_size(len);
+        try!(stream.read_exact(&mut res_buffer.buf));
+        let response = try!(DnsPacket::from_buffer(&mut res_buffer));
+
+        if !response.header.response || response.header.id != request.header.id {
+            return Err(invalid(format!("mismatched {} response from {}", qtype, server)));
+        }
+        if let Some(ref mut signer) = signer {
+            try!(signer.verify(&res_buffer.buf, tsig::now()));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(invalid(format!(
+                "{} of {} refused by {} with {:?}",
+                qtype, name, server, response.header.rescode
+            )));
+        }
+
+        let first = records.is_empty();
+        records.extend(response.answers);
+        match records.first() {
+            Some(&DnsRecord::SOA { .. }) => {}
+            _ => return Err(invalid(format!("{} from {} doesn't start with the SOA", qtype, server))),
+        }
+        if first && records.len() == 1 && qtype == QueryType::IXFR || is_complete(&records) {
+            if signer.as_ref().is_some_and(|s| s.has_unsigned()) {
+                return Err(invalid(format!("{} from {} ends unsigned", qtype, server)));
+            }
+            return Ok(records);
+        }
+    }
+}
+
+fn serial(record: &DnsRecord) -> Option<u32> {
+    match *record {
+        DnsRecord::SOA { serial, .. } => Some(serial),
+        _ => None,
+    }
+}
+
+/// Whether transferred `records` hold changes rather than the whole zone:
+/// then the record after the new SOA is the SOA we started from.
+fn is_incremental(records: &[DnsRecord]) -> bool {
+    records.len() > 1 && serial(&records[1]).is_some() && serial(&records[1]) != serial(&records[0])
+}
+
+/// Whether transferred `records` end with the closing SOA. In incremental
+/// transfers the new SOA also starts the last batch of additions.
+fn is_complete(records: &[DnsRecord]) -> bool {
+    let new_serial = serial(&records[0]);
+    let seen = records[1..]
+        .iter()
+        .filter(|r| serial(r).is_some() && serial(r) == new_serial)
+        .count();
+    let needed = if is_incremental(records) { 2 } else { 1 };
+    seen == needed && serial(&records[records.len() - 1]).is_some()
+}
+
+/// The zone after a transfer of `records`: the transferred zone itself, or
+/// `current` with the transferred changes made to it. Fails if the changes
+/// don't fit `current`.
+pub fn apply(current: Option<&Zone>, records: &[DnsRecord]) -> Result<Vec<DnsRecord>, Error> {
+    if !is_incremental(records) {
+        return Ok(records[..records.len() - 1].to_vec());
+    }
+    let mut zone = match current {
+        Some(x) => x.records(),
+        None => return Err(invalid("incremental transfer without a zone to apply it to".into())),
+    };
+
+    // Each batch of removals starts with the old SOA, each batch of
+    // additions with the new one
+    let mut removing = false;
+    for record in &records[1..records.len() - 1] {
+        if serial(record).is_some() {
+            removing = !removing;
+        }
+        if removing {
+            match zone.iter().position(|r| r == record) {
+                Some(i) => {
+                    zone.remove(i);
+                }
+                None => return Err(invalid(format!("removes a record we don't have: {}", record))),
+            }
+        } else if !zone.contains(record) {
+            zone.push(record.clone());
+        }
+    }
+    Ok(zone)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn response(request: &DnsPacket, with_question: bool) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.response = true;
+    packet.header.authoritative_answer = true;
+    if with_question {
+        packet.questions = request.questions.clone();
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_question::DnsQuestion;
+    use zone_parser::ZoneParser;
+
+    fn zone(serial: u32, hosts: &[&str]) -> Zone {
+        let mut text = format!("$TTL 60\n@ SOA ns1 hostmaster {} 2 3 4 5\n@ NS ns1\n", serial);
+        for host in hosts {
+            text.push_str(&format!("{} A 192.0.2.1\n", host));
+        }
+        let mut parser = ZoneParser::new("example.com");
+        parser.parse_str(&text, "test.zone").unwrap();
+        Zone::new("example.com", parser.records).unwrap()
+    }
+
+    fn serials_and_names(records: &[DnsRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| match *r {
+                DnsRecord::SOA { serial, .. } => format!("SOA {}", serial),
+                _ => r.get_domain().to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn brackets_axfr_with_the_soa() {
+        let records = axfr_records(&zone(7, &["www"]));
+        assert_eq!(
+            serials_and_names(&records),
+            vec!["SOA 7", "example.com", "www.example.com", "SOA 7"]
+        );
+    }
+
+    #[test]
+    fn sends_journalled_changes() {
+        let v1 = zone(1, &["a", "b"]);
+        let mut v2 = zone(2, &["a", "c"]);
+        v2.follow(&v1);
+        let mut v3 = zone(3, &["a", "c", "d"]);
+        v3.follow(&v2);
+
+        assert_eq!(
+            serials_and_names(&ixfr_records(&v3, 1)),
+            vec![
+                "SOA 3",
+                "SOA 1",
+                "b.example.com",
+                "SOA 2",
+                "c.example.com",
+                "SOA 2",
+                "SOA 3",
+                "d.example.com",
+                "SOA 3",
+            ]
+        );
+        assert_eq!(serials_and_names(&ixfr_records(&v3, 3)), vec!["SOA 3"]);
+        // Versions the journal doesn't know fall back to the whole zone
+        assert_eq!(ixfr_records(&v3, 0), axfr_records(&v3));
+    }
+
+    #[test]
+    fn applies_incremental_transfers() {
+        let v1 = zone(1, &["a", "b"]);
+        let mut v2 = zone(2, &["a", "c"]);
+        v2.follow(&v1);
+        let mut v3 = zone(3, &["a", "c", "d"]);
+        v3.follow(&v2);
+
+        let records = ixfr_records(&v3, 1);
+        assert!(is_complete(&records));
+        assert!(!is_complete(&records[..records.len() - 1]));
+        let mut applied = apply(Some(&v1), &records).unwrap();
+        let mut expected = v3.records();
+        applied.sort_by_key(|r| r.to_string());
+        expected.sort_by_key(|r| r.to_string());
+        assert_eq!(applied, expected);
+
+        // Changes against a version we don't have
+        assert!(apply(Some(&v2), &records).is_err());
+        assert!(apply(None, &records).is_err());
+
+        let records = axfr_records(&v3);
+        assert!(is_complete(&records));
+        assert!(!is_complete(&records[..2]));
+        assert_eq!(apply(Some(&v1), &records).unwrap().len(), v3.records().len());
+    }
+
+    #[test]
+    fn splits_large_transfers() {
+        let hosts = (0..2000).map(|i| format!("host{}", i)).collect::<Vec<String>>();
+        let zone = zone(1, &hosts.iter().map(|h| h.as_str()).collect::<Vec<&str>>());
+
+        let mut request = DnsPacket::new();
+        request.header.id = 77;
+        request
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+        let messages = messages(&request, axfr_records(&zone)).unwrap();
+
+        assert!(messages.len() > 1);
+        assert_eq!(messages[0].questions.len(), 1);
+        assert!(messages[1..].iter().all(|m| m.questions.is_empty()));
+        assert!(messages.iter().all(|m| m.header.id == 77));
+        let total = messages.iter().map(|m| m.answers.len()).sum::<usize>();
+        assert_eq!(total, 2003);
+    }
+}
@@ -0,0 +1,481 @@
+//! TSIG (RFC 8945): messages signed with a secret shared between client and
+//! server, authenticating zone transfers and updates. Each message carries
+//! its MAC in a TSIG record at the end of the additional section, and the
+//! MAC of a response covers the one of the request it answers, chaining the
+//! messages of a transfer together.
+
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_header::DnsHeader;
+use dns_name;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use query_type::QueryType;
+
+// TSIG errors, carried in the record since they don't fit the header
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+// How far apart the signer's clock and ours may be, in seconds
+const FUDGE: u16 = 300;
+const CLASS_ANY: u16 = 255;
+
+/// Seconds since the epoch, as TSIG records give their time in.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn error_name(error: u16) -> String {
+    match error {
+        BADSIG => "BADSIG".to_string(),
+        BADKEY => "BADKEY".to_string(),
+        BADTIME => "BADTIME".to_string(),
+        _ => error.to_string(),
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// A shared secret, known by its name on both ends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TsigKey {
+    pub name: String,
+    // hmac-sha256, hmac-sha384 or hmac-sha512
+    pub algorithm: String,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: &str, secret: Vec<u8>) -> TsigKey {
+        TsigKey {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm: algorithm.trim_end_matches('.').to_ascii_lowercase(),
+            secret: secret,
+        }
+    }
+
+    pub fn is_supported(algorithm: &str) -> bool {
+        TsigKey::new("", algorithm, Vec::new()).hmac().is_some()
+    }
+
+    fn hmac(&self) -> Option<hmac::Key> {
+        let algorithm = match self.algorithm.as_str() {
+            "hmac-sha256" => hmac::HMAC_SHA256,
+            "hmac-sha384" => hmac::HMAC_SHA384,
+            "hmac-sha512" => hmac::HMAC_SHA512,
+            _ => return None,
+        };
+        Some(hmac::Key::new(algorithm, &self.secret))
+    }
+
+    fn matches(&self, tsig: &Tsig) -> bool {
+        dns_name::names_equal(&self.name, &tsig.key)
+            && dns_name::names_equal(&self.algorithm, &tsig.algorithm)
+    }
+}
+
+/// The TSIG record of a message.
+struct Tsig {
+    key: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    /// Finds the TSIG record of the message `msg`, returning where it starts
+    /// along with it. It has to be the last record.
+    fn find(msg: &[u8]) -> Result<Option<(usize, Tsig)>, Error> {
+        let mut buffer = BytePacketBuffer::with_size(msg.len());
+        buffer.buf.copy_from_slice(msg);
+        let mut header = DnsHeader::new();
+        try!(header.read(&mut buffer));
+        for _ in 0..header.questions {
+            let mut question = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+            try!(question.read(&mut buffer));
+        }
+
+        let count = header.answers as usize + header.authoritative_entries as usize
+            + header.resource_entries as usize;
+        let mut last = None;
+        for i in 0..count {
+            let start = buffer.pos();
+            let (record, _) = try!(DnsRecord::read_with_class(&mut buffer));
+            if record.get_querytype() == QueryType::TSIG {
+                if i != count - 1 || header.resource_entries == 0 {
+                    return Err(invalid("TSIG record isn't the last one".into()));
+                }
+                last = Some(start);
+            }
+        }
+        let start = match last {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        buffer.pos = start;
+        let mut key = String::new();
+        try!(buffer.read_qname(&mut key));
+        // Type, class and TTL, then the data length
+        buffer.pos += 8;
+        let data_end = try!(buffer.read_u16()) as usize + buffer.pos();
+        let mut algorithm = String::new();
+        try!(buffer.read_qname(&mut algorithm));
+        let time_signed = ((try!(buffer.read_u16()) as u64) << 32) | try!(buffer.read_u32()) as u64;
+        let fudge = try!(buffer.read_u16());
+        let mac_len = try!(buffer.read_u16()) as usize;
+        let mac = try!(buffer.read_bytes(mac_len));
+        let original_id = try!(buffer.read_u16());
+        let error = try!(buffer.read_u16());
+        let other_len = try!(buffer.read_u16()) as usize;
+        let other = try!(buffer.read_bytes(other_len));
+        if buffer.pos() != data_end {
+            return Err(invalid("malformed TSIG record".into()));
+        }
+
+        let tsig = Tsig {
+            key: key,
+            algorithm: algorithm,
+            time_signed: time_signed,
+            fudge: fudge,
+            mac: mac,
+            original_id: original_id,
+            error: error,
+            other: other,
+        };
+        Ok(Some((start, tsig)))
+    }
+
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<(), Error> {
+        try!(buffer.write_qname(&self.key));
+        try!(buffer.write_u16(QueryType::TSIG.to_num()));
+        try!(buffer.write_u16(CLASS_ANY));
+        try!(buffer.write_u32(0));
+
+        let len_pos = buffer.pos();
+        try!(buffer.write_u16(0));
+        try!(buffer.write_qname(&self.algorithm));
+        try!(buffer.write_u16((self.time_signed >> 32) as u16));
+        try!(buffer.write_u32(self.time_signed as u32));
+        try!(buffer.write_u16(self.fudge));
+        try!(buffer.write_u16(self.mac.len() as u16));
+        try!(buffer.write_bytes(&self.mac));
+        try!(buffer.write_u16(self.original_id));
+        try!(buffer.write_u16(self.error));
+        try!(buffer.write_u16(self.other.len() as u16));
+        try!(buffer.write_bytes(&self.other));
+
+        let len = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, len as u16)
+    }
+
+    /// The TSIG variables a MAC covers after the message (RFC 8945 section
+    /// 4.3.3). Only the timers are covered in the messages of a transfer
+    /// after the first one.
+    fn variables(&self, timers_only: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        if !timers_only {
+            data.extend(dns_name::to_canonical_wire(&self.key));
+            data.extend(&[(CLASS_ANY >> 8) as u8, CLASS_ANY as u8, 0, 0, 0, 0]);
+            data.extend(dns_name::to_canonical_wire(&self.algorithm));
+        }
+        for i in (0..6).rev() {
+            data.push((self.time_signed >> (8 * i)) as u8);
+        }
+        data.extend(&[(self.fudge >> 8) as u8, self.fudge as u8]);
+        if !timers_only {
+            data.extend(&[(self.error >> 8) as u8, self.error as u8]);
+            data.extend(&[(self.other.len() >> 8) as u8, self.other.len() as u8]);
+            data.extend(&self.other);
+        }
+        data
+    }
+}
+
+/// `msg` as it was before `tsig`, starting at `start`, was added to it: with
+/// one record less, and the ID it was signed with.
+fn unsigned(msg: &[u8], start: usize, tsig: &Tsig) -> Vec<u8> {
+    let mut res = msg[..start].to_vec();
+    res[0] = (tsig.original_id >> 8) as u8;
+    res[1] = tsig.original_id as u8;
+    let count = ((res[10] as u16) << 8 | res[11] as u16) - 1;
+    res[10] = (count >> 8) as u8;
+    res[11] = count as u8;
+    res
+}
+
+/// Signs and checks the messages of one exchange with a key: a request and
+/// the responses to it, which may be several for a zone transfer.
+pub struct Signer {
+    pub key: TsigKey,
+    // Why a request's signature didn't check out, told to the client in the
+    // response
+    pub error: u16,
+    // MAC of the last signed message, which the next one's covers
+    prior: Option<Vec<u8>>,
+    // When the request was signed, echoed back in BADTIME responses
+    request_time: u64,
+    // Responses signed or checked so far
+    responses: usize,
+    // Unsigned messages of a transfer since the last signed one
+    pending: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(key: TsigKey) -> Signer {
+        Signer {
+            key: key,
+            error: 0,
+            prior: None,
+            request_time: 0,
+            responses: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Checks the signature of the request `msg` with whichever of `keys` it
+    /// names. Unsigned requests have no signer; failing ones get one with the
+    /// `error` their response should carry.
+    pub fn check_request(keys: &[TsigKey], msg: &[u8], now: u64) -> Result<Option<Signer>, Error> {
+        let (start, tsig) = match try!(Tsig::find(msg)) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let key = keys.iter().find(|key| key.matches(&tsig)).cloned();
+        let mut signer = match key {
+            Some(key) => Signer::new(key),
+            None => {
+                let mut signer = Signer::new(TsigKey::new(&tsig.key, &tsig.algorithm, Vec::new()));
+                signer.error = BADKEY;
+                return Ok(Some(signer));
+            }
+        };
+        signer.request_time = tsig.time_signed;
+        if !signer.verify_mac(&unsigned(msg, start, &tsig), &tsig) {
+            signer.error = BADSIG;
+            return Ok(Some(signer));
+        }
+        if !in_time(&tsig, now) {
+            signer.error = BADTIME;
+        }
+        signer.prior = Some(tsig.mac);
+        Ok(Some(signer))
+    }
+
+    /// Signs the message in `buffer`, up to its position, adding the TSIG
+    /// record to it. Responses to requests that failed their check carry
+    /// the error; only BADTIME ones are signed then.
+    pub fn sign(&mut self, buffer: &mut BytePacketBuffer, now: u64) -> Result<(), Error> {
+        let end = buffer.pos();
+        if end < 12 {
+            return Err(invalid("message too short to sign".into()));
+        }
+        let mut tsig = Tsig {
+            key: self.key.name.clone(),
+            algorithm: self.key.algorithm.clone(),
+            time_signed: now,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: (buffer.buf[0] as u16) << 8 | buffer.buf[1] as u16,
+            error: self.error,
+            other: Vec::new(),
+        };
+        if self.error == BADTIME {
+            tsig.time_signed = self.request_time;
+            tsig.other = (0..6).rev().map(|i| (now >> (8 * i)) as u8).collect();
+        }
+        if self.error != BADKEY && self.error != BADSIG {
+            let data = self.signed_data(&buffer.buf[..end], &tsig);
+            let key = try!(self.key
+                .hmac()
+                .ok_or_else(|| invalid(format!("unsupported algorithm {}", self.key.algorithm))));
+            tsig.mac = hmac::sign(&key, &data).as_ref().to_vec();
+        }
+
+        try!(tsig.write(buffer));
+        let count = ((buffer.buf[10] as u16) << 8 | buffer.buf[11] as u16) + 1;
+        try!(buffer.set_u16(10, count));
+
+        if self.prior.is_some() {
+            self.responses += 1;
+        }
+        self.prior = Some(tsig.mac);
+        Ok(())
+    }
+
+    /// Checks the signature of the response `msg` to the request we signed.
+    /// Messages of a transfer after the first may go unsigned, as long as a
+    /// later one is signed.
+    pub fn verify(&mut self, msg: &[u8], now: u64) -> Result<(), Error> {
+        let (start, tsig) = match try!(Tsig::find(msg)) {
+            Some(x) => x,
+            None if self.responses > 0 => {
+                self.pending.extend(msg);
+                return Ok(());
+            }
+            None => return Err(invalid("unsigned response".into())),
+        };
+
+        if !self.key.matches(&tsig) {
+            return Err(invalid(format!("response signed with another key, {}", tsig.key)));
+        }
+        if tsig.error != 0 {
+            return Err(invalid(format!("TSIG error {}", error_name(tsig.error))));
+        }
+        if !self.verify_mac(&unsigned(msg, start, &tsig), &tsig) {
+            return Err(invalid(format!("TSIG error {} in response", error_name(BADSIG))));
+        }
+        if !in_time(&tsig, now) {
+            return Err(invalid(format!("TSIG error {} in response", error_name(BADTIME))));
+        }
+
+        self.prior = Some(tsig.mac);
+        self.pending.clear();
+        self.responses += 1;
+        Ok(())
+    }
+
+    /// Whether messages have come in since the last signed one, which a
+    /// finished transfer mustn't end with.
+    pub fn has_unsigned(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// What the MAC of the message `msg` with `tsig` covers: the MAC before
+    /// it, any unsigned messages in between, the message itself and the
+    /// TSIG variables.
+    fn signed_data(&self, msg: &[u8], tsig: &Tsig) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(ref prior) = self.prior {
+            data.extend(&[(prior.len() >> 8) as u8, prior.len() as u8]);
+            data.extend(prior);
+        }
+        data.extend(&self.pending);
+        data.extend(msg);
+        data.extend(tsig.variables(self.responses > 0));
+        data
+    }
+
+    fn verify_mac(&self, msg: &[u8], tsig: &Tsig) -> bool {
+        match self.key.hmac() {
+            Some(key) => hmac::verify(&key, &self.signed_data(msg, tsig), &tsig.mac).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn in_time(tsig: &Tsig, now: u64) -> bool {
+    let diff = if now > tsig.time_signed {
+        now - tsig.time_signed
+    } else {
+        tsig.time_signed - now
+    };
+    diff <= tsig.fudge as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_packet::DnsPacket;
+
+    fn key() -> TsigKey {
+        TsigKey::new("transfer.", "hmac-sha256", b"secret".to_vec())
+    }
+
+    /// `packet` written out, and signed by `signer` at `now` if given.
+    fn message(mut packet: DnsPacket, signer: Option<&mut Signer>, now: u64) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        if let Some(signer) = signer {
+            signer.sign(&mut buffer, now).unwrap();
+        }
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    fn query() -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::AXFR));
+        packet
+    }
+
+    fn response() -> DnsPacket {
+        let mut packet = query();
+        packet.header.response = true;
+        packet
+    }
+
+    #[test]
+    fn signs_and_checks_exchanges() {
+        let now = 1_700_000_000;
+        let mut client = Signer::new(key());
+        let request = message(query(), Some(&mut client), now);
+
+        let mut buffer = BytePacketBuffer::with_size(request.len());
+        buffer.buf.copy_from_slice(&request);
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(packet.resources[0].get_querytype(), QueryType::TSIG);
+
+        let mut server = Signer::check_request(&[key()], &request, now + 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.error, 0);
+
+        // The messages of a transfer are chained together, and some may go
+        // unsigned
+        client.verify(&message(response(), Some(&mut server), now + 10), now + 10).unwrap();
+        client.verify(&message(response(), None, now), now).unwrap();
+        assert!(client.has_unsigned());
+        server.pending = message(response(), None, now);
+        client.verify(&message(response(), Some(&mut server), now + 11), now + 11).unwrap();
+        assert!(!client.has_unsigned());
+
+        // Unsigned requests are left alone
+        assert!(Signer::check_request(&[key()], &message(query(), None, now), now)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reports_bad_signatures() {
+        let now = 1_700_000_000;
+        let mut request = message(query(), Some(&mut Signer::new(key())), now);
+
+        let other = TsigKey::new("other", "hmac-sha256", b"secret".to_vec());
+        let signer = Signer::check_request(&[other], &request, now).unwrap().unwrap();
+        assert_eq!(signer.error, BADKEY);
+
+        let mut late = Signer::check_request(&[key()], &request, now + 301).unwrap().unwrap();
+        assert_eq!(late.error, BADTIME);
+        let e = Signer::new(key())
+            .verify(&message(response(), Some(&mut late), now + 301), now + 301)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "TSIG error BADTIME");
+
+        // Changing the question breaks the MAC
+        request[13] ^= 0x20;
+        let mut signer = Signer::check_request(&[key()], &request, now).unwrap().unwrap();
+        assert_eq!(signer.error, BADSIG);
+        let e = Signer::new(key())
+            .verify(&message(response(), Some(&mut signer), now), now)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "TSIG error BADSIG");
+    }
+}
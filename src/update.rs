@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_header::DnsHeader;
+use dns_name;
+use dns_question::DnsQuestion;
+use dns_record::DnsRecord;
+use opcode::Opcode;
+use query_type::QueryType;
+use result_code::ResultCode;
+use zone::{self, Zone};
+
+// The classes that say what a record in an UPDATE means (RFC 2136 section
+// 2.4 and 2.5)
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_NONE: u16 = 254;
+pub const CLASS_ANY: u16 = 255;
+
+/// Records sent without data, naming an RRset or a name rather than a
+/// record.
+fn is_empty(record: &DnsRecord) -> bool {
+    match *record {
+        DnsRecord::UNKNOWN { ref data, .. } => data.is_empty(),
+        _ => false,
+    }
+}
+
+/// Types that only make sense in questions, such as ANY and AXFR.
+fn is_meta(qtype: QueryType) -> bool {
+    qtype == QueryType::OPT || qtype.to_num() >= 128 && qtype.to_num() <= 255
+}
+
+/// `record` as compared when looking for it in a zone: owner name case and
+/// TTL don't count.
+fn normalised(record: &DnsRecord) -> DnsRecord {
+    let mut record = record.clone();
+    let domain = record.get_domain().trim_end_matches('.').to_ascii_lowercase();
+    record.set_domain(&domain);
+    record.set_ttl(0);
+    record
+}
+
+fn same_record(a: &DnsRecord, b: &DnsRecord) -> bool {
+    normalised(a) == normalised(b)
+}
+
+fn at(record: &DnsRecord, name: &str) -> bool {
+    dns_name::names_equal(record.get_domain(), name)
+}
+
+/// A dynamic update (RFC 2136). It has the four sections of a `DnsPacket`,
+/// used for the zone, prerequisites, updates and additional data, but the
+/// class of each record is kept since it says what the record stands for.
+pub struct Update {
+    pub header: DnsHeader,
+    pub zone: Vec<DnsQuestion>,
+    pub prerequisites: Vec<(DnsRecord, u16)>,
+    pub updates: Vec<(DnsRecord, u16)>,
+    pub additional: Vec<(DnsRecord, u16)>,
+}
+
+impl Update {
+    pub fn new(zone: &str) -> Update {
+        let mut header = DnsHeader::new();
+        header.opcode = Opcode::UPDATE;
+        Update {
+            header: header,
+            zone: vec![DnsQuestion::new(zone.to_string(), QueryType::SOA)],
+            prerequisites: Vec::new(),
+            updates: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Update, Error> {
+        let mut header = DnsHeader::new();
+        try!(header.read(buffer));
+
+        let mut zone = Vec::new();
+        for _ in 0..header.questions {
+            let mut question = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+            try!(question.read(buffer));
+            zone.push(question);
+        }
+        let mut sections = Vec::new();
+        for &count in &[
+            header.answers,
+            header.authoritative_entries,
+            header.resource_entries,
+        ] {
+            let mut records = Vec::new();
+            for _ in 0..count {
+                records.push(try!(DnsRecord::read_with_class(buffer)));
+            }
+            sections.push(records);
+        }
+
+        let additional = sections.pop().unwrap();
+        let updates = sections.pop().unwrap();
+        let prerequisites = sections.pop().unwrap();
+        Ok(Update {
+            header: header,
+            zone: zone,
+            prerequisites: prerequisites,
+            updates: updates,
+            additional: additional,
+        })
+    }
+
+    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Error> {
+        self.header.questions = self.zone.len() as u16;
+        self.header.answers = self.prerequisites.len() as u16;
+        self.header.authoritative_entries = self.updates.len() as u16;
+        self.header.resource_entries = self.additional.len() as u16;
+
+        try!(self.header.write(buffer));
+        for question in &self.zone {
+            try!(question.write(buffer));
+        }
+        for section in &[&self.prerequisites, &self.updates, &self.additional] {
+            for &(ref record, class) in section.iter() {
+                try!(record.write_with_class(buffer, class));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the prerequisites against `zone` (RFC 2136 section 3.2),
+    /// failing with the response code for the first one that doesn't hold.
+    pub fn check(&self, zone: &Zone) -> Result<(), ResultCode> {
+        // RRsets that have to exist with exactly these records
+        let mut rrsets = BTreeMap::new();
+
+        for &(ref record, class) in &self.prerequisites {
+            let name = record.get_domain();
+            let qtype = record.get_querytype();
+            if record.get_ttl() != 0 {
+                return Err(ResultCode::FORMERR);
+            }
+            if !dns_name::is_subdomain(name, &zone.name) {
+                return Err(ResultCode::NOTZONE);
+            }
+
+            match class {
+                CLASS_ANY | CLASS_NONE if !is_empty(record) => return Err(ResultCode::FORMERR),
+                CLASS_ANY if qtype == QueryType::ANY => {
+                    if zone.records_at(name).is_empty() {
+                        return Err(ResultCode::NXDOMAIN);
+                    }
+                }
+                CLASS_ANY => {
+                    if zone.rrset(name, qtype).is_empty() {
+                        return Err(ResultCode::NXRRSET);
+                    }
+                }
+                CLASS_NONE if qtype == QueryType::ANY => {
+                    if !zone.records_at(name).is_empty() {
+                        return Err(ResultCode::YXDOMAIN);
+                    }
+                }
+                CLASS_NONE => {
+                    if !zone.rrset(name, qtype).is_empty() {
+                        return Err(ResultCode::YXRRSET);
+                    }
+                }
+                CLASS_IN if !is_empty(record) && !is_meta(qtype) => {
+                    let key = (name.trim_end_matches('.').to_ascii_lowercase(), qtype);
+                    rrsets
+                        .entry(key)
+                        .or_insert_with(Vec::new)
+                        .push(normalised(record));
+                }
+                _ => return Err(ResultCode::FORMERR),
+            }
+        }
+
+        for ((name, qtype), mut expected) in rrsets {
+            let mut actual = zone
+                .rrset(&name, qtype)
+                .iter()
+                .map(normalised)
+                .collect::<Vec<DnsRecord>>();
+            expected.sort();
+            expected.dedup();
+            actual.sort();
+            if actual != expected {
+                return Err(ResultCode::NXRRSET);
+            }
+        }
+        Ok(())
+    }
+
+    /// Catches malformed updates before anything is changed (RFC 2136
+    /// section 3.4.1).
+    fn prescan(&self, zone: &Zone) -> Result<(), ResultCode> {
+        for &(ref record, class) in &self.updates {
+            let qtype = record.get_querytype();
+            if !dns_name::is_subdomain(record.get_domain(), &zone.name) {
+                return Err(ResultCode::NOTZONE);
+            }
+            let valid = match class {
+                CLASS_IN => !is_empty(record) && !is_meta(qtype),
+                CLASS_ANY => {
+                    record.get_ttl() == 0 && is_empty(record)
+                        && (!is_meta(qtype) || qtype == QueryType::ANY)
+                }
+                CLASS_NONE => record.get_ttl() == 0 && !is_empty(record) && !is_meta(qtype),
+                _ => false,
+            };
+            if !valid {
+                return Err(ResultCode::FORMERR);
+            }
+        }
+        Ok(())
+    }
+
+    /// The records of `zone` with the updates made (RFC 2136 section
+    /// 3.4.2), or None if they don't change anything. Unless the updates
+    /// set a newer SOA themselves, the serial goes up by one.
+    pub fn apply(&self, zone: &Zone) -> Result<Option<Vec<DnsRecord>>, ResultCode> {
+        try!(self.prescan(zone));
+
+        let mut records = zone.records();
+        let mut changed = false;
+        for &(ref record, class) in &self.updates {
+            let name = record.get_domain();
+            let qtype = record.get_querytype();
+            let at_apex = dns_name::names_equal(name, &zone.name);
+            // The SOA and NS records at the apex are only replaced, never
+            // deleted outright
+            let protected = |r: &DnsRecord| {
+                at_apex && (r.get_querytype() == QueryType::SOA || r.get_querytype() == QueryType::NS)
+            };
+
+            match class {
+                CLASS_IN if qtype == QueryType::SOA => {
+                    let newer = match (record, zone.soa()) {
+                        (
+                            &DnsRecord::SOA { serial, .. },
+                            &DnsRecord::SOA { serial: current, .. },
+                        ) => zone::serial_newer(serial, current),
+                        _ => false,
+                    };
+                    if at_apex && newer {
+                        records.retain(|r| r.get_querytype() != QueryType::SOA);
+                        records.push(record.clone());
+                        changed = true;
+                    }
+                }
+                CLASS_IN => {
+                    let has_cname = records
+                        .iter()
+                        .any(|r| at(r, name) && r.get_querytype() == QueryType::CNAME);
+                    let has_other = records
+                        .iter()
+                        .any(|r| at(r, name) && r.get_querytype() != QueryType::CNAME);
+                    // Nothing can live next to a CNAME (RFC 2136 section
+                    // 3.4.2.2), and a new CNAME replaces the old one
+                    if qtype == QueryType::CNAME && has_other || qtype != QueryType::CNAME && has_cname {
+                        continue;
+                    }
+                    if qtype == QueryType::CNAME {
+                        records.retain(|r| !(at(r, name) && r.get_querytype() == QueryType::CNAME));
+                    }
+
+                    if records.contains(record) {
+                        continue;
+                    }
+                    // The same record with another TTL is replaced
+                    records.retain(|r| !same_record(r, record));
+                    records.push(record.clone());
+                    changed = true;
+                }
+                CLASS_ANY => {
+                    let before = records.len();
+                    records.retain(|r| {
+                        !at(r, name) || protected(r)
+                            || qtype != QueryType::ANY && r.get_querytype() != qtype
+                    });
+                    changed |= records.len() != before;
+                }
+                _ => {
+                    if qtype == QueryType::SOA {
+                        continue;
+                    }
+                    let ns_count = records
+                        .iter()
+                        .filter(|r| protected(r) && r.get_querytype() == QueryType::NS)
+                        .count();
+                    if at_apex && qtype == QueryType::NS && ns_count <= 1 {
+                        continue;
+                    }
+                    let before = records.len();
+                    records.retain(|r| !same_record(r, record));
+                    changed |= records.len() != before;
+                }
+            }
+        }
+
+        if !changed {
+            return Ok(None);
+        }
+
+        let serial = zone.serial();
+        for record in records.iter_mut() {
+            if let DnsRecord::SOA { serial: ref mut new, .. } = *record {
+                if *new == serial {
+                    *new = serial.wrapping_add(1);
+                }
+            }
+        }
+        Ok(Some(records))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use zone_parser::ZoneParser;
+
+    fn zone() -> Zone {
+        let mut parser = ZoneParser::new("example.com");
+        parser
+            .parse_str(
+                "$TTL 60
+@ SOA ns1 hostmaster 1 2 3 4 5
+@ NS ns1
+ns1 A 192.0.2.1
+www A 192.0.2.2
+www A 192.0.2.3
+alias CNAME www
+",
+                "test.zone",
+            )
+            .unwrap();
+        Zone::new("example.com", parser.records).unwrap()
+    }
+
+    fn a(name: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl: ttl,
+        }
+    }
+
+    fn rrset(name: &str, qtype: QueryType) -> DnsRecord {
+        DnsRecord::UNKNOWN {
+            domain: name.to_string(),
+            qtype: qtype.to_num(),
+            data: Vec::new(),
+            ttl: 0,
+        }
+    }
+
+    fn check(prerequisites: Vec<(DnsRecord, u16)>) -> Result<(), ResultCode> {
+        let mut update = Update::new("example.com");
+        update.prerequisites = prerequisites;
+        update.check(&zone())
+    }
+
+    fn apply(updates: Vec<(DnsRecord, u16)>) -> Result<Option<Zone>, ResultCode> {
+        let mut update = Update::new("example.com");
+        update.updates = updates;
+        update
+            .apply(&zone())
+            .map(|records| records.map(|r| Zone::new("example.com", r).unwrap()))
+    }
+
+    #[test]
+    fn keeps_classes_and_empty_rrsets() {
+        let mut update = Update::new("example.com");
+        update
+            .prerequisites
+            .push((rrset("new.example.com", QueryType::ANY), CLASS_NONE));
+        update
+            .updates
+            .push((a("new.example.com", [192, 0, 2, 9], 300), CLASS_IN));
+        update
+            .updates
+            .push((rrset("www.example.com", QueryType::A), CLASS_ANY));
+
+        let mut buffer = BytePacketBuffer::new();
+        update.write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        let read = Update::read(&mut buffer).unwrap();
+
+        assert_eq!(read.header.opcode, Opcode::UPDATE);
+        assert_eq!(read.zone, update.zone);
+        assert_eq!(read.prerequisites, update.prerequisites);
+        assert_eq!(read.updates, update.updates);
+        assert_eq!(read.updates[1].0.get_querytype(), QueryType::A);
+    }
+
+    #[test]
+    fn checks_prerequisites() {
+        let www = "www.example.com";
+        assert_eq!(check(vec![(rrset(www, QueryType::ANY), CLASS_ANY)]), Ok(()));
+        assert_eq!(
+            check(vec![(rrset("no.example.com", QueryType::ANY), CLASS_ANY)]),
+            Err(ResultCode::NXDOMAIN)
+        );
+        assert_eq!(
+            check(vec![(rrset(www, QueryType::AAAA), CLASS_ANY)]),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check(vec![(rrset(www, QueryType::ANY), CLASS_NONE)]),
+            Err(ResultCode::YXDOMAIN)
+        );
+        assert_eq!(
+            check(vec![(rrset(www, QueryType::A), CLASS_NONE)]),
+            Err(ResultCode::YXRRSET)
+        );
+        assert_eq!(
+            check(vec![(rrset("www.example.org", QueryType::A), CLASS_NONE)]),
+            Err(ResultCode::NOTZONE)
+        );
+
+        // Value dependent: the whole RRset, in any order and case
+        assert_eq!(
+            check(vec![
+                (a("WWW.example.com", [192, 0, 2, 3], 0), CLASS_IN),
+                (a(www, [192, 0, 2, 2], 0), CLASS_IN),
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            check(vec![(a(www, [192, 0, 2, 2], 0), CLASS_IN)]),
+            Err(ResultCode::NXRRSET)
+        );
+        assert_eq!(
+            check(vec![(a(www, [192, 0, 2, 2], 60), CLASS_IN)]),
+            Err(ResultCode::FORMERR)
+        );
+    }
+
+    #[test]
+    fn applies_updates_and_bumps_the_serial() {
+        let zone = apply(vec![
+            (a("new.example.com", [192, 0, 2, 9], 300), CLASS_IN),
+            (a("www.example.com", [192, 0, 2, 2], 0), CLASS_NONE),
+        ]).unwrap()
+            .unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert_eq!(zone.rrset("new.example.com", QueryType::A).len(), 1);
+        assert_eq!(
+            zone.rrset("www.example.com", QueryType::A),
+            vec![a("www.example.com", [192, 0, 2, 3], 60)]
+        );
+
+        let zone = apply(vec![(rrset("www.example.com", QueryType::ANY), CLASS_ANY)])
+            .unwrap()
+            .unwrap();
+        assert!(zone.records_at("www.example.com").is_empty());
+
+        // Already there, or not allowed: nothing changes
+        assert!(
+            apply(vec![(a("ns1.example.com", [192, 0, 2, 1], 60), CLASS_IN)])
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            apply(vec![(a("alias.example.com", [192, 0, 2, 1], 60), CLASS_IN)])
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            apply(vec![(rrset("example.com", QueryType::ANY), CLASS_ANY)])
+                .unwrap()
+                .is_none()
+        );
+
+        assert_eq!(
+            apply(vec![(rrset("www.example.com", QueryType::A), CLASS_IN)]).err(),
+            Some(ResultCode::FORMERR)
+        );
+        assert_eq!(
+            apply(vec![(a("www.example.org", [192, 0, 2, 1], 60), CLASS_IN)]).err(),
+            Some(ResultCode::NOTZONE)
+        );
+    }
+}
@@ -0,0 +1,486 @@
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_packet::DnsPacket;
+use dns_record::DnsRecord;
+use presentation;
+use query_type::QueryType;
+use resolver::random_id;
+use result_code::ResultCode;
+use tsig::{self, Signer, TsigKey};
+use update::{Update, CLASS_ANY, CLASS_IN, CLASS_NONE};
+use zone_parser::ZoneParser;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A script of dynamic updates, in the language of nsupdate, as it's run:
+/// where updates go, and the one being built.
+pub struct Script {
+    pub server: SocketAddr,
+    pub key: Option<TsigKey>,
+    pub tcp: bool,
+    zone: Option<String>,
+    ttl: Option<u32>,
+    prerequisites: Vec<(DnsRecord, u16)>,
+    updates: Vec<(DnsRecord, u16)>,
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script {
+            server: SocketAddr::from(([127, 0, 0, 1], 53)),
+            key: None,
+            tcp: false,
+            zone: None,
+            ttl: None,
+            prerequisites: Vec::new(),
+            updates: Vec::new(),
+        }
+    }
+
+    /// Runs the script in `input`, called `name` in errors, writing the
+    /// response code for each update to `out`. Tells whether every update
+    /// succeeded, or stops at the first line that doesn't make sense.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        name: &str,
+        input: R,
+        out: &mut W,
+    ) -> Result<bool, String> {
+        let mut succeeded = true;
+        for (i, line) in input.lines().enumerate() {
+            let line = try!(line.map_err(|e| format!("{}: {}", name, e)));
+            if line.trim_start().starts_with(';') || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match self.command(&line) {
+                Ok(Some(mut update)) => succeeded &= self.send(&mut update, out),
+                Ok(None) => {}
+                Err(e) => return Err(format!("{}:{}: {}", name, i + 1, e)),
+            }
+        }
+        // Whatever is left at the end goes too
+        match self.take_update() {
+            Ok(Some(mut update)) => succeeded &= self.send(&mut update, out),
+            Ok(None) => {}
+            Err(e) => return Err(format!("{}: {}", name, e)),
+        }
+        Ok(succeeded)
+    }
+
+    /// Runs one line of the script, returning the update to send if it
+    /// says to send one.
+    pub fn command(&mut self, line: &str) -> Result<Option<Update>, String> {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        if words.is_empty() {
+            return self.take_update();
+        }
+
+        match (words[0], words.len()) {
+            ("send", 1) => return self.take_update(),
+            ("server", 2) | ("server", 3) => {
+                let ip = try!(words[1]
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("invalid server {}", words[1])));
+                let port = match words.get(2) {
+                    Some(port) => try!(port
+                        .parse::<u16>()
+                        .map_err(|_| format!("invalid port {}", port))),
+                    None => 53,
+                };
+                self.server = SocketAddr::new(ip, port);
+            }
+            ("zone", 2) => self.zone = Some(words[1].trim_end_matches('.').to_string()),
+            ("key", 3) => {
+                self.key = Some(try!(parse_key(&format!("{}:{}", words[1], words[2]))))
+            }
+            ("ttl", 2) => {
+                self.ttl = Some(try!(words[1]
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid TTL {}", words[1]))))
+            }
+            ("prereq", _) if words.len() >= 3 => try!(self.prereq(words[1], &words[2..])),
+            ("update", _) if words.len() >= 3 => try!(self.update(words[1], &words[2..])),
+            _ => return Err(format!("can't make sense of {}", line.trim())),
+        }
+        Ok(None)
+    }
+
+    fn prereq(&mut self, kind: &str, words: &[&str]) -> Result<(), String> {
+        let name = words[0];
+        let prerequisite = match (kind, words.len()) {
+            ("nxdomain", 1) => (empty(name, QueryType::ANY), CLASS_NONE),
+            ("yxdomain", 1) => (empty(name, QueryType::ANY), CLASS_ANY),
+            ("nxrrset", 2) => (empty(name, try!(parse_type(words[1]))), CLASS_NONE),
+            ("yxrrset", 2) => (empty(name, try!(parse_type(words[1]))), CLASS_ANY),
+            ("yxrrset", _) if words.len() > 2 => {
+                let data = words[2..].join(" ");
+                (try!(parse_record(name, "0", words[1], &data)), CLASS_IN)
+            }
+            _ => return Err(format!("can't make sense of prereq {} {}", kind, words.join(" "))),
+        };
+        self.prerequisites.push(prerequisite);
+        Ok(())
+    }
+
+    fn update(&mut self, kind: &str, words: &[&str]) -> Result<(), String> {
+        let name = words[0];
+        let mut rest = &words[1..];
+        let ttl = match rest.first() {
+            Some(ttl) if ttl.starts_with(|c: char| c.is_ascii_digit()) => {
+                rest = &rest[1..];
+                Some(ttl.to_string())
+            }
+            _ => None,
+        };
+        if rest.first().is_some_and(|class| class.eq_ignore_ascii_case("IN")) {
+            rest = &rest[1..];
+        }
+
+        let update = match (kind, rest.len()) {
+            ("add", n) if n >= 2 => {
+                let ttl = match ttl.or_else(|| self.ttl.map(|ttl| ttl.to_string())) {
+                    Some(x) => x,
+                    None => return Err("no TTL given and no ttl set".to_string()),
+                };
+                let data = rest[1..].join(" ");
+                (try!(parse_record(name, &ttl, rest[0], &data)), CLASS_IN)
+            }
+            ("delete", 0) => (empty(name, QueryType::ANY), CLASS_ANY),
+            ("delete", 1) => (empty(name, try!(parse_type(rest[0]))), CLASS_ANY),
+            ("delete", _) => {
+                let data = rest[1..].join(" ");
+                (try!(parse_record(name, "0", rest[0], &data)), CLASS_NONE)
+            }
+            _ => return Err(format!("can't make sense of update {} {}", kind, words.join(" "))),
+        };
+        self.updates.push(update);
+        Ok(())
+    }
+
+    /// The update built so far, if there is one.
+    fn take_update(&mut self) -> Result<Option<Update>, String> {
+        if self.prerequisites.is_empty() && self.updates.is_empty() {
+            return Ok(None);
+        }
+        let zone = match self.zone {
+            Some(ref x) => x,
+            None => return Err("no zone to update, set one with the zone command".to_string()),
+        };
+
+        let mut update = Update::new(zone);
+        update.header.id = random_id();
+        update.prerequisites = self.prerequisites.drain(..).collect();
+        update.updates = self.updates.drain(..).collect();
+        Ok(Some(update))
+    }
+
+    /// Sends `update` and writes out the response code, telling whether
+    /// it's NOERROR.
+    fn send<W: Write>(&self, update: &mut Update, out: &mut W) -> bool {
+        let zone = update.zone[0].name.clone();
+        let mut signer = self.key.clone().map(Signer::new);
+        match exchange(update, signer.as_mut(), self.server, self.tcp) {
+            Ok(response) => {
+                let rcode = response.header.rescode;
+                let _ = writeln!(out, "Update of {} answered with {:?}", zone, rcode);
+                response.header.rescode == ResultCode::NOERROR
+            }
+            Err(e) => {
+                let _ = writeln!(out, "Update of {} failed: {}", zone, e);
+                false
+            }
+        }
+    }
+}
+
+impl Default for Script {
+    fn default() -> Script {
+        Script::new()
+    }
+}
+
+/// A record without data, standing for a whole RRset or name in an update.
+fn empty(name: &str, qtype: QueryType) -> DnsRecord {
+    DnsRecord::UNKNOWN {
+        domain: name.trim_end_matches('.').to_string(),
+        qtype: qtype.to_num(),
+        data: Vec::new(),
+        ttl: 0,
+    }
+}
+
+fn parse_type(text: &str) -> Result<QueryType, String> {
+    QueryType::from_name(text).ok_or_else(|| format!("unknown type {}", text))
+}
+
+/// A record given the way a zone file would, with names taken as absolute.
+fn parse_record(name: &str, ttl: &str, qtype: &str, data: &str) -> Result<DnsRecord, String> {
+    let text = format!("{}. {} IN {} {}\n", name.trim_end_matches('.'), ttl, qtype, data);
+    let mut parser = ZoneParser::new("");
+    match parser.parse_str(&text, "") {
+        // Less the position, which is in the text above and not the script
+        Err(e) => Err(e.to_string().splitn(4, ':').last().unwrap_or("").trim().to_string()),
+        Ok(()) => parser
+            .records
+            .pop()
+            .ok_or_else(|| format!("no record in {}", text.trim())),
+    }
+}
+
+/// A TSIG key given as `[algorithm:]name:secret`, the secret in base64.
+pub fn parse_key(text: &str) -> Result<TsigKey, String> {
+    let fields = text.split(':').collect::<Vec<&str>>();
+    let (algorithm, name, secret) = match fields.len() {
+        2 => ("hmac-sha256", fields[0], fields[1]),
+        3 => (fields[0], fields[1], fields[2]),
+        _ => return Err(format!("invalid key {}", text)),
+    };
+    if !TsigKey::is_supported(algorithm) {
+        return Err(format!("unsupported TSIG algorithm {}", algorithm));
+    }
+    let secret = try!(presentation::parse_base64(secret)
+        .ok_or_else(|| format!("invalid base64 secret for key {}", name)));
+    Ok(TsigKey::new(name, algorithm, secret))
+}
+
+/// Sends `update`, signed by `signer` if given, and returns the response,
+/// retrying over TCP if it doesn't fit in a datagram.
+fn exchange(
+    update: &mut Update,
+    mut signer: Option<&mut Signer>,
+    server: SocketAddr,
+    tcp: bool,
+) -> Result<DnsPacket, Error> {
+    let mut req_buffer = BytePacketBuffer::with_size(0xFFFF);
+    try!(update.write(&mut req_buffer));
+    if let Some(ref mut signer) = signer {
+        try!(signer.sign(&mut req_buffer, tsig::now()));
+    }
+    let len = req_buffer.pos();
+    let data = try!(req_buffer.get_range(0, len)).to_vec();
+
+    let response = if tcp || len > 512 {
+        try!(exchange_tcp(&data, server))
+    } else {
+        let response = try!(exchange_udp(&data, server));
+        // The truncation flag
+        if response.len() > 2 && response[2] & 0x02 > 0 {
+            try!(exchange_tcp(&data, server))
+        } else {
+            response
+        }
+    };
+
+    let mut res_buffer = BytePacketBuffer::with_size(response.len());
+    res_buffer.buf.copy_from_slice(&response);
+    let packet = try!(DnsPacket::from_buffer(&mut res_buffer));
+    if !packet.header.response || packet.header.id != update.header.id {
+        return Err(Error::new(ErrorKind::InvalidData, "mismatched response"));
+    }
+    if let Some(signer) = signer {
+        try!(signer.verify(&response, tsig::now()));
+    }
+    Ok(packet)
+}
+
+fn exchange_tcp(data: &[u8], server: SocketAddr) -> Result<Vec<u8>, Error> {
+    let mut stream = try!(TcpStream::connect_timeout(&server, TIMEOUT));
+    try!(stream.set_read_timeout(Some(TIMEOUT)));
+    let len = data.len();
+    try!(stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8]));
+    try!(stream.write_all(data));
+
+    let mut len_buf = [0u8; 2];
+    try!(stream.read_exact(&mut len_buf));
+    let mut response = vec![0u8; ((len_buf[0] as usize) << 8) | len_buf[1] as usize];
+    try!(stream.read_exact(&mut response));
+    Ok(response)
+}
+
+fn exchange_udp(data: &[u8], server: SocketAddr) -> Result<Vec<u8>, Error> {
+    let local: SocketAddr = if server.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = try!(UdpSocket::bind(local));
+    try!(socket.set_read_timeout(Some(TIMEOUT)));
+    try!(socket.send_to(data, server));
+
+    loop {
+        let mut response = vec![0u8; 0xFFFF];
+        let (len, src) = try!(socket.recv_from(&mut response));
+        // Skip anything that isn't the reply to our update
+        if src == server && len >= 2 && response[0..2] == data[0..2] {
+            response.truncate(len);
+            return Ok(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    fn a(name: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl: ttl,
+        }
+    }
+
+    /// The update `lines` send, as it goes over the wire.
+    fn sent(lines: &[&str]) -> Update {
+        let mut script = Script::new();
+        for line in lines {
+            assert!(script.command(line).unwrap().is_none(), "{} sent an update", line);
+        }
+        let mut update = script.command("send").unwrap().unwrap();
+        let mut buffer = BytePacketBuffer::with_size(0xFFFF);
+        update.write(&mut buffer).unwrap();
+        buffer.pos = 0;
+        Update::read(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn encodes_each_line_as_rfc_2136_has_it() {
+        let update = sent(&[
+            "zone example.com.",
+            "ttl 60",
+            "prereq nxdomain new.example.com",
+            "prereq yxdomain example.com",
+            "prereq nxrrset www.example.com AAAA",
+            "prereq yxrrset www.example.com A",
+            "prereq yxrrset www.example.com A 192.0.2.2",
+            "update add new.example.com 300 A 192.0.2.1",
+            "update add new.example.com IN A 192.0.2.3",
+            "update delete www.example.com",
+            "update delete www.example.com A",
+            "update delete www.example.com IN A 192.0.2.2",
+        ]);
+        assert_eq!(update.zone[0].name, "example.com");
+        assert_eq!(update.zone[0].qtype, QueryType::SOA);
+        assert_eq!(
+            update.prerequisites,
+            vec![
+                (empty("new.example.com", QueryType::ANY), CLASS_NONE),
+                (empty("example.com", QueryType::ANY), CLASS_ANY),
+                (empty("www.example.com", QueryType::AAAA), CLASS_NONE),
+                (empty("www.example.com", QueryType::A), CLASS_ANY),
+                (a("www.example.com", [192, 0, 2, 2], 0), CLASS_IN),
+            ]
+        );
+        assert_eq!(
+            update.updates,
+            vec![
+                (a("new.example.com", [192, 0, 2, 1], 300), CLASS_IN),
+                (a("new.example.com", [192, 0, 2, 3], 60), CLASS_IN),
+                (empty("www.example.com", QueryType::ANY), CLASS_ANY),
+                (empty("www.example.com", QueryType::A), CLASS_ANY),
+                (a("www.example.com", [192, 0, 2, 2], 0), CLASS_NONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_settings() {
+        let mut script = Script::new();
+        script.command("server 192.0.2.53").unwrap();
+        assert_eq!(script.server, "192.0.2.53:53".parse().unwrap());
+        script.command("server ::1 5353").unwrap();
+        assert_eq!(script.server, "[::1]:5353".parse().unwrap());
+        script.command("key hmac-sha512:update.key c2VjcmV0").unwrap();
+        assert_eq!(script.key.as_ref().unwrap().name, "update.key");
+        assert_eq!(script.key.as_ref().unwrap().algorithm, "hmac-sha512");
+        assert!(parse_key("update.key:c2VjcmV0").is_ok());
+        assert!(parse_key("md4:update.key:c2VjcmV0").is_err());
+
+        // Nothing to send, so nothing is sent
+        assert!(script.command("").unwrap().is_none());
+        assert!(script.command("send").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_number() {
+        let run = |text: &str| Script::new().run("test", text.as_bytes(), &mut Vec::new());
+        assert_eq!(
+            run("zone example.com\n; no TTL\nupdate add www.example.com A 192.0.2.1\n"),
+            Err("test:3: no TTL given and no ttl set".to_string())
+        );
+        assert_eq!(
+            run("update delete www.example.com\n"),
+            Err("test: no zone to update, set one with the zone command".to_string())
+        );
+
+        let error = |line: &str| Script::new().command(line).err().unwrap();
+        assert_eq!(error("server dns.example"), "invalid server dns.example");
+        assert_eq!(error("server 192.0.2.53 domain"), "invalid port domain");
+        assert_eq!(error("ttl forever"), "invalid TTL forever");
+        assert_eq!(error("prereq nxdomain"), "can't make sense of prereq nxdomain");
+        assert_eq!(
+            error("prereq nxrrset www.example.com"),
+            "can't make sense of prereq nxrrset www.example.com"
+        );
+        assert_eq!(error("prereq nxrrset www.example.com AB"), "unknown type AB");
+        assert_eq!(
+            error("update replace www.example.com A"),
+            "can't make sense of update replace www.example.com A"
+        );
+        assert_eq!(error("update delete www.example.com AB"), "unknown type AB");
+        assert!(error("update add www.example.com 60 A 192.0.2").contains("192.0.2"));
+        assert_eq!(error("send now"), "can't make sense of send now");
+    }
+
+    /// A server answering every update with `rcode`.
+    fn server(rcode: ResultCode) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || loop {
+            let mut request = [0u8; 512];
+            let (_, client) = socket.recv_from(&mut request).unwrap();
+            let mut response = DnsPacket::new();
+            response.header.id = (request[0] as u16) << 8 | request[1] as u16;
+            response.header.response = true;
+            response.header.rescode = rcode;
+            let mut buffer = BytePacketBuffer::with_size(512);
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[..buffer.pos()], client).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn reports_the_response_code() {
+        // Two updates: one sent by an empty line, the other by the end
+        let script = |addr: SocketAddr| {
+            format!(
+                "server {} {}\nzone example.com\nupdate delete www.example.com A\n\n{}",
+                addr.ip(),
+                addr.port(),
+                "prereq yxdomain example.com\n"
+            )
+        };
+
+        let mut out = Vec::new();
+        let text = script(server(ResultCode::NOERROR));
+        assert_eq!(Script::new().run("test", text.as_bytes(), &mut out), Ok(true));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Update of example.com answered with NOERROR\n".repeat(2)
+        );
+
+        let mut out = Vec::new();
+        let text = script(server(ResultCode::REFUSED));
+        assert_eq!(Script::new().run("test", text.as_bytes(), &mut out), Ok(false));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Update of example.com answered with REFUSED\n".repeat(2)
+        );
+    }
+}
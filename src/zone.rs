@@ -0,0 +1,680 @@
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind};
+
+use dns_name;
+use dns_packet::DnsPacket;
+use dns_record::DnsRecord;
+use presentation;
+use query_type::QueryType;
+use result_code::ResultCode;
+use zone_diff::ZoneDiff;
+
+/// How many CNAMEs inside the zone are followed for a single answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How many versions of a zone IXFR can bring a secondary up from.
+const MAX_JOURNAL: usize = 100;
+
+/// True if serial `a` comes after `b` (RFC 1982 section 3.2).
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_dnssec_type(qtype: QueryType) -> bool {
+    match qtype {
+        QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3 => true,
+        _ => false,
+    }
+}
+
+/// The data of a zone we're authoritative for, and the answers it gives.
+pub struct Zone {
+    pub name: String,
+    // Records by lowercased owner name
+    records: BTreeMap<String, Vec<DnsRecord>>,
+    // Owner names plus the empty non-terminals between them and the apex
+    names: BTreeSet<String>,
+    // Changes that led up to this version, oldest first
+    pub journal: Vec<ZoneDiff>,
+}
+
+impl Zone {
+    /// Checks that `records` make up a zone: everything at or below `name`,
+    /// one SOA at the apex and nothing next to a CNAME.
+    pub fn new(name: &str, records: Vec<DnsRecord>) -> Result<Zone, Error> {
+        let mut zone = Zone {
+            name: name.trim_end_matches('.').to_string(),
+            records: BTreeMap::new(),
+            names: BTreeSet::new(),
+            journal: Vec::new(),
+        };
+        let apex = key(name);
+        let invalid = |msg: String| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("zone {}: {}", presentation::name(&apex), msg),
+            )
+        };
+
+        let mut soa_count = 0;
+        for record in records {
+            let owner = key(record.get_domain());
+            if !dns_name::is_subdomain(&owner, &apex) {
+                return Err(invalid(format!(
+                    "{} is outside the zone",
+                    presentation::name(&owner)
+                )));
+            }
+            match record.get_querytype() {
+                QueryType::SOA if owner != apex => {
+                    return Err(invalid(format!(
+                        "SOA record for {} isn't at the apex",
+                        presentation::name(&owner)
+                    )));
+                }
+                QueryType::SOA => soa_count += 1,
+                QueryType::OPT => return Err(invalid("OPT records can't be zone data".into())),
+                _ => {}
+            }
+
+            for name in dns_name::ancestors(&owner) {
+                zone.names.insert(name.to_string());
+                if name == apex {
+                    break;
+                }
+            }
+            let rrs = zone.records.entry(owner).or_insert_with(Vec::new);
+            if !rrs.contains(&record) {
+                rrs.push(record);
+            }
+        }
+
+        if soa_count != 1 {
+            return Err(invalid(format!(
+                "expected one SOA record at the apex, found {}",
+                soa_count
+            )));
+        }
+
+        for (owner, rrs) in &zone.records {
+            let has_cname = rrs.iter().any(|r| r.get_querytype() == QueryType::CNAME);
+            let has_other = rrs.iter().any(|r| {
+                let qtype = r.get_querytype();
+                qtype != QueryType::CNAME && !is_dnssec_type(qtype)
+            });
+            if has_cname && has_other {
+                return Err(invalid(format!(
+                    "{} has a CNAME and other data",
+                    presentation::name(owner)
+                )));
+            }
+        }
+
+        Ok(zone)
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        // `new` made sure there's exactly one
+        self.records[&key(&self.name)]
+            .iter()
+            .find(|r| r.get_querytype() == QueryType::SOA)
+            .unwrap()
+    }
+
+    pub fn serial(&self) -> u32 {
+        match *self.soa() {
+            DnsRecord::SOA { serial, .. } => serial,
+            _ => 0,
+        }
+    }
+
+    /// Every record in the zone, by owner name.
+    pub fn records(&self) -> Vec<DnsRecord> {
+        self.records.values().flat_map(|rrs| rrs.iter().cloned()).collect()
+    }
+
+    /// Takes over the journal of `old`, the version this one replaces, and
+    /// adds the changes between the two.
+    pub fn follow(&mut self, old: &Zone) {
+        let (serial, old_serial) = (self.serial(), old.serial());
+        if serial == old_serial {
+            self.journal = old.journal.clone();
+            return;
+        }
+        if !serial_newer(serial, old_serial) {
+            warn!(
+                "Serial of zone {} went back from {} to {}, dropping its journal",
+                self.name, old_serial, serial
+            );
+            return;
+        }
+
+        let mut journal = old.journal.clone();
+        journal.push(ZoneDiff::new(old, self));
+        let excess = journal.len().saturating_sub(MAX_JOURNAL);
+        journal.drain(..excess);
+        self.journal = journal;
+    }
+
+    /// The changes from version `serial` to this one, if the journal goes
+    /// back that far.
+    pub fn changes_since(&self, serial: u32) -> Option<&[ZoneDiff]> {
+        let start = match self.journal.iter().position(|d| d.from_serial() == serial) {
+            Some(x) => x,
+            None => return None,
+        };
+        let changes = &self.journal[start..];
+        let linked = changes
+            .windows(2)
+            .all(|w| w[0].to_serial() == w[1].from_serial());
+        if !linked || changes[changes.len() - 1].to_serial() != self.serial() {
+            return None;
+        }
+        Some(changes)
+    }
+
+    /// The records of type `qtype` at `name`.
+    pub fn rrset(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        match self.records.get(&key(name)) {
+            Some(rrs) => rrs
+                .iter()
+                .filter(|r| r.get_querytype() == qtype)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Every record at `name`.
+    pub fn records_at(&self, name: &str) -> Vec<DnsRecord> {
+        self.records.get(&key(name)).cloned().unwrap_or_default()
+    }
+
+    fn signatures(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        self.rrset(name, QueryType::RRSIG)
+            .into_iter()
+            .filter(|r| match *r {
+                DnsRecord::RRSIG { type_covered, .. } => type_covered == qtype,
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// The records for `name` and `qtype`, with the flags and response code
+    /// set but no header ID or question.
+    pub fn answer(&self, name: &str, qtype: QueryType, dnssec_ok: bool) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+
+        let mut name = name.trim_end_matches('.').to_string();
+        for _ in 0..MAX_CNAME_CHAIN {
+            match self.lookup(&name, qtype, dnssec_ok, &mut packet) {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+
+        self.add_additional(&mut packet);
+        packet
+    }
+
+    /// Adds what the zone has for `name` to `packet`, returning the target
+    /// of a CNAME that should be followed inside the zone.
+    fn lookup(
+        &self,
+        name: &str,
+        qtype: QueryType,
+        dnssec_ok: bool,
+        packet: &mut DnsPacket,
+    ) -> Option<String> {
+        let owner = key(name);
+        let apex = key(&self.name);
+
+        // Delegations and DNAMEs take over everything below them, so look
+        // for them from the apex down
+        let mut above = dns_name::ancestors(&owner)
+            .into_iter()
+            .take_while(|&n| n != apex)
+            .collect::<Vec<&str>>();
+        above.push(&apex);
+        for &cut in above.iter().rev() {
+            let at_name = cut == owner;
+            let ns = self.rrset(cut, QueryType::NS);
+            // DS records belong to the parent side of a cut
+            if cut != apex && !ns.is_empty() && !(at_name && qtype == QueryType::DS) {
+                if packet.answers.is_empty() {
+                    self.referral(cut, ns, dnssec_ok, packet);
+                }
+                return None;
+            }
+
+            if !at_name {
+                if let Some(dname) = self.rrset(cut, QueryType::DNAME).into_iter().next() {
+                    return self.synthesise_dname(name, cut, dname, dnssec_ok, packet);
+                }
+            }
+        }
+
+        if self.records.contains_key(&owner) {
+            return self.answer_from(&owner, None, qtype, dnssec_ok, packet);
+        }
+
+        if self.names.contains(&owner) {
+            // An empty non-terminal exists but has no data
+            self.no_data(name, dnssec_ok, packet);
+            return None;
+        }
+
+        // RFC 4592: a wildcard at the closest encloser answers for any name
+        // below it that doesn't exist
+        let encloser = dns_name::ancestors(&owner)
+            .into_iter()
+            .find(|n| self.names.contains(*n))
+            .unwrap_or(&apex)
+            .to_string();
+        let wildcard = if encloser.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", encloser)
+        };
+
+        if self.records.contains_key(&wildcard) {
+            if dnssec_ok {
+                // The wildcard could only be used because the name itself
+                // doesn't exist
+                self.add_covering_nsec(&owner, &mut packet.authorities);
+            }
+            return self.answer_from(&wildcard, Some(name), qtype, dnssec_ok, packet);
+        }
+
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        self.add_soa(dnssec_ok, packet);
+        if dnssec_ok {
+            self.add_covering_nsec(&owner, &mut packet.authorities);
+            self.add_covering_nsec(&wildcard, &mut packet.authorities);
+        }
+        None
+    }
+
+    /// Answers from the records at `owner`, renamed to `name` for answers
+    /// synthesised from a wildcard.
+    fn answer_from(
+        &self,
+        owner: &str,
+        name: Option<&str>,
+        qtype: QueryType,
+        dnssec_ok: bool,
+        packet: &mut DnsPacket,
+    ) -> Option<String> {
+        let renamed = |mut record: DnsRecord| {
+            if let Some(name) = name {
+                record.set_domain(name);
+            }
+            record
+        };
+        let rrs = &self.records[owner];
+
+        let matching = rrs
+            .iter()
+            .filter(|r| {
+                let rtype = r.get_querytype();
+                if qtype == QueryType::ANY {
+                    dnssec_ok || !is_dnssec_type(rtype)
+                } else {
+                    rtype == qtype
+                }
+            })
+            .cloned()
+            .collect::<Vec<DnsRecord>>();
+        if !matching.is_empty() {
+            if dnssec_ok && qtype != QueryType::ANY && qtype != QueryType::RRSIG {
+                packet
+                    .answers
+                    .extend(matching.into_iter().map(&renamed));
+                packet
+                    .answers
+                    .extend(self.signatures(owner, qtype).into_iter().map(&renamed));
+            } else {
+                packet.answers.extend(matching.into_iter().map(&renamed));
+            }
+            return None;
+        }
+
+        if let Some(cname) = self.rrset(owner, QueryType::CNAME).into_iter().next() {
+            let target = match cname {
+                DnsRecord::CNAME { ref host, .. } => host.clone(),
+                _ => return None,
+            };
+            packet.answers.push(renamed(cname));
+            if dnssec_ok {
+                packet.answers.extend(
+                    self.signatures(owner, QueryType::CNAME)
+                        .into_iter()
+                        .map(&renamed),
+                );
+            }
+            if dns_name::is_subdomain(&target, &self.name) {
+                return Some(target);
+            }
+            return None;
+        }
+
+        self.no_data(owner, dnssec_ok, packet);
+        None
+    }
+
+    fn synthesise_dname(
+        &self,
+        name: &str,
+        owner: &str,
+        dname: DnsRecord,
+        dnssec_ok: bool,
+        packet: &mut DnsPacket,
+    ) -> Option<String> {
+        let (target, ttl) = match dname {
+            DnsRecord::DNAME { ref host, ttl, .. } => (host.clone(), ttl),
+            _ => return None,
+        };
+
+        // RFC 6672 section 2.2: replace the DNAME owner's labels with the
+        // target's
+        let prefix = dns_name::label_count(name) - dns_name::label_count(owner);
+        let mut labels = dns_name::labels(name)[..prefix].to_vec();
+        labels.extend(dns_name::labels(&target));
+        let host = labels.join(".");
+
+        packet.answers.push(dname);
+        if dnssec_ok {
+            packet
+                .answers
+                .extend(self.signatures(owner, QueryType::DNAME));
+        }
+        if dns_name::to_wire(&host).len() > 255 {
+            packet.header.rescode = ResultCode::YXDOMAIN;
+            return None;
+        }
+        packet.answers.push(DnsRecord::CNAME {
+            domain: name.to_string(),
+            host: host.clone(),
+            ttl: ttl,
+        });
+
+        if dns_name::is_subdomain(&host, &self.name) {
+            return Some(host);
+        }
+        None
+    }
+
+    fn referral(&self, cut: &str, ns: Vec<DnsRecord>, dnssec_ok: bool, packet: &mut DnsPacket) {
+        packet.header.authoritative_answer = false;
+
+        for record in &ns {
+            if let DnsRecord::NS { ref host, .. } = *record {
+                // Glue is only ours to give for names inside the zone
+                if !dns_name::is_subdomain(host, &self.name) {
+                    continue;
+                }
+                for qtype in &[QueryType::A, QueryType::AAAA] {
+                    packet.resources.extend(self.rrset(host, *qtype));
+                }
+            }
+        }
+        packet.authorities.extend(ns);
+
+        if dnssec_ok {
+            let ds = self.rrset(cut, QueryType::DS);
+            if ds.is_empty() {
+                // Prove the delegation is insecure
+                let nsec = self.rrset(cut, QueryType::NSEC);
+                if !nsec.is_empty() {
+                    packet.authorities.extend(nsec);
+                    packet
+                        .authorities
+                        .extend(self.signatures(cut, QueryType::NSEC));
+                }
+            } else {
+                packet.authorities.extend(ds);
+                packet
+                    .authorities
+                    .extend(self.signatures(cut, QueryType::DS));
+            }
+        }
+    }
+
+    fn no_data(&self, name: &str, dnssec_ok: bool, packet: &mut DnsPacket) {
+        self.add_soa(dnssec_ok, packet);
+        if dnssec_ok {
+            let nsec = self.rrset(name, QueryType::NSEC);
+            if nsec.is_empty() {
+                self.add_covering_nsec(&key(name), &mut packet.authorities);
+            } else {
+                packet.authorities.extend(nsec);
+                packet
+                    .authorities
+                    .extend(self.signatures(name, QueryType::NSEC));
+            }
+        }
+    }
+
+    /// The SOA for a negative answer, with the TTL the answer may be cached
+    /// for (RFC 2308 section 3).
+    fn add_soa(&self, dnssec_ok: bool, packet: &mut DnsPacket) {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = soa {
+            soa.set_ttl(cmp::min(minimum, ttl));
+        }
+        packet.authorities.push(soa);
+        if dnssec_ok {
+            packet
+                .authorities
+                .extend(self.signatures(&self.name, QueryType::SOA));
+        }
+    }
+
+    /// The NSEC record whose span covers `name`, and its signature.
+    fn add_covering_nsec(&self, name: &str, records: &mut Vec<DnsRecord>) {
+        for rrs in self.records.values() {
+            for record in rrs {
+                let (owner, next) = match *record {
+                    DnsRecord::NSEC {
+                        ref domain,
+                        ref next_domain,
+                        ..
+                    } => (domain, next_domain),
+                    _ => continue,
+                };
+
+                let after_owner = dns_name::canonical_cmp(owner, name) == Ordering::Less;
+                let before_next = dns_name::canonical_cmp(name, next) == Ordering::Less;
+                // The last NSEC in the zone wraps around to the apex
+                let last = dns_name::canonical_cmp(next, owner) != Ordering::Greater;
+                if after_owner && (before_next || last) {
+                    if !records.contains(record) {
+                        records.push(record.clone());
+                        records.extend(self.signatures(owner, QueryType::NSEC));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Addresses for the names in NS and MX answers, where the zone has
+    /// them.
+    fn add_additional(&self, packet: &mut DnsPacket) {
+        let mut hosts = Vec::new();
+        for record in &packet.answers {
+            match *record {
+                DnsRecord::NS { ref host, .. } | DnsRecord::MX { ref host, .. } => {
+                    if dns_name::is_subdomain(host, &self.name) {
+                        hosts.push(host.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for host in hosts {
+            for qtype in &[QueryType::A, QueryType::AAAA] {
+                for record in self.rrset(&host, *qtype) {
+                    if !packet.resources.contains(&record) {
+                        packet.resources.push(record);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zone_parser::ZoneParser;
+
+    fn zone(text: &str) -> Zone {
+        let mut parser = ZoneParser::new("example.com");
+        parser.parse_str(text, "test.zone").unwrap();
+        Zone::new("example.com", parser.records).unwrap()
+    }
+
+    fn example() -> Zone {
+        zone(
+            "$TTL 3600
+@         SOA   ns1 hostmaster 1 7200 900 1209600 300
+@         NS    ns1
+@         MX    10 mail
+ns1       A     192.0.2.1
+mail      A     192.0.2.2
+www       CNAME web
+web       A     192.0.2.3
+out       CNAME www.example.net.
+a.b.c     A     192.0.2.4
+*.wild    TXT   \"wildcard\"
+*.wild    MX    10 mail
+sub       NS    ns.sub
+sub       NS    ns.example.net.
+ns.sub    A     192.0.2.5
+old       DNAME example.org.
+",
+        )
+    }
+
+    fn names(records: &[DnsRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| format!("{} {:?}", r.get_domain(), r.get_querytype()))
+            .collect()
+    }
+
+    #[test]
+    fn answers_with_data_and_additionals() {
+        let packet = example().answer("example.com", QueryType::MX, false);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(names(&packet.answers), vec!["example.com MX"]);
+        assert_eq!(names(&packet.resources), vec!["mail.example.com A"]);
+    }
+
+    #[test]
+    fn follows_cnames_inside_the_zone() {
+        let packet = example().answer("WWW.example.com", QueryType::A, false);
+        assert_eq!(
+            names(&packet.answers),
+            vec!["www.example.com CNAME", "web.example.com A"]
+        );
+
+        let packet = example().answer("out.example.com", QueryType::A, false);
+        assert_eq!(names(&packet.answers), vec!["out.example.com CNAME"]);
+    }
+
+    #[test]
+    fn refers_to_delegated_zones() {
+        let packet = example().answer("host.sub.example.com", QueryType::A, false);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            names(&packet.authorities),
+            vec!["sub.example.com NS", "sub.example.com NS"]
+        );
+        assert_eq!(names(&packet.resources), vec!["ns.sub.example.com A"]);
+    }
+
+    #[test]
+    fn answers_negatively_with_the_soa() {
+        let zone = example();
+
+        let packet = zone.answer("nope.example.com", QueryType::A, false);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(names(&packet.authorities), vec!["example.com SOA"]);
+        assert_eq!(packet.authorities[0].get_ttl(), 300);
+
+        // No AAAA, and an empty non-terminal, both exist without data
+        for name in &["web.example.com", "b.c.example.com"] {
+            let packet = zone.answer(name, QueryType::AAAA, false);
+            assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+            assert!(packet.answers.is_empty());
+            assert_eq!(names(&packet.authorities), vec!["example.com SOA"]);
+        }
+    }
+
+    #[test]
+    fn synthesises_from_wildcards() {
+        let zone = example();
+
+        let packet = zone.answer("x.y.wild.example.com", QueryType::TXT, false);
+        assert_eq!(names(&packet.answers), vec!["x.y.wild.example.com TXT"]);
+
+        let packet = zone.answer("x.wild.example.com", QueryType::MX, false);
+        assert_eq!(names(&packet.answers), vec!["x.wild.example.com MX"]);
+        assert_eq!(names(&packet.resources), vec!["mail.example.com A"]);
+
+        let packet = zone.answer("x.wild.example.com", QueryType::A, false);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+
+        // The wildcard doesn't cover names below a name that exists
+        let packet = zone.answer("x.c.example.com", QueryType::A, false);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn synthesises_cnames_from_dnames() {
+        let packet = example().answer("www.old.example.com", QueryType::A, false);
+        assert_eq!(
+            names(&packet.answers),
+            vec!["old.example.com DNAME", "www.old.example.com CNAME"]
+        );
+        match packet.answers[1] {
+            DnsRecord::CNAME { ref host, .. } => assert_eq!(host, "www.example.org"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn rejects_broken_zones() {
+        let mut parser = ZoneParser::new("example.com");
+        parser
+            .parse_str("$TTL 60\nwww A 192.0.2.1\nwww.example.net. A 192.0.2.1\n", "z")
+            .unwrap();
+        let records = parser.records;
+
+        let error = |records: &[DnsRecord]| match Zone::new("example.com", records.to_vec()) {
+            Ok(_) => panic!("expected the zone to be rejected"),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(
+            error(&records[..1]),
+            "zone example.com.: expected one SOA record at the apex, found 0"
+        );
+        assert_eq!(
+            error(&records),
+            "zone example.com.: www.example.net. is outside the zone"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::net::{IpAddr, SocketAddr};
+
+use network::Network;
+use tsig::TsigKey;
+
+/// A zone to serve authoritatively, from the `[[zones]]` config tables.
+/// Zones with primaries are secondaries, kept up to date by transferring
+/// them from there.
+#[derive(Clone, Debug)]
+pub struct ZoneConfig {
+    pub name: String,
+    // Zone file to load the records from; for a secondary, where the latest
+    // transfer is kept
+    pub file: Option<String>,
+    // Servers a secondary zone is transferred from
+    pub primaries: Vec<SocketAddr>,
+    // Clients that may transfer the zone with AXFR or IXFR
+    pub allow_transfer: Vec<Network>,
+    // Servers told with NOTIFY when the zone changes
+    pub notify: Vec<SocketAddr>,
+    // Servers besides the primaries whose NOTIFY a secondary zone heeds
+    pub allow_notify: Vec<Network>,
+    // Clients that may change the zone with UPDATE
+    pub allow_update: Vec<Network>,
+    // Names of the TSIG keys that may transfer or update the zone from
+    // anywhere
+    pub transfer_keys: Vec<String>,
+    pub update_keys: Vec<String>,
+    // Key a secondary signs its requests to the primaries with
+    pub key: Option<TsigKey>,
+}
+
+impl ZoneConfig {
+    pub fn new(name: &str) -> ZoneConfig {
+        ZoneConfig {
+            name: name.trim_end_matches('.').to_string(),
+            file: None,
+            primaries: Vec::new(),
+            allow_transfer: Vec::new(),
+            notify: Vec::new(),
+            allow_notify: Vec::new(),
+            allow_update: Vec::new(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            key: None,
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        !self.primaries.is_empty()
+    }
+
+    /// True if `client` may tell us about changes to the zone with NOTIFY.
+    pub fn allows_notify(&self, client: IpAddr) -> bool {
+        self.primaries
+            .iter()
+            .any(|p| Network::host(p.ip()).contains(client))
+            || self.allow_notify.iter().any(|n| n.contains(client))
+    }
+
+    /// True if `client` may transfer the zone, with a request signed with
+    /// `key` if it was.
+    pub fn allows_transfer(&self, client: IpAddr, key: Option<&str>) -> bool {
+        self.allow_transfer.iter().any(|n| n.contains(client)) || has_key(&self.transfer_keys, key)
+    }
+
+    /// True if `client` may change the zone, with an update signed with
+    /// `key` if it was.
+    pub fn allows_update(&self, client: IpAddr, key: Option<&str>) -> bool {
+        self.allow_update.iter().any(|n| n.contains(client)) || has_key(&self.update_keys, key)
+    }
+}
+
+fn has_key(keys: &[String], key: Option<&str>) -> bool {
+    match key {
+        Some(key) => keys.iter().any(|k| k.eq_ignore_ascii_case(key)),
+        None => false,
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+
+use dns_record::DnsRecord;
+use query_type::QueryType;
+use zone::Zone;
+
+/// The changes from one version of a zone to the next, in the shape IXFR
+/// sends them (RFC 1995 section 4).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneDiff {
+    // The old SOA, then the records that were removed
+    pub removed: Vec<DnsRecord>,
+    // The new SOA, then the records that were added
+    pub added: Vec<DnsRecord>,
+}
+
+fn serial(soa: &DnsRecord) -> u32 {
+    match *soa {
+        DnsRecord::SOA { serial, .. } => serial,
+        _ => 0,
+    }
+}
+
+impl ZoneDiff {
+    pub fn new(old: &Zone, new: &Zone) -> ZoneDiff {
+        let old_records = old.records();
+        let new_records = new.records();
+        let before = old_records.iter().collect::<HashSet<&DnsRecord>>();
+        let after = new_records.iter().collect::<HashSet<&DnsRecord>>();
+
+        let not_soa = |r: &&DnsRecord| r.get_querytype() != QueryType::SOA;
+        let mut removed = vec![old.soa().clone()];
+        removed.extend(
+            old_records
+                .iter()
+                .filter(not_soa)
+                .filter(|r| !after.contains(r))
+                .cloned(),
+        );
+        let mut added = vec![new.soa().clone()];
+        added.extend(
+            new_records
+                .iter()
+                .filter(not_soa)
+                .filter(|r| !before.contains(r))
+                .cloned(),
+        );
+
+        ZoneDiff {
+            removed: removed,
+            added: added,
+        }
+    }
+
+    pub fn from_serial(&self) -> u32 {
+        serial(&self.removed[0])
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        serial(&self.added[0])
+    }
+}
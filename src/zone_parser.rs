@@ -0,0 +1,1165 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use byte_packet_buffer::BytePacketBuffer;
+use dns_name;
+use dns_record::DnsRecord;
+use presentation;
+use query_type::QueryType;
+
+/// How deeply `$INCLUDE`s may nest, which also stops include loops.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The most records a single `$GENERATE` may create.
+const MAX_GENERATED: u32 = 65536;
+
+/// A word or quoted string, with its escapes left in place.
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+/// The tokens of one record or directive, which parentheses may spread
+/// over several lines.
+struct Entry {
+    tokens: Vec<Token>,
+    blank_owner: bool,
+}
+
+fn error(file: &str, token: &Token, msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}:{}:{}: {}", file, token.line, token.column, msg),
+    )
+}
+
+fn tokenize(text: &str, file: &str) -> Result<Vec<Entry>, Error> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut entries = Vec::new();
+    let mut tokens = Vec::new();
+    let mut blank_owner = false;
+    let mut open_parens = Vec::new();
+    let (mut line, mut column) = (1, 1);
+    let mut line_blank = chars.first().is_some_and(|&c| c == ' ' || c == '\t');
+
+    let mut i = 0;
+    while i < chars.len() {
+        let start = Token {
+            text: String::new(),
+            line: line,
+            column: column,
+        };
+
+        match chars[i] {
+            '\n' => {
+                if open_parens.is_empty() && !tokens.is_empty() {
+                    entries.push(Entry {
+                        tokens: mem::replace(&mut tokens, Vec::new()),
+                        blank_owner: blank_owner,
+                    });
+                }
+                i += 1;
+                line += 1;
+                column = 1;
+                line_blank = i < chars.len() && (chars[i] == ' ' || chars[i] == '\t');
+                continue;
+            }
+            ';' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            ' ' | '\t' | '\r' => {
+                i += 1;
+                column += 1;
+                continue;
+            }
+            '(' => {
+                open_parens.push(start);
+                i += 1;
+                column += 1;
+                continue;
+            }
+            ')' => {
+                if open_parens.pop().is_none() {
+                    return Err(error(file, &start, "unbalanced )"));
+                }
+                i += 1;
+                column += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut token = start;
+        if chars[i] == '"' {
+            i += 1;
+            column += 1;
+            loop {
+                if i >= chars.len() {
+                    return Err(error(file, &token, "unterminated string"));
+                }
+                let c = chars[i];
+                i += 1;
+                if c == '"' {
+                    column += 1;
+                    break;
+                }
+
+                token.text.push(c);
+                let escaped = c == '\\' && i < chars.len();
+                if escaped {
+                    token.text.push(chars[i]);
+                }
+                for &c in chars[i - 1..i + escaped as usize].iter() {
+                    if c == '\n' {
+                        line += 1;
+                        column = 1;
+                    } else {
+                        column += 1;
+                    }
+                }
+                if escaped {
+                    i += 1;
+                }
+            }
+        } else {
+            while i < chars.len() {
+                let c = chars[i];
+                if c.is_whitespace() || c == ';' || c == '(' || c == ')' || c == '"' {
+                    break;
+                }
+                token.text.push(c);
+                i += 1;
+                column += 1;
+                if c == '\\' && i < chars.len() && chars[i] != '\n' {
+                    token.text.push(chars[i]);
+                    i += 1;
+                    column += 1;
+                }
+            }
+        }
+
+        if tokens.is_empty() {
+            blank_owner = line_blank;
+        }
+        tokens.push(token);
+    }
+
+    if let Some(paren) = open_parens.last() {
+        return Err(error(file, paren, "unbalanced ("));
+    }
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            tokens: tokens,
+            blank_owner: blank_owner,
+        });
+    }
+    Ok(entries)
+}
+
+/// The byte a `\X` or `\DDD` escape at the start of `bytes` stands for, and
+/// how long the escape is.
+fn escape(bytes: &[u8]) -> Option<(u8, usize)> {
+    if bytes.len() >= 4 && bytes[1..4].iter().all(|b| b.is_ascii_digit()) {
+        let value = bytes[1..4]
+            .iter()
+            .fold(0u32, |acc, &b| acc * 10 + (b - b'0') as u32);
+        if value > 255 {
+            return None;
+        }
+        return Some((value as u8, 4));
+    }
+    bytes.get(1).map(|&b| (b, 2))
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            res.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let (b, len) = match escape(&bytes[i..]) {
+            Some(x) => x,
+            None => return None,
+        };
+        res.push(b);
+        i += len;
+    }
+    Some(res)
+}
+
+/// `text` as a domain name, relative to `origin` unless it ends in a dot.
+pub fn resolve_name(text: &str, origin: &str) -> Result<String, String> {
+    if text == "@" {
+        return Ok(origin.to_string());
+    }
+    if text == "." {
+        return Ok(String::new());
+    }
+
+    let bytes = text.as_bytes();
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut absolute = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => {
+                let (b, len) = match escape(&bytes[i..]) {
+                    Some(x) => x,
+                    None => return Err(format!("invalid escape in {}", text)),
+                };
+                if b == b'.' {
+                    return Err("escaped dots in labels aren't supported".to_string());
+                }
+                label.push(b);
+                i += len;
+            }
+            b'.' => {
+                if label.is_empty() {
+                    return Err(format!("empty label in {}", text));
+                }
+                labels.push(String::from_utf8_lossy(&label).into_owned());
+                label.clear();
+                absolute = i == bytes.len() - 1;
+                i += 1;
+            }
+            b => {
+                label.push(b);
+                i += 1;
+            }
+        }
+        if label.len() > 63 {
+            return Err(format!("label longer than 63 bytes in {}", text));
+        }
+    }
+    if !label.is_empty() {
+        labels.push(String::from_utf8_lossy(&label).into_owned());
+    }
+
+    let mut name = labels.join(".");
+    if !absolute && !origin.is_empty() {
+        name = format!("{}.{}", name, origin);
+    }
+    if dns_name::to_wire(&name).len() > 255 {
+        return Err(format!("{} is longer than 255 bytes", text));
+    }
+    Ok(name)
+}
+
+/// A TTL in seconds, or with BIND style units such as `1h30m`.
+fn parse_ttl(text: &str) -> Option<u32> {
+    if text.is_empty() {
+        return None;
+    }
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        return text.parse::<u32>().ok();
+    }
+
+    let mut total = 0u64;
+    let mut number = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0u64) * 10 + digit as u64);
+            if number > Some(u32::max_value() as u64) {
+                return None;
+            }
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        match number.take() {
+            Some(x) => total += x * unit,
+            None => return None,
+        }
+    }
+
+    if number.is_some() || total > u32::max_value() as u64 {
+        return None;
+    }
+    Some(total as u32)
+}
+
+/// Expands the `$`, `${offset,width,radix}` and `\$` in a `$GENERATE`
+/// template for iteration `i`.
+fn substitute(template: &str, i: u32) -> Result<String, String> {
+    let chars = template.chars().collect::<Vec<char>>();
+    let mut res = String::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        match chars[pos] {
+            '\\' if chars.get(pos + 1) == Some(&'$') => {
+                res.push('$');
+                pos += 2;
+            }
+            '\\' => {
+                // Other escapes are left for the name or data parser
+                res.extend(chars[pos..chars.len().min(pos + 2)].iter());
+                pos += 2;
+            }
+            '$' if chars.get(pos + 1) == Some(&'{') => {
+                let end = match chars[pos..].iter().position(|&c| c == '}') {
+                    Some(x) => pos + x,
+                    None => return Err(format!("unterminated modifier in {}", template)),
+                };
+                let modifier = chars[pos + 2..end].iter().collect::<String>();
+                let mut parts = modifier.split(',');
+                let offset = try!(parts
+                    .next()
+                    .and_then(|x| x.trim().parse::<i64>().ok())
+                    .ok_or(format!("invalid offset in {}", template)));
+                let width = try!(parts
+                    .next()
+                    .map_or(Some(0), |x| x.trim().parse::<usize>().ok())
+                    .ok_or(format!("invalid width in {}", template)));
+                let radix = parts.next().map_or("d", |x| x.trim());
+
+                let value = i as i64 + offset;
+                if value < 0 {
+                    return Err(format!("{} makes a negative number", template));
+                }
+                res.push_str(&match radix {
+                    "d" => format!("{:01$}", value, width),
+                    "o" => format!("{:01$o}", value, width),
+                    "x" => format!("{:01$x}", value, width),
+                    "X" => format!("{:01$X}", value, width),
+                    _ => return Err(format!("unknown radix {} in {}", radix, template)),
+                });
+                pos = end + 1;
+            }
+            '$' => {
+                res.push_str(&i.to_string());
+                pos += 1;
+            }
+            c => {
+                res.push(c);
+                pos += 1;
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Walks the fields of a record, reporting errors at the offending token.
+struct Fields<'a> {
+    file: &'a str,
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(file: &'a str, tokens: &'a [Token]) -> Fields<'a> {
+        Fields {
+            file: file,
+            tokens: tokens,
+            pos: 0,
+        }
+    }
+
+    fn error(&self, token: &Token, msg: &str) -> Error {
+        error(self.file, token, msg)
+    }
+
+    fn next(&mut self, what: &str) -> Result<&'a Token, Error> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => {
+                let last = &self.tokens[self.tokens.len() - 1];
+                Err(self.error(last, &format!("expected {} after {}", what, last.text)))
+            }
+        }
+    }
+
+    fn number<T: FromStr>(&mut self, what: &str) -> Result<T, Error> {
+        let token = try!(self.next(what));
+        token
+            .text
+            .parse::<T>()
+            .map_err(|_| self.error(token, &format!("expected {}, not {}", what, token.text)))
+    }
+
+    fn ttl(&mut self, what: &str) -> Result<u32, Error> {
+        let token = try!(self.next(what));
+        parse_ttl(&token.text)
+            .ok_or_else(|| self.error(token, &format!("expected {}, not {}", what, token.text)))
+    }
+
+    fn name(&mut self, what: &str, origin: &str) -> Result<String, Error> {
+        let token = try!(self.next(what));
+        resolve_name(&token.text, origin).map_err(|msg| self.error(token, &msg))
+    }
+
+    /// The remaining tokens, run together as binary data usually is.
+    fn joined(&mut self, what: &str) -> Result<(String, &'a Token), Error> {
+        let first = try!(self.next(what));
+        let mut text = first.text.clone();
+        while let Some(token) = self.tokens.get(self.pos) {
+            text.push_str(&token.text);
+            self.pos += 1;
+        }
+        Ok((text, first))
+    }
+
+    fn types(&mut self) -> Result<Vec<QueryType>, Error> {
+        let mut types = Vec::new();
+        while let Some(token) = self.tokens.get(self.pos) {
+            match QueryType::from_name(&token.text) {
+                Some(x) => types.push(x),
+                None => return Err(self.error(token, &format!("unknown type {}", token.text))),
+            }
+            self.pos += 1;
+        }
+        types.sort_by_key(|t| t.to_num());
+        types.dedup();
+        Ok(types)
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        match self.tokens.get(self.pos) {
+            Some(token) => Err(self.error(token, &format!("unexpected {}", token.text))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads RFC 1035 master files into records.
+pub struct ZoneParser {
+    pub origin: String,
+    pub records: Vec<DnsRecord>,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+    depth: usize,
+}
+
+impl ZoneParser {
+    pub fn new(origin: &str) -> ZoneParser {
+        ZoneParser {
+            origin: origin.trim_end_matches('.').to_string(),
+            records: Vec::new(),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            depth: 0,
+        }
+    }
+
+    /// All records in the zone file at `path`, with relative names taken to
+    /// be under `origin`.
+    pub fn load(path: &str, origin: &str) -> Result<Vec<DnsRecord>, Error> {
+        let mut parser = ZoneParser::new(origin);
+        try!(parser.parse_file(path));
+        Ok(parser.records)
+    }
+
+    pub fn parse_file(&mut self, path: &str) -> Result<(), Error> {
+        let mut text = String::new();
+        try!(File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))));
+        self.parse_str(&text, path)
+    }
+
+    /// Adds the records in `text`, naming `file` in errors. Included files
+    /// are looked up next to `file`.
+    pub fn parse_str(&mut self, text: &str, file: &str) -> Result<(), Error> {
+        for entry in try!(tokenize(text, file)) {
+            if entry.tokens[0].text.starts_with('$') {
+                try!(self.directive(&entry.tokens, file));
+                continue;
+            }
+
+            let record = try!(self.record(&entry.tokens, entry.blank_owner, file));
+            self.add(record);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, record: DnsRecord) {
+        self.last_owner = Some(record.get_domain().to_string());
+        self.last_ttl = Some(record.get_ttl());
+        self.records.push(record);
+    }
+
+    fn directive(&mut self, tokens: &[Token], file: &str) -> Result<(), Error> {
+        let mut fields = Fields::new(file, tokens);
+        let directive = try!(fields.next("a directive"));
+        match directive.text.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                self.origin = try!(fields.name("an origin", &self.origin));
+                fields.finish()
+            }
+            "$TTL" => {
+                self.default_ttl = Some(try!(fields.ttl("a TTL")));
+                fields.finish()
+            }
+            "$INCLUDE" => {
+                let token = try!(fields.next("a file name"));
+                let name = try!(unescape(&token.text)
+                    .ok_or_else(|| fields.error(token, "invalid escape in file name")));
+                let name = String::from_utf8_lossy(&name).into_owned();
+                let origin = match tokens.get(2) {
+                    Some(_) => Some(try!(fields.name("an origin", &self.origin))),
+                    None => None,
+                };
+                try!(fields.finish());
+                self.include(&name, origin, file, token)
+            }
+            "$GENERATE" => self.generate(tokens, file),
+            _ => Err(fields.error(
+                directive,
+                &format!("unknown directive {}", directive.text),
+            )),
+        }
+    }
+
+    fn include(
+        &mut self,
+        name: &str,
+        origin: Option<String>,
+        file: &str,
+        token: &Token,
+    ) -> Result<(), Error> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(error(file, token, "too many nested $INCLUDEs"));
+        }
+
+        let path = match Path::new(file).parent() {
+            Some(dir) => dir.join(name),
+            None => Path::new(name).to_path_buf(),
+        };
+
+        // The included file can't change the origin or owner of this one
+        // (RFC 1035 section 5.1)
+        let saved_origin = self.origin.clone();
+        let saved_owner = self.last_owner.clone();
+        if let Some(origin) = origin {
+            self.origin = origin;
+        }
+
+        self.depth += 1;
+        let res = self.parse_file(&path.to_string_lossy());
+        self.depth -= 1;
+
+        self.origin = saved_origin;
+        self.last_owner = saved_owner;
+        res
+    }
+
+    /// `$GENERATE start-stop[/step] lhs [ttl] [class] type rhs`, as BIND
+    /// has it.
+    fn generate(&mut self, tokens: &[Token], file: &str) -> Result<(), Error> {
+        if tokens.len() < 5 {
+            let last = &tokens[tokens.len() - 1];
+            return Err(error(file, last, "$GENERATE needs a range, owner, type and data"));
+        }
+
+        let range = &tokens[1];
+        let bad_range = || error(file, range, &format!("invalid range {}", range.text));
+        let (span, step) = match range.text.find('/') {
+            Some(x) => (&range.text[..x], try!(range.text[x + 1..].parse::<u32>()
+                .map_err(|_| bad_range()))),
+            None => (range.text.as_str(), 1),
+        };
+        let mut bounds = span.splitn(2, '-').map(|x| x.parse::<u32>().ok());
+        let (start, stop) = match (bounds.next(), bounds.next()) {
+            (Some(Some(start)), Some(Some(stop))) if start <= stop && step > 0 => (start, stop),
+            _ => return Err(bad_range()),
+        };
+        if (stop - start) / step >= MAX_GENERATED {
+            return Err(error(file, range, "$GENERATE range is too large"));
+        }
+
+        let lhs = &tokens[2];
+        let rhs = &tokens[tokens.len() - 1];
+        let mut i = start;
+        loop {
+            let mut record_tokens = vec![lhs.clone()];
+            record_tokens[0].text =
+                try!(substitute(&lhs.text, i).map_err(|e| error(file, lhs, &e)));
+            record_tokens.extend(tokens[3..tokens.len() - 1].iter().cloned());
+            let mut data = rhs.clone();
+            data.text = try!(substitute(&rhs.text, i).map_err(|e| error(file, rhs, &e)));
+            record_tokens.push(data);
+
+            let record = try!(self.record(&record_tokens, false, file));
+            self.add(record);
+
+            if stop - i < step {
+                break;
+            }
+            i += step;
+        }
+        Ok(())
+    }
+
+    fn record(&self, tokens: &[Token], blank_owner: bool, file: &str) -> Result<DnsRecord, Error> {
+        let mut fields = Fields::new(file, tokens);
+        let domain = if blank_owner {
+            match self.last_owner {
+                Some(ref x) => x.clone(),
+                None => return Err(fields.error(&tokens[0], "no previous owner name to use")),
+            }
+        } else {
+            try!(fields.name("an owner name", &self.origin))
+        };
+
+        // The TTL and class may come in either order, and both are optional
+        let mut ttl = None;
+        let mut has_class = false;
+        let (qtype, type_token) = loop {
+            let token = try!(fields.next("a record type"));
+            if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(try!(parse_ttl(&token.text)
+                    .ok_or_else(|| fields.error(token, &format!("invalid TTL {}", token.text)))));
+                continue;
+            }
+            if !has_class {
+                if let Some(class) = presentation::parse_class(&token.text) {
+                    if class != 1 {
+                        return Err(fields.error(token, "only class IN is supported"));
+                    }
+                    has_class = true;
+                    continue;
+                }
+            }
+            match QueryType::from_name(&token.text) {
+                Some(x) => break (x, token),
+                None => {
+                    return Err(fields.error(token, &format!("unknown type {}", token.text)));
+                }
+            }
+        };
+
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(x) => x,
+            None => return Err(fields.error(type_token, "no TTL given and no $TTL set")),
+        };
+
+        if tokens.get(fields.pos).is_some_and(|t| t.text == "\\#") {
+            fields.pos += 1;
+            return generic(&mut fields, domain, qtype, ttl, type_token);
+        }
+
+        let origin = &self.origin;
+        let record = match qtype {
+            QueryType::A => {
+                let token = try!(fields.next("an IPv4 address"));
+                DnsRecord::A {
+                    domain: domain,
+                    addr: try!(token.text.parse::<Ipv4Addr>().map_err(|_| {
+                        fields.error(token, &format!("invalid IPv4 address {}", token.text))
+                    })),
+                    ttl: ttl,
+                }
+            }
+            QueryType::AAAA => {
+                let token = try!(fields.next("an IPv6 address"));
+                DnsRecord::AAAA {
+                    domain: domain,
+                    addr: try!(token.text.parse::<Ipv6Addr>().map_err(|_| {
+                        fields.error(token, &format!("invalid IPv6 address {}", token.text))
+                    })),
+                    ttl: ttl,
+                }
+            }
+            QueryType::NS => DnsRecord::NS {
+                domain: domain,
+                host: try!(fields.name("a host name", origin)),
+                ttl: ttl,
+            },
+            QueryType::CNAME => DnsRecord::CNAME {
+                domain: domain,
+                host: try!(fields.name("a host name", origin)),
+                ttl: ttl,
+            },
+            QueryType::PTR => DnsRecord::PTR {
+                domain: domain,
+                host: try!(fields.name("a host name", origin)),
+                ttl: ttl,
+            },
+            QueryType::DNAME => DnsRecord::DNAME {
+                domain: domain,
+                host: try!(fields.name("a target name", origin)),
+                ttl: ttl,
+            },
+            QueryType::MX => DnsRecord::MX {
+                domain: domain,
+                priority: try!(fields.number("a preference")),
+                host: try!(fields.name("a mail exchange", origin)),
+                ttl: ttl,
+            },
+            QueryType::TXT => {
+                let mut data = Vec::new();
+                loop {
+                    let token = try!(fields.next("a character string"));
+                    let string = try!(unescape(&token.text)
+                        .ok_or_else(|| fields.error(token, "invalid escape")));
+                    if string.len() > 255 {
+                        return Err(fields.error(token, "string longer than 255 bytes"));
+                    }
+                    data.push(string);
+                    if fields.pos == tokens.len() {
+                        break;
+                    }
+                }
+                DnsRecord::TXT {
+                    domain: domain,
+                    data: data,
+                    ttl: ttl,
+                }
+            }
+            QueryType::SOA => DnsRecord::SOA {
+                domain: domain,
+                m_name: try!(fields.name("a primary server name", origin)),
+                r_name: try!(fields.name("a mailbox name", origin)),
+                serial: try!(fields.number("a serial")),
+                refresh: try!(fields.ttl("a refresh time")),
+                retry: try!(fields.ttl("a retry time")),
+                expire: try!(fields.ttl("an expire time")),
+                minimum: try!(fields.ttl("a minimum TTL")),
+                ttl: ttl,
+            },
+            QueryType::DS => {
+                let key_tag = try!(fields.number("a key tag"));
+                let algorithm = try!(fields.number("an algorithm"));
+                let digest_type = try!(fields.number("a digest type"));
+                let (digest, token) = try!(fields.joined("a digest"));
+                DnsRecord::DS {
+                    domain: domain,
+                    key_tag: key_tag,
+                    algorithm: algorithm,
+                    digest_type: digest_type,
+                    digest: try!(presentation::parse_hex(&digest)
+                        .ok_or_else(|| fields.error(token, "invalid hex digest"))),
+                    ttl: ttl,
+                }
+            }
+            QueryType::DNSKEY => {
+                let flags = try!(fields.number("flags"));
+                let protocol = try!(fields.number("a protocol"));
+                let algorithm = try!(fields.number("an algorithm"));
+                let (key, token) = try!(fields.joined("a public key"));
+                DnsRecord::DNSKEY {
+                    domain: domain,
+                    flags: flags,
+                    protocol: protocol,
+                    algorithm: algorithm,
+                    public_key: try!(presentation::parse_base64(&key)
+                        .ok_or_else(|| fields.error(token, "invalid base64 public key"))),
+                    ttl: ttl,
+                }
+            }
+            QueryType::RRSIG => {
+                let token = try!(fields.next("a covered type"));
+                let type_covered = try!(QueryType::from_name(&token.text)
+                    .ok_or_else(|| fields.error(token, &format!("unknown type {}", token.text))));
+                let algorithm = try!(fields.number("an algorithm"));
+                let labels = try!(fields.number("a label count"));
+                let original_ttl = try!(fields.ttl("an original TTL"));
+                let mut times = Vec::new();
+                for what in &["an expiration time", "an inception time"] {
+                    let token = try!(fields.next(what));
+                    let invalid = format!("invalid time {}", token.text);
+                    times.push(try!(presentation::parse_timestamp(&token.text)
+                        .ok_or_else(|| fields.error(token, &invalid))));
+                }
+                let key_tag = try!(fields.number("a key tag"));
+                let signer_name = try!(fields.name("a signer name", origin));
+                let (signature, token) = try!(fields.joined("a signature"));
+                DnsRecord::RRSIG {
+                    domain: domain,
+                    type_covered: type_covered,
+                    algorithm: algorithm,
+                    labels: labels,
+                    original_ttl: original_ttl,
+                    expiration: times[0],
+                    inception: times[1],
+                    key_tag: key_tag,
+                    signer_name: signer_name,
+                    signature: try!(presentation::parse_base64(&signature)
+                        .ok_or_else(|| fields.error(token, "invalid base64 signature"))),
+                    ttl: ttl,
+                }
+            }
+            QueryType::NSEC => DnsRecord::NSEC {
+                domain: domain,
+                next_domain: try!(fields.name("a next domain name", origin)),
+                types: try!(fields.types()),
+                ttl: ttl,
+            },
+            QueryType::NSEC3 => {
+                let hash_algorithm = try!(fields.number("a hash algorithm"));
+                let flags = try!(fields.number("flags"));
+                let iterations = try!(fields.number("an iteration count"));
+                let token = try!(fields.next("a salt"));
+                let salt = if token.text == "-" {
+                    Vec::new()
+                } else {
+                    try!(presentation::parse_hex(&token.text)
+                        .ok_or_else(|| fields.error(token, "invalid hex salt")))
+                };
+                let token = try!(fields.next("a next hashed owner name"));
+                let next_hashed = try!(presentation::parse_base32hex(&token.text)
+                    .ok_or_else(|| fields.error(token, "invalid base32hex hash")));
+                DnsRecord::NSEC3 {
+                    domain: domain,
+                    hash_algorithm: hash_algorithm,
+                    flags: flags,
+                    iterations: iterations,
+                    salt: salt,
+                    next_hashed: next_hashed,
+                    types: try!(fields.types()),
+                    ttl: ttl,
+                }
+            }
+            QueryType::OPT => {
+                return Err(fields.error(type_token, "OPT records can't appear in zone files"));
+            }
+            QueryType::UNKNOWN(_) => {
+                return Err(fields.error(
+                    type_token,
+                    &format!("{} records need the \\# generic syntax", type_token.text),
+                ));
+            }
+        };
+
+        try!(fields.finish());
+        Ok(record)
+    }
+}
+
+/// A record given as `\# length hex` (RFC 3597 section 5), which any type
+/// may use.
+fn generic(
+    fields: &mut Fields,
+    domain: String,
+    qtype: QueryType,
+    ttl: u32,
+    type_token: &Token,
+) -> Result<DnsRecord, Error> {
+    if qtype == QueryType::OPT {
+        return Err(fields.error(type_token, "OPT records can't appear in zone files"));
+    }
+
+    let len = try!(fields.number::<u16>("a data length"));
+    let data = if fields.pos < fields.tokens.len() {
+        let (hex, token) = try!(fields.joined("hex data"));
+        try!(presentation::parse_hex(&hex).ok_or_else(|| fields.error(token, "invalid hex data")))
+    } else {
+        Vec::new()
+    };
+    if data.len() != len as usize {
+        return Err(fields.error(
+            type_token,
+            &format!("data is {} bytes long, not {}", data.len(), len),
+        ));
+    }
+
+    // Known types are turned into their usual record by reading them the
+    // way they'd arrive off the wire
+    let wire_name = dns_name::to_wire(&domain);
+    let mut buffer = BytePacketBuffer::with_size(wire_name.len() + 10 + data.len());
+    try!(buffer.write_bytes(&wire_name));
+    try!(buffer.write_u16(qtype.to_num()));
+    try!(buffer.write_u16(1));
+    try!(buffer.write_u32(ttl));
+    try!(buffer.write_u16(len));
+    try!(buffer.write_bytes(&data));
+    let end = buffer.pos();
+
+    buffer.pos = 0;
+    match DnsRecord::read(&mut buffer) {
+        Ok(ref record) if buffer.pos() == end => Ok(record.clone()),
+        _ => Err(fields.error(
+            type_token,
+            &format!("invalid data for a {} record", type_token.text),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn parse(text: &str) -> Result<Vec<DnsRecord>, Error> {
+        let mut parser = ZoneParser::new("example.com.");
+        try!(parser.parse_str(text, "test.zone"));
+        Ok(parser.records)
+    }
+
+    fn error_message(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_a_zone() {
+        let records = parse(
+            "$TTL 1h
+@   IN  SOA ns1 hostmaster.example.com. (
+            2024010101 ; serial
+            2h 30m 2w 300 )
+        NS  ns1
+        NS  ns2.example.net.
+        MX  10 mail
+ns1 300 A   192.0.2.1
+    IN 600 AAAA 2001:db8::1
+www CNAME @
+txt TXT \"v=spf1 -all\" plain \"say \\\"hi\\\"\\009\"
+$ORIGIN sub
+a\\032b A 192.0.2.2 ; comment
+",
+        ).unwrap();
+
+        assert_eq!(records.len(), 9);
+        assert_eq!(
+            records[0],
+            DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 1800,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[1].get_domain(), "example.com");
+        assert_eq!(
+            records[2],
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns2.example.net".to_string(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            records[3],
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[4].get_ttl(), 300);
+        assert_eq!(
+            records[5],
+            DnsRecord::AAAA {
+                domain: "ns1.example.com".to_string(),
+                addr: "2001:db8::1".parse().unwrap(),
+                ttl: 600,
+            }
+        );
+        assert_eq!(
+            records[6],
+            DnsRecord::CNAME {
+                domain: "www.example.com".to_string(),
+                host: "example.com".to_string(),
+                ttl: 3600,
+            }
+        );
+        assert_eq!(
+            records[7],
+            DnsRecord::TXT {
+                domain: "txt.example.com".to_string(),
+                data: vec![
+                    b"v=spf1 -all".to_vec(),
+                    b"plain".to_vec(),
+                    b"say \"hi\"\t".to_vec(),
+                ],
+                ttl: 3600,
+            }
+        );
+        assert_eq!(records[8].get_domain(), "a b.sub.example.com");
+    }
+
+    #[test]
+    fn reads_back_presented_records() {
+        let text = "$TTL 300
+@ DS 60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118
+@ DNSKEY 256 3 8 AwEAAaz/ AQAB
+@ RRSIG A 8 2 300 20240229000000 1709164800 12345 example.com. ZXhh bXBsZQ==
+@ NSEC www A NS SOA RRSIG NSEC
+@ NSEC3 1 0 10 - CPNMUOJ1E8 A AAAA
+@ NSEC3 1 1 0 AABB CO
+@ TYPE65 \\# 3 010203
+@ TYPE99 \\# 0
+@ MX \\# 6 000A 026D7800
+";
+        let records = parse(text).unwrap();
+        assert_eq!(records.len(), 9);
+        assert_eq!(
+            records[2],
+            DnsRecord::RRSIG {
+                domain: "example.com".to_string(),
+                type_covered: QueryType::A,
+                algorithm: 8,
+                labels: 2,
+                original_ttl: 300,
+                expiration: 1709164800,
+                inception: 1709164800,
+                key_tag: 12345,
+                signer_name: "example.com".to_string(),
+                signature: b"example".to_vec(),
+                ttl: 300,
+            }
+        );
+        assert_eq!(
+            records[8],
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mx".to_string(),
+                ttl: 300,
+            }
+        );
+
+        // Whatever the records print as has to parse back to the same thing
+        let presented = records
+            .iter()
+            .map(|record| record.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        assert_eq!(parse(&presented).unwrap(), records);
+    }
+
+    #[test]
+    fn generates_records() {
+        let records = parse(
+            "$TTL 60
+$GENERATE 1-9/4 host-${10,3,x} A 192.0.2.$
+$GENERATE 0-1 $.2.0.192.in-addr.arpa. PTR host\\$$.example.com.",
+        ).unwrap();
+
+        let names = records
+            .iter()
+            .map(|r| r.get_domain().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            names,
+            vec![
+                "host-00b.example.com",
+                "host-00f.example.com",
+                "host-013.example.com",
+                "0.2.0.192.in-addr.arpa",
+                "1.2.0.192.in-addr.arpa",
+            ]
+        );
+        assert_eq!(
+            records[2],
+            DnsRecord::A {
+                domain: "host-013.example.com".to_string(),
+                addr: "192.0.2.9".parse().unwrap(),
+                ttl: 60,
+            }
+        );
+        assert_eq!(
+            records[4],
+            DnsRecord::PTR {
+                domain: "1.2.0.192.in-addr.arpa".to_string(),
+                host: "host$1.example.com".to_string(),
+                ttl: 60,
+            }
+        );
+    }
+
+    #[test]
+    fn includes_files() {
+        let dir = env::temp_dir().join(format!("zone_parser_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.zone");
+        let hosts = dir.join("hosts.zone");
+        fs::File::create(&main)
+            .unwrap()
+            .write_all(b"$TTL 60\nwww A 192.0.2.1\n$INCLUDE hosts.zone lan\n  A 192.0.2.3\n")
+            .unwrap();
+        fs::File::create(&hosts)
+            .unwrap()
+            .write_all(b"printer A 192.0.2.2\n$ORIGIN elsewhere.\n")
+            .unwrap();
+
+        let records = ZoneParser::load(&main.to_string_lossy(), "example.com").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names = records
+            .iter()
+            .map(|r| r.get_domain().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            names,
+            vec![
+                "www.example.com",
+                "printer.lan.example.com",
+                "www.example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        assert_eq!(
+            error_message("$TTL 60\nwww A 192.0.2.1\n   MX ten mail\n"),
+            "test.zone:3:7: expected a preference, not ten"
+        );
+        assert_eq!(
+            error_message("www A 192.0.2.1\n"),
+            "test.zone:1:5: no TTL given and no $TTL set"
+        );
+        assert_eq!(
+            error_message("$TTL 60\n  A 192.0.2.1\n"),
+            "test.zone:2:3: no previous owner name to use"
+        );
+        assert_eq!(
+            error_message("$TTL 60\n@ SOA ns1 host ( 1 2 3 4\n"),
+            "test.zone:2:16: unbalanced ("
+        );
+        assert_eq!(
+            error_message("$TTL 60\n@ TXT \"open\n"),
+            "test.zone:2:7: unterminated string"
+        );
+        assert_eq!(
+            error_message("$TTL 60\n@ CH A 192.0.2.1\n"),
+            "test.zone:2:3: only class IN is supported"
+        );
+        assert_eq!(
+            error_message("$TTL 60\n@ A 192.0.2.1 extra\n"),
+            "test.zone:2:15: unexpected extra"
+        );
+        assert_eq!(
+            error_message("$TTL 60\n@ TYPE65 \\# 2 01\n"),
+            "test.zone:2:3: data is 1 bytes long, not 2"
+        );
+        assert_eq!(
+            error_message("$FOO bar\n"),
+            "test.zone:1:1: unknown directive $FOO"
+        );
+    }
+}